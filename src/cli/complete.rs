@@ -0,0 +1,83 @@
+use clap::ValueEnum;
+use clap_complete::Shell;
+
+/// Values that are only known at completion time
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum CompletionKind {
+    /// Server names and aliases visible from the current scope
+    Servers,
+    /// Names of all scopes
+    Scopes,
+    /// `SERVER:PATH` arguments, the paths are listed by the server
+    RemotePaths,
+}
+
+/// Shell code appended to the static completions, it asks the binary
+/// for the values that depend on the configuration
+pub fn dynamic_script(shell: Shell, bin: &str) -> Option<String> {
+    let script = match shell {
+        Shell::Bash => format!(
+            r#"
+_{bin}_dynamic() {{
+    _{bin} "$@"
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    if [[ ${{COMP_CWORD}} -eq 1 && "$cur" != -* ]]; then
+        COMPREPLY+=( $(compgen -W "$({bin} complete servers 2>/dev/null)" -- "$cur") )
+    elif [[ ${{COMP_CWORD}} -eq 2 && "${{COMP_WORDS[1]}}" == "use" ]] \
+        || [[ "${{COMP_WORDS[1]}}" =~ ^(rm|remove|set|ping|watch|warm)$ && "$prev" =~ ^(-s|--scope)$ ]]; then
+        COMPREPLY=( $(compgen -W "$({bin} complete scopes 2>/dev/null)" -- "$cur") )
+    elif [[ "${{COMP_WORDS[1]}}" == "cp" && "$cur" != -* ]]; then
+        # ':' splits words in bash, so the whole argument is taken from the line
+        local word="${{COMP_LINE:0:COMP_POINT}}" IFS=$'\n'
+        word="${{word##* }}"
+        COMPREPLY=( $({bin} complete remote-paths "$word" 2>/dev/null) )
+        [[ "$word" == *:* ]] && COMPREPLY=( "${{COMPREPLY[@]#"${{word%:*}}:"}}" )
+        compopt -o nospace
+    fi
+}}
+
+complete -F _{bin}_dynamic -o bashdefault -o default {bin}
+"#
+        ),
+        Shell::Zsh => format!(
+            r#"
+_{bin}_values() {{
+    local -a values
+    if [[ $words[2] == cp ]] && (( CURRENT > 2 )) && [[ $words[CURRENT] != -* ]]; then
+        values=(${{(f)"$({bin} complete remote-paths "$words[CURRENT]" 2>/dev/null)"}})
+        compadd -S '' -a values
+        return
+    elif (( CURRENT == 2 )) && [[ $words[2] != -* ]]; then
+        values=(${{(f)"$({bin} complete servers 2>/dev/null)"}})
+    elif (( CURRENT == 3 )) && [[ $words[2] == use ]] \
+        || [[ $words[2] == (rm|remove|set|ping|watch|warm) && $words[CURRENT-1] == (-s|--scope) ]]; then
+        values=(${{(f)"$({bin} complete scopes 2>/dev/null)"}})
+    fi
+    compadd -a values
+}}
+
+_{bin}_dynamic() {{
+    _{bin} "$@"
+    _{bin}_values
+}}
+
+if [ "$funcstack[1]" = "_{bin}" ]; then
+    _{bin}_values
+else
+    compdef _{bin}_dynamic {bin}
+fi
+"#
+        ),
+        Shell::Fish => format!(
+            r#"
+complete -c {bin} -n "__fish_{bin}_needs_command" -f -a "({bin} complete servers 2>/dev/null)"
+complete -c {bin} -n "__fish_{bin}_using_subcommand use" -f -a "({bin} complete scopes 2>/dev/null)"
+complete -c {bin} -n "__fish_{bin}_using_subcommand rm; or __fish_{bin}_using_subcommand set; or __fish_{bin}_using_subcommand ping; or __fish_{bin}_using_subcommand watch; or __fish_{bin}_using_subcommand warm" -s s -l scope -f -r -a "({bin} complete scopes 2>/dev/null)"
+complete -c {bin} -n "__fish_{bin}_using_subcommand cp" -f -a "({bin} complete remote-paths (commandline -ct) 2>/dev/null)"
+"#
+        ),
+        _ => return None,
+    };
+
+    Some(script)
+}
@@ -1,16 +1,83 @@
 use anyhow::Result;
+use clap::CommandFactory;
 use clap::Parser;
 use env_logger::{Target, WriteStyle};
 use log::LevelFilter;
-use std::io::Write;
+use std::env;
+use std::ffi::OsString;
+use std::io::{IsTerminal, Write};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use crate::{cli::parser::Cli, execute_subcommand, handle_server_connection};
+use crate::cli::output::{ExplainedError, JsonError, split_hint};
+use crate::cli::pager;
+use crate::error::{CliError, Failure};
+use crate::storage::config::Config;
+use crate::storage::context::active_scope;
+use crate::storage::provider::{CONFIG_PATH, StorageProvider};
+use crate::{cli::parser::Cli, execute_subcommand, handle_server_connection, pick_server};
 
-pub fn start_cli() -> Result<()> {
-    let args = Cli::parse();
-    setup_logging(args.verbose);
+static QUIET: AtomicBool = AtomicBool::new(false);
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+static DNS_CACHE: AtomicBool = AtomicBool::new(true);
+static REPLACE_HOST_KEY: AtomicBool = AtomicBool::new(false);
+static EXPLAIN: AtomicBool = AtomicBool::new(false);
+static HINTS: AtomicBool = AtomicBool::new(true);
+static ASCII: AtomicBool = AtomicBool::new(false);
 
-    if let Err(err) = match args {
+/// Runs the command line, an error is printed and turned into the exit code
+pub fn start_cli() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            let hints = HINTS.load(Ordering::Relaxed);
+            if JSON_ERRORS.load(Ordering::Relaxed) {
+                let mut json = JsonError::from(&err);
+                if !hints {
+                    json.hint = None;
+                }
+                eprint!("{}", json);
+            } else if EXPLAIN.load(Ordering::Relaxed) {
+                eprint!("{}", explain(&err, hints));
+            } else if hints {
+                eprintln!("{}", err);
+            } else {
+                eprintln!("{}", split_hint(&err.to_string()).0);
+            }
+            match err.downcast_ref::<CliError>() {
+                // Passed on like ssh does (truncated the same way as by shells)
+                Some(CliError::ScriptFailed(status)) => ExitCode::from(*status as u8),
+                _ => Failure::of(&err).into(),
+            }
+        }
+    }
+}
+
+fn run() -> Result<()> {
+    let raw_args: Vec<OsString> = env::args_os().collect();
+    // The flags take effect before the config is read for command aliases, so its
+    // errors are reported as requested. Arguments appended to an alias may only parse
+    // once it's expanded, logging is then set up afterwards.
+    let typed = Cli::try_parse_from(&raw_args).ok();
+    let logging = typed.is_some();
+    if let Some(args) = &typed {
+        apply_flags(args);
+        setup_logging(args.verbose, args.quiet);
+    }
+    let args = match expand_alias(&raw_args)? {
+        Some(expanded) => Cli::parse_from(expanded),
+        None => match typed {
+            Some(args) => args,
+            None => Cli::parse_from(raw_args),
+        },
+    };
+    apply_flags(&args);
+    if !logging {
+        setup_logging(args.verbose, args.quiet);
+    }
+    let paged = args.paged();
+
+    match args {
         Cli {
             server: Some(uri),
             remote_cmd,
@@ -21,31 +88,109 @@ pub fn start_cli() -> Result<()> {
             subcommand: Some(cmd),
             ..
         } => {
-            if let Some(output) = execute_subcommand(cmd)? {
-                print!("{}", output);
+            match execute_subcommand(cmd)? {
+                Some(output) if paged => pager::print(&output.to_string()),
+                Some(output) => print!("{}", output),
+                None => {}
             }
 
             Ok(())
         }
-        _ => Ok(()),
-    } {
-        eprintln!("{}", err);
+        // Without arguments, a server is picked interactively when possible
+        Cli { conn_flags, .. } if std::io::stdin().is_terminal() => pick_server(conn_flags),
+        _ => Cli::command().print_help().map_err(Into::into),
     }
+}
+
+/// Whether informational messages are suppressed
+#[inline]
+pub(crate) fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Whether resolved addresses may be taken from (and saved to) the cache
+#[inline]
+pub(crate) fn use_dns_cache() -> bool {
+    DNS_CACHE.load(Ordering::Relaxed)
+}
+
+/// Whether a changed server key replaces the recorded one without asking
+#[inline]
+pub(crate) fn replace_host_key() -> bool {
+    REPLACE_HOST_KEY.load(Ordering::Relaxed)
+}
+
+/// Whether `--ascii` was given (the config may ask for it as well)
+#[inline]
+pub(crate) fn ascii_output() -> bool {
+    ASCII.load(Ordering::Relaxed)
+}
+
+/// The error with what was going on: the command, the config and the scope
+/// (looked up again, unless that's what failed)
+fn explain(err: &anyhow::Error, hints: bool) -> ExplainedError<'_> {
+    let scope = Config::load_from_file()
+        .ok()
+        .and_then(|config| active_scope(&config).ok());
+
+    ExplainedError {
+        error: err,
+        hints,
+        command: shlex::try_join(env::args().collect::<Vec<_>>().iter().map(String::as_str))
+            .unwrap_or_default(),
+        config: &CONFIG_PATH,
+        scope,
+    }
+}
+
+/// Stores the global flags for the rest of the run
+fn apply_flags(args: &Cli) {
+    QUIET.store(args.quiet, Ordering::Relaxed);
+    JSON_ERRORS.store(args.json_output(), Ordering::Relaxed);
+    DNS_CACHE.store(!args.no_dns_cache, Ordering::Relaxed);
+    REPLACE_HOST_KEY.store(args.replace_host_key, Ordering::Relaxed);
+    EXPLAIN.store(args.explain, Ordering::Relaxed);
+    HINTS.store(!args.no_hints, Ordering::Relaxed);
+    ASCII.store(args.ascii, Ordering::Relaxed);
+}
+
+/// The arguments with a command alias from the config (the first argument) replaced
+/// by its invocation, `None` if there is no such alias. Subcommands can't be shadowed,
+/// and the expansion isn't expanded again.
+fn expand_alias(args: &[OsString]) -> Result<Option<Vec<OsString>>> {
+    let Some(name) = args.get(1).and_then(|arg| arg.to_str()) else {
+        return Ok(None);
+    };
+    if name.starts_with('-') || Cli::command().find_subcommand(name).is_some() {
+        return Ok(None);
+    }
+    let config = Config::load_from_file()?;
+    let Some(invocation) = config.command_aliases.get(name) else {
+        return Ok(None);
+    };
+    let expanded = shlex::split(invocation).ok_or_else(|| CliError::InvalidAlias(name.into()))?;
+    let mut args = args.to_vec();
+    args.splice(1..2, expanded.into_iter().map(OsString::from));
 
-    Ok(())
+    Ok(Some(args))
 }
 
 #[inline]
-fn setup_logging(verbose: u8) {
-    let log_level = match verbose {
-        0 => LevelFilter::Warn,
-        1 => LevelFilter::Info,
-        _ => LevelFilter::Debug,
+fn setup_logging(verbose: u8, quiet: bool) {
+    // Dependencies (e.g. russh) only get verbose at the highest level
+    let (own_level, deps_level) = match verbose {
+        _ if quiet => (LevelFilter::Error, LevelFilter::Error),
+        0 => (LevelFilter::Warn, LevelFilter::Warn),
+        1 => (LevelFilter::Info, LevelFilter::Warn),
+        2 => (LevelFilter::Debug, LevelFilter::Info),
+        _ => (LevelFilter::Trace, LevelFilter::Trace),
     };
     env_logger::builder()
-        .filter(None, log_level)
+        .filter(None, deps_level)
+        .filter(Some(env!("CARGO_CRATE_NAME")), own_level)
         .write_style(WriteStyle::Auto)
-        .target(Target::Stdout)
+        // Keeps the output of commands clean for pipes
+        .target(Target::Stderr)
         .format(|buf, record| {
             let lvl = record.level();
             let color = buf.default_level_style(lvl);
@@ -1,86 +1,1613 @@
+use std::collections::HashMap;
 use std::fmt::{self, Display, Write};
+use std::iter;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use std::time::Duration;
 
-use crate::storage::config::{Scope, ScopedServer, Server, ServerEntry};
+use crate::cli::control;
+use crate::client::data::{Source, TracedField};
+use crate::client::forward::{ForwardState, ForwardStatus};
+use crate::client::mdns::Announced;
+use crate::client::preflight::{Step, StepStatus};
+use crate::client::probe::Probe;
+use crate::error::Failure;
+use crate::storage::config::{
+    Config, Finding, FoundServer, Scope, ScopedServer, Server, ServerChange, ServerEntry, Severity,
+    StaleReason, StaleServer, is_pattern,
+};
+use crate::storage::context::{HistoryEntry, SCOPE_ENV, ScopeOrigin, last_connected};
+use crate::storage::csv;
+use crate::storage::import::LIST_SEPARATOR;
+use crate::storage::provider::StorageProvider;
+use crate::storage::theme::{Color, Theme, ThemePreset};
 use indexmap::IndexMap;
 use itertools::{Either, Itertools};
 use owo_colors::{OwoColorize, Style, Styled};
+use serde::Serialize;
 
-macro_rules! define_styles {
-    ($($name:ident: $color:ident),* $(,)?) => {
-        $(const $name: Style = Style::new().$color();)*
+/// Styles of the output elements
+struct Palette {
+    scope: Style,
+    server: Style,
+    attr: Style,
+    value: Style,
+    ok: Style,
+    failed: Style,
+    warning: Style,
+}
+
+impl Palette {
+    const DARK: Self = Self {
+        scope: Style::new().bright_magenta(),
+        server: Style::new().bright_green(),
+        attr: Style::new().blue(),
+        value: Style::new().white(),
+        ok: Style::new().green(),
+        failed: Style::new().red(),
+        warning: Style::new().yellow(),
+    };
+    /// Values in the terminal's own color, yellow is hardly readable on white
+    const LIGHT: Self = Self {
+        scope: Style::new().magenta(),
+        server: Style::new().green(),
+        attr: Style::new().blue(),
+        value: Style::new(),
+        ok: Style::new().green(),
+        failed: Style::new().red(),
+        warning: Style::new().truecolor(175, 95, 0),
+    };
+    const PLAIN: Self = Self {
+        scope: Style::new(),
+        server: Style::new(),
+        attr: Style::new(),
+        value: Style::new(),
+        ok: Style::new(),
+        failed: Style::new(),
+        warning: Style::new(),
+    };
+
+    /// The preset with the overridden elements
+    fn from_theme(theme: &Theme) -> Self {
+        let preset = match theme.preset.unwrap_or_default() {
+            ThemePreset::Dark => Self::DARK,
+            ThemePreset::Light => Self::LIGHT,
+            ThemePreset::Plain => Self::PLAIN,
+        };
+        let style = |color: &Option<Color>, preset: Style| {
+            color
+                .as_ref()
+                .map_or(preset, |color| Style::new().color(color.color))
+        };
+
+        Self {
+            scope: style(&theme.scope, preset.scope),
+            server: style(&theme.server, preset.server),
+            attr: style(&theme.attr, preset.attr),
+            value: style(&theme.value, preset.value),
+            ok: style(&theme.ok, preset.ok),
+            failed: style(&theme.failed, preset.failed),
+            warning: style(&theme.warning, preset.warning),
+        }
+    }
+}
+
+/// Read on the first use of the styles, tests always get the defaults
+static CONFIG: LazyLock<Option<Config>> = LazyLock::new(|| match cfg!(test) {
+    true => None,
+    false => Config::load_from_file().ok(),
+});
+
+static PALETTE: LazyLock<Palette> = LazyLock::new(|| {
+    let theme = CONFIG.as_ref().and_then(|config| config.theme.clone());
+    Palette::from_theme(&theme.unwrap_or_default())
+});
+
+/// `--ascii` or `ascii: true` in the config
+static ASCII: LazyLock<bool> =
+    LazyLock::new(|| control::ascii_output() || CONFIG.as_ref().is_some_and(|config| config.ascii));
+
+/// Tree branches of the same width without box-drawing characters
+fn branch(last: bool, ascii: bool) -> &'static str {
+    match (last, ascii) {
+        (false, false) => "├─╴",
+        (true, false) => "└─╴",
+        (false, true) => "|- ",
+        (true, true) => "`- ",
+    }
+}
+
+macro_rules! styled_static {
+    ($name:ident, $element:ident, $value:expr) => {
+        static $name: LazyLock<Styled<&'static str>> =
+            LazyLock::new(|| PALETTE.$element.style($value));
     };
 }
 
-define_styles! {
-    SCOPE: bright_magenta,
-    SERVER: bright_green,
-    ATTR: blue,
-    VALUE: white,
+styled_static!(SCOPE_SUFFIX, scope, "/");
+styled_static!(SERVER_SUFFIX, server, ":");
+styled_static!(ATTR_SUFFIX, attr, ":");
+styled_static!(TREE_BRANCH, attr, branch(false, *ASCII));
+styled_static!(LAST_BRANCH, attr, branch(true, *ASCII));
+
+const INDENT: &str = "  ";
+const SCOPE_INDENT: &str = "    ";
+const SCOPE_FIELD_CAPACITY: usize = 37;
+const COLUMN_GAP: &str = "  ";
+/// Placeholder for an empty table cell
+const MISSING: &str = "-";
+const DEFAULT_SCOPE: &str = "(default)";
+const CSV_HEADER: [&str; 9] = [
+    "name",
+    "scope",
+    "address",
+    "user",
+    "port",
+    "key",
+    "known_hosts",
+    "tags",
+    "aliases",
+];
+
+/// Result data for the list command
+pub enum LsOutput {
+    /// Mixed list of scopes and global servers
+    All(IndexMap<String, ServerEntry>),
+    /// Only scopes (optionally prints default scope first)
+    AllScopes(Option<Box<Scope>>, IndexMap<String, Scope>),
+    /// A single named scope with its servers
+    Scope(String, IndexMap<String, ScopedServer>),
+}
+
+/// Result data for the show command
+pub enum ShowOutput {
+    /// Every server with the given name along with the scope it lives in
+    Servers(Vec<(Option<String>, String, ScopedServer)>),
+    /// A single named scope
+    Scope(String, Box<Scope>),
+}
+
+/// Result data for the find command
+pub struct FindOutput(pub Vec<FoundServer>);
+
+/// Result data for the resolve command
+pub struct ResolveOutput {
+    pub host: String,
+    pub address: String,
+    /// Scope the server was found in
+    pub scope: Option<String>,
+    pub fields: Vec<TracedField>,
+}
+
+/// Result data for the test command
+#[derive(Serialize)]
+pub struct TestOutput {
+    pub verdict: TestVerdict,
+    /// The checked config file
+    pub path: Box<str>,
+    pub findings: Vec<Finding>,
+}
+
+/// Result data for the audit command
+#[derive(Serialize)]
+pub struct AuditOutput {
+    pub verdict: TestVerdict,
+    pub findings: Vec<Finding>,
+}
+
+/// Result data for the current command
+pub struct CurrentOutput {
+    /// Empty when no scope is active
+    pub scope: String,
+    pub origin: ScopeOrigin,
+    /// Settings of the scope, `None` if the config doesn't define it
+    pub settings: Option<Scope>,
+    /// Servers of the scope
+    pub servers: usize,
+    /// Servers outside of scopes, reachable from any scope
+    pub global_servers: usize,
+    pub context: String,
+    /// File the context is stored in
+    pub context_path: &'static str,
+}
+
+/// Result data for the check command
+#[derive(Serialize)]
+pub struct CheckOutput {
+    pub server: String,
+    pub steps: Vec<Step>,
+}
+
+/// The config passes unless there are errors (warnings are allowed)
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TestVerdict {
+    Pass,
+    Fail,
+}
+
+/// Result data for the history command (the most recent connection first)
+pub struct HistoryOutput(pub Vec<HistoryEntry>);
+
+/// Result data for the prune command in the dry-run mode
+pub struct PruneOutput(pub Vec<StaleServer>);
+
+/// Result data for the import commands in the dry-run mode
+pub struct ImportOutput(pub Vec<(String, Server)>);
+
+/// Result data for the discover command in the dry-run mode
+pub struct DiscoverOutput(pub Vec<Announced>);
+
+/// Result data for the rm command in the dry-run mode
+pub struct RmOutput {
+    /// Scope removed along with all of its servers
+    pub scope: Option<String>,
+    pub servers: Vec<String>,
+}
+
+/// Result data for the set command applied to the servers of a scope:
+/// the settings each server gets
+pub struct SetPreview(pub Vec<ServerChange>);
+
+/// Result data for the ping command
+pub struct PingOutput(pub Vec<Probe>);
+
+/// A round of checks of the watch command
+pub struct WatchOutput {
+    pub probes: Vec<Probe>,
+    /// Unix time each server last went up or down (or was first checked)
+    pub since: Vec<u64>,
+    /// Unix time the round finished
+    pub checked: u64,
+    pub interval: u64,
+}
+
+/// Result data for the warm command, how long each connection took to set up
+pub struct WarmOutput(pub Vec<(Option<String>, String, anyhow::Result<Duration>)>);
+
+/// Result data for the context ls command: name and active scope of each context
+/// along with the name of the one in use
+pub struct ContextsOutput(pub Vec<(String, String)>, pub String);
+
+/// Result data for the forward ls command
+pub struct ForwardsOutput(pub Vec<ForwardStatus>);
+
+/// Values for dynamic shell completions, one per line
+pub struct CompletionOutput(pub Vec<String>);
+
+/// Result data for the list command, a server with its effective settings per line
+/// (and when it was last connected to)
+pub struct LongOutput(
+    pub Vec<(Option<String>, String, Server)>,
+    pub Option<LastSeen>,
+);
+
+/// Result data for the tree command, the whole config from the defaults down to the servers
+pub struct TreeOutput(pub Config);
+
+/// Field names with their values as printed
+type FieldTexts = Vec<(&'static str, String)>;
+
+/// Connection times (Unix seconds) by the name the server was connected by
+pub struct LastSeen {
+    pub times: HashMap<String, u64>,
+    pub now: u64,
+}
+
+/// How long ago a server was last connected to, if ever
+struct Ago(Option<u64>);
+
+/// Result data for the export command, servers with their effective settings as CSV
+pub struct CsvOutput(pub Vec<(Option<String>, String, Server)>);
+
+/// Result data for the list command laid out in aligned columns
+pub struct TableOutput {
+    header: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+}
+
+/// Serializable counterpart of the listing results, printed as JSON
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum JsonOutput {
+    Servers(Vec<JsonServer>),
+    Scopes(Vec<JsonScope>),
+    Scope(Box<JsonScope>),
+    Resolved(JsonResolved),
+    Test(TestOutput),
+    Audit(AuditOutput),
+    Check(CheckOutput),
+}
+
+/// A server along with the scope it lives in (`None` for global servers)
+#[derive(Serialize)]
+pub struct JsonServer {
+    pub scope: Option<String>,
+    pub name: String,
+    #[serde(flatten)]
+    pub server: Server,
+}
+
+/// A named scope (`None` for the default settings)
+#[derive(Serialize)]
+pub struct JsonScope {
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub scope: Scope,
+}
+
+/// Resolved connection parameters by name
+#[derive(Serialize)]
+pub struct JsonResolved {
+    pub host: String,
+    pub address: String,
+    pub scope: Option<String>,
+    pub fields: IndexMap<&'static str, JsonField>,
+}
+
+#[derive(Serialize)]
+pub struct JsonField {
+    pub value: String,
+    pub source: Source,
+}
+
+/// An error with its chain of causes and context, printed with `--explain`
+pub struct ExplainedError<'a> {
+    pub error: &'a anyhow::Error,
+    pub hints: bool,
+    /// Command line that failed
+    pub command: String,
+    pub config: &'a str,
+    /// Active scope, if it could be determined
+    pub scope: Option<String>,
+}
+
+/// An error as a single line of JSON for wrappers of the command
+#[derive(Serialize)]
+pub struct JsonError {
+    pub code: Failure,
+    pub message: String,
+    pub hint: Option<String>,
+    /// Underlying cause, if any
+    pub source: Option<String>,
+}
+
+impl Display for LsOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::All(server_entries) => {
+                let (scopes, servers): (Vec<_>, Vec<_>) =
+                    server_entries
+                        .into_iter()
+                        .partition_map(|(name, entry)| match entry {
+                            ServerEntry::Scope(scope) => Either::Left((name, scope)),
+                            ServerEntry::Global(server) => Either::Right((name, server)),
+                        });
+                for (scope, servers) in scopes.iter() {
+                    print_scoped_servers(f, scope, servers)?;
+                }
+                if !servers.is_empty() {
+                    if !scopes.is_empty() {
+                        writeln!(f)?;
+                    }
+                    print_servers(f, &servers, true)?;
+                }
+            }
+            Self::AllScopes(default, scopes) => {
+                if let Some(default_scope) = default {
+                    default_scope.print(f, "")?;
+                    if !scopes.is_empty() {
+                        writeln!(f)?;
+                    }
+                }
+                for (name, scope) in scopes {
+                    writeln!(
+                        f,
+                        "{}{}\n{}",
+                        name.style(PALETTE.scope),
+                        *SCOPE_SUFFIX,
+                        scope
+                    )?;
+                }
+            }
+            Self::Scope(scope, servers) => {
+                print_scoped_servers(f, scope, servers)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for ShowOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Servers(found) => {
+                for (i, (scope, name, server)) in found.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    let (name_indent, attr_indent) = match scope {
+                        Some(scope) => {
+                            writeln!(f, "{}{}", scope.style(PALETTE.scope), *SCOPE_SUFFIX)?;
+                            (INDENT, SCOPE_INDENT)
+                        }
+                        None => ("", INDENT),
+                    };
+                    match server {
+                        ScopedServer::Address(address) => writeln!(
+                            f,
+                            "{}{}{} {} {}",
+                            name_indent,
+                            name.style(PALETTE.server),
+                            *SERVER_SUFFIX,
+                            address.style(PALETTE.value),
+                            "(address)".style(PALETTE.attr),
+                        )?,
+                        ScopedServer::Override(inner) => {
+                            writeln!(
+                                f,
+                                "{}{}{} {}",
+                                name_indent,
+                                name.style(PALETTE.server),
+                                *SERVER_SUFFIX,
+                                "(override)".style(PALETTE.attr),
+                            )?;
+                            inner.print(f, attr_indent)?;
+                            writeln!(f)?;
+                        }
+                    }
+                }
+            }
+            Self::Scope(name, scope) => {
+                writeln!(
+                    f,
+                    "{}{}\n{}",
+                    name.style(PALETTE.scope),
+                    *SCOPE_SUFFIX,
+                    scope
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for SetPreview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.0 {
+            let before = change.before.with_fields(field_texts);
+            let fields = change
+                .after
+                .with_fields(field_texts)
+                .into_iter()
+                .filter_map(|(name, value)| {
+                    let old = before.iter().find(|(field, _)| *field == name);
+                    match old {
+                        Some((_, old)) if *old == value => None,
+                        _ => {
+                            let old = old.map_or(MISSING, |(_, old)| old.as_str());
+                            Some((name, format!("{} -> {}", old, value)))
+                        }
+                    }
+                })
+                .collect::<Vec<_>>();
+            let fields: Vec<(&str, &dyn Display)> = fields
+                .iter()
+                .map(|(name, value)| (*name, value as &dyn Display))
+                .collect();
+            writeln!(f, "{}{}", change.name.style(PALETTE.server), *SERVER_SUFFIX)?;
+            print_attributes(f, &fields, INDENT, true)?;
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for TreeOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let config = &self.0;
+        let defaults = config
+            .default
+            .as_ref()
+            .map(|scope| scope.with_fields(field_texts))
+            .unwrap_or_default();
+        let root = [(DEFAULT_SCOPE, defaults.as_slice())];
+        writeln!(f, "{}", DEFAULT_SCOPE.style(PALETTE.scope))?;
+        print_shadowing(f, Vec::new(), &defaults, &[], INDENT)?;
+        for (name, entry) in &config.servers {
+            if let ServerEntry::Global(server) = entry {
+                print_tree_server(f, name, server, &root)?;
+            }
+        }
+
+        // Server blocks of scopes missing from the config still get their own branch
+        let block_names = config
+            .servers
+            .iter()
+            .filter_map(|(name, entry)| match entry {
+                ServerEntry::Scope(_) => Some(name),
+                ServerEntry::Global(_) => None,
+            });
+        for name in config.scopes.keys().chain(block_names).unique() {
+            let fields = config
+                .scopes
+                .get(name)
+                .map(|scope| scope.with_fields(field_texts))
+                .unwrap_or_default();
+            writeln!(f, "\n{}{}", name.style(PALETTE.scope), *SCOPE_SUFFIX)?;
+            print_shadowing(f, Vec::new(), &fields, &root, INDENT)?;
+            let Some(ServerEntry::Scope(servers)) = config.servers.get(name) else {
+                continue;
+            };
+            let parents = [(name.as_str(), fields.as_slice()), root[0]];
+            for (server_name, server) in servers {
+                print_tree_server(f, server_name, server, &parents)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for FindOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for found in &self.0 {
+            if let Some(scope) = &found.scope {
+                write!(f, "{}{}", scope.style(PALETTE.scope), *SCOPE_SUFFIX)?;
+            }
+            let matched = found
+                .matched
+                .iter()
+                .filter(|(field, _)| *field != "name" && *field != "address")
+                .map(|(field, value)| format!("{} {}", field, value))
+                .join(", ");
+            write!(
+                f,
+                "{}{} {}",
+                found.name.style(PALETTE.server),
+                *SERVER_SUFFIX,
+                found.address.style(PALETTE.value),
+            )?;
+            if !matched.is_empty() {
+                write!(f, " {}", format_args!("({})", matched).style(PALETTE.attr))?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl LsOutput {
+    /// Every listed server along with its scope (`None` for global servers)
+    pub fn into_servers(self) -> Vec<(Option<String>, String, ScopedServer)> {
+        match self {
+            Self::All(entries) => entries
+                .into_iter()
+                .flat_map(|(name, entry)| match entry {
+                    ServerEntry::Global(server) => vec![(None, name, server)],
+                    ServerEntry::Scope(servers) => servers
+                        .into_iter()
+                        .map(|(server_name, server)| (Some(name.clone()), server_name, server))
+                        .collect(),
+                })
+                .collect(),
+            Self::AllScopes(..) => Vec::new(),
+            Self::Scope(scope, servers) => servers
+                .into_iter()
+                .map(|(name, server)| (Some(scope.clone()), name, server))
+                .collect(),
+        }
+    }
+}
+
+impl From<LsOutput> for JsonOutput {
+    fn from(output: LsOutput) -> Self {
+        match output {
+            LsOutput::AllScopes(default, scopes) => Self::Scopes(
+                default
+                    .map(|scope| JsonScope {
+                        name: None,
+                        scope: *scope,
+                    })
+                    .into_iter()
+                    .chain(scopes.into_iter().map(|(name, scope)| JsonScope {
+                        name: Some(name),
+                        scope,
+                    }))
+                    .collect(),
+            ),
+            servers => Self::Servers(
+                servers
+                    .into_servers()
+                    .into_iter()
+                    .map(|(scope, name, server)| JsonServer::new(scope, name, server))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<ShowOutput> for JsonOutput {
+    fn from(output: ShowOutput) -> Self {
+        match output {
+            ShowOutput::Servers(found) => Self::Servers(
+                found
+                    .into_iter()
+                    .map(|(scope, name, server)| JsonServer::new(scope, name, server))
+                    .collect(),
+            ),
+            ShowOutput::Scope(name, scope) => Self::Scope(Box::new(JsonScope {
+                name: Some(name),
+                scope: *scope,
+            })),
+        }
+    }
+}
+
+impl From<ResolveOutput> for JsonOutput {
+    fn from(output: ResolveOutput) -> Self {
+        Self::Resolved(JsonResolved {
+            host: output.host,
+            address: output.address,
+            scope: output.scope,
+            fields: output
+                .fields
+                .into_iter()
+                .map(|(name, value, source)| (name, JsonField { value, source }))
+                .collect(),
+        })
+    }
+}
+
+impl JsonServer {
+    fn new(scope: Option<String>, name: String, server: ScopedServer) -> Self {
+        Self {
+            scope,
+            name,
+            server: server.into(),
+        }
+    }
+}
+
+impl TableOutput {
+    /// Servers with their effective settings
+    pub fn servers(
+        servers: Vec<(Option<String>, String, Server)>,
+        wide: bool,
+        last_seen: Option<&LastSeen>,
+    ) -> Self {
+        let mut header = vec!["NAME", "SCOPE", "ADDRESS", "USER", "PORT", "KEY"];
+        if wide {
+            header.extend(["ALIASES", "TAGS", "KNOWN_HOSTS"]);
+        }
+        if last_seen.is_some() {
+            header.push("LAST");
+        }
+        let rows = servers
+            .into_iter()
+            .map(|(scope, name, server)| {
+                let last = last_seen.map(|last_seen| {
+                    last_seen
+                        .ago(&name, &server)
+                        .map_or(MISSING.into(), |ago| ago.to_string())
+                });
+                let mut row = vec![
+                    name,
+                    scope.unwrap_or_else(|| MISSING.into()),
+                    server.address,
+                    cell(&server.scope.user),
+                    cell(&server.scope.port),
+                    path_cell(&server.scope.private_key),
+                ];
+                if wide {
+                    row.extend([
+                        list_cell(&server.aliases),
+                        list_cell(&server.tags),
+                        path_cell(&server.scope.known_hosts),
+                    ]);
+                }
+                row.extend(last);
+                row
+            })
+            .collect();
+
+        Self { header, rows }
+    }
+
+    /// Scopes with their own settings, the defaults come first
+    pub fn scopes(
+        default: Option<Box<Scope>>,
+        scopes: IndexMap<String, Scope>,
+        wide: bool,
+    ) -> Self {
+        let mut header = vec!["SCOPE", "USER", "PORT", "KEY"];
+        if wide {
+            header.extend(["KNOWN_HOSTS", "TIMEOUT", "NETWORK"]);
+        }
+        header.push("DESCRIPTION");
+        let default = default.map(|scope| (DEFAULT_SCOPE.to_string(), *scope));
+        let rows = default
+            .into_iter()
+            .chain(scopes)
+            .map(|(name, scope)| {
+                let mut row = vec![
+                    name,
+                    cell(&scope.user),
+                    cell(&scope.port),
+                    path_cell(&scope.private_key),
+                ];
+                if wide {
+                    row.extend([
+                        path_cell(&scope.known_hosts),
+                        cell(&scope.timeout),
+                        cell(&scope.network),
+                    ]);
+                }
+                row.push(cell(&scope.description));
+                row
+            })
+            .collect();
+
+        Self { header, rows }
+    }
+}
+
+impl Display for TableOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let widths: Vec<_> = (0..self.header.len())
+            .map(|i| {
+                self.rows
+                    .iter()
+                    .map(|row| row[i].chars().count())
+                    .chain(iter::once(self.header[i].len()))
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+        let last = self.header.len() - 1;
+
+        for (i, title) in self.header.iter().enumerate() {
+            let padded = if i == last {
+                title.to_string()
+            } else {
+                format!("{:width$}{}", title, COLUMN_GAP, width = widths[i])
+            };
+            write!(f, "{}", padded.style(PALETTE.attr))?;
+        }
+        writeln!(f)?;
+        for row in &self.rows {
+            for (i, value) in row.iter().enumerate() {
+                let padded = if i == last {
+                    value.clone()
+                } else {
+                    format!("{:width$}{}", value, COLUMN_GAP, width = widths[i])
+                };
+                if i == 0 {
+                    write!(f, "{}", padded.style(PALETTE.server))?;
+                } else {
+                    write!(f, "{}", padded)?;
+                }
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[inline]
+fn cell<T: Display>(value: &Option<T>) -> String {
+    value
+        .as_ref()
+        .map_or_else(|| MISSING.into(), ToString::to_string)
+}
+
+#[inline]
+fn path_cell(path: &Option<PathBuf>) -> String {
+    cell(&path.as_ref().map(|path| path.display()))
+}
+
+#[inline]
+fn list_cell(values: &Option<Vec<String>>) -> String {
+    cell(&values.as_ref().map(|values| values.join(",")))
+}
+
+impl Display for CsvOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        let list = |values: &Option<Vec<String>>| {
+            optional(
+                values
+                    .as_ref()
+                    .map(|values| values.join(&LIST_SEPARATOR.to_string())),
+            )
+        };
+        let path =
+            |path: &Option<PathBuf>| optional(path.as_ref().map(|p| p.display().to_string()));
+
+        writeln!(f, "{}", csv::row(CSV_HEADER))?;
+        for (scope, name, server) in &self.0 {
+            let row = [
+                name.clone(),
+                optional(scope.clone()),
+                server.address.clone(),
+                optional(server.scope.user.clone()),
+                optional(server.scope.port.map(|port| port.to_string())),
+                path(&server.scope.private_key),
+                path(&server.scope.known_hosts),
+                list(&server.tags),
+                list(&server.aliases),
+            ];
+            writeln!(f, "{}", csv::row(row))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for LongOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (scope, name, server) in &self.0 {
+            // Never used servers stand out
+            let last = self
+                .1
+                .as_ref()
+                .and_then(|last_seen| last_seen.ago(name, server));
+            let style = match last {
+                Some(Ago(None)) => PALETTE.warning,
+                _ => PALETTE.attr,
+            };
+            if let Some(scope) = scope {
+                write!(f, "{}{}", scope.style(PALETTE.scope), *SCOPE_SUFFIX)?;
+            }
+            write!(f, "{}{} ", name.style(PALETTE.server), *SERVER_SUFFIX)?;
+            let Scope {
+                user,
+                port,
+                private_key,
+                ..
+            } = &server.scope;
+            if let Some(user) = user {
+                write!(f, "{}", format_args!("{}@", user).style(PALETTE.value))?;
+            }
+            write!(f, "{}", server.address.style(PALETTE.value))?;
+            if let Some(port) = port {
+                write!(f, "{}", format_args!(":{}", port).style(PALETTE.value))?;
+            }
+            if let Some(key) = private_key {
+                write!(
+                    f,
+                    " {}",
+                    format_args!("(key {})", key.display()).style(PALETTE.attr)
+                )?;
+            }
+            if let Some(last) = last {
+                write!(f, " {}", format_args!("({})", last).style(style))?;
+            }
+            // Only the first line, to keep a server per line
+            if let Some(notes) = server
+                .notes
+                .as_deref()
+                .and_then(|notes| notes.lines().next())
+            {
+                write!(f, " {}", format_args!("# {}", notes).style(PALETTE.attr))?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<CheckOutput> for JsonOutput {
+    fn from(output: CheckOutput) -> Self {
+        Self::Check(output)
+    }
+}
+
+impl From<AuditOutput> for JsonOutput {
+    fn from(output: AuditOutput) -> Self {
+        Self::Audit(output)
+    }
+}
+
+impl From<TestOutput> for JsonOutput {
+    fn from(output: TestOutput) -> Self {
+        Self::Test(output)
+    }
+}
+
+impl Display for JsonOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string_pretty(self).map_err(|_| fmt::Error)?;
+        writeln!(f, "{}", json)
+    }
+}
+
+/// Hints are appended to messages as "(hint: ...)"
+pub fn split_hint(message: &str) -> (&str, Option<&str>) {
+    match message
+        .strip_suffix(')')
+        .and_then(|m| m.rsplit_once(" (hint: "))
+    {
+        Some((message, hint)) => (message, Some(hint)),
+        None => (message, None),
+    }
+}
+
+impl From<&anyhow::Error> for JsonError {
+    fn from(err: &anyhow::Error) -> Self {
+        let message = err.to_string();
+        let (message, hint) = split_hint(&message);
+
+        Self {
+            code: Failure::of(err),
+            message: message.to_string(),
+            hint: hint.map(str::to_string),
+            source: err.chain().nth(1).map(|cause| cause.to_string()),
+        }
+    }
+}
+
+impl Display for ExplainedError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut hints = Vec::new();
+        for (i, cause) in self.error.chain().enumerate() {
+            let message = cause.to_string();
+            let (message, hint) = split_hint(&message);
+            if i == 0 {
+                writeln!(
+                    f,
+                    "{}{} {}",
+                    "error".style(PALETTE.failed),
+                    *ATTR_SUFFIX,
+                    message
+                )?;
+            } else {
+                let label = "caused by".style(PALETTE.attr);
+                writeln!(f, "{}{}{} {}", INDENT, label, *ATTR_SUFFIX, message)?;
+            }
+            hints.extend(hint.filter(|_| self.hints).map(str::to_string));
+        }
+        for hint in hints {
+            writeln!(
+                f,
+                "{}{}{} {}",
+                INDENT,
+                "hint".style(PALETTE.attr),
+                *ATTR_SUFFIX,
+                hint
+            )?;
+        }
+        let failure = Failure::of(self.error);
+        let details = [
+            (
+                "failure",
+                format!("{} (exit code {})", failure, failure as u8),
+            ),
+            ("command", self.command.clone()),
+            ("config", self.config.to_string()),
+            (
+                "scope",
+                match self.scope.as_deref() {
+                    Some("") => "(none)".into(),
+                    Some(scope) => scope.into(),
+                    None => "(unknown)".into(),
+                },
+            ),
+        ];
+        for (name, value) in details {
+            writeln!(
+                f,
+                "{}{}{} {}",
+                INDENT,
+                name.style(PALETTE.attr),
+                *ATTR_SUFFIX,
+                value
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        writeln!(f, "{}", json)
+    }
+}
+
+impl Display for RmOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let indent = match &self.scope {
+            Some(scope) => {
+                writeln!(f, "{}{}", scope.style(PALETTE.scope), *SCOPE_SUFFIX)?;
+                INDENT
+            }
+            None => "",
+        };
+        for server in &self.servers {
+            writeln!(f, "{}{}", indent, server.style(PALETTE.server))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for ResolveOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(scope) = &self.scope {
+            writeln!(f, "{}{}", scope.style(PALETTE.scope), *SCOPE_SUFFIX)?;
+        }
+        let indent = if self.scope.is_some() { INDENT } else { "" };
+        writeln!(
+            f,
+            "{}{}{} {}",
+            indent,
+            self.host.style(PALETTE.server),
+            *SERVER_SUFFIX,
+            self.address.style(PALETTE.value),
+        )?;
+        let annotated: Vec<_> = self
+            .fields
+            .iter()
+            .map(|(name, value, source)| (*name, Annotated(value, *source)))
+            .collect();
+        let fields: Vec<(&str, &dyn Display)> = annotated
+            .iter()
+            .map(|(name, value)| (*name, value as &dyn Display))
+            .collect();
+        let attr_indent = if self.scope.is_some() {
+            SCOPE_INDENT
+        } else {
+            INDENT
+        };
+        print_attributes(f, &fields, attr_indent, true)?;
+
+        writeln!(f)
+    }
+}
+
+impl Display for CurrentOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let origin = match &self.origin {
+            ScopeOrigin::Env => format!("from {}", SCOPE_ENV),
+            ScopeOrigin::File(path) => format!("from '{}'", path.display()),
+            ScopeOrigin::Network => "matches the network".into(),
+            ScopeOrigin::Context(None) => "chosen with use".into(),
+            ScopeOrigin::Context(Some(expires)) => {
+                format!("chosen with use, until {} UTC", UtcTime(*expires))
+            }
+        };
+        let scope = if self.scope.is_empty() {
+            "(none)".to_string()
+        } else {
+            format!("{} ({})", self.scope, origin)
+        };
+        let context = format!("{} (stored in '{}')", self.context, self.context_path);
+        let servers = format!(
+            "{} in the scope, {} global",
+            self.servers, self.global_servers
+        );
+        print_attributes(
+            f,
+            &[
+                ("scope", &scope),
+                ("context", &context),
+                ("servers", &servers),
+            ],
+            "",
+            true,
+        )?;
+        match &self.settings {
+            Some(settings) => write!(
+                f,
+                "{}{}\n{}",
+                self.scope.style(PALETTE.scope),
+                *SCOPE_SUFFIX,
+                settings
+            ),
+            None if self.scope.is_empty() => Ok(()),
+            None => writeln!(
+                f,
+                "{}",
+                format!("Scope '{}' isn't defined in the config", self.scope)
+                    .style(PALETTE.warning)
+            ),
+        }
+    }
+}
+
+impl Display for HistoryOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.0 {
+            let status = match entry.exit_status {
+                Some(code) => format!("exit {}", code),
+                None => "no exit status".into(),
+            };
+            writeln!(
+                f,
+                "{} {}{} {} {}",
+                UtcTime(entry.timestamp).style(PALETTE.attr),
+                entry.host.style(PALETTE.server),
+                *SERVER_SUFFIX,
+                format_args!("{}@{}", entry.user, entry.address).style(PALETTE.value),
+                format_args!("({}, {})", HumanDuration(entry.duration), status).style(PALETTE.attr),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Unix time in the `YYYY-MM-DD HH:MM:SS` form (UTC)
+pub struct UtcTime(pub u64);
+
+impl Display for UtcTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Civil from days: https://howardhinnant.github.io/date_algorithms.html
+        let (days, secs) = (self.0 / 86400, self.0 % 86400);
+        let z = days + 719468;
+        let era = z / 146097;
+        let doe = z % 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + u64::from(month <= 2);
+
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )
+    }
+}
+
+impl Display for PruneOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0
+            .iter()
+            .try_for_each(|server| writeln!(f, "{}", server))
+    }
+}
+
+impl Display for ImportOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, server) in &self.0 {
+            let user = server.scope.user.as_ref().map(|user| format!("{}@", user));
+            let port = server.scope.port.map(|port| format!(":{}", port));
+            write!(
+                f,
+                "{}{} {}",
+                name.style(PALETTE.server),
+                *SERVER_SUFFIX,
+                format!(
+                    "{}{}{}",
+                    user.unwrap_or_default(),
+                    server.address,
+                    port.unwrap_or_default()
+                )
+                .style(PALETTE.value)
+            )?;
+            if let Some(aliases) = &server.aliases {
+                write!(
+                    f,
+                    " {}",
+                    format!("({})", aliases.join(", ")).style(PALETTE.attr)
+                )?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for DiscoverOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0
+            .iter()
+            .try_for_each(|announced| writeln!(f, "{}", announced))
+    }
+}
+
+impl Display for Announced {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{} {}",
+            self.short_name().style(PALETTE.server),
+            *SERVER_SUFFIX,
+            format!("{}:{}", self.host, self.port).style(PALETTE.value)
+        )?;
+        if !self.addresses.is_empty() {
+            write!(
+                f,
+                " {}",
+                format!("({})", self.addresses.iter().join(", ")).style(PALETTE.attr)
+            )?;
+        }
+
+        Ok(())
+    }
 }
 
-macro_rules! styled_const {
-    ($name:ident, $style:expr, $value:expr) => {
-        const $name: Styled<&'static str> = $style.style($value);
-    };
+impl Display for StaleServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(scope) = &self.scope {
+            write!(f, "{}{}", scope.style(PALETTE.scope), *SCOPE_SUFFIX)?;
+        }
+        write!(
+            f,
+            "{}{} {}",
+            self.name.style(PALETTE.server),
+            *SERVER_SUFFIX,
+            self.reasons.iter().join(", ").style(PALETTE.value)
+        )
+    }
 }
 
-styled_const!(SCOPE_SUFFIX, SCOPE, "/");
-styled_const!(SERVER_SUFFIX, SERVER, ":");
-styled_const!(ATTR_SUFFIX, ATTR, ":");
-styled_const!(TREE_BRANCH, ATTR, "├─╴");
-styled_const!(LAST_BRANCH, ATTR, "└─╴");
+impl Display for StaleReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unused(Some(last)) => write!(f, "last used {}", UtcTime(*last)),
+            Self::Unused(None) => f.write_str("never used"),
+            Self::Unresolved => f.write_str("address does not resolve"),
+            Self::MissingKey(path) => write!(f, "missing {}", path.display()),
+        }
+    }
+}
 
-const INDENT: &str = "  ";
-const SCOPE_INDENT: &str = "    ";
-const SCOPE_FIELD_CAPACITY: usize = 12;
+/// Seconds in the `1h 2m 3s` form
+struct HumanDuration(u64);
 
-/// Result data for the list command
-pub enum LsOutput {
-    /// Mixed list of scopes and global servers
-    All(IndexMap<String, ServerEntry>),
-    /// Only scopes (optionally prints default scope first)
-    AllScopes(Option<Box<Scope>>, IndexMap<String, Scope>),
-    /// A single named scope with its servers
-    Scope(String, IndexMap<String, ScopedServer>),
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (hours, minutes, seconds) = (self.0 / 3600, self.0 / 60 % 60, self.0 % 60);
+        if hours > 0 {
+            write!(f, "{}h {}m {}s", hours, minutes, seconds)
+        } else if minutes > 0 {
+            write!(f, "{}m {}s", minutes, seconds)
+        } else {
+            write!(f, "{}s", seconds)
+        }
+    }
 }
 
-/// Result data for the test command
-pub struct TestOutput(pub Result<Box<str>, String>);
+impl LastSeen {
+    /// The latest connection by the name or any alias of the server.
+    /// Patterns are connected to by other names, so they have none.
+    fn ago(&self, name: &String, server: &Server) -> Option<Ago> {
+        if is_pattern(name) {
+            return None;
+        }
+        let names = iter::once(name).chain(server.aliases.iter().flatten());
+        let last = last_connected(&self.times, names);
 
-impl Display for LsOutput {
+        Some(Ago(last.map(|last| self.now.saturating_sub(last))))
+    }
+}
+
+impl Display for Ago {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::All(server_entries) => {
-                let (scopes, servers): (Vec<_>, Vec<_>) =
-                    server_entries
-                        .into_iter()
-                        .partition_map(|(name, entry)| match entry {
-                            ServerEntry::Scope(scope) => Either::Left((name, scope)),
-                            ServerEntry::Global(server) => Either::Right((name, server)),
-                        });
-                for (scope, servers) in scopes.iter() {
-                    print_scoped_servers(f, scope, servers)?;
-                }
-                if !servers.is_empty() {
-                    if !scopes.is_empty() {
-                        writeln!(f)?;
-                    }
-                    print_servers(f, &servers, true)?;
-                }
+        match self.0 {
+            None => f.write_str("never"),
+            Some(secs) if secs < 60 => f.write_str("just now"),
+            Some(secs) if secs < 3600 => write!(f, "{}m ago", secs / 60),
+            Some(secs) if secs < 86400 => write!(f, "{}h ago", secs / 3600),
+            Some(secs) => write!(f, "{}d ago", secs / 86400),
+        }
+    }
+}
+
+/// A value followed by the layer it comes from
+struct Annotated<'a>(&'a str, Source);
+
+impl Display for Annotated<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.0,
+            format_args!("({})", self.1).style(PALETTE.attr)
+        )
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Flag => "flag",
+            Self::Server => "server",
+            Self::Scope => "scope",
+            Self::Default => "default",
+            Self::BuiltIn => "built-in",
+        })
+    }
+}
+
+impl TestVerdict {
+    pub fn of(findings: &[Finding]) -> Self {
+        if findings.iter().any(|f| f.severity == Severity::Error) {
+            Self::Fail
+        } else {
+            Self::Pass
+        }
+    }
+}
+
+impl TestOutput {
+    pub fn new(path: Box<str>, findings: Vec<Finding>) -> Self {
+        Self {
+            verdict: TestVerdict::of(&findings),
+            path,
+            findings,
+        }
+    }
+}
+
+impl Display for TestOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        print_findings(f, &self.findings)?;
+        match self.verdict {
+            TestVerdict::Pass => writeln!(f, "The configuration file {} syntax is ok", self.path),
+            TestVerdict::Fail => writeln!(f, "The configuration file {} has errors", self.path),
+        }
+    }
+}
+
+impl AuditOutput {
+    pub fn new(findings: Vec<Finding>) -> Self {
+        Self {
+            verdict: TestVerdict::of(&findings),
+            findings,
+        }
+    }
+}
+
+impl Display for AuditOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        print_findings(f, &self.findings)?;
+        let (errors, warnings): (Vec<_>, Vec<_>) = self
+            .findings
+            .iter()
+            .partition(|finding| finding.severity == Severity::Error);
+        if self.findings.is_empty() {
+            writeln!(f, "No problems found")
+        } else {
+            writeln!(
+                f,
+                "{} error(s), {} warning(s)",
+                errors.len(),
+                warnings.len()
+            )
+        }
+    }
+}
+
+impl Display for CheckOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in &self.steps {
+            let status = match step.status {
+                StepStatus::Ok => "ok     ".style(PALETTE.ok),
+                StepStatus::Warning => "warning".style(PALETTE.warning),
+                StepStatus::Failed => "failed ".style(PALETTE.failed),
+            };
+            writeln!(
+                f,
+                "{} {}{} {}",
+                status,
+                step.name.style(PALETTE.attr),
+                *ATTR_SUFFIX,
+                step.detail
+            )?;
+        }
+        let failed = self
+            .steps
+            .iter()
+            .filter(|step| step.status == StepStatus::Failed)
+            .count();
+        if failed == 0 {
+            writeln!(
+                f,
+                "'{}' is ready to connect",
+                self.server.style(PALETTE.server)
+            )
+        } else {
+            writeln!(
+                f,
+                "'{}' has {} problem(s)",
+                self.server.style(PALETTE.server),
+                failed
+            )
+        }
+    }
+}
+
+#[inline]
+fn print_findings(f: &mut fmt::Formatter<'_>, findings: &[Finding]) -> fmt::Result {
+    for finding in findings {
+        match finding.severity {
+            Severity::Error => write!(f, "{}", "error".style(PALETTE.failed))?,
+            Severity::Warning => write!(f, "{}", "warning".style(PALETTE.warning))?,
+        }
+        writeln!(
+            f,
+            " {}: {}",
+            finding.path.style(PALETTE.attr),
+            finding.message
+        )?;
+    }
+
+    Ok(())
+}
+
+impl Display for PingOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for probe in &self.0 {
+            if let Some(scope) = &probe.scope {
+                write!(f, "{}{}", scope.style(PALETTE.scope), *SCOPE_SUFFIX)?;
             }
-            Self::AllScopes(default, scopes) => {
-                if let Some(default_scope) = default {
-                    default_scope.print(f, "")?;
-                    if !scopes.is_empty() {
-                        writeln!(f)?;
+            write!(
+                f,
+                "{}{} {} ",
+                probe.name.style(PALETTE.server),
+                *SERVER_SUFFIX,
+                format_args!("{}:{}", probe.address, probe.port).style(PALETTE.value),
+            )?;
+            match &probe.result {
+                Ok(reachable) => {
+                    let latency = format!("{} ms", reachable.latency.as_millis());
+                    write!(
+                        f,
+                        "{} {}",
+                        "ok".style(PALETTE.ok),
+                        latency.style(PALETTE.attr)
+                    )?;
+                    if let Some(banner) = &reachable.banner {
+                        write!(f, " {}", banner.style(PALETTE.attr))?;
                     }
+                    writeln!(f)?;
                 }
-                for (name, scope) in scopes {
-                    writeln!(f, "{}{}\n{}", name.style(SCOPE), SCOPE_SUFFIX, scope)?;
-                }
+                Err(err) => writeln!(
+                    f,
+                    "{} {}",
+                    "unreachable".style(PALETTE.failed),
+                    err.style(PALETTE.attr)
+                )?,
             }
-            Self::Scope(scope, servers) => {
-                print_scoped_servers(f, scope, servers)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for WatchOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let up = self
+            .probes
+            .iter()
+            .filter(|probe| probe.result.is_ok())
+            .count();
+        writeln!(
+            f,
+            "{} {} {}",
+            format_args!("{}/{} up", up, self.probes.len()).style(if up == self.probes.len() {
+                PALETTE.ok
+            } else {
+                PALETTE.failed
+            }),
+            format_args!(
+                "(checked {} UTC, every {}s)",
+                UtcTime(self.checked),
+                self.interval
+            )
+            .style(PALETTE.attr),
+            "Ctrl-C to stop".style(PALETTE.attr),
+        )?;
+        writeln!(f)?;
+
+        let rows: Vec<[String; 2]> = self
+            .probes
+            .iter()
+            .map(|probe| {
+                let name = match &probe.scope {
+                    Some(scope) => format!("{}/{}", scope, probe.name),
+                    None => probe.name.clone(),
+                };
+                [name, format!("{}:{}", probe.address, probe.port)]
+            })
+            .collect();
+        let header = ["SERVER", "ADDRESS", "STATE", "LATENCY", "SINCE", "DETAILS"];
+        let widths: Vec<_> = (0..2)
+            .map(|i| {
+                rows.iter()
+                    .map(|row| row[i].chars().count())
+                    .chain(iter::once(header[i].len()))
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+        // The state, latency and time columns fit their titles or values like "12m 30s"
+        let widths = [widths[0], widths[1], 4, 7, 11];
+
+        for (title, width) in header.iter().zip(widths) {
+            write!(
+                f,
+                "{}",
+                format!("{:width$}{}", title, COLUMN_GAP).style(PALETTE.attr)
+            )?;
+        }
+        writeln!(f, "{}", header[5].style(PALETTE.attr))?;
+        for ((probe, [name, address]), since) in self.probes.iter().zip(&rows).zip(&self.since) {
+            let since = HumanDuration(self.checked.saturating_sub(*since)).to_string();
+            let ((state, style), latency, details) = match &probe.result {
+                Ok(reachable) => (
+                    ("up", PALETTE.ok),
+                    format!("{} ms", reachable.latency.as_millis()),
+                    reachable.banner.clone().unwrap_or_default(),
+                ),
+                Err(err) => (
+                    ("down", PALETTE.failed),
+                    MISSING.to_string(),
+                    err.to_string(),
+                ),
+            };
+            writeln!(
+                f,
+                "{}{}{}{:w3$}{}{:w4$}{}{}",
+                format!("{:w0$}{}", name, COLUMN_GAP, w0 = widths[0]).style(PALETTE.server),
+                format!("{:w1$}{}", address, COLUMN_GAP, w1 = widths[1]).style(PALETTE.value),
+                format!("{:w2$}{}", state, COLUMN_GAP, w2 = widths[2]).style(style),
+                latency,
+                COLUMN_GAP,
+                since,
+                COLUMN_GAP,
+                details.style(PALETTE.attr),
+                w3 = widths[3],
+                w4 = widths[4],
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for WarmOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (scope, name, result) in &self.0 {
+            if let Some(scope) = scope {
+                write!(f, "{}{}", scope.style(PALETTE.scope), *SCOPE_SUFFIX)?;
+            }
+            write!(f, "{}{} ", name.style(PALETTE.server), *SERVER_SUFFIX)?;
+            match result {
+                Ok(elapsed) => {
+                    let elapsed = format!("{} ms", elapsed.as_millis());
+                    writeln!(
+                        f,
+                        "{} {}",
+                        "ok".style(PALETTE.ok),
+                        elapsed.style(PALETTE.attr)
+                    )?;
+                }
+                Err(err) => writeln!(
+                    f,
+                    "{} {}",
+                    "failed".style(PALETTE.failed),
+                    err.style(PALETTE.attr)
+                )?,
             }
         }
 
@@ -88,12 +1615,63 @@ impl Display for LsOutput {
     }
 }
 
-impl Display for TestOutput {
+impl Display for ContextsOutput {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self.0 {
-            Ok(path) => write!(f, "The configuration file {} syntax is ok", path),
-            Err(e) => write!(f, "{}", e),
+        let Self(contexts, current) = self;
+        for (name, scope) in contexts {
+            let marker = if name == current { "* " } else { INDENT };
+            let scope = if scope.is_empty() {
+                "no scope".to_string()
+            } else {
+                format!("scope '{}'", scope)
+            };
+            writeln!(
+                f,
+                "{}{} {}",
+                marker.style(PALETTE.value),
+                name.style(PALETTE.scope),
+                format!("({})", scope).style(PALETTE.attr)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for ForwardsOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return writeln!(f, "No forwards are running");
+        }
+        for forward in &self.0 {
+            writeln!(
+                f,
+                "{}{} {} -> {} via {} {}",
+                forward.name.style(PALETTE.server),
+                *SERVER_SUFFIX,
+                forward.local,
+                forward.remote,
+                forward.server,
+                format!(
+                    "({}, {} restarts, pid {})",
+                    forward.state, forward.restarts, forward.pid
+                )
+                .style(PALETTE.attr)
+            )?;
+            if forward.state == ForwardState::Reconnecting
+                && let Some(error) = &forward.last_error
+            {
+                writeln!(f, "{}{}", INDENT, error.style(PALETTE.warning))?;
+            }
         }
+
+        Ok(())
+    }
+}
+
+impl Display for CompletionOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|value| writeln!(f, "{}", value))
     }
 }
 
@@ -103,6 +1681,7 @@ impl Display for TestOutput {
 /// - Default: adds field as-is if Some
 /// - `as path`: converts PathBuf to display format
 /// - `as list`: joins iterables with ", "
+/// - `as paths`: joins iterables of PathBuf with ", "
 macro_rules! push_fields {
     ($fields:ident, { $($field:ident $(as $variant:ident)?),* $(,)? }) => {
         $(
@@ -121,6 +1700,13 @@ macro_rules! push_fields {
             $fields.push(($name, &binding));
         }
     };
+    (@inner $fields:ident, $name:expr, $opt:expr, paths) => {
+        let binding;
+        if let &Some(ref v) = $opt {
+            binding = v.iter().map(|p| p.display()).join(", ");
+            $fields.push(($name, &binding));
+        }
+    };
     (@inner $fields:ident, $name:expr, $opt:expr, list) => {
         let binding;
         if let &Some(ref v) = $opt {
@@ -132,54 +1718,123 @@ macro_rules! push_fields {
 
 impl Scope {
     fn print(&self, f: &mut impl Write, indent: &'static str) -> fmt::Result {
+        self.with_fields(|fields| print_attributes(f, fields, indent, true))
+    }
+
+    /// Passes the fields that are set to the action
+    fn with_fields<R>(&self, action: impl FnOnce(&[(&'static str, &dyn Display)]) -> R) -> R {
         let Self {
+            description,
             user,
             port,
             known_hosts,
+            extra_known_hosts,
+            host_key_check,
+            pinned_keys,
+            visual_host_key,
+            known_hosts_read_only,
             private_key,
+            password,
+            password_auth,
+            password_attempts,
+            preferred_auth,
+            sudo,
+            shared_sudo_password,
+            auth_log,
+            event_log,
             openssh_cert,
             kex,
             alg,
             cipher,
             mac,
+            profile,
             timeout,
             interval,
             retries,
+            idle_timeout,
+            buffer_size,
+            terminal_title,
+            term,
+            log_sessions,
+            login_script,
+            dns_cache_ttl,
+            network,
+            hooks,
+            socket,
         } = self;
         let mut fields: Vec<(&'static str, &dyn Display)> =
             Vec::with_capacity(SCOPE_FIELD_CAPACITY);
 
         push_fields!(fields, {
+            description,
             user,
             port,
             known_hosts as path,
+            extra_known_hosts as paths,
+            host_key_check,
+            pinned_keys as list,
+            visual_host_key,
+            known_hosts_read_only,
             private_key as path,
+            password,
+            password_auth,
+            password_attempts,
+            preferred_auth as list,
+            sudo,
+            shared_sudo_password,
+            auth_log,
+            event_log,
             openssh_cert as path,
             kex as list,
             alg as list,
             cipher as list,
             mac as list,
+            profile,
             timeout,
             interval,
             retries,
+            idle_timeout,
+            buffer_size,
+            terminal_title,
+            term,
+            log_sessions as path,
+            login_script as list,
+            dns_cache_ttl,
+            network,
+            hooks,
+            socket,
         });
 
-        print_attributes(f, &fields, indent, true)
+        action(&fields)
     }
 }
 
 impl Server {
     fn print(&self, f: &mut impl Write, indent: &'static str) -> fmt::Result {
-        let Self { address, scope } = self;
-        let fields: Vec<(&'static str, &dyn Display)> = vec![("address", address)];
         let is_last = self.scope.is_empty();
-        print_attributes(f, &fields, indent, is_last)?;
+        self.with_fields(|fields| print_attributes(f, fields, indent, is_last))?;
         if !is_last {
-            scope.print(f, indent)?;
+            self.scope.print(f, indent)?;
         }
 
         Ok(())
     }
+
+    /// Passes the fields of the server itself (not the connection settings) to the action
+    fn with_fields<R>(&self, action: impl FnOnce(&[(&'static str, &dyn Display)]) -> R) -> R {
+        let Self {
+            address,
+            fallback,
+            tags,
+            aliases,
+            notes,
+            scope: _,
+        } = self;
+        let mut fields: Vec<(&'static str, &dyn Display)> = vec![("address", address)];
+        push_fields!(fields, { fallback as list, tags as list, aliases as list, notes });
+
+        action(&fields)
+    }
 }
 
 impl Display for Scope {
@@ -194,7 +1849,7 @@ fn print_scoped_servers(
     scope: &str,
     servers: &IndexMap<String, ScopedServer>,
 ) -> fmt::Result {
-    writeln!(f, "{}{}", scope.style(SCOPE), SCOPE_SUFFIX)?;
+    writeln!(f, "{}{}", scope.style(PALETTE.scope), *SCOPE_SUFFIX)?;
     print_servers(f, &servers.iter().collect::<Vec<_>>(), false)
 }
 
@@ -216,15 +1871,21 @@ fn print_servers(
                 f,
                 "{}{}{} {}",
                 name_indent,
-                name.style(SERVER),
-                SERVER_SUFFIX,
-                address.style(VALUE),
+                name.style(PALETTE.server),
+                *SERVER_SUFFIX,
+                address.style(PALETTE.value),
             )?;
         }
     }
     for (name, server) in servers {
         if let ScopedServer::Override(inner) = server {
-            writeln!(f, "{}{}{}", name_indent, name.style(SERVER), SERVER_SUFFIX)?;
+            writeln!(
+                f,
+                "{}{}{}",
+                name_indent,
+                name.style(PALETTE.server),
+                *SERVER_SUFFIX
+            )?;
             inner.print(f, attr_indent)?;
             writeln!(f)?;
         }
@@ -244,9 +1905,9 @@ fn print_attributes(
             writeln!(
                 f,
                 "{}{} {}",
-                key.style(ATTR),
-                ATTR_SUFFIX,
-                value.style(VALUE)
+                key.style(PALETTE.attr),
+                *ATTR_SUFFIX,
+                value.style(PALETTE.value)
             )?;
         }
         return Ok(());
@@ -264,16 +1925,106 @@ fn print_attributes(
             f,
             "{}{}{}{} {}",
             indent,
-            LAST_BRANCH,
-            last.0.style(ATTR),
-            ATTR_SUFFIX,
-            last.1.style(VALUE),
+            *LAST_BRANCH,
+            last.0.style(PALETTE.attr),
+            *ATTR_SUFFIX,
+            last.1.style(PALETTE.value),
         )
     } else {
         write_tree_attr(f, indent, last.0, last.1)
     }
 }
 
+fn field_texts(fields: &[(&'static str, &dyn Display)]) -> FieldTexts {
+    fields
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect()
+}
+
+/// A server of the tree, its settings marked where they shadow the ones of the parents
+fn print_tree_server(
+    f: &mut impl Write,
+    name: &str,
+    server: &ScopedServer,
+    parents: &[(&str, &[(&'static str, String)])],
+) -> fmt::Result {
+    match server {
+        ScopedServer::Address(address) => writeln!(
+            f,
+            "{}{}{} {}",
+            INDENT,
+            name.style(PALETTE.server),
+            *SERVER_SUFFIX,
+            address.style(PALETTE.value)
+        ),
+        ScopedServer::Override(server) => {
+            writeln!(
+                f,
+                "{}{}{}",
+                INDENT,
+                name.style(PALETTE.server),
+                *SERVER_SUFFIX
+            )?;
+            print_shadowing(
+                f,
+                server.with_fields(field_texts),
+                &server.scope.with_fields(field_texts),
+                parents,
+                SCOPE_INDENT,
+            )
+        }
+    }
+}
+
+/// Prints the own fields followed by the settings, each setting that is also set
+/// by a parent gets a comment with the values it overrides
+fn print_shadowing(
+    f: &mut impl Write,
+    own: FieldTexts,
+    settings: &[(&'static str, String)],
+    parents: &[(&str, &[(&'static str, String)])],
+    indent: &str,
+) -> fmt::Result {
+    let lines = own
+        .into_iter()
+        .map(|(name, value)| (name, value, String::new()))
+        .chain(settings.iter().map(|(name, value)| {
+            let shadowed = parents
+                .iter()
+                .filter_map(|(parent, fields)| {
+                    let (_, shadowed) = fields.iter().find(|(field, _)| field == name)?;
+                    Some(format!("{}: {}", parent, shadowed))
+                })
+                .join(", ");
+            let comment = match shadowed.is_empty() {
+                true => String::new(),
+                false => format!(" # overrides {}", shadowed),
+            };
+            (*name, value.clone(), comment)
+        }))
+        .collect::<Vec<_>>();
+
+    for (i, (name, value, comment)) in lines.iter().enumerate() {
+        let branch = match i + 1 == lines.len() {
+            true => &*LAST_BRANCH,
+            false => &*TREE_BRANCH,
+        };
+        writeln!(
+            f,
+            "{}{}{}{} {}{}",
+            indent,
+            branch,
+            name.style(PALETTE.attr),
+            *ATTR_SUFFIX,
+            value.style(PALETTE.value),
+            comment.style(PALETTE.warning)
+        )?;
+    }
+
+    Ok(())
+}
+
 #[inline]
 fn write_tree_attr(
     f: &mut impl Write,
@@ -285,9 +2036,238 @@ fn write_tree_attr(
         f,
         "{}{}{}{} {}",
         indent,
-        TREE_BRANCH,
-        key.style(ATTR),
-        ATTR_SUFFIX,
-        value.style(VALUE)
+        *TREE_BRANCH,
+        key.style(PALETTE.attr),
+        *ATTR_SUFFIX,
+        value.style(PALETTE.value)
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::CliError;
+
+    #[test]
+    fn tree_marks_overrides() {
+        let config: Config = serde_yml::from_str(
+            "user: admin
+port: 22
+scopes:
+  prod:
+    user: deploy
+servers:
+  bastion: 10.0.0.1
+  prod:
+    db:
+      address: 10.0.1.1
+      user: postgres
+      port: 5432
+",
+        )
+        .unwrap();
+        let expected = "(default)
+  ├─╴user: admin
+  └─╴port: 22
+  bastion: 10.0.0.1
+
+prod/
+  └─╴user: deploy # overrides (default): admin
+  db:
+    ├─╴address: 10.0.1.1
+    ├─╴user: postgres # overrides prod: deploy, (default): admin
+    └─╴port: 5432 # overrides (default): 22
+";
+        assert_eq!(strip_styles(&TreeOutput(config).to_string()), expected);
+    }
+
+    #[test]
+    fn ascii_branches() {
+        assert_eq!(branch(false, false).chars().count(), 3);
+        assert_eq!(branch(false, true), "|- ");
+        assert_eq!(branch(true, true), "`- ");
+        assert_eq!(strip_styles(&TREE_BRANCH.to_string()), "├─╴");
+    }
+
+    #[test]
+    fn theme_palette() {
+        let theme = Theme {
+            preset: Some(ThemePreset::Plain),
+            scope: Some("cyan".parse().unwrap()),
+            ..Default::default()
+        };
+        let palette = Palette::from_theme(&theme);
+        assert_eq!(
+            "a".style(palette.scope).to_string(),
+            "a".style(Style::new().cyan()).to_string()
+        );
+        assert_eq!("a".style(palette.server).to_string(), "a");
+
+        let palette = Palette::from_theme(&Theme::default());
+        assert_eq!(
+            "a".style(palette.server).to_string(),
+            "a".style(Style::new().bright_green()).to_string()
+        );
+    }
+
+    #[test]
+    fn format_history_time() {
+        assert_eq!(UtcTime(0).to_string(), "1970-01-01 00:00:00");
+        assert_eq!(UtcTime(951_827_696).to_string(), "2000-02-29 12:34:56");
+        assert_eq!(HumanDuration(3723).to_string(), "1h 2m 3s");
+        assert_eq!(HumanDuration(59).to_string(), "59s");
+        assert_eq!(Ago(None).to_string(), "never");
+        assert_eq!(Ago(Some(59)).to_string(), "just now");
+        assert_eq!(Ago(Some(7200)).to_string(), "2h ago");
+        assert_eq!(Ago(Some(3 * 86400 + 5)).to_string(), "3d ago");
+    }
+
+    #[test]
+    fn last_seen_by_alias() {
+        let mut server = Server::new("10.0.0.1".into());
+        server.aliases = Some(vec!["primary".into()]);
+        let last_seen = LastSeen {
+            times: HashMap::from([("db".into(), 100), ("primary".into(), 400)]),
+            now: 1000,
+        };
+        assert_eq!(last_seen.ago(&"db".into(), &server).unwrap().0, Some(600));
+        assert_eq!(
+            last_seen.ago(&"web".into(), &Server::default()).unwrap().0,
+            None
+        );
+        assert!(last_seen.ago(&"web-*".into(), &Server::default()).is_none());
+    }
+
+    #[test]
+    fn table_alignment() {
+        let mut server = Server::new("10.0.0.1".into());
+        server.scope.port = Some(22);
+        let servers = vec![
+            (Some("work".into()), "db".into(), server.clone()),
+            (None, "web-frontend".into(), server),
+        ];
+        let table = TableOutput::servers(servers, false, None);
+        assert_eq!(table.rows[0][1], "work");
+        assert_eq!(table.rows[1][3], MISSING);
+
+        let text = table.to_string();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        // The address column starts at the same position in every line
+        let column = lines[1].find("10.0.0.1");
+        assert!(column.is_some());
+        assert_eq!(lines[2].find("10.0.0.1"), column);
+    }
+
+    #[test]
+    fn long_line_notes() {
+        let mut server = Server::new("10.0.0.1".into());
+        server.scope.user = Some("root".into());
+        server.notes = Some("console via iDRAC\nticket OPS-42".into());
+        let text = strip_styles(&LongOutput(vec![(None, "db".into(), server)], None).to_string());
+        assert_eq!(text, "db: root@10.0.0.1 # console via iDRAC\n");
+    }
+
+    #[test]
+    fn list_as_json() {
+        let mut servers = IndexMap::new();
+        servers.insert("db".to_string(), ScopedServer::Address("10.0.0.1".into()));
+        let output = JsonOutput::from(LsOutput::Scope("work".into(), servers));
+        let json: serde_json::Value =
+            serde_json::from_str(&output.to_string()).expect("Valid JSON");
+        assert_eq!(
+            json,
+            serde_json::json!([{"scope": "work", "name": "db", "address": "10.0.0.1"}])
+        );
+    }
+
+    #[test]
+    fn error_as_json() {
+        let err = anyhow::Error::from(CliError::InvalidAlias("up".into()));
+        let json: serde_json::Value =
+            serde_json::from_str(&JsonError::from(&err).to_string()).expect("Valid JSON");
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "code": "config",
+                "message": "Command alias 'up' has unbalanced quotes",
+                "hint": "check the config file",
+                "source": null,
+            })
+        );
+    }
+
+    /// Text without the color codes
+    fn strip_styles(styled: &str) -> String {
+        let mut plain = String::with_capacity(styled.len());
+        let mut chars = styled.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|&c| c == 'm');
+            } else {
+                plain.push(c);
+            }
+        }
+
+        plain
+    }
+
+    #[test]
+    fn current_scope() {
+        let settings = Scope {
+            user: Some("deploy".into()),
+            ..Default::default()
+        };
+        let output = CurrentOutput {
+            scope: "prod".into(),
+            origin: ScopeOrigin::Context(Some(951_827_696)),
+            settings: Some(settings),
+            servers: 3,
+            global_servers: 1,
+            context: "work".into(),
+            context_path: "/home/alice/.shh/cache.json",
+        };
+        let text = strip_styles(&output.to_string());
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines[..4],
+            [
+                "scope: prod (chosen with use, until 2000-02-29 12:34:56 UTC)",
+                "context: work (stored in '/home/alice/.shh/cache.json')",
+                "servers: 3 in the scope, 1 global",
+                "prod/",
+            ]
+        );
+        assert!(lines[4].ends_with("user: deploy"));
+    }
+
+    #[test]
+    fn explained_error() {
+        let err =
+            anyhow::Error::from(CliError::InvalidAlias("up".into())).context("Loading config");
+        let explained = |hints| {
+            ExplainedError {
+                error: &err,
+                hints,
+                command: "shh up".into(),
+                config: "/home/alice/.shh/config.yml",
+                scope: Some(String::new()),
+            }
+            .to_string()
+        };
+        let lines: Vec<_> = explained(true).lines().map(strip_styles).collect();
+        assert_eq!(
+            lines,
+            [
+                "error: Loading config",
+                "  caused by: Command alias 'up' has unbalanced quotes",
+                "  hint: check the config file",
+                "  failure: config (exit code 3)",
+                "  command: shh up",
+                "  config: /home/alice/.shh/config.yml",
+                "  scope: (none)",
+            ]
+        );
+        assert!(!explained(false).contains("hint"));
+    }
+}
@@ -0,0 +1,80 @@
+use crossterm::terminal;
+use log::debug;
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::process::{Command, Stdio};
+
+/// Used when `$PAGER` isn't set, `-R` passes the colors through
+const DEFAULT_PAGER: &str = "less -R";
+
+/// Prints the output, through the pager if it's going to a terminal
+/// and doesn't fit on the screen. A pager that can't be started is skipped.
+pub fn print(output: &str) {
+    if io::stdout().is_terminal()
+        && let Ok((width, height)) = terminal::size()
+        && screen_lines(output, width.into()) >= height.into()
+        && page(output).is_ok()
+    {
+        return;
+    }
+    print!("{}", output);
+}
+
+/// Writes the output to the pager and waits for it to be closed
+fn page(output: &str) -> io::Result<()> {
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PAGER.into());
+    let args = shlex::split(&pager).unwrap_or_default();
+    let Some((program, args)) = args.split_first() else {
+        return Err(io::ErrorKind::InvalidInput.into());
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+        .inspect_err(|e| debug!("Failed to start the pager '{}': {}", pager, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // Quitting the pager early closes the pipe, which isn't a failure
+        let _ = stdin.write_all(output.as_bytes());
+    }
+    child.wait()?;
+
+    Ok(())
+}
+
+/// Rows the text takes on a screen of the width, long lines wrap
+fn screen_lines(text: &str, width: usize) -> usize {
+    text.lines()
+        .map(|line| visible_width(line).div_ceil(width.max(1)).max(1))
+        .sum()
+}
+
+/// Characters of the line without the color codes
+fn visible_width(line: &str) -> usize {
+    let mut width = 0;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|&c| c == 'm');
+        } else {
+            width += 1;
+        }
+    }
+
+    width
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_on_screen() {
+        assert_eq!(visible_width("\x1b[34m├─╴\x1b[0maddress"), 10);
+        assert_eq!(screen_lines("a\n\nb\n", 80), 3);
+        assert_eq!(screen_lines(&"x".repeat(81), 80), 2);
+        assert_eq!(screen_lines("", 80), 0);
+    }
+}
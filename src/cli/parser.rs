@@ -1,21 +1,32 @@
+use crate::cli::complete::CompletionKind;
+use crate::cli::shell_init::{DEFAULT_TEMPLATE, PromptShell};
+use crate::client::forward::ForwardSpec;
 use crate::error::CliError;
 use crate::storage::config::{Scope, Server};
+use crate::storage::import::ColumnMap;
+use crate::storage::theme::Color;
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Args, Command, FromArgMatches, Parser, Subcommand, ValueEnum};
 use clap_complete::Shell;
 use itertools::Itertools;
 use serde::Serialize;
 use serde::Serializer;
 use serde::{Deserialize, Deserializer};
 use std::fmt::Display;
+use std::io::BufRead;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
+
+/// Placeholder for the server to read servers from stdin instead
+pub const STDIN_SERVERS: &str = "-";
 
 #[derive(Debug, Parser)]
 #[command(version)]
 #[command(about = "🐚 Seashell is a handy SSH client written in Rust (sea noise inside)")]
-#[command(arg_required_else_help = true)]
 pub struct Cli {
-    /// Connect to the server [user@]hostname[:port]
+    /// Connect to the server [user@]hostname[:port][?option=value&...]
+    /// ('-' reads servers from stdin)
     pub server: Option<ServerUri>,
     /// Command to execute on the remote server
     pub remote_cmd: Option<String>,
@@ -24,9 +35,73 @@ pub struct Cli {
     pub conn_flags: Scope,
     #[command(subcommand)]
     pub subcommand: Option<CliSubcommand>,
-    /// Enable detailed logging (-v INFO, -vv DEBUG)
+    /// Enable detailed logging (-v INFO, -vv DEBUG, -vvv TRACE including dependencies)
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub verbose: u8,
+    /// Print only errors and the output of commands
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Resolve hostnames again instead of using the addresses cached by earlier connections
+    #[arg(long)]
+    pub no_dns_cache: bool,
+    /// Replace the recorded key of a server whose key changed (e.g. after a reinstall)
+    #[arg(long)]
+    pub replace_host_key: bool,
+    /// Print errors with all their causes and the context they happened in
+    #[arg(long, global = true)]
+    pub explain: bool,
+    /// Leave the "(hint: ...)" suffixes out of error messages
+    #[arg(long, global = true)]
+    pub no_hints: bool,
+    /// Draw trees with ASCII characters only, for terminals and logs that mangle box drawing
+    #[arg(long, global = true)]
+    pub ascii: bool,
+    /// Print long listings (ls, show, tree, history) directly instead of through $PAGER
+    #[arg(long, global = true)]
+    pub no_pager: bool,
+}
+
+impl Cli {
+    /// Whether the output, errors included, is requested as JSON
+    pub fn json_output(&self) -> bool {
+        matches!(
+            self.subcommand,
+            Some(
+                CliSubcommand::Ls {
+                    format: ListFormat::Json,
+                    ..
+                } | CliSubcommand::Show {
+                    format: OutputFormat::Json,
+                    ..
+                } | CliSubcommand::Resolve {
+                    format: OutputFormat::Json,
+                    ..
+                } | CliSubcommand::Test {
+                    format: OutputFormat::Json,
+                    ..
+                } | CliSubcommand::Check {
+                    format: OutputFormat::Json,
+                    ..
+                } | CliSubcommand::Audit {
+                    format: OutputFormat::Json,
+                }
+            )
+        )
+    }
+
+    /// Whether the output may go through the pager
+    pub fn paged(&self) -> bool {
+        !self.no_pager
+            && matches!(
+                self.subcommand,
+                Some(
+                    CliSubcommand::Ls { .. }
+                        | CliSubcommand::Show { .. }
+                        | CliSubcommand::Tree
+                        | CliSubcommand::History { .. }
+                )
+            )
+    }
 }
 
 #[derive(Debug, Subcommand)]
@@ -39,12 +114,76 @@ pub enum CliSubcommand {
         /// Show all scopes
         #[arg(short, long)]
         scopes: bool,
+        /// Show only servers having all of these tags
+        #[arg(short, long, value_name = "CSV", value_delimiter = ',')]
+        tag: Vec<String>,
+        /// Put the most frequently and recently used servers first
+        #[arg(short, long)]
+        frecent: bool,
+        /// Show only servers connecting as this user (taking scopes into account)
+        #[arg(long, value_name = "STRING")]
+        user: Option<String>,
+        /// Show only servers connecting to this port (taking scopes into account)
+        #[arg(long, value_name = "NUM")]
+        port: Option<u16>,
+        /// Show only servers using this private key (taking scopes into account)
+        #[arg(long, value_name = "FILE_PATH")]
+        key: Option<PathBuf>,
+        /// Show each server on one line with its effective user, port and key
+        #[arg(short, long, conflicts_with_all = ["scopes", "format"])]
+        long: bool,
+        /// Show how long ago each server was last connected to ("3d ago", or "never"),
+        /// one server per line unless a table format is chosen
+        #[arg(long, conflicts_with = "scopes")]
+        last: bool,
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: ListFormat,
+    },
+    /// Show a single server or scope
+    Show {
+        /// Name of the server (or scope)
+        name: String,
+        /// Show a scope instead of a server
+        #[arg(short, long)]
+        scope: bool,
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Show the defaults, scopes and servers as one tree, marking overridden settings
+    Tree,
+    /// Search servers by name, alias, address or tag in all scopes
+    #[command(visible_alias = "search")]
+    Find {
+        /// Substring or characters in order (fuzzy)
+        query: String,
+    },
+    /// Show the resolved connection parameters and where they come from
+    Resolve {
+        /// Server to resolve [user@]hostname[:port]
+        server: ServerUri,
+        /// Connection details as they would be passed explicitly
+        #[command(flatten)]
+        conn_flags: Scope,
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
     },
     /// Change scope
     Use {
         /// Scope to switch to
-        scope: String,
+        #[arg(required_unless_present = "auto")]
+        scope: Option<String>,
+        /// Keep the scope active only for a time, e.g. 30m, 1h or 1h30m
+        #[arg(long = "for", value_name = "DURATION", value_parser = parse_duration)]
+        ttl: Option<Duration>,
+        /// Drop the chosen scope, the one matching the network is used instead
+        #[arg(long, conflicts_with_all = ["scope", "ttl"])]
+        auto: bool,
     },
+    /// Show the active scope, where it comes from, its settings and the context
+    Current,
     /// Add server
     #[command(visible_alias = "server")]
     AddServer {
@@ -61,48 +200,595 @@ pub enum CliSubcommand {
     AddScope {
         /// Name of the scope
         name: String,
+        /// What the scope is for, shown when listing scopes
+        #[arg(long = "desc", value_name = "TEXT")]
+        description: Option<String>,
         #[command(flatten)]
         scope: Scope,
     },
     /// Remove server or scope
     #[command(visible_alias = "remove")]
     Rm {
-        /// Name of the server
-        #[arg(required_unless_present = "scope")]
-        server: Option<String>,
-        /// Name of the scope
-        #[arg(short, long = "scope", conflicts_with = "server")]
+        /// Names of the servers
+        #[arg(required_unless_present_any = ["scope", "tag"])]
+        servers: Vec<String>,
+        /// Name of the scope (removed along with its servers)
+        #[arg(short, long = "scope", conflicts_with_all = ["servers", "tag"])]
         scope: Option<String>,
+        /// Remove all servers having all of these tags
+        #[arg(short, long, value_name = "CSV", value_delimiter = ',')]
+        #[arg(conflicts_with = "servers")]
+        tag: Vec<String>,
+        /// Remove a scope with servers without confirmation
+        #[arg(short, long)]
+        force: bool,
+        /// Only show what would be removed
+        #[arg(short = 'n', long, conflicts_with = "force")]
+        dry_run: bool,
+    },
+    /// Modify server or scope
+    Set {
+        /// Name of the server
+        #[arg(required_unless_present = "scope_name")]
+        name: Option<String>,
+        /// Name of the scope to modify instead of a server
+        #[arg(short, long = "scope", conflicts_with_all = ["name", "address", "fallback", "tags", "aliases", "notes"])]
+        scope_name: Option<String>,
+        /// New address of the server
+        #[arg(long, value_name = "STRING")]
+        address: Option<String>,
+        /// New fallback addresses of the server (replace existing ones, an empty value removes them)
+        #[arg(short = 'F', long = "fallback")]
+        #[arg(value_name = "CSV", value_delimiter = ',')]
+        fallback: Option<Vec<String>>,
+        /// New tags of the server (replace existing ones)
+        #[arg(short = 'T', long = "tag")]
+        #[arg(value_name = "CSV", value_delimiter = ',')]
+        tags: Option<Vec<String>>,
+        /// New aliases of the server (replace existing ones)
+        #[arg(short = 'A', long = "alias")]
+        #[arg(value_name = "CSV", value_delimiter = ',')]
+        aliases: Option<Vec<String>>,
+        /// New notes of the server (an empty value removes them)
+        #[arg(short = 'N', long, value_name = "TEXT")]
+        notes: Option<String>,
+        /// New description of the scope
+        #[arg(long = "desc", value_name = "TEXT", conflicts_with = "name")]
+        description: Option<String>,
+        /// Apply the settings to every server of the scope instead of the scope itself
+        #[arg(long, requires = "scope_name", conflicts_with = "description")]
+        servers: bool,
+        /// Only show what would change on the servers
+        #[arg(short = 'n', long, requires = "servers")]
+        dry_run: bool,
+        /// Change the servers without confirmation
+        #[arg(short, long, requires = "servers", conflicts_with = "dry_run")]
+        yes: bool,
+        #[command(flatten)]
+        scope: Scope,
+    },
+    /// Copy server under a new name
+    #[command(visible_alias = "duplicate")]
+    Clone {
+        /// Name of the server to copy
+        name: String,
+        /// Name of the copy
+        new_name: String,
+        /// Address of the copy
+        #[arg(long, value_name = "STRING")]
+        address: Option<String>,
+        /// Connection details to change in the copy
+        #[command(flatten)]
+        scope: Scope,
+    },
+    /// Rename server or scope
+    #[command(visible_alias = "rename")]
+    Mv {
+        /// Current name of the server (or scope)
+        old: String,
+        /// New name of the server (or scope)
+        new: String,
+        /// Rename a scope instead of a server
+        #[arg(short, long)]
+        scope: bool,
+    },
+    /// Manage contexts, each with its own active scope and history
+    Context {
+        #[command(subcommand)]
+        action: ContextAction,
     },
     /// Set default connection data
     Default {
         #[command(flatten)]
         scope: Scope,
     },
+    /// Manage secrets stored in the OS keyring
+    Secret {
+        #[command(subcommand)]
+        action: SecretAction,
+    },
+    /// Synchronize the work directory with a git remote (or a directory with a server)
+    #[command(args_conflicts_with_subcommands = true)]
+    Sync {
+        #[command(subcommand)]
+        action: Option<SyncAction>,
+        /// Remote repository to push to and pull from (saved for later runs)
+        #[arg(short, long, value_name = "URL")]
+        remote: Option<String>,
+    },
+    /// Find and remove stale servers (checks everything if no criteria are given)
+    Prune {
+        /// Servers not connected to for this many days
+        #[arg(short, long, value_name = "DAYS")]
+        days: Option<u64>,
+        /// With the days, also servers that aren't in the history at all
+        #[arg(long)]
+        never_used: bool,
+        /// Servers whose address does not resolve
+        #[arg(long)]
+        dns: bool,
+        /// Servers referencing missing key files
+        #[arg(short, long)]
+        keys: bool,
+        /// Only list stale servers
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Remove all stale servers without confirmation
+        #[arg(short, long, conflicts_with = "dry_run")]
+        yes: bool,
+    },
+    /// Create servers from other sources
+    Import {
+        #[command(subcommand)]
+        source: ImportSource,
+    },
+    /// Print servers with their effective settings for other tools
+    Export {
+        #[arg(value_enum)]
+        format: ExportFormat,
+        /// Export only the servers of this scope (default: all servers)
+        #[arg(short, long)]
+        scope: Option<String>,
+    },
+    /// Find SSH servers announced on the local network (mDNS/DNS-SD) and add new ones
+    Discover {
+        /// Scope to add the servers to (default: the active one)
+        #[arg(short, long)]
+        scope: Option<String>,
+        /// Seconds to wait for answers
+        #[arg(long, value_name = "SECS", default_value_t = 3)]
+        timeout: u64,
+        /// Use the announced IP addresses instead of the .local names
+        #[arg(long)]
+        ip: bool,
+        /// Only list the new servers
+        #[arg(long)]
+        dry_run: bool,
+        /// Add all new servers without confirmation
+        #[arg(short, long, conflicts_with = "dry_run")]
+        yes: bool,
+    },
+    /// Check whether servers accept connections (without authenticating)
+    Ping {
+        /// Servers to check ('-' reads them from stdin)
+        #[arg(required_unless_present_any = ["scope", "all"])]
+        servers: Vec<ServerUri>,
+        /// Check all servers of the scope
+        #[arg(short, long, conflicts_with_all = ["servers", "all"])]
+        scope: Option<String>,
+        /// Check all servers (in every scope and global ones)
+        #[arg(short, long, conflicts_with = "servers")]
+        all: bool,
+        /// Also wait for the SSH banner of the server
+        #[arg(long)]
+        ssh: bool,
+        /// Seconds to wait for each server
+        #[arg(long, value_name = "SECS", default_value_t = 5)]
+        timeout: u64,
+        /// Maximum number of servers checked at once
+        #[arg(short, long, value_name = "NUM", default_value_t = 16)]
+        jobs: usize,
+    },
+    /// Keep checking servers, showing whether they are up in a table refreshed in place
+    Watch {
+        /// Servers to watch ('-' reads them from stdin)
+        #[arg(required_unless_present_any = ["scope", "all"])]
+        servers: Vec<ServerUri>,
+        /// Watch all servers of the scope
+        #[arg(short, long, conflicts_with_all = ["servers", "all"])]
+        scope: Option<String>,
+        /// Watch all servers (in every scope and global ones)
+        #[arg(short, long, conflicts_with = "servers")]
+        all: bool,
+        /// Also wait for the SSH banner of the servers
+        #[arg(long)]
+        ssh: bool,
+        /// Seconds to wait for each server
+        #[arg(long, value_name = "SECS", default_value_t = 5)]
+        timeout: u64,
+        /// Maximum number of servers checked at once
+        #[arg(short, long, value_name = "NUM", default_value_t = 16)]
+        jobs: usize,
+        /// Seconds between the starts of two rounds of checks
+        #[arg(short, long, value_name = "SECS", default_value_t = 5)]
+        interval: u64,
+    },
+    /// Connect and authenticate ahead of time (e.g. before a maintenance window)
+    Warm {
+        /// Servers to warm up ('-' reads them from stdin)
+        #[arg(required_unless_present_any = ["scope", "all"])]
+        servers: Vec<ServerUri>,
+        /// Warm up all servers of the scope
+        #[arg(short, long, conflicts_with_all = ["servers", "all"])]
+        scope: Option<String>,
+        /// Warm up all servers (in every scope and global ones)
+        #[arg(short, long, conflicts_with = "servers")]
+        all: bool,
+        /// Maximum number of servers connected to at once
+        #[arg(short, long, value_name = "NUM", default_value_t = 16)]
+        jobs: usize,
+    },
+    /// Forward local ports to hosts reachable from a server
+    Forward {
+        #[command(subcommand)]
+        action: ForwardAction,
+    },
+    /// Upload a local script to the server, run it with the arguments and remove it
+    /// (exits with the status of the script)
+    Run {
+        server: ServerUri,
+        /// Script to run (by its shebang, or by sh without one)
+        #[arg(value_name = "SCRIPT_FILE")]
+        script: PathBuf,
+        /// Arguments passed to the script
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Copy a file (or a directory) from one server to another through this machine
+    /// (each server is connected to with its own settings)
+    Cp {
+        /// File to copy, as SERVER:PATH
+        source: RemotePath,
+        /// File or directory to copy to, as SERVER:PATH
+        destination: RemotePath,
+        /// Copy the source directory into the destination directory (needs tar on both)
+        #[arg(short, long)]
+        recursive: bool,
+        /// Leave out files matching the pattern when copying a directory (repeatable)
+        #[arg(long, value_name = "PATTERN", requires = "recursive")]
+        exclude: Vec<String>,
+    },
+    /// Manage known server keys
+    Hosts {
+        #[command(subcommand)]
+        action: HostsAction,
+    },
+    /// Trust host certificates signed by a CA (adds a @cert-authority entry to known_hosts)
+    TrustCa {
+        /// Public key file of the CA
+        #[arg(value_name = "PUBKEY_FILE")]
+        key: PathBuf,
+        /// Hosts the CA is trusted for
+        #[arg(short, long, value_name = "PATTERN", default_value = "*")]
+        domain: String,
+        /// known_hosts file to add the entry to (default: the one of the default settings)
+        #[arg(long, value_name = "FILE_PATH")]
+        known_hosts: Option<PathBuf>,
+    },
+    /// Pick a server interactively and connect to it
+    Pick,
+    /// Browse scopes and servers in a full-screen interface
+    Tui,
+    /// Show recent connections
+    History {
+        /// Show only connections to this server
+        server: Option<String>,
+        /// Maximum number of connections to show
+        #[arg(short = 'n', long, value_name = "NUM", default_value_t = 20)]
+        limit: usize,
+    },
     /// Edit the configuration file
     Edit,
-    /// Check the configuration syntax
-    Test,
+    /// Check the configuration syntax and settings
+    Test {
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Check everything a connection to the server needs, short of connecting
+    /// (config entry, key and certificate, known_hosts, DNS)
+    Check {
+        /// Server to check [user@]hostname[:port]
+        server: ServerUri,
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Check the configuration for weak security settings (fails on errors)
+    Audit {
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
     /// Generate shell completions
     Generate {
         /// Shell type
         #[arg(value_enum)]
         shell: Shell,
     },
+    /// Print the active scope for a shell prompt (nothing when no scope is active)
+    Prompt {
+        #[command(flatten)]
+        style: PromptStyle,
+        /// Shell the prompt is for, to mark the color codes as zero-width
+        #[arg(long, value_enum)]
+        shell: Option<PromptShell>,
+    },
+    /// Print the shell code showing the active scope in the prompt
+    /// (e.g. `eval "$(shh shell-init bash)"` in ~/.bashrc)
+    ShellInit {
+        #[arg(value_enum)]
+        shell: PromptShell,
+        #[command(flatten)]
+        style: PromptStyle,
+    },
+    /// Print values for dynamic shell completions
+    #[command(hide = true)]
+    Complete {
+        #[arg(value_enum)]
+        kind: CompletionKind,
+        /// Word being completed (for remote paths)
+        #[arg(default_value = "")]
+        word: String,
+    },
 }
 
-/// URI format: [user@]host[:port]
+/// How the scope is shown in the prompt
+#[derive(Args, Debug)]
+pub struct PromptStyle {
+    /// Template of the segment, `{scope}` and `{context}` are replaced
+    #[arg(long, value_name = "TEMPLATE", default_value = DEFAULT_TEMPLATE)]
+    pub format: String,
+    /// Color of the segment (a name like `red` or `bright cyan`, or `#rrggbb`)
+    #[arg(long)]
+    pub color: Option<Color>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ForwardAction {
+    /// Forward a local port through the server (like ssh -L)
+    Start {
+        /// Server to forward through [user@]hostname[:port]
+        server: ServerUri,
+        /// What to forward: [BIND:]PORT:HOST:HOSTPORT
+        spec: ForwardSpec,
+        /// Keep forwarding in the background, also after the terminal closes
+        #[arg(short, long)]
+        detach: bool,
+        /// Serve as the background process started by --detach
+        #[arg(long, hide = true)]
+        daemon: bool,
+        /// Scope the server is looked up in, as resolved when the forward was started
+        #[arg(long, hide = true, requires = "daemon", value_name = "SCOPE")]
+        in_scope: Option<String>,
+    },
+    /// List running forwards
+    Ls,
+    /// Stop a running forward
+    Stop {
+        /// Name of the forward (see `forward ls`)
+        name: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SyncAction {
+    /// Send the files of the local directory that the server lacks or has in another version
+    Push {
+        server: ServerUri,
+        #[arg(value_name = "LOCAL_DIR")]
+        local: PathBuf,
+        #[arg(value_name = "REMOTE_DIR")]
+        remote: String,
+        /// Only list the files that would be sent
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Fetch the files of the directory on the server that are missing or differ locally
+    Pull {
+        server: ServerUri,
+        #[arg(value_name = "LOCAL_DIR")]
+        local: PathBuf,
+        #[arg(value_name = "REMOTE_DIR")]
+        remote: String,
+        /// Only list the files that would be fetched
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ContextAction {
+    /// Switch to the context (created if it doesn't exist)
+    Use { name: String },
+    /// List contexts with their active scopes
+    Ls,
+    /// Remove a context along with its history
+    #[command(visible_alias = "remove")]
+    Rm { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum HostsAction {
+    /// Copy entries of an OpenSSH known_hosts file (plain and hashed ones)
+    Import {
+        #[arg(value_name = "FILE_PATH", default_value = "~/.ssh/known_hosts")]
+        path: PathBuf,
+        /// known_hosts file to add the entries to (default: the one of the default settings)
+        #[arg(long, value_name = "FILE_PATH")]
+        known_hosts: Option<PathBuf>,
+    },
+    /// Remove the keys recorded for a host (e.g. after it was reinstalled)
+    #[command(visible_alias = "remove")]
+    Rm {
+        /// Hostname or IP as recorded in known_hosts
+        host: String,
+        /// Port the keys were recorded for
+        #[arg(short, long, default_value_t = 22)]
+        port: u16,
+        /// known_hosts file to remove the keys from (default: the one of the default settings)
+        #[arg(long, value_name = "FILE_PATH")]
+        known_hosts: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ImportSource {
+    /// Entries of a hosts file (IP NAME [ALIASES...] per line)
+    Hosts {
+        #[arg(value_name = "FILE_PATH", default_value = "/etc/hosts")]
+        path: PathBuf,
+        /// Only names in the domain, the suffix is dropped from server names
+        #[arg(short, long, value_name = "SUFFIX")]
+        domain: Option<String>,
+        /// Scope to add the servers to (default: the active one)
+        #[arg(short, long)]
+        scope: Option<String>,
+        /// Only list the servers that would be created
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Rows of a CSV file (e.g. exported from a spreadsheet or CMDB)
+    Csv {
+        #[arg(value_name = "FILE_PATH")]
+        path: PathBuf,
+        /// Columns of the settings, numbered from 1 (fields: name, address, user,
+        /// port, key, tags, aliases; lists in a cell are separated by ';')
+        #[arg(
+            short,
+            long,
+            value_name = "FIELD=COLUMN",
+            value_delimiter = ',',
+            required = true
+        )]
+        map: Vec<ColumnMap>,
+        /// Skip the first row
+        #[arg(long)]
+        header: bool,
+        /// Scope to add the servers to (default: the active one)
+        #[arg(short, long)]
+        scope: Option<String>,
+        /// Only list the servers that would be created
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SecretAction {
+    /// Store a secret (referenced in config as keyring:NAME)
+    Set {
+        /// Name of the secret
+        name: String,
+    },
+    /// Remove a secret
+    Rm {
+        /// Name of the secret
+        name: String,
+    },
+}
+
+/// How servers (or scopes) are listed
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum ListFormat {
+    /// Colored tree for humans
+    #[default]
+    Tree,
+    /// Aligned columns with the effective settings
+    Table,
+    /// Table with more columns
+    Wide,
+    /// Structured data for scripts
+    Json,
+}
+
+/// How results of the show and resolve commands are printed
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Colored tree for humans
+    #[default]
+    Tree,
+    /// Structured data for scripts
+    Json,
+}
+
+/// How the export command prints the servers
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ExportFormat {
+    /// Array of objects with all settings
+    Json,
+    /// A row per server, lists are separated by ';' (same as `import csv` reads)
+    Csv,
+}
+
+/// Path on a server: `SERVER:PATH`, where the server may have a port (`db:2222:/tmp/x`)
+#[derive(Debug, Clone)]
+pub struct RemotePath {
+    pub server: ServerUri,
+    pub path: String,
+}
+
+impl RemotePath {
+    /// Splits at the first `:` outside brackets that isn't followed by a port,
+    /// the path may be empty
+    pub fn split(input: &str) -> Option<(&str, &str)> {
+        input
+            .match_indices(':')
+            .map(|(i, _)| (&input[..i], &input[i + 1..]))
+            .find(|(server, path)| {
+                let rest = path.trim_start_matches(|c: char| c.is_ascii_digit());
+                let is_port = rest.len() < path.len() && rest.starts_with([':', '?']);
+                server.matches('[').count() == server.matches(']').count() && !is_port
+            })
+    }
+}
+
+impl FromStr for RemotePath {
+    type Err = CliError;
+
+    fn from_str(input: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || CliError::InvalidRemotePath(input.into());
+        let (server, path) = Self::split(input)
+            .filter(|(_, path)| !path.is_empty())
+            .ok_or_else(invalid)?;
+
+        Ok(Self {
+            server: server.parse()?,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// URI format: [user@]host[:port][?option=value&...]
 #[derive(Debug, Clone)]
 pub struct ServerUri {
     pub address: String,
     pub user: Option<String>,
     pub port: Option<u16>,
+    /// Connection options of the query, they take precedence over the flags
+    pub options: Box<Scope>,
+    /// The query as given, so that the URI can be passed on as is
+    pub query: Option<Box<str>>,
 }
 
 impl FromStr for ServerUri {
     type Err = CliError;
 
     fn from_str(input: &str) -> std::result::Result<Self, Self::Err> {
+        let (input, options, query) = match input.split_once('?') {
+            Some((input, query)) => (input, Box::new(parse_query(query)?), Some(query.into())),
+            None => (input, Box::default(), None),
+        };
         let (user, host_port) = match input.split_once('@') {
             None => (None, input),
             Some(("", _)) => return Err(CliError::UserMissing),
@@ -154,10 +840,165 @@ impl FromStr for ServerUri {
             user,
             address,
             port,
+            options,
+            query,
+        })
+    }
+}
+
+/// Duration of units `s`, `m`, `h` and `d`, which can be combined (`1h30m`)
+pub fn parse_duration(value: &str) -> std::result::Result<Duration, CliError> {
+    let invalid = || CliError::InvalidValue("duration", value.into());
+    let mut secs: u64 = 0;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let number: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        let unit = rest[digits..].chars().next().ok_or_else(invalid)?;
+        let unit_secs = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return Err(invalid()),
+        };
+        secs = number
+            .checked_mul(unit_secs)
+            .and_then(|n| secs.checked_add(n))
+            .ok_or_else(invalid)?;
+        rest = &rest[digits + unit.len_utf8()..];
+    }
+    if secs == 0 {
+        return Err(invalid());
+    }
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// Options a URI query may set: what identifies the connection, nothing that
+/// weakens host key checks, sends input or writes files (a pasted string is untrusted)
+const QUERY_OPTIONS: [&str; 10] = [
+    "user",
+    "port",
+    "private-key",
+    "openssh-cert",
+    "preferred-auth",
+    "kex",
+    "alg",
+    "cipher",
+    "mac",
+    "profile",
+];
+
+/// Options of the URI query (`port=2222&user=admin&key=~/.ssh/alt`) are named like the long
+/// flags, with `_` allowed for `-` and `key` for `private-key`. Values may be percent-encoded.
+fn parse_query(query: &str) -> std::result::Result<Scope, CliError> {
+    let args = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = match pair.split_once('=') {
+                Some((name, value)) => (name, Some(percent_decode(value)?)),
+                None => (pair, None),
+            };
+            let name = match name.replace('_', "-").as_str() {
+                "key" => "private-key".to_string(),
+                name => name.to_string(),
+            };
+            if !QUERY_OPTIONS.contains(&name.as_str()) {
+                return Err(CliError::InvalidQuery(
+                    format!("'{}' can't be set in a URI", name).into(),
+                ));
+            }
+            Ok(match value {
+                Some(value) => format!("--{}={}", name, value),
+                None => format!("--{}", name),
+            })
         })
+        .collect::<std::result::Result<Vec<_>, CliError>>()?;
+
+    let invalid = |e: clap::Error| {
+        // Only the first line, without the usage
+        let message = e.to_string();
+        let message = message.lines().next().unwrap_or_default();
+        CliError::InvalidQuery(message.trim_start_matches("error: ").into())
+    };
+    // Only long names are used, short ones are left out
+    let matches = Scope::augment_args(Command::new("uri").no_binary_name(true))
+        .mut_args(|arg| arg.short(None))
+        .try_get_matches_from(args)
+        .map_err(invalid)?;
+
+    Scope::from_arg_matches(&matches).map_err(invalid)
+}
+
+fn percent_decode(value: &str) -> std::result::Result<String, CliError> {
+    let invalid = || CliError::InvalidQuery(format!("invalid escape in '{}'", value).into());
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2).ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+impl Display for ServerUri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        match (self.address.contains(':'), self.port) {
+            (true, Some(port)) => write!(f, "[{}]:{}", self.address, port)?,
+            (false, Some(port)) => write!(f, "{}:{}", self.address, port)?,
+            (_, None) => write!(f, "{}", self.address)?,
+        }
+        if let Some(query) = &self.query {
+            write!(f, "?{}", query)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ServerUri {
+    /// Whether the servers are to be read from stdin
+    pub fn is_stdin(&self) -> bool {
+        self.address == STDIN_SERVERS
+            && self.user.is_none()
+            && self.port.is_none()
+            && self.options.is_empty()
     }
 }
 
+/// Parses a newline-separated list of servers, blank lines and `#` comments are skipped
+pub fn read_servers(input: impl BufRead) -> Result<Vec<ServerUri>> {
+    let mut servers = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        servers.push(line.parse()?);
+    }
+
+    Ok(servers)
+}
+
 // serde makes default: Some(...), even though all Scope fields are None
 pub fn empty_scope_is_none<'de, D>(deserializer: D) -> Result<Option<Scope>, D::Error>
 where
@@ -260,8 +1101,24 @@ define_name_wrapper!(MacName, russh::mac::Name, try_from, "MAC algorithm", mac,)
 
 #[cfg(test)]
 mod tests {
-    use super::ServerUri;
+    use super::{RemotePath, ServerUri, parse_duration, read_servers};
+    use std::path::PathBuf;
     use std::str::FromStr;
+    use std::time::Duration;
+
+    #[test]
+    fn servers_from_input() {
+        let input = "db1\n\n  # staging\nalice@web:2222  \n";
+        let servers = read_servers(input.as_bytes()).expect("Valid list");
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].address, "db1");
+        assert_eq!(servers[1].user.as_deref(), Some("alice"));
+        assert_eq!(servers[1].port, Some(2222));
+
+        assert!(read_servers("db1\nuser@\n".as_bytes()).is_err());
+        assert!(ServerUri::from_str("-").unwrap().is_stdin());
+        assert!(!ServerUri::from_str("user@-").unwrap().is_stdin());
+    }
 
     #[test]
     fn uri_parsing_success() {
@@ -275,6 +1132,7 @@ mod tests {
             ("user@[::1]", Some("user"), "::1", None),
             ("[::1]", None, "::1", None),
             ("::1", None, "::1", None),
+            ("host?user=admin", None, "host", None),
         ];
         for (input, user, host, port) in cases {
             let uri = ServerUri::from_str(input).expect("Failed to parse URI");
@@ -285,6 +1143,78 @@ mod tests {
         }
     }
 
+    #[test]
+    fn remote_paths() {
+        let parse = |input: &str| {
+            let remote: RemotePath = input.parse().unwrap();
+            (remote.server.address, remote.server.port, remote.path)
+        };
+        assert_eq!(
+            parse("db:/var/log/app.log"),
+            ("db".into(), None, "/var/log/app.log".into())
+        );
+        assert_eq!(
+            parse("alice@db:2222:~/a:b"),
+            ("db".into(), Some(2222), "~/a:b".into())
+        );
+        assert_eq!(parse("[::1]:22:x"), ("::1".into(), Some(22), "x".into()));
+        assert_eq!(
+            parse("db:22?user=bob:x"),
+            ("db".into(), Some(22), "x".into())
+        );
+        assert_eq!(
+            parse("[fe80::1]:/tmp"),
+            ("fe80::1".into(), None, "/tmp".into())
+        );
+        for invalid in ["db", "db:", ":/tmp"] {
+            assert!(invalid.parse::<RemotePath>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("45s").ok(), Some(Duration::from_secs(45)));
+        assert_eq!(
+            parse_duration("1h30m").ok(),
+            Some(Duration::from_secs(5400))
+        );
+        assert_eq!(
+            parse_duration("2d").ok(),
+            Some(Duration::from_secs(172_800))
+        );
+        for invalid in ["", "10", "h", "1x", "0m", "1h 30m", "1é", "5mé"] {
+            assert!(parse_duration(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn uri_query_options() {
+        let input = "admin@host:2200?port=2222&key=~/.ssh/alt%20key";
+        let uri = ServerUri::from_str(input).expect("Valid query");
+        assert_eq!((uri.address.as_str(), uri.port), ("host", Some(2200)));
+        assert_eq!(uri.options.port, Some(2222));
+        assert_eq!(
+            uri.options.private_key,
+            Some(PathBuf::from("~/.ssh/alt key"))
+        );
+        // Passed on with the options
+        assert_eq!(uri.to_string(), input);
+
+        let uri =
+            ServerUri::from_str("host?preferred_auth=publickey&kex=curve25519-sha256").unwrap();
+        assert_eq!(uri.options.kex.map(|kex| kex.len()), Some(1));
+
+        for unsafe_option in [
+            "host-key-check=accept-all",
+            "known-hosts=/tmp/kh",
+            "send=rm%20-rf%20~",
+            "log-sessions=/tmp",
+            "sudo",
+        ] {
+            assert!(ServerUri::from_str(&format!("host?{}", unsafe_option)).is_err());
+        }
+    }
+
     #[test]
     fn uri_parsing_failure() {
         let cases = vec![
@@ -297,6 +1227,9 @@ mod tests {
             "[]",
             "user@[::1]:bruh",
             "user@[::1]bruh",
+            "host?bogus=1",
+            "host?port=x",
+            "host?user=%zz",
         ];
         for input in cases {
             assert!(ServerUri::from_str(input).is_err());
@@ -0,0 +1,150 @@
+use std::io::{self, Write};
+
+use anyhow::Result;
+use crossterm::cursor::{MoveToColumn, MoveUp};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Print, PrintStyledContent, Stylize};
+use crossterm::terminal::{self, Clear, ClearType};
+use crossterm::{execute, queue};
+
+use crate::client::connect::RawModeGuard;
+use crate::storage::config::is_subsequence;
+
+const MAX_VISIBLE: usize = 10;
+const PROMPT: &str = "> ";
+
+/// Lets the user pick one of the candidates by typing a part of it.
+/// Returns `None` if the picker was cancelled or nothing matched.
+pub fn pick(candidates: &[String]) -> Result<Option<String>> {
+    // The UI goes to stderr so that stdout stays clean
+    let mut out = io::stderr();
+    let _guard = RawModeGuard::new()?;
+
+    let mut query = String::new();
+    let mut selected = 0;
+    let picked = loop {
+        let matches = filter(candidates, &query);
+        selected = selected.min(matches.len().saturating_sub(1));
+        render(&mut out, &query, &matches, candidates.len(), selected)?;
+
+        let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: KeyEventKind::Press,
+            ..
+        }) = event::read()?
+        else {
+            continue;
+        };
+        match (code, modifiers) {
+            (KeyCode::Esc, _) | (KeyCode::Char('c' | 'd'), KeyModifiers::CONTROL) => break None,
+            (KeyCode::Enter, _) => break matches.get(selected).map(|s| s.to_string()),
+            (KeyCode::Up, _) | (KeyCode::Char('p'), KeyModifiers::CONTROL) => {
+                selected = selected.saturating_sub(1);
+            }
+            (KeyCode::Down, _) | (KeyCode::Char('n'), KeyModifiers::CONTROL) => selected += 1,
+            (KeyCode::Backspace, _) => {
+                query.pop();
+                selected = 0;
+            }
+            (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => {
+                query.push(c);
+                selected = 0;
+            }
+            _ => {}
+        }
+    };
+    execute!(out, MoveToColumn(0), Clear(ClearType::FromCursorDown))?;
+
+    Ok(picked)
+}
+
+/// Prefix matches first, then substrings, then characters in order;
+/// the original order is kept within each group
+fn filter<'a>(candidates: &'a [String], query: &str) -> Vec<&'a str> {
+    let query = query.to_lowercase();
+    let mut ranked: Vec<_> = candidates
+        .iter()
+        .filter_map(|candidate| {
+            let lower = candidate.to_lowercase();
+            let rank = if lower.starts_with(&query) {
+                0
+            } else if lower.contains(&query) {
+                1
+            } else if is_subsequence(&query, &lower) {
+                2
+            } else {
+                return None;
+            };
+            Some((rank, candidate.as_str()))
+        })
+        .collect();
+    ranked.sort_by_key(|(rank, _)| *rank);
+
+    ranked.into_iter().map(|(_, candidate)| candidate).collect()
+}
+
+/// Draws the prompt with the matches below it and returns the cursor to the prompt
+fn render(
+    out: &mut impl Write,
+    query: &str,
+    matches: &[&str],
+    total: usize,
+    selected: usize,
+) -> Result<()> {
+    // Unknown width is reported as zero by some terminals
+    let width = match terminal::size() {
+        Ok((width, _)) if width > 2 => usize::from(width) - 2,
+        _ => usize::MAX,
+    };
+    let first = selected.saturating_sub(MAX_VISIBLE - 1);
+    let visible = &matches[first..matches.len().min(first + MAX_VISIBLE)];
+
+    queue!(
+        out,
+        MoveToColumn(0),
+        Clear(ClearType::FromCursorDown),
+        Print(PROMPT),
+        Print(query),
+        PrintStyledContent(format!("  {}/{}", matches.len(), total).dark_grey()),
+    )?;
+    for (i, name) in visible.iter().enumerate() {
+        let name: String = name.chars().take(width).collect();
+        queue!(out, Print("\r\n"))?;
+        if first + i == selected {
+            queue!(
+                out,
+                PrintStyledContent(format!("> {}", name).green().bold())
+            )?;
+        } else {
+            queue!(out, Print(format!("  {}", name)))?;
+        }
+    }
+    if !visible.is_empty() {
+        queue!(out, MoveUp(visible.len() as u16))?;
+    }
+    queue!(
+        out,
+        MoveToColumn((PROMPT.len() + query.chars().count()) as u16)
+    )?;
+    out.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_ranks_matches() {
+        let candidates = ["db-replica", "primary-db", "web", "db1"].map(String::from);
+        assert_eq!(
+            filter(&candidates, "db"),
+            ["db-replica", "db1", "primary-db"]
+        );
+        assert_eq!(filter(&candidates, "pdb"), ["primary-db"]);
+        assert_eq!(filter(&candidates, "").len(), candidates.len());
+        assert!(filter(&candidates, "xyz").is_empty());
+    }
+}
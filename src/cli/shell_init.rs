@@ -0,0 +1,124 @@
+use clap::ValueEnum;
+use owo_colors::{DynColors, Style};
+
+/// Placeholders of the prompt template
+const SCOPE_PLACEHOLDER: &str = "{scope}";
+const CONTEXT_PLACEHOLDER: &str = "{context}";
+/// Template used by `prompt` without `--format`
+pub const DEFAULT_TEMPLATE: &str = "({scope}) ";
+
+/// Shells the prompt segment can be wired into
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum PromptShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// The template with the scope and context filled in, nothing when no scope is active.
+/// Color codes are marked as zero-width the way the shell expects, so that
+/// the line editor still knows where the cursor is.
+pub fn render(
+    template: &str,
+    scope: &str,
+    context: &str,
+    color: Option<DynColors>,
+    shell: Option<PromptShell>,
+) -> String {
+    if scope.is_empty() {
+        return String::new();
+    }
+    let text = template
+        .replace(SCOPE_PLACEHOLDER, scope)
+        .replace(CONTEXT_PLACEHOLDER, context);
+    // zsh expands `%` sequences in the output of prompt substitutions
+    let text = match shell {
+        Some(PromptShell::Zsh) => text.replace('%', "%%"),
+        _ => text,
+    };
+    let Some(color) = color else {
+        return text;
+    };
+
+    let style = Style::new().color(color);
+    let (prefix, suffix) = (
+        style.prefix_formatter().to_string(),
+        style.suffix_formatter().to_string(),
+    );
+    let invisible = |code: &str| match shell {
+        Some(PromptShell::Bash) => format!("\x01{}\x02", code),
+        Some(PromptShell::Zsh) => format!("%{{{}%}}", code),
+        Some(PromptShell::Fish) | None => code.to_string(),
+    };
+
+    format!("{}{}{}", invisible(&prefix), text, invisible(&suffix))
+}
+
+/// Shell code putting the output of `prompt` in front of the existing prompt,
+/// with the arguments (each preceded by a space). Sourcing it again changes nothing.
+pub fn init_script(shell: PromptShell, bin: &str, args: &str) -> String {
+    match shell {
+        PromptShell::Bash => format!(
+            r#"_{bin}_prompt() {{
+    {bin} prompt --shell bash{args} 2>/dev/null
+}}
+if [[ "$PS1" != *'$(_{bin}_prompt)'* ]]; then
+    PS1='$(_{bin}_prompt)'"$PS1"
+fi
+"#
+        ),
+        PromptShell::Zsh => format!(
+            r#"_{bin}_prompt() {{
+    {bin} prompt --shell zsh{args} 2>/dev/null
+}}
+setopt prompt_subst
+if [[ "$PROMPT" != *'$(_{bin}_prompt)'* ]]; then
+    PROMPT='$(_{bin}_prompt)'"$PROMPT"
+fi
+"#
+        ),
+        PromptShell::Fish => format!(
+            r#"if not functions -q _{bin}_fish_prompt
+    functions -c fish_prompt _{bin}_fish_prompt
+    function fish_prompt
+        {bin} prompt --shell fish{args} 2>/dev/null
+        _{bin}_fish_prompt
+    end
+end
+"#
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use owo_colors::AnsiColors;
+
+    #[test]
+    fn prompt_segment() {
+        assert_eq!(render(DEFAULT_TEMPLATE, "", "work", None, None), "");
+        assert_eq!(
+            render("[{context}:{scope}] ", "prod", "work", None, None),
+            "[work:prod] "
+        );
+        assert_eq!(
+            render("{scope} 100%", "prod", "", None, Some(PromptShell::Zsh)),
+            "prod 100%%"
+        );
+
+        let red = Some(DynColors::Ansi(AnsiColors::Red));
+        assert_eq!(
+            render("{scope}", "prod", "", red, Some(PromptShell::Bash)),
+            "\x01\x1b[31m\x02prod\x01\x1b[0m\x02"
+        );
+        assert_eq!(
+            render("{scope}", "prod", "", red, Some(PromptShell::Zsh)),
+            "%{\x1b[31m%}prod%{\x1b[0m%}"
+        );
+        assert_eq!(
+            render("{scope}", "prod", "", red, None),
+            "\x1b[31mprod\x1b[0m"
+        );
+    }
+}
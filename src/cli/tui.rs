@@ -0,0 +1,478 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListState};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::cli::parser::ServerUri;
+use crate::client::data::{ConnectionData, Source};
+use crate::error::CliError;
+use crate::storage::config::{Config, Scope, ScopedServer, Server, ServerEntry, is_pattern};
+use crate::storage::provider::StorageProvider;
+
+const GLOBAL_SCOPE: &str = "(global)";
+const HELP: &str = "Tab panel  ↑↓ move  Enter connect  e edit  q quit";
+/// Server fields that can be edited in place
+const EDITABLE_FIELDS: [&str; 8] = [
+    "address",
+    "user",
+    "port",
+    "known_hosts",
+    "private_key",
+    "timeout",
+    "interval",
+    "retries",
+];
+
+/// A server chosen to connect to: its scope (`None` for global) and name
+pub type Selection = (Option<String>, String);
+
+/// Servers of a scope, the global ones have no scope
+#[derive(Debug, PartialEq)]
+struct Group {
+    scope: Option<String>,
+    servers: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Panel {
+    Scopes,
+    Servers,
+    Fields,
+}
+
+/// A row of the details panel: field name, effective value and its origin
+type Field = (&'static str, String, Option<Source>);
+
+struct Browser<'a> {
+    config: &'a mut Config,
+    groups: Vec<Group>,
+    panel: Panel,
+    group: usize,
+    server: usize,
+    field: usize,
+    /// New value of the selected field while editing
+    input: Option<String>,
+    status: String,
+}
+
+/// Restores the terminal even if drawing fails
+struct ScreenGuard(DefaultTerminal);
+
+impl ScreenGuard {
+    fn new() -> Result<Self> {
+        Ok(Self(ratatui::try_init()?))
+    }
+}
+
+impl Drop for ScreenGuard {
+    fn drop(&mut self) {
+        let _ = ratatui::try_restore();
+    }
+}
+
+/// Browses scopes and servers, edits are saved immediately.
+/// Returns the server to connect to, if one was chosen.
+pub fn run(config: &mut Config, current_scope: &str) -> Result<Option<Selection>> {
+    let groups = groups(config);
+    let group = groups
+        .iter()
+        .position(|g| g.scope.as_deref() == Some(current_scope))
+        .unwrap_or_default();
+    let mut browser = Browser {
+        config,
+        groups,
+        panel: Panel::Servers,
+        group,
+        server: 0,
+        field: 0,
+        input: None,
+        status: String::new(),
+    };
+    let mut screen = ScreenGuard::new()?;
+
+    loop {
+        screen.0.draw(|frame| browser.draw(frame))?;
+        let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: KeyEventKind::Press,
+            ..
+        }) = event::read()?
+        else {
+            continue;
+        };
+        if browser.input.is_some() {
+            browser.edit(code, modifiers);
+            continue;
+        }
+        browser.status.clear();
+        match (code, modifiers) {
+            (KeyCode::Char('q'), _)
+            | (KeyCode::Esc, _)
+            | (KeyCode::Char('c'), KeyModifiers::CONTROL) => return Ok(None),
+            (KeyCode::Tab | KeyCode::Right, _) => browser.switch_panel(true),
+            (KeyCode::BackTab | KeyCode::Left, _) => browser.switch_panel(false),
+            (KeyCode::Up | KeyCode::Char('k'), _) => browser.move_selection(false),
+            (KeyCode::Down | KeyCode::Char('j'), _) => browser.move_selection(true),
+            (KeyCode::Char('e'), _) => browser.start_edit(),
+            (KeyCode::Enter, _) => match browser.selection() {
+                Some(selection) => return Ok(Some(selection)),
+                None => browser.status = "Select a server that is not a pattern".into(),
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Scopes in the config order followed by the global servers
+fn groups(config: &Config) -> Vec<Group> {
+    let mut groups: Vec<_> = config
+        .scopes
+        .keys()
+        .map(|scope| Group {
+            scope: Some(scope.clone()),
+            servers: match config.servers.get(scope) {
+                Some(ServerEntry::Scope(servers)) => servers.keys().cloned().collect(),
+                _ => Vec::new(),
+            },
+        })
+        .collect();
+    groups.push(Group {
+        scope: None,
+        servers: config
+            .servers
+            .iter()
+            .filter(|(_, entry)| matches!(entry, ServerEntry::Global(_)))
+            .map(|(name, _)| name.clone())
+            .collect(),
+    });
+
+    groups
+}
+
+impl Browser<'_> {
+    fn selected_name(&self) -> Option<&String> {
+        self.groups[self.group].servers.get(self.server)
+    }
+
+    fn selected_server(&self) -> Option<&ScopedServer> {
+        let name = self.selected_name()?;
+        match (
+            &self.groups[self.group].scope,
+            self.config.servers.get(name),
+        ) {
+            (None, Some(ServerEntry::Global(server))) => Some(server),
+            (Some(scope), _) => match self.config.servers.get(scope)? {
+                ServerEntry::Scope(servers) => servers.get(name),
+                ServerEntry::Global(_) => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn selected_server_mut(&mut self) -> Option<&mut ScopedServer> {
+        let name = self.groups[self.group].servers.get(self.server)?;
+        match &self.groups[self.group].scope {
+            None => match self.config.servers.get_mut(name)? {
+                ServerEntry::Global(server) => Some(server),
+                ServerEntry::Scope(_) => None,
+            },
+            Some(scope) => match self.config.servers.get_mut(scope)? {
+                ServerEntry::Scope(servers) => servers.get_mut(name),
+                ServerEntry::Global(_) => None,
+            },
+        }
+    }
+
+    /// Effective settings of the selected server
+    fn fields(&self) -> Vec<Field> {
+        let (Some(name), Some(server)) = (self.selected_name(), self.selected_server()) else {
+            return Vec::new();
+        };
+        let server: Server = server.clone().into();
+        let scope = self.groups[self.group]
+            .scope
+            .as_ref()
+            .and_then(|scope| self.config.scopes.get(scope))
+            .cloned()
+            .unwrap_or_default();
+        let uri = ServerUri {
+            address: name.clone(),
+            user: None,
+            port: None,
+            options: Box::default(),
+            query: None,
+        };
+        let traced = ConnectionData::trace(
+            &uri,
+            &Scope::default(),
+            &server.scope,
+            &scope,
+            &self.config.default.clone().unwrap_or_default(),
+        );
+
+        let mut fields = vec![("address", server.address, None)];
+        fields.extend(
+            traced
+                .into_iter()
+                .map(|(name, value, source)| (name, value, Some(source))),
+        );
+
+        fields
+    }
+
+    fn selection(&self) -> Option<Selection> {
+        let name = self.selected_name()?;
+        if is_pattern(name) {
+            return None;
+        }
+
+        Some((self.groups[self.group].scope.clone(), name.clone()))
+    }
+
+    fn switch_panel(&mut self, forward: bool) {
+        self.panel = match (self.panel, forward) {
+            (Panel::Scopes, true) | (Panel::Fields, false) => Panel::Servers,
+            (Panel::Servers, true) => Panel::Fields,
+            (Panel::Servers, false) => Panel::Scopes,
+            (panel, _) => panel,
+        };
+    }
+
+    fn move_selection(&mut self, down: bool) {
+        let (index, len) = match self.panel {
+            Panel::Scopes => (&mut self.group, self.groups.len()),
+            Panel::Servers => (&mut self.server, self.groups[self.group].servers.len()),
+            Panel::Fields => {
+                let len = self.fields().len();
+                (&mut self.field, len)
+            }
+        };
+        *index = if down {
+            (*index + 1).min(len.saturating_sub(1))
+        } else {
+            index.saturating_sub(1)
+        };
+        if self.panel == Panel::Scopes {
+            self.server = 0;
+            self.field = 0;
+        }
+    }
+
+    fn start_edit(&mut self) {
+        if self.panel != Panel::Fields {
+            self.status = "Select a field to edit".into();
+            return;
+        }
+        let Some((name, _, _)) = self.fields().get(self.field).cloned() else {
+            return;
+        };
+        if !EDITABLE_FIELDS.contains(&name) {
+            self.status = format!("'{}' can only be changed in the config file", name);
+            return;
+        }
+        if let Some(server) = self.selected_server() {
+            self.input = Some(field_value(&server.clone().into(), name));
+        }
+    }
+
+    fn edit(&mut self, code: KeyCode, modifiers: KeyModifiers) {
+        let Some(input) = self.input.as_mut() else {
+            return;
+        };
+        match (code, modifiers) {
+            (KeyCode::Esc, _) | (KeyCode::Char('c'), KeyModifiers::CONTROL) => self.input = None,
+            (KeyCode::Backspace, _) => {
+                input.pop();
+            }
+            (KeyCode::Char(c), KeyModifiers::NONE | KeyModifiers::SHIFT) => input.push(c),
+            (KeyCode::Enter, _) => {
+                let value = self.input.take().unwrap_or_default();
+                self.status = match self.save_field(&value) {
+                    Ok(name) => format!("Saved '{}'", name),
+                    Err(err) => err.to_string(),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn save_field(&mut self, value: &str) -> Result<&'static str> {
+        let name = self.fields()[self.field].0;
+        let Some(entry) = self.selected_server_mut() else {
+            return Err(CliError::ServerNotFound(Box::default()).into());
+        };
+        let mut server: Server = entry.clone().into();
+        set_field(&mut server, name, value)?;
+        *entry = server.into();
+        self.config.save_to_file()?;
+
+        Ok(name)
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [body, footer] =
+            Layout::vertical([Constraint::Fill(1), Constraint::Length(1)]).areas(frame.area());
+        let [scopes_area, servers_area, fields_area] = Layout::horizontal([
+            Constraint::Length((body.width / 5).max(12)),
+            Constraint::Length((body.width / 4).max(16)),
+            Constraint::Fill(1),
+        ])
+        .areas(body);
+
+        let scopes: Vec<_> = self
+            .groups
+            .iter()
+            .map(|g| g.scope.clone().unwrap_or_else(|| GLOBAL_SCOPE.into()))
+            .collect();
+        self.draw_list(
+            frame,
+            scopes_area,
+            "Scopes",
+            scopes,
+            self.group,
+            Panel::Scopes,
+        );
+        let servers = self.groups[self.group].servers.clone();
+        self.draw_list(
+            frame,
+            servers_area,
+            "Servers",
+            servers,
+            self.server,
+            Panel::Servers,
+        );
+        let fields: Vec<_> = self
+            .fields()
+            .into_iter()
+            .map(|(name, value, source)| match source {
+                Some(source) => format!("{}: {} ({})", name, value, source),
+                None => format!("{}: {}", name, value),
+            })
+            .collect();
+        self.draw_list(
+            frame,
+            fields_area,
+            "Settings",
+            fields,
+            self.field,
+            Panel::Fields,
+        );
+
+        let line = match &self.input {
+            Some(input) => {
+                let name = self.fields().get(self.field).map_or("", |f| f.0);
+                let prompt = format!("{}: ", name);
+                let cursor = footer.x + (prompt.chars().count() + input.chars().count()) as u16;
+                frame.set_cursor_position(Position::new(
+                    cursor.min(footer.right().saturating_sub(1)),
+                    footer.y,
+                ));
+                Line::from(vec![Span::from(prompt).bold(), Span::from(input.as_str())])
+            }
+            None if !self.status.is_empty() => Line::from(self.status.as_str()).yellow(),
+            None => Line::from(HELP).dark_gray(),
+        };
+        frame.render_widget(line, footer);
+    }
+
+    /// A panel with its title, the selection is highlighted more in the active one
+    fn draw_list(
+        &self,
+        frame: &mut Frame,
+        area: Rect,
+        title: &str,
+        items: Vec<String>,
+        selected: usize,
+        panel: Panel,
+    ) {
+        let (title_style, highlight) = if self.panel == panel {
+            (
+                Style::new().add_modifier(Modifier::BOLD | Modifier::UNDERLINED),
+                Style::new().fg(Color::Black).bg(Color::Green),
+            )
+        } else {
+            (Style::new().dark_gray(), Style::new().green())
+        };
+        let list = List::new(items)
+            .block(Block::new().title(Span::styled(title, title_style)))
+            .highlight_style(highlight);
+        let mut state = ListState::default().with_selected(Some(selected));
+        // A column between the panels
+        let area = Rect {
+            width: area.width.saturating_sub(1),
+            ..area
+        };
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+}
+
+/// Value set on the server itself, not inherited
+fn field_value(server: &Server, name: &str) -> String {
+    let scope = &server.scope;
+    let path = |path: &Option<PathBuf>| {
+        path.as_ref()
+            .map(|path| path.display().to_string())
+            .unwrap_or_default()
+    };
+    let number = |value: Option<String>| value.unwrap_or_default();
+    match name {
+        "address" => server.address.clone(),
+        "user" => scope.user.clone().unwrap_or_default(),
+        "port" => number(scope.port.map(|v| v.to_string())),
+        "known_hosts" => path(&scope.known_hosts),
+        "private_key" => path(&scope.private_key),
+        "timeout" => number(scope.timeout.map(|v| v.to_string())),
+        "interval" => number(scope.interval.map(|v| v.to_string())),
+        "retries" => number(scope.retries.map(|v| v.to_string())),
+        _ => String::new(),
+    }
+}
+
+/// Sets a field of the server, an empty value removes the override
+fn set_field(server: &mut Server, name: &'static str, value: &str) -> Result<(), CliError> {
+    let value = value.trim();
+    let invalid = || CliError::InvalidValue(name, value.into());
+    let text = (!value.is_empty()).then(|| value.to_string());
+    let scope = &mut server.scope;
+    match name {
+        "address" if value.is_empty() => return Err(invalid()),
+        "address" => server.address = value.into(),
+        "user" => scope.user = text,
+        "port" => scope.port = text.map(|v| v.parse()).transpose().map_err(|_| invalid())?,
+        "known_hosts" => scope.known_hosts = text.map(PathBuf::from),
+        "private_key" => scope.private_key = text.map(PathBuf::from),
+        "timeout" => scope.timeout = text.map(|v| v.parse()).transpose().map_err(|_| invalid())?,
+        "interval" => {
+            scope.interval = text.map(|v| v.parse()).transpose().map_err(|_| invalid())?
+        }
+        "retries" => scope.retries = text.map(|v| v.parse()).transpose().map_err(|_| invalid())?,
+        _ => return Err(invalid()),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_server_fields() {
+        let mut server = Server::new("10.0.0.1".into());
+        set_field(&mut server, "port", "2222").expect("Valid port");
+        set_field(&mut server, "private_key", "~/.ssh/id").expect("Valid path");
+        assert_eq!(server.scope.port, Some(2222));
+        assert_eq!(field_value(&server, "private_key"), "~/.ssh/id");
+
+        assert!(set_field(&mut server, "port", "http").is_err());
+        assert!(set_field(&mut server, "address", " ").is_err());
+        set_field(&mut server, "port", "").expect("Empty value clears");
+        assert_eq!(server.scope.port, None);
+    }
+}
@@ -1,53 +1,147 @@
 use anyhow::Result;
+use clap::ValueEnum;
 use crossterm::terminal::{self, disable_raw_mode, enable_raw_mode};
-use log::info;
+use itertools::Itertools;
+use log::{debug, info, warn};
 use russh::client::{AuthResult, Handle, KeyboardInteractiveAuthResponse, Msg};
 use russh::keys::agent::client::AgentClient;
 use russh::keys::{
     HashAlg, PrivateKey, PrivateKeyWithHashAlg, load_openssh_certificate, load_secret_key, ssh_key,
 };
-use russh::{Channel, ChannelMsg, MethodKind};
+use russh::{Channel, ChannelMsg, Disconnect, MethodKind};
 use secrecy::{ExposeSecret, SecretString};
-use std::io::Write;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io;
 use std::mem;
-use std::net::SocketAddr;
-use std::path::Path;
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::lookup_host;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex, Once, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpStream, lookup_host};
+use tokio::signal::unix::{Signal, SignalKind, signal};
 
 use crate::client::data::ConnectionData;
-use crate::client::handler::ClientHandler;
-use crate::error::{ConnectionError, FileError, SessionError};
+use crate::client::handler::{ClientHandler, Negotiated};
+use crate::client::prompt::{PromptProvider, TerminalPrompts};
+use crate::client::transcript::Transcript;
+use crate::client::transfer::{FileList, LIST_FILES, parse_listing, tar_file_list};
+use crate::client::verify::HostKeyVerifier;
+use crate::error::{CliError, ConnectionError, FileError, SessionError};
+use crate::storage::context::Context;
+use crate::storage::event_log::{Event, EventKind, record_auth_attempt};
+use crate::storage::provider::StorageProvider;
+use crate::storage::secret::SecretRef;
 
 const MAX_PASSPHRASE_ATTEMPTS: u8 = 3;
 const DEFAULT_TERM: &str = "xterm";
-const SESSION_BUFFER_SIZE: usize = 4096;
 const RESIZE_INTERVAL_MS: u64 = 200;
+/// The login script is typed once the output of the shell pauses for this long
+/// (the prompt has appeared), or after the longer wait if the shell stays silent
+const LOGIN_SETTLE_MS: u64 = 300;
+const LOGIN_WAIT_MS: u64 = 3000;
+/// Seconds before an idle session is closed at which it's announced
+const IDLE_WARNINGS_SECS: [u64; 3] = [60, 30, 10];
 const STDIN_FD: i32 = 0;
 const STDOUT_FD: i32 = 1;
+const SFTP_SUBSYSTEM: &str = "sftp";
+/// Time each address gets to accept the connection when there are fallback
+/// addresses and no timeout is configured
+const FALLBACK_CONNECT_SECS: u64 = 5;
+/// Prompt sudo is told to use, so that it can be recognized in the output
+const SUDO_PROMPT: &str = "[shh-sudo-password]";
+
+/// Passwords for sudo once asked for, by `user@address` (or under "" when they're
+/// shared by the servers of a batch)
+static SUDO_PASSWORDS: Mutex<BTreeMap<String, SecretString>> = Mutex::new(BTreeMap::new());
+
+// Single point of entry for the module, returns the exit status of the remote side
+pub async fn initiate_connection(mut data: ConnectionData) -> Result<Option<u32>> {
+    let remote_cmd = data.remote_cmd.take();
+    let sudo = data.sudo.then(|| {
+        SudoPrompt::new(
+            data.password.clone(),
+            format!("{}@{}", data.user, data.address),
+            data.shared_sudo_password,
+            Arc::new(TerminalPrompts),
+        )
+    });
+    let (event_log, target) = (data.event_log, data.target());
+    let session = Session::connect(data).await?;
+    let started = Instant::now();
+
+    let result = match (remote_cmd, sudo) {
+        (Some(cmd), Some(mut sudo)) => session.exec_sudo(&cmd, &mut sudo).await,
+        (Some(cmd), None) => session.exec(&cmd).await,
+        (None, _) => session.shell().await,
+    };
+    if let Some(event_log) = event_log {
+        let exit_status = result.as_ref().ok().copied().flatten();
+        event_log.record(&Event {
+            kind: EventKind::Disconnect,
+            target: &target,
+            fields: vec![
+                ("duration", started.elapsed().as_secs().to_string()),
+                (
+                    "exit_status",
+                    exit_status.map_or_else(|| "-".into(), |s| s.to_string()),
+                ),
+            ],
+            error: result.as_ref().err().map(ToString::to_string),
+        });
+    }
 
-// Single point of entry for the module
-pub async fn initiate_connection(data: ConnectionData) -> Result<()> {
-    let mut conn = Connection::new(data).await?;
-    conn.establish().await?;
-    conn.authenticate().await?;
+    result
+}
 
-    if let Some(cmd) = conn.data.remote_cmd.as_ref() {
-        conn.execute_command(cmd).await?;
-    } else {
-        conn.start_interactive().await?;
-    }
+/// Authentication method that can be preferred in the config
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    /// SSH agent, certificate and private key
+    Publickey,
+    KeyboardInteractive,
+    Password,
+}
 
-    Ok(())
+/// Authenticated SSH session, usable by other programs as well.
+/// Each method opens its own channel, so they can be used side by side.
+pub struct Session {
+    socket: SocketAddr,
+    handle: Arc<Handle<ClientHandler>>,
+    /// Size of the buffers for terminal input and output
+    buffer_size: usize,
+    /// Interactive sessions without input for this long are closed
+    idle_timeout: Option<Duration>,
+    /// Shown in the terminal title during interactive sessions
+    title: Option<String>,
+    /// `user@address` the session is with
+    target: String,
+    /// Terminal type of interactive sessions, overriding the local one
+    term: Option<String>,
+    /// Directory interactive sessions are recorded to
+    log_sessions: Option<PathBuf>,
+    /// Lines typed into the shell after it starts
+    login_script: Vec<String>,
 }
 
-// Represents an SSH connection
+// Represents an SSH connection being established
 struct Connection {
     data: ConnectionData,
     socket: SocketAddr,
+    /// Opened to the first reachable address, taken by the handshake
+    stream: Option<TcpStream>,
     session: Option<Handle<ClientHandler>>,
+    prompts: Arc<dyn PromptProvider>,
+    /// Fingerprint of the server key, set during the handshake
+    server_key: Arc<OnceLock<String>>,
+    negotiated: Arc<OnceLock<Negotiated>>,
+    /// Authentication method that is being tried (for the auth log)
+    method: &'static str,
 }
 
 macro_rules! session {
@@ -60,57 +154,297 @@ macro_rules! session {
 }
 
 macro_rules! prompt {
-    (echo => $($arg:tt)*) => {{
-        print!("{}: ", format_args!($($arg)*));
-        std::io::stdout().flush()?;
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input)?;
-        input.trim().to_string()
-    }};
-    ($($arg:tt)*) => {{
-        print!("{}: ", format_args!($($arg)*));
-        std::io::stdout().flush()?;
-        SecretString::from(rpassword::read_password()?)
-    }};
+    ($prompts:expr, echo => $($arg:tt)*) => {
+        $prompts.text(&format!("{}: ", format_args!($($arg)*)))?
+    };
+    ($prompts:expr, $($arg:tt)*) => {
+        $prompts.secret(&format!("{}: ", format_args!($($arg)*)))?
+    };
 }
 
-impl Connection {
-    async fn new(data: ConnectionData) -> Result<Self> {
-        // Maybe not a socket (domain:port)
-        let sock = format!("{}:{}", data.address, data.port);
-        let socket = if let Ok(s) = sock.parse() {
-            s
+/// Answers the password prompt of sudo: with the password from the keyring,
+/// then with the one asked for earlier, then by asking
+struct SudoPrompt {
+    password: Option<SecretRef>,
+    /// `user@address` shown when asking
+    target: String,
+    /// The password asked for is reused for other servers, not only this one
+    shared: bool,
+    prompts: Arc<dyn PromptProvider>,
+    /// End of the output that may be the beginning of the prompt
+    pending: Vec<u8>,
+    keyring_tried: bool,
+    cache_tried: bool,
+    asked: bool,
+}
+
+enum SudoAnswer {
+    Password(SecretString),
+    /// Every password was rejected, nothing more to try
+    GiveUp,
+}
+
+impl SudoPrompt {
+    fn new(
+        password: Option<SecretRef>,
+        target: String,
+        shared: bool,
+        prompts: Arc<dyn PromptProvider>,
+    ) -> Self {
+        Self {
+            password,
+            target,
+            shared,
+            prompts,
+            pending: Vec::new(),
+            keyring_tried: false,
+            cache_tried: false,
+            asked: false,
+        }
+    }
+
+    /// Removes the prompt from the output, returns the answer if it was there.
+    /// A repeated prompt means the previous password was rejected. The prompt may
+    /// be split between chunks, so an end that could start it is held back.
+    fn check(&mut self, output: &[u8]) -> Result<(Vec<u8>, Option<SudoAnswer>)> {
+        let marker = SUDO_PROMPT.as_bytes();
+        let mut text = mem::take(&mut self.pending);
+        text.extend_from_slice(output);
+        if let Some(start) = find_bytes(&text, marker) {
+            let mut rest = text[..start].to_vec();
+            rest.extend_from_slice(&text[start + marker.len()..]);
+            return Ok((rest, Some(self.answer()?)));
+        }
+        let held = (1..marker.len())
+            .rev()
+            .find(|&len| text.ends_with(&marker[..len]))
+            .unwrap_or(0);
+        self.pending = text.split_off(text.len() - held);
+
+        Ok((text, None))
+    }
+
+    /// Output held back by [`SudoPrompt::check`], once no more is coming
+    fn finish(&mut self) -> Vec<u8> {
+        mem::take(&mut self.pending)
+    }
+
+    fn answer(&mut self) -> Result<SudoAnswer> {
+        if !mem::replace(&mut self.keyring_tried, true)
+            && let Some(password) = self.keyring_password()
+        {
+            return Ok(SudoAnswer::Password(password));
+        }
+        let key = if self.shared {
+            String::new()
         } else {
-            info!("Resolving address '{}'...", data.address);
+            self.target.clone()
+        };
+        let mut cached = SUDO_PASSWORDS.lock().expect("not poisoned");
+        if !mem::replace(&mut self.cache_tried, true)
+            && let Some(password) = cached.get(&key)
+        {
+            return Ok(SudoAnswer::Password(password.clone()));
+        }
+        if mem::replace(&mut self.asked, true) {
+            warn!("Password for sudo on {} was rejected", self.target);
+            cached.remove(&key);
+            return Ok(SudoAnswer::GiveUp);
+        }
+        let password = prompt!(self.prompts, "[sudo] password for {}", self.target);
+        cached.insert(key, password.clone());
 
-            lookup_host(sock)
-                .await
-                .map_err(ConnectionError::Dns)?
-                .next()
-                .expect("address should be resolved")
+        Ok(SudoAnswer::Password(password))
+    }
+
+    fn keyring_password(&self) -> Option<SecretString> {
+        let secret_ref = self.password.as_ref()?;
+        match secret_ref.resolve() {
+            Ok(Some(password)) => {
+                info!("Using password from '{}' for sudo", secret_ref);
+                return Some(password);
+            }
+            Ok(None) => warn!("Secret '{}' not found in the keyring", secret_ref),
+            Err(e) => warn!("{}", e),
+        }
+
+        None
+    }
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Where an address was resolved from
+#[derive(Clone, Copy, PartialEq)]
+enum Lookup {
+    Cache,
+    Resolver,
+}
+
+/// Consults the DNS cache first when it's enabled, successful lookups are cached.
+/// Problems with the cache file are only reported.
+async fn resolve(address: &str, data: &ConnectionData) -> Result<(IpAddr, Lookup)> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let context = if data.dns_cache {
+        Context::load_from_file()
+            .inspect_err(|e| warn!("DNS cache is unavailable: {}", e))
+            .ok()
+    } else {
+        None
+    };
+    if let Some(ip) = context
+        .as_ref()
+        .and_then(|c| c.cached_addresses(address, now))
+        .and_then(|addresses| addresses.first())
+    {
+        debug!("'{}' found in the DNS cache: {}", address, ip);
+        return Ok((*ip, Lookup::Cache));
+    }
+    info!("Resolving address '{}'...", address);
+
+    let resolved: Vec<_> = lookup_host((address, data.port))
+        .await
+        .map_err(ConnectionError::Dns)?
+        .map(|addr| addr.ip())
+        .collect();
+    debug!("'{}' resolved to {}", address, resolved.iter().join(", "));
+    let Some(&ip) = resolved.first() else {
+        return Err(ConnectionError::Dns(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no addresses found for '{}'", address),
+        ))
+        .into());
+    };
+    if let Some(context) = context
+        && let Err(e) = context
+            .cache_addresses(address.into(), resolved, now, data.dns_cache_ttl)
+            .save_to_file()
+    {
+        warn!("Failed to update the DNS cache: {}", e);
+    }
+
+    Ok((ip, Lookup::Resolver))
+}
+
+/// Drops the cached addresses of a server that couldn't be reached at them
+fn forget_addresses(address: &str) {
+    if let Err(e) =
+        Context::load_from_file().and_then(|c| c.forget_addresses(address).save_to_file())
+    {
+        warn!("Failed to update the DNS cache: {}", e);
+    }
+}
+
+/// Opens the TCP connection, within the limit if there is one
+async fn connect_socket(
+    socket: SocketAddr,
+    data: &ConnectionData,
+    limit: Option<Duration>,
+) -> Result<TcpStream> {
+    info!("Connecting to {}:{}...", socket.ip(), socket.port());
+
+    let connect = data.socket.connect(socket);
+    let stream = match limit {
+        Some(limit) => tokio::time::timeout(limit, connect)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => connect.await,
+    }
+    .map_err(|e| SessionError::Connect(e.into()))?;
+
+    Ok(stream)
+}
+
+/// Resolves the address and opens the TCP connection. When a cached address
+/// can't be connected to, the name is resolved again and the new address tried.
+async fn open(
+    address: &str,
+    data: &ConnectionData,
+    limit: Option<Duration>,
+) -> Result<(SocketAddr, TcpStream)> {
+    // Maybe not a socket (domain:port)
+    let sock = format!("{}:{}", address, data.port);
+    if let Ok(socket) = sock.parse() {
+        return Ok((socket, connect_socket(socket, data, limit).await?));
+    }
+
+    let (ip, lookup) = resolve(address, data).await?;
+    let socket = SocketAddr::new(ip, data.port);
+    match connect_socket(socket, data, limit).await {
+        Err(e) if lookup == Lookup::Cache => {
+            warn!(
+                "Cached address {} of '{}' failed ({}), resolving again",
+                ip, address, e
+            );
+            forget_addresses(address);
+            let (ip, _) = resolve(address, data).await?;
+            let socket = SocketAddr::new(ip, data.port);
+            Ok((socket, connect_socket(socket, data, limit).await?))
+        }
+        stream => Ok((socket, stream?)),
+    }
+}
+
+impl Connection {
+    /// Connects to the first of the address and the fallback ones that can be reached,
+    /// which becomes the address of the connection
+    async fn new(mut data: ConnectionData, prompts: Arc<dyn PromptProvider>) -> Result<Self> {
+        let fallback = mem::take(&mut data.fallback);
+        let limit = (!fallback.is_empty()).then(|| {
+            data.config
+                .inactivity_timeout
+                .unwrap_or(Duration::from_secs(FALLBACK_CONNECT_SECS))
+        });
+        let mut addresses = std::iter::once(data.address.clone())
+            .chain(fallback)
+            .peekable();
+        let (address, socket, stream) = loop {
+            let address = addresses.next().expect("should have the address");
+            match (open(&address, &data, limit).await, addresses.peek()) {
+                (Ok((socket, stream)), _) => break (address, socket, stream),
+                (Err(e), Some(next)) => {
+                    warn!("'{}' can't be reached ({}), trying '{}'", address, e, next)
+                }
+                (Err(e), None) => return Err(e),
+            }
         };
+        if address != data.address {
+            info!("Connected through the fallback address '{}'", address);
+            data.address = address;
+        }
 
         Ok(Self {
             data,
             socket,
+            stream: Some(stream),
             session: None,
+            prompts,
+            server_key: Arc::default(),
+            negotiated: Arc::default(),
+            method: "none",
         })
     }
 
-    async fn establish(&mut self) -> Result<()> {
-        let handler = ClientHandler::new(self.socket.ip(), mem::take(&mut self.data.known_hosts));
+    async fn establish(&mut self, verifier: Box<dyn HostKeyVerifier>) -> Result<()> {
+        let handler = ClientHandler::new(&self.data.address, self.socket, verifier);
+        self.server_key = handler.server_key();
+        self.negotiated = handler.negotiated();
         let config = Arc::new(mem::take(&mut self.data.config));
 
-        info!(
-            "Connecting to {}:{}...",
-            self.socket.ip(),
-            self.socket.port()
-        );
-
-        self.session = russh::client::connect(config, self.socket, handler)
+        let started = Instant::now();
+        let stream = self.stream.take().expect("should be opened");
+        self.session = russh::client::connect_stream(config, stream, handler)
             .await
             .map_err(SessionError::Connect)?
             .into();
+        debug!(
+            "Handshake completed in {} ms",
+            started.elapsed().as_millis()
+        );
 
         Ok(())
     }
@@ -131,7 +465,16 @@ impl Connection {
             allowed_methods
         );
 
-        for method in allowed_methods.iter() {
+        // Methods the server doesn't allow are left out of the preferred ones
+        let methods: Vec<MethodKind> = match &self.data.preferred_auth {
+            Some(preferred) => preferred
+                .iter()
+                .map(|&method| MethodKind::from(method))
+                .filter(|method| allowed_methods.contains(method))
+                .collect(),
+            None => allowed_methods.to_vec(),
+        };
+        for method in methods.iter() {
             let authenticated = match method {
                 MethodKind::PublicKey => {
                     let hash_alg = session!(self)
@@ -143,6 +486,15 @@ impl Connection {
                         || self.try_certificate_auth().await?
                         || self.try_publickey_auth(hash_alg).await?
                 }
+                MethodKind::KeyboardInteractive | MethodKind::Password
+                    if !self.data.password_auth =>
+                {
+                    info!(
+                        "Skipping {:?} authentication (password_auth is off)",
+                        method
+                    );
+                    continue;
+                }
                 MethodKind::KeyboardInteractive => self.try_keyboard_interactive_auth().await?,
                 MethodKind::Password => self.try_password_auth().await?,
                 _ => continue,
@@ -156,59 +508,30 @@ impl Connection {
             .map(Into::into)
             .collect::<Vec<String>>()
             .join(", ");
+        if !self.data.password_auth {
+            return Err(SessionError::PasswordAuthDisabled(allowed_methods).into());
+        }
 
         Err(SessionError::AuthFailed(allowed_methods).into())
     }
 
-    async fn execute_command(&self, command: &str) -> Result<()> {
-        info!("Executing command '{}'...", command);
-
-        let session = session!(self);
-        let mut channel = session.channel_open_session().await?;
-        channel.exec(true, command).await?;
-
-        let mut stdout = tokio::io::stdout();
-        while let Some(msg) = channel.wait().await {
-            match msg {
-                ChannelMsg::Data { data } => {
-                    stdout.write_all(&data).await?;
-                    stdout.flush().await?;
-                }
-                ChannelMsg::ExitStatus { exit_status: _ } => break,
-                _ => {}
-            }
+    /// One line on how the connection was made (shown with `-v`)
+    fn summary(&self) -> String {
+        let mut summary = format!("Connected to {}", self.socket);
+        if self.data.address != self.socket.ip().to_string() {
+            summary += &format!(" ({})", self.data.address);
+        }
+        summary += &format!(" as {} with {}", self.data.user, self.method);
+        if let Some(negotiated) = self.negotiated.get() {
+            summary += &format!("; {}", negotiated);
         }
 
-        Ok(())
-    }
-
-    async fn start_interactive(&mut self) -> Result<()> {
-        info!("Preparing interactive session...");
-
-        let session = session!(mut self);
-        let mut channel = session.channel_open_session().await?;
-
-        let (width, height) = terminal::size().map_err(FileError::Std)?;
-        let term = std::env::var("TERM").unwrap_or(DEFAULT_TERM.into());
-
-        channel
-            .request_pty(false, &term, width.into(), height.into(), 0, 0, &[])
-            .await
-            .map_err(SessionError::Terminal)?;
-
-        channel
-            .request_shell(true)
-            .await
-            .map_err(SessionError::Terminal)?;
-
-        let result = run_session(&mut channel).await;
-        println!("Connection to {} closed.", self.socket.ip());
-
-        result
+        summary
     }
 
     async fn try_agent_auth(&mut self, hash_alg: Option<HashAlg>) -> Result<bool> {
         info!("Trying SSH agent authentication...");
+        self.method = "agent";
 
         let Ok(mut agent) = AgentClient::connect_env().await else {
             info!("SSH agent not available (environment variable 'SSH_AUTH_SOCK' not set)");
@@ -245,6 +568,7 @@ impl Connection {
 
     async fn try_certificate_auth(&mut self) -> Result<bool> {
         info!("Trying OpenSSH certificate authentication...");
+        self.method = "certificate";
 
         let (key_path, cert_path) = match (&self.data.private_key, &self.data.openssh_cert) {
             (Some(k), Some(c)) => (k, c),
@@ -253,7 +577,7 @@ impl Connection {
                 return Ok(false);
             }
         };
-        let key = load_private_key(key_path).map_err(SessionError::PrivateKey)?;
+        let key = load_private_key(key_path, &*self.prompts).map_err(SessionError::PrivateKey)?;
         let cert = load_openssh_certificate(cert_path).map_err(SessionError::OpenSSHCert)?;
 
         let session = session!(mut self);
@@ -270,6 +594,7 @@ impl Connection {
 
     async fn try_publickey_auth(&mut self, hash_alg: Option<HashAlg>) -> Result<bool> {
         info!("Trying public key authentication...");
+        self.method = "publickey";
 
         let key_path = match &self.data.private_key {
             Some(k) => k,
@@ -278,7 +603,7 @@ impl Connection {
                 return Ok(false);
             }
         };
-        let key = load_private_key(key_path).map_err(SessionError::PrivateKey)?;
+        let key = load_private_key(key_path, &*self.prompts).map_err(SessionError::PrivateKey)?;
         let pair = PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg);
 
         let session = session!(mut self);
@@ -295,6 +620,7 @@ impl Connection {
 
     async fn try_keyboard_interactive_auth(&mut self) -> Result<bool> {
         info!("Trying keyboard-interactive authentication...");
+        self.method = "keyboard-interactive";
 
         let session = session!(mut self);
         let mut response = session
@@ -320,17 +646,19 @@ impl Connection {
                     info!("Keyboard-interactive authentication request received");
 
                     if !name.is_empty() {
-                        println!("{}", name);
+                        self.prompts.message(&name);
                     }
                     if !instructions.is_empty() {
-                        println!("\n{}", instructions);
+                        self.prompts.message(&format!("\n{}", instructions));
                     }
                     let mut responses = Vec::new();
                     for prompt in prompts {
                         let answer = if prompt.echo {
-                            prompt!(echo => "{}", prompt.prompt)
+                            prompt!(self.prompts, echo => "{}", prompt.prompt)
                         } else {
-                            prompt!("{}", prompt.prompt).expose_secret().into()
+                            prompt!(self.prompts, "{}", prompt.prompt)
+                                .expose_secret()
+                                .into()
                         };
 
                         responses.push(answer);
@@ -348,23 +676,630 @@ impl Connection {
 
     async fn try_password_auth(&mut self) -> Result<bool> {
         info!("Trying password authentication...");
+        self.method = "password";
 
         let session = session!(mut self);
-        let password = prompt!("{}@{}'s password", self.data.user, self.data.address);
+        if let Some(secret_ref) = &self.data.password {
+            match secret_ref.resolve() {
+                Ok(Some(password)) => {
+                    info!("Using password from '{}'", secret_ref);
 
-        session
-            .authenticate_password(&self.data.user, password.expose_secret())
+                    if let Ok(AuthResult::Success) = session
+                        .authenticate_password(&self.data.user, password.expose_secret())
+                        .await
+                    {
+                        info!("Password authentication succeeded");
+                        return Ok(true);
+                    }
+                    warn!("Password from '{}' was rejected", secret_ref);
+                }
+                Ok(None) => warn!("Secret '{}' not found in the keyring", secret_ref),
+                Err(e) => warn!("{}", e),
+            }
+        }
+        for attempt in 1..=self.data.password_attempts {
+            if attempt > 1 {
+                self.prompts.message("Permission denied, please try again.");
+            }
+            let password = prompt!(
+                self.prompts,
+                "{}@{}'s password",
+                self.data.user,
+                self.data.address
+            );
+            let result = session
+                .authenticate_password(&self.data.user, password.expose_secret())
+                .await
+                .map_err(SessionError::PasswordAuth)?;
+            if let AuthResult::Success = result {
+                info!("Password authentication succeeded");
+                return Ok(true);
+            }
+        }
+        info!("Password authentication failed");
+
+        Ok(false)
+    }
+}
+
+impl Session {
+    /// Connects to the server, verifies its host key as configured and authenticates,
+    /// asking questions on the terminal
+    pub async fn connect(mut data: ConnectionData) -> Result<Self> {
+        let prompts: Arc<dyn PromptProvider> = Arc::new(TerminalPrompts);
+        let verifier = data.verifier(Arc::clone(&prompts));
+        Self::connect_with(data, verifier, prompts).await
+    }
+
+    /// Same as [`Session::connect`], but the host key is verified by the given verifier
+    /// and the questions (e.g. passwords) are answered by the given provider
+    pub async fn connect_with(
+        data: ConnectionData,
+        verifier: Box<dyn HostKeyVerifier>,
+        prompts: Arc<dyn PromptProvider>,
+    ) -> Result<Self> {
+        let (event_log, target) = (data.event_log, data.target());
+        let record = |kind, fields, error: Option<&anyhow::Error>| {
+            if let Some(event_log) = event_log {
+                event_log.record(&Event {
+                    kind,
+                    target: &target,
+                    fields,
+                    error: error.map(ToString::to_string),
+                });
+            }
+        };
+        let mut conn = match Connection::new(data, prompts).await {
+            Ok(conn) => conn,
+            Err(e) => {
+                record(EventKind::Connect, Vec::new(), Some(&e));
+                return Err(e);
+            }
+        };
+        let mut result = conn.establish(verifier).await;
+        let fingerprint = conn
+            .server_key
+            .get()
+            .map_or("-", String::as_str)
+            .to_string();
+        record(
+            EventKind::Connect,
+            vec![
+                ("socket", conn.socket.to_string()),
+                ("fingerprint", fingerprint.clone()),
+            ],
+            result.as_ref().err(),
+        );
+        if result.is_ok() {
+            result = conn.authenticate().await;
+            record(
+                EventKind::Auth,
+                vec![("method", conn.method.to_string())],
+                result.as_ref().err(),
+            );
+        }
+        if conn.data.auth_log {
+            record_auth_attempt(&Event {
+                kind: EventKind::Auth,
+                target: &target,
+                fields: vec![
+                    ("socket", conn.socket.to_string()),
+                    ("method", conn.method.to_string()),
+                    ("fingerprint", fingerprint),
+                ],
+                error: result.as_ref().err().map(ToString::to_string),
+            });
+        }
+        result?;
+        info!("{}", conn.summary());
+        let target = format!("{}@{}", conn.data.user, conn.data.address);
+
+        Ok(Self {
+            socket: conn.socket,
+            handle: Arc::new(conn.session.take().expect("should be connected")),
+            buffer_size: conn.data.buffer_size,
+            idle_timeout: conn.data.idle_timeout,
+            title: conn.data.terminal_title.then(|| target.clone()),
+            target,
+            log_sessions: conn.data.log_sessions.take(),
+            login_script: mem::take(&mut conn.data.login_script),
+            term: conn.data.term.take(),
+        })
+    }
+
+    /// Runs the command, printing its output to stdout, and returns its exit status
+    pub async fn exec(&self, command: &str) -> Result<Option<u32>> {
+        info!("Executing command '{}'...", command);
+
+        let mut channel = self.handle.channel_open_session().await?;
+        channel.exec(true, command).await?;
+
+        self.print_output(&mut channel).await
+    }
+
+    /// Runs the command and returns what it writes to stdout, if it succeeds
+    pub async fn output(&self, command: &str) -> Result<Vec<u8>> {
+        debug!("Executing command '{}' for its output...", command);
+
+        let mut channel = self.handle.channel_open_session().await?;
+        channel.exec(true, command).await?;
+        let (mut output, mut exit_status) = (Vec::new(), None);
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { data } => output.extend_from_slice(&data),
+                ChannelMsg::ExitStatus {
+                    exit_status: status,
+                } => exit_status = Some(status),
+                _ => {}
+            }
+        }
+
+        match exit_status {
+            Some(0) => Ok(output),
+            status => Err(SessionError::CommandFailed(status.unwrap_or(255)).into()),
+        }
+    }
+
+    /// Same as [`Session::exec`], but the command is run with sudo, whose password
+    /// prompt is answered and kept out of the output
+    async fn exec_sudo(&self, command: &str, sudo: &mut SudoPrompt) -> Result<Option<u32>> {
+        info!("Executing command '{}' with sudo...", command);
+        let command = format!(
+            "sudo -S -p {} sh -c {}",
+            shlex::try_quote(SUDO_PROMPT)?,
+            shlex::try_quote(command)?
+        );
+
+        let mut channel = self.handle.channel_open_session().await?;
+        channel.exec(true, command).await?;
+
+        self.print_output_with(&mut channel, Some(sudo)).await
+    }
+
+    /// Uploads the script to a temporary file on the server, runs it with the arguments
+    /// and returns its exit status. The file is removed once the script is done.
+    pub async fn run_script(&self, script: &[u8], args: &[String]) -> Result<Option<u32>> {
+        let args = shlex::try_join(args.iter().map(String::as_str))?;
+        let command = format!(
+            "f=$(mktemp \"${{TMPDIR:-/tmp}}/shh-run.XXXXXX\") || exit 1; \
+            trap 'rm -f \"$f\"' EXIT; trap 'exit 129' HUP INT TERM; \
+            cat > \"$f\" && chmod 700 \"$f\" && \"$f\" {}",
+            args
+        );
+        info!("Running script ({} bytes) with '{}'...", script.len(), args);
+
+        let mut channel = self.handle.channel_open_session().await?;
+        // Wrapped, since the login shell of the user may not be POSIX
+        channel
+            .exec(true, format!("sh -c {}", shlex::try_quote(&command)?))
+            .await?;
+        channel.data(script).await?;
+        channel.eof().await?;
+
+        self.print_output(&mut channel).await
+    }
+
+    /// Streams the file to the path on the other server (into it, if it's a directory)
+    /// through this machine, returns the number of bytes copied
+    pub async fn copy_to(&self, path: &str, target: &Session, target_path: &str) -> Result<u64> {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let read = format!("cat -- {}", remote_path_arg(path)?);
+        let write = format!(
+            "f={}; if [ -d \"$f\" ]; then f=\"$f\"/{}; fi; cat > \"$f\"",
+            remote_path_arg(target_path)?,
+            shlex::try_quote(name)?
+        );
+        info!("Copying '{}' to '{}'...", path, target_path);
+
+        self.relay(read, target, write).await
+    }
+
+    /// Streams the directory into the directory on the other server (created if
+    /// it's missing) as a tar archive, so permissions and modification times are kept.
+    /// Symbolic links are copied as links, never followed. Returns the size of the archive.
+    pub async fn copy_tree_to(
+        &self,
+        path: &str,
+        target: &Session,
+        target_path: &str,
+        exclude: &[String],
+    ) -> Result<u64> {
+        let (parent, name) = split_remote_path(path);
+        let parent = match parent {
+            "~" => parent.to_string(),
+            parent => remote_path_arg(parent)?,
+        };
+        let exclude: String = exclude
+            .iter()
+            .map(|pattern| Ok(format!(" --exclude={}", shlex::try_quote(pattern)?)))
+            .collect::<Result<_>>()?;
+        let read = format!(
+            "tar -C {} -cf -{} -- {}",
+            parent,
+            exclude,
+            shlex::try_quote(name)?
+        );
+        let target_path = remote_path_arg(target_path)?;
+        let write = format!(
+            "mkdir -p -- {} && tar -C {} -xpf -",
+            target_path, target_path
+        );
+        info!("Copying directory '{}' to '{}'...", path, target_path);
+
+        self.relay(read, target, write).await
+    }
+
+    /// Regular files under the directory, none if it doesn't exist
+    pub async fn list_files(&self, dir: &str) -> Result<FileList> {
+        let command = format!(
+            "cd {} 2>/dev/null || exit 0; {}",
+            remote_path_arg(dir)?,
+            LIST_FILES
+        );
+        let output = self
+            .output(&format!("sh -c {}", shlex::try_quote(&command)?))
+            .await?;
+
+        Ok(parse_listing(&output))
+    }
+
+    /// Sends the files of the local directory into the directory on the server
+    /// (created if it's missing) as a tar archive, returns the size of the archive
+    pub async fn push_files(&self, local: &Path, dir: &str, files: &[String]) -> Result<u64> {
+        let write = format!(
+            "mkdir -p -- {} && tar -C {} -xpf -",
+            remote_path_arg(dir)?,
+            remote_path_arg(dir)?
+        );
+        info!("Sending {} files to '{}'...", files.len(), dir);
+
+        let mut tar = tokio::process::Command::new("tar")
+            .arg("-C")
+            .arg(local)
+            .args(["-cf", "-", "--null", "-T", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| CliError::CopyFailed(format!("tar can't be run: {}", e).into()))?;
+        let list = tar_file_list(files);
+        let mut names = tar.stdin.take().expect("stdin is piped");
+        tokio::spawn(async move { names.write_all(&list).await });
+        let mut archive = tar.stdout.take().expect("stdout is piped");
+
+        let mut channel = self.handle.channel_open_session().await?;
+        channel
+            .exec(true, format!("sh -c {}", shlex::try_quote(&write)?))
+            .await?;
+        let mut buffer = vec![0; self.buffer_size];
+        let mut sent = 0;
+        loop {
+            let read = archive.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            sent += read as u64;
+            channel.data(&buffer[..read]).await?;
+        }
+        channel.eof().await?;
+        let (mut errors, mut write_status) = (Vec::new(), None);
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::ExtendedData { data, ext: 1 } => errors.extend_from_slice(&data),
+                ChannelMsg::ExitStatus { exit_status } => write_status = Some(exit_status),
+                _ => {}
+            }
+        }
+        let local = tar.wait_with_output().await?;
+        errors.extend_from_slice(&local.stderr);
+
+        check_transfer(local.status.success(), write_status, errors)?;
+        Ok(sent)
+    }
+
+    /// Fetches the files of the directory on the server into the local directory
+    /// (created if it's missing) as a tar archive, returns the size of the archive
+    pub async fn pull_files(&self, dir: &str, local: &Path, files: &[String]) -> Result<u64> {
+        let read = format!("cd {} && tar -cf - --null -T -", remote_path_arg(dir)?);
+        info!("Fetching {} files from '{}'...", files.len(), dir);
+
+        fs::create_dir_all(local).await?;
+        let mut tar = tokio::process::Command::new("tar")
+            .arg("-C")
+            .arg(local)
+            .args(["-xpf", "-"])
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| CliError::CopyFailed(format!("tar can't be run: {}", e).into()))?;
+        let mut archive = tar.stdin.take().expect("stdin is piped");
+
+        let mut channel = self.handle.channel_open_session().await?;
+        channel
+            .exec(true, format!("sh -c {}", shlex::try_quote(&read)?))
+            .await?;
+        // The names are read while the archive is being written
+        let list = tar_file_list(files);
+        let mut names = channel.make_writer();
+        tokio::spawn(async move {
+            names.write_all(&list).await?;
+            names.shutdown().await
+        });
+        let (mut errors, mut read_status, mut received) = (Vec::new(), None, 0);
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { data } => {
+                    received += data.len() as u64;
+                    archive.write_all(&data).await?;
+                }
+                ChannelMsg::ExtendedData { data, ext: 1 } => errors.extend_from_slice(&data),
+                ChannelMsg::ExitStatus { exit_status } => read_status = Some(exit_status),
+                _ => {}
+            }
+        }
+        drop(archive);
+        let local = tar.wait_with_output().await?;
+        errors.extend_from_slice(&local.stderr);
+
+        check_transfer(local.status.success(), read_status, errors)?;
+        Ok(received)
+    }
+
+    /// Pipes the output of the command into the command on the other server,
+    /// returns the number of bytes passed
+    async fn relay(&self, read: String, target: &Session, write: String) -> Result<u64> {
+        let mut reader = self.handle.channel_open_session().await?;
+        reader.exec(true, read).await?;
+        let mut writer = target.handle.channel_open_session().await?;
+        writer
+            .exec(true, format!("sh -c {}", shlex::try_quote(&write)?))
+            .await?;
+
+        let mut errors = Vec::new();
+        let (mut copied, mut read_status) = (0, None);
+        while let Some(msg) = reader.wait().await {
+            match msg {
+                ChannelMsg::Data { data } => {
+                    copied += data.len() as u64;
+                    writer.data(&data[..]).await?;
+                }
+                ChannelMsg::ExtendedData { data, ext: 1 } => errors.extend_from_slice(&data),
+                ChannelMsg::ExitStatus { exit_status } => read_status = Some(exit_status),
+                _ => {}
+            }
+        }
+        writer.eof().await?;
+        let mut write_status = None;
+        while let Some(msg) = writer.wait().await {
+            match msg {
+                ChannelMsg::ExtendedData { data, ext: 1 } => errors.extend_from_slice(&data),
+                ChannelMsg::ExitStatus { exit_status } => write_status = Some(exit_status),
+                _ => {}
+            }
+        }
+
+        if read_status != Some(0) || write_status != Some(0) {
+            let errors = String::from_utf8_lossy(&errors).trim().to_string();
+            let reason = if errors.is_empty() {
+                format!(
+                    "exit status {} (reading), {} (writing)",
+                    read_status.map_or("-".into(), |s| s.to_string()),
+                    write_status.map_or("-".into(), |s| s.to_string())
+                )
+            } else {
+                errors
+            };
+            return Err(CliError::CopyFailed(reason.into()).into());
+        }
+
+        Ok(copied)
+    }
+
+    /// Prints what the command writes to stdout and stderr until it exits,
+    /// returns its exit status
+    async fn print_output(&self, channel: &mut Channel<Msg>) -> Result<Option<u32>> {
+        self.print_output_with(channel, None).await
+    }
+
+    async fn print_output_with(
+        &self,
+        channel: &mut Channel<Msg>,
+        mut sudo: Option<&mut SudoPrompt>,
+    ) -> Result<Option<u32>> {
+        let mut stdout = BufWriter::with_capacity(self.buffer_size, tokio::io::stdout());
+        let mut stderr = tokio::io::stderr();
+        let mut exit_status = None;
+        loop {
+            // Output is flushed only once no more data is waiting
+            let msg = tokio::select! {
+                biased;
+                msg = channel.wait() => msg,
+                flushed = stdout.flush(), if !stdout.buffer().is_empty() => {
+                    flushed?;
+                    continue;
+                }
+            };
+            match msg {
+                Some(ChannelMsg::Data { data }) => stdout.write_all(&data).await?,
+                Some(ChannelMsg::ExtendedData { data, ext: 1 }) => {
+                    stdout.flush().await?;
+                    let Some(sudo) = sudo.as_deref_mut() else {
+                        stderr.write_all(&data).await?;
+                        continue;
+                    };
+                    let (rest, answer) = sudo.check(&data)?;
+                    stderr.write_all(&rest).await?;
+                    match answer {
+                        Some(SudoAnswer::Password(password)) => {
+                            let line = format!("{}\n", password.expose_secret());
+                            channel.data(line.as_bytes()).await?;
+                        }
+                        // sudo gives up once stdin is closed
+                        Some(SudoAnswer::GiveUp) => channel.eof().await?,
+                        None => {}
+                    }
+                }
+                Some(ChannelMsg::ExitStatus {
+                    exit_status: status,
+                }) => {
+                    exit_status = Some(status);
+                    break;
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        stdout.flush().await?;
+        if let Some(sudo) = sudo {
+            stderr.write_all(&sudo.finish()).await?;
+        }
+
+        Ok(exit_status)
+    }
+
+    /// Starts a remote shell attached to the local terminal, returns its exit status
+    pub async fn shell(&self) -> Result<Option<u32>> {
+        info!("Preparing interactive session...");
+
+        let mut channel = self.handle.channel_open_session().await?;
+
+        let (width, height) = terminal::size().map_err(FileError::Std)?;
+        let term = match &self.term {
+            Some(term) => term.clone(),
+            None => std::env::var("TERM").unwrap_or(DEFAULT_TERM.into()),
+        };
+
+        channel
+            .request_pty(false, &term, width.into(), height.into(), 0, 0, &[])
+            .await
+            .map_err(SessionError::Terminal)?;
+
+        channel
+            .request_shell(true)
             .await
-            .map_err(SessionError::PasswordAuth)?;
+            .map_err(SessionError::Terminal)?;
 
-        info!("Password authentication succeeded");
+        let transcript = match &self.log_sessions {
+            Some(dir) => {
+                let transcript = Transcript::create(dir, &self.target)
+                    .await
+                    .map_err(FileError::Std)?;
+                info!("Recording the session to '{}'", transcript.path().display());
+                Some(transcript)
+            }
+            None => None,
+        };
+        let idle = self.idle_timeout.map(IdleTimer::new);
+        let title = self.title.as_deref().map(TitleGuard::new);
+        let login = (!self.login_script.is_empty()).then(|| {
+            self.login_script
+                .iter()
+                .flat_map(|line| [line.as_bytes(), b"\n"].concat())
+                .collect()
+        });
+        let result = run_session(&mut channel, self.buffer_size, idle, transcript, login).await;
+        drop(title);
+        status!("Connection to {} closed.", self.socket.ip());
 
-        Ok(true)
+        result
+    }
+
+    /// Forwards connections accepted on the local address to `host:port`
+    /// as seen from the server, until the listener fails
+    pub async fn forward_local(&self, local: SocketAddr, host: &str, port: u16) -> Result<()> {
+        let listener = TcpListener::bind(local).await?;
+        info!("Forwarding {} to {}:{}...", local, host, port);
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            self.forward_connection(stream, peer, host, port);
+        }
+    }
+
+    /// Passes an accepted connection to `host:port` as seen from the server
+    /// in a task of its own
+    pub fn forward_connection(
+        &self,
+        mut stream: TcpStream,
+        peer: SocketAddr,
+        host: &str,
+        port: u16,
+    ) {
+        debug!("Forwarding connection from {}", peer);
+        let (handle, host) = (Arc::clone(&self.handle), host.to_string());
+        tokio::spawn(async move {
+            let forwarded = async {
+                let channel = handle
+                    .channel_open_direct_tcpip(
+                        host,
+                        port.into(),
+                        peer.ip().to_string(),
+                        peer.port().into(),
+                    )
+                    .await?;
+                tokio::io::copy_bidirectional(&mut stream, &mut channel.into_stream()).await?;
+                anyhow::Ok(())
+            };
+            if let Err(e) = forwarded.await {
+                warn!("Forwarded connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+
+    /// Checks that the server still answers, failing if it doesn't within the timeout
+    pub async fn ping(&self, timeout: Duration) -> Result<()> {
+        if self.handle.is_closed() {
+            return Err(SessionError::Closed.into());
+        }
+        tokio::time::timeout(timeout, self.handle.send_ping())
+            .await
+            .map_err(|_| SessionError::NoReply(timeout.as_secs()))??;
+
+        Ok(())
+    }
+
+    /// Opens a channel running the SFTP subsystem, the SFTP protocol
+    /// itself is spoken over its stream (see `Channel::into_stream`)
+    pub async fn sftp(&self) -> Result<Channel<Msg>> {
+        info!("Starting SFTP subsystem...");
+
+        let channel = self.handle.channel_open_session().await?;
+        channel.request_subsystem(true, SFTP_SUBSYSTEM).await?;
+
+        Ok(channel)
+    }
+
+    /// Tells the server the session is over, channels still open are closed
+    pub async fn close(&self) -> Result<()> {
+        self.handle
+            .disconnect(Disconnect::ByApplication, "", "en")
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl From<AuthMethod> for MethodKind {
+    fn from(method: AuthMethod) -> Self {
+        match method {
+            AuthMethod::Publickey => Self::PublicKey,
+            AuthMethod::KeyboardInteractive => Self::KeyboardInteractive,
+            AuthMethod::Password => Self::Password,
+        }
+    }
+}
+
+impl Display for AuthMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str((&MethodKind::from(*self)).into())
     }
 }
 
 #[inline]
-fn load_private_key(key_path: &Path) -> Result<PrivateKey, russh::keys::Error> {
+fn load_private_key(
+    key_path: &Path,
+    prompts: &dyn PromptProvider,
+) -> Result<PrivateKey, russh::keys::Error> {
     info!(
         "Trying to load private key from '{}'...",
         key_path.display()
@@ -382,7 +1317,7 @@ fn load_private_key(key_path: &Path) -> Result<PrivateKey, russh::keys::Error> {
 
     let key_path_display = key_path.display();
     for _ in 1..=MAX_PASSPHRASE_ATTEMPTS {
-        let passphrase = prompt!("Enter passphrase for key '{key_path_display}'");
+        let passphrase = prompt!(prompts, "Enter passphrase for key '{key_path_display}'");
 
         match load_secret_key(key_path, Some(passphrase.expose_secret())) {
             Ok(key) => {
@@ -401,10 +1336,22 @@ fn load_private_key(key_path: &Path) -> Result<PrivateKey, russh::keys::Error> {
     Err(russh::keys::Error::SshKey(ssh_key::Error::Crypto))
 }
 
-struct RawModeGuard;
+/// Restores the terminal when dropped. Panics restore it as well
+/// (release builds abort on panic, so destructors don't run).
+pub(crate) struct RawModeGuard;
 
 impl RawModeGuard {
-    fn new() -> Result<Self> {
+    pub(crate) fn new() -> Result<Self> {
+        static PANIC_HOOK: Once = Once::new();
+        PANIC_HOOK.call_once(|| {
+            let default_hook = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                if terminal::is_raw_mode_enabled().unwrap_or(false) {
+                    let _ = disable_raw_mode();
+                }
+                default_hook(info);
+            }));
+        });
         enable_raw_mode()?;
         Ok(Self)
     }
@@ -416,47 +1363,242 @@ impl Drop for RawModeGuard {
     }
 }
 
-async fn run_session(channel: &mut Channel<Msg>) -> Result<()> {
+/// Signals that would kill the process while the terminal is in raw mode,
+/// caught so that the session ends normally and the terminal is restored
+struct TerminationSignals {
+    interrupt: Signal,
+    terminate: Signal,
+    hangup: Signal,
+}
+
+impl TerminationSignals {
+    fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    /// Name of the next signal received
+    async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.interrupt.recv() => "SIGINT",
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.hangup.recv() => "SIGHUP",
+        }
+    }
+}
+
+/// Quoted for the remote shell, a leading `~/` is left outside to be expanded
+pub fn remote_path_arg(path: &str) -> Result<String> {
+    Ok(match path.strip_prefix("~/") {
+        Some(rest) => format!("~/{}", shlex::try_quote(rest)?),
+        None => shlex::try_quote(path)?.into_owned(),
+    })
+}
+
+/// Fails with what tar reported when the local or the remote side didn't succeed
+fn check_transfer(local_ok: bool, remote_status: Option<u32>, errors: Vec<u8>) -> Result<()> {
+    if local_ok && remote_status == Some(0) {
+        return Ok(());
+    }
+    let errors = String::from_utf8_lossy(&errors).trim().to_string();
+    let reason = if errors.is_empty() {
+        format!(
+            "tar failed (exit status {} on the server)",
+            remote_status.map_or("-".into(), |s| s.to_string())
+        )
+    } else {
+        errors
+    };
+
+    Err(CliError::CopyFailed(reason.into()).into())
+}
+
+/// Directory holding the path and the name in it, trailing slashes aside
+/// (`/` and `~` are split into themselves and `.`)
+fn split_remote_path(path: &str) -> (&str, &str) {
+    let trimmed = path.trim_end_matches('/');
+    match trimmed.rsplit_once('/') {
+        _ if trimmed.is_empty() && path.starts_with('/') => ("/", "."),
+        _ if trimmed.is_empty() => (".", "."),
+        _ if trimmed == "~" => ("~", "."),
+        Some(("", name)) => ("/", name),
+        Some((parent, name)) => (parent, name),
+        None => (".", trimmed),
+    }
+}
+
+/// Shows a title in the terminal, the previous one is restored when dropped
+/// (by the title stack of xterm, which most terminals implement)
+struct TitleGuard;
+
+impl TitleGuard {
+    fn new(title: &str) -> Self {
+        // Control characters would end the sequence early
+        let title: String = title.chars().filter(|c| !c.is_control()).collect();
+        // Push the current title, then set the window and icon title (OSC 0)
+        print!("\x1b[22;0t\x1b]0;{}\x07", title);
+        _ = std::io::Write::flush(&mut std::io::stdout());
+        Self
+    }
+}
+
+impl Drop for TitleGuard {
+    fn drop(&mut self) {
+        // Pop the saved title
+        print!("\x1b[23;0t");
+        _ = std::io::Write::flush(&mut std::io::stdout());
+    }
+}
+
+/// Counts down from the last input of an interactive session
+struct IdleTimer {
+    limit: Duration,
+    last_input: Instant,
+    /// Warnings (of `IDLE_WARNINGS_SECS`) already shown since the last input
+    warned: usize,
+}
+
+enum IdleEvent {
+    /// Index of the warning in `IDLE_WARNINGS_SECS`
+    Warning(usize),
+    TimedOut,
+}
+
+impl IdleTimer {
+    fn new(limit: Duration) -> Self {
+        Self {
+            limit,
+            last_input: Instant::now(),
+            warned: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last_input = Instant::now();
+        self.warned = 0;
+    }
+
+    /// When the next warning is due, or the end of the session once no warning is left.
+    /// Warnings earlier than the whole limit are skipped.
+    fn next(&self) -> (Instant, IdleEvent) {
+        let end = self.last_input + self.limit;
+        let warning = (self.warned..IDLE_WARNINGS_SECS.len())
+            .map(|i| (i, Duration::from_secs(IDLE_WARNINGS_SECS[i])))
+            .find(|&(_, left)| left < self.limit);
+        match warning {
+            Some((i, left)) => (end - left, IdleEvent::Warning(i)),
+            None => (end, IdleEvent::TimedOut),
+        }
+    }
+}
+
+/// Reads from the terminal are sent whole (up to the buffer size) and the output
+/// is written out once no more data is waiting, so bulk transfers aren't chopped up.
+/// With an idle timer, the session is closed after a countdown when there's no input.
+async fn run_session(
+    channel: &mut Channel<Msg>,
+    buffer_size: usize,
+    mut idle: Option<IdleTimer>,
+    mut transcript: Option<Transcript>,
+    mut login: Option<Vec<u8>>,
+) -> Result<Option<u32>> {
     let mut stdin = tokio_fd::AsyncFd::try_from(STDIN_FD)?;
-    let mut stdout = tokio_fd::AsyncFd::try_from(STDOUT_FD)?;
+    let mut stdout = BufWriter::with_capacity(buffer_size, tokio_fd::AsyncFd::try_from(STDOUT_FD)?);
 
-    let mut buf = [0u8; SESSION_BUFFER_SIZE];
+    let mut buf = vec![0u8; buffer_size.max(1)];
     let mut stdin_closed = false;
 
     let (mut width, mut height) = terminal::size()?;
     let mut resize_check = tokio::time::interval(Duration::from_millis(RESIZE_INTERVAL_MS));
 
+    let mut signals = TerminationSignals::new()?;
     let _guard = RawModeGuard::new()?;
+    let mut login_at = Instant::now() + Duration::from_millis(LOGIN_WAIT_MS);
 
-    loop {
+    let exit_status = loop {
+        let (idle_at, idle_event) = match &idle {
+            Some(idle) => {
+                let (at, event) = idle.next();
+                (at, Some(event))
+            }
+            None => (Instant::now(), None),
+        };
+        // Input goes first, so that keystrokes (e.g. Ctrl+C) get through a flood of output
         tokio::select! {
+            biased;
             outgoing = stdin.read(&mut buf), if !stdin_closed => {
                 match outgoing {
                     Ok(0) => {
                         stdin_closed = true;
                         _ = channel.eof().await;
                     }
-                    Ok(n) => channel.data(&buf[..n]).await?,
+                    Ok(n) => {
+                        if let Some(idle) = idle.as_mut() {
+                            idle.reset();
+                        }
+                        channel.data(&buf[..n]).await?
+                    }
                     Err(e) => return Err(e.into()),
                 }
             }
             incoming = channel.wait() => {
-                if let Some(msg) = incoming {
-                    match msg {
-                        ChannelMsg::Data { data } => {
-                            stdout.write_all(&data).await?;
-                            stdout.flush().await?;
+                match incoming {
+                    Some(ChannelMsg::Data { data }) => {
+                        if login.is_some() {
+                            login_at = Instant::now() + Duration::from_millis(LOGIN_SETTLE_MS);
                         }
-                        ChannelMsg::ExitStatus { exit_status: _ } => {
-                            if !stdin_closed {
-                                _ = channel.eof().await;
-                            }
-                            break;
+                        stdout.write_all(&data).await?;
+                        if let Some(transcript) = transcript.as_mut() {
+                            transcript.write(&data).await?;
                         }
-                        _ => {}
                     }
-                } else {
-                    break;
+                    Some(ChannelMsg::ExitStatus { exit_status }) => {
+                        if !stdin_closed {
+                            _ = channel.eof().await;
+                        }
+                        break Some(exit_status);
+                    }
+                    Some(_) => {}
+                    None => break None,
+                }
+            }
+            flushed = stdout.flush(), if !stdout.buffer().is_empty() => flushed?,
+            _ = tokio::time::sleep_until(idle_at.into()), if idle_event.is_some() => {
+                let idle = idle.as_mut().expect("idle timer is set");
+                // Raw mode, so lines need a carriage return
+                if let Some(IdleEvent::Warning(i)) = idle_event {
+                    idle.warned = i + 1;
+                    let notice = format!(
+                        "\r\n[shh] No input for a while, the session closes in {} seconds \
+                        (press a key to keep it)\r\n",
+                        IDLE_WARNINGS_SECS[i]
+                    );
+                    stdout.write_all(notice.as_bytes()).await?;
+                    continue;
+                }
+                let notice = format!(
+                    "\r\n[shh] Closing the session, no input for {} min\r\n",
+                    idle.limit.as_secs() / 60
+                );
+                stdout.write_all(notice.as_bytes()).await?;
+                info!("Idle timeout reached");
+                _ = channel.close().await;
+                break None;
+            }
+            signal = signals.recv() => {
+                let notice = format!("\r\n[shh] Closing the session ({})\r\n", signal);
+                stdout.write_all(notice.as_bytes()).await?;
+                info!("Received {}", signal);
+                _ = channel.close().await;
+                break None;
+            }
+            _ = tokio::time::sleep_until(login_at.into()), if login.is_some() => {
+                if let Some(input) = login.take() {
+                    debug!("Typing the login script");
+                    channel.data(&input[..]).await?;
                 }
             }
             _ = resize_check.tick() => {
@@ -466,7 +1608,104 @@ async fn run_session(channel: &mut Channel<Msg>) -> Result<()> {
                 }
             }
         }
+    };
+    stdout.flush().await?;
+    if let Some(transcript) = transcript {
+        transcript.finish(exit_status).await?;
     }
 
-    Ok(())
+    Ok(exit_status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Answers every password question with the same password, counting them
+    #[derive(Default)]
+    struct FixedPassword(AtomicUsize);
+
+    impl PromptProvider for FixedPassword {
+        fn text(&self, _: &str) -> io::Result<String> {
+            unreachable!("Only passwords are asked")
+        }
+
+        fn secret(&self, _: &str) -> io::Result<SecretString> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok("hunter2".into())
+        }
+
+        fn message(&self, _: &str) {}
+    }
+
+    fn password(answer: Option<SudoAnswer>) -> Option<String> {
+        match answer? {
+            SudoAnswer::Password(password) => Some(password.expose_secret().to_string()),
+            SudoAnswer::GiveUp => None,
+        }
+    }
+
+    #[test]
+    fn sudo_prompt_across_chunks() {
+        let prompts = Arc::new(FixedPassword::default());
+        let mut sudo = SudoPrompt::new(None, "alice@db-split".into(), false, prompts.clone());
+
+        let (rest, answer) = sudo.check(b"warning\n[shh-sudo").unwrap();
+        assert_eq!(
+            (rest.as_slice(), answer.is_none()),
+            (&b"warning\n"[..], true)
+        );
+        let (rest, answer) = sudo.check(b"-password]").unwrap();
+        assert_eq!(rest, b"");
+        assert_eq!(password(answer).as_deref(), Some("hunter2"));
+
+        // Held back only while it can still become the prompt
+        let (rest, _) = sudo.check(b"done [").unwrap();
+        assert_eq!(rest, b"done ");
+        let (rest, _) = sudo.check(b"x]").unwrap();
+        assert_eq!(rest, b"[x]");
+        sudo.check(b"[shh").unwrap();
+        assert_eq!(sudo.finish(), b"[shh");
+        assert_eq!(prompts.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn sudo_password_per_host() {
+        let prompts = Arc::new(FixedPassword::default());
+        let prompt = SUDO_PROMPT.as_bytes();
+        let mut first = SudoPrompt::new(None, "alice@db-cache1".into(), false, prompts.clone());
+        assert!(password(first.check(prompt).unwrap().1).is_some());
+
+        // The other host is asked for its own password, the same one reuses it
+        let mut other = SudoPrompt::new(None, "alice@db-cache2".into(), false, prompts.clone());
+        assert!(password(other.check(prompt).unwrap().1).is_some());
+        assert_eq!(prompts.0.load(Ordering::Relaxed), 2);
+        let mut again = SudoPrompt::new(None, "alice@db-cache1".into(), false, prompts.clone());
+        assert!(password(again.check(prompt).unwrap().1).is_some());
+        assert_eq!(prompts.0.load(Ordering::Relaxed), 2);
+
+        // Rejected: asked once more, then given up and forgotten
+        assert!(password(again.check(prompt).unwrap().1).is_some());
+        assert_eq!(prompts.0.load(Ordering::Relaxed), 3);
+        assert!(matches!(
+            again.check(prompt).unwrap().1,
+            Some(SudoAnswer::GiveUp)
+        ));
+        let cached = SUDO_PASSWORDS
+            .lock()
+            .unwrap()
+            .contains_key("alice@db-cache1");
+        assert!(!cached);
+    }
+
+    #[test]
+    fn split_paths() {
+        assert_eq!(split_remote_path("/srv/app/build/"), ("/srv/app", "build"));
+        assert_eq!(split_remote_path("/build"), ("/", "build"));
+        assert_eq!(split_remote_path("~/build"), ("~", "build"));
+        assert_eq!(split_remote_path("build"), (".", "build"));
+        assert_eq!(split_remote_path("/"), ("/", "."));
+        assert_eq!(split_remote_path("~/"), ("~", "."));
+    }
 }
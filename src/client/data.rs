@@ -1,33 +1,100 @@
-use crate::cli::parser::ServerUri;
+use crate::cli::parser::{AlgoName, CipherName, KexName, MacName, ServerUri};
+use crate::client::connect::AuthMethod;
+use crate::client::hooks::Hooks;
+use crate::client::profile::SecurityProfile;
+use crate::client::prompt::PromptProvider;
+use crate::client::socket::SocketOptions;
+use crate::client::verify::{
+    AcceptAllVerifier, HostKeyCheck, HostKeyVerifier, KnownHostsVerifier, PinnedVerifier,
+};
 use crate::{
     error::ConnectionError,
     storage::{
         config::{Scope, Server},
+        context::DNS_CACHE_TTL,
+        event_log::EventLog,
         provider::{WORK_DIR, get_full_path},
+        secret::SecretRef,
     },
 };
+use itertools::Itertools;
+use serde::Serialize;
+use std::mem;
+use std::sync::Arc;
 use std::time::Duration;
 use std::{env, path::PathBuf};
 
-const DEFAULT_SSH_PORT: u16 = 22;
-const DEFAULT_KNOWN_HOSTS_FILE: &str = "known_hosts";
+pub const DEFAULT_SSH_PORT: u16 = 22;
+/// Large enough for pasted text and streamed logs to pass in few messages
+pub const DEFAULT_BUFFER_SIZE: usize = 32 * 1024;
+pub const DEFAULT_KNOWN_HOSTS_FILE: &str = "known_hosts";
+/// Same as OpenSSH (`NumberOfPasswordPrompts`)
+const DEFAULT_PASSWORD_ATTEMPTS: u8 = 3;
+const OPENSSH_KNOWN_HOSTS_FILE: &str = "~/.ssh/known_hosts";
 
 /// Represents the data required to establish a connection to a server
 #[derive(Debug)]
 pub struct ConnectionData {
     // Fundamentals
     pub address: String,
+    // Tried in order when the address can't be reached
+    pub fallback: Vec<String>,
     pub user: String,
     pub port: u16,
     pub remote_cmd: Option<String>,
     // Files
     pub known_hosts: PathBuf,
+    pub extra_known_hosts: Vec<PathBuf>,
     pub private_key: Option<PathBuf>,
     pub openssh_cert: Option<PathBuf>,
+    // Host key verification
+    pub host_key_check: HostKeyCheck,
+    pub pinned_keys: Vec<String>,
+    pub visual_host_key: bool,
+    pub known_hosts_read_only: bool,
+    // Secrets
+    pub password: Option<SecretRef>,
+    pub password_auth: bool,
+    pub password_attempts: u8,
+    pub preferred_auth: Option<Vec<AuthMethod>>,
+    pub sudo: bool,
+    pub shared_sudo_password: bool,
+    // Record of connection attempts
+    pub auth_log: bool,
+    pub event_log: Option<EventLog>,
+    // Local commands around the connection
+    pub hooks: Hooks,
+    // Session I/O
+    pub buffer_size: usize,
+    pub idle_timeout: Option<Duration>,
+    pub terminal_title: bool,
+    pub term: Option<String>,
+    pub log_sessions: Option<PathBuf>,
+    pub login_script: Vec<String>,
+    pub socket: SocketOptions,
+    // Name resolution (reuses addresses resolved by earlier connections)
+    pub dns_cache: bool,
+    pub dns_cache_ttl: u64,
+    // Replaces the recorded key of a server whose key changed without asking
+    pub replace_host_key: bool,
     // russh Config
     pub config: russh::client::Config,
 }
 
+/// Layer of the configuration a connection parameter was taken from
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    Flag,
+    Server,
+    Scope,
+    Default,
+    BuiltIn,
+}
+
+/// A resolved connection parameter with its origin
+pub type TracedField = (&'static str, String, Source);
+
 /// Cascades through multiple optional sources, applying optional transformations.
 /// Syntax: field => source1, source2, ...; map = transform; default = hardcoded value
 macro_rules! cascade {
@@ -47,7 +114,12 @@ impl ConnectionData {
         // Already in server
         _ = uri.address;
 
-        let Server { address, scope } = server;
+        let Server {
+            address,
+            fallback,
+            scope,
+            ..
+        } = server;
 
         let user = cascade!(user => uri, flags, scope, global;
             default = env::var("USER").ok().ok_or(ConnectionError::UserRequired)?;
@@ -59,6 +131,10 @@ impl ConnectionData {
             map = get_full_path;
             default = WORK_DIR.join(DEFAULT_KNOWN_HOSTS_FILE);
         );
+        let extra_known_hosts = cascade!(extra_known_hosts => flags, scope, global;
+            map = |v| v.into_iter().map(get_full_path).collect();
+            default = vec![default_openssh_known_hosts()];
+        );
         let private_key = cascade!(private_key => flags, scope, global;
             map = get_full_path;
         );
@@ -66,25 +142,58 @@ impl ConnectionData {
             map = get_full_path;
         );
 
+        let host_key_check = cascade!(host_key_check => flags, scope, global;
+            default = HostKeyCheck::default();
+        );
+        let pinned_keys = cascade!(pinned_keys => flags, scope, global;
+            default = Vec::new();
+        );
+        let visual_host_key = cascade!(visual_host_key => flags, scope, global; default = false);
+        let known_hosts_read_only = cascade!(known_hosts_read_only => flags, scope, global;
+            default = false;
+        );
+
+        let password = cascade!(password => flags, scope, global);
+        let password_auth = cascade!(password_auth => flags, scope, global; default = true);
+        let password_attempts = cascade!(password_attempts => flags, scope, global;
+            default = DEFAULT_PASSWORD_ATTEMPTS;
+        );
+        let preferred_auth = cascade!(preferred_auth => flags, scope, global);
+        let sudo = cascade!(sudo => flags, scope, global; default = false);
+        let shared_sudo_password = cascade!(shared_sudo_password => flags, scope, global;
+            default = false;
+        );
+        let auth_log = cascade!(auth_log => flags, scope, global; default = false);
+        let event_log = cascade!(event_log => flags, scope, global);
+        // Each hook is taken from the first layer setting it
+        let hooks = [flags.hooks, scope.hooks, global.hooks]
+            .into_iter()
+            .flatten()
+            .reduce(Hooks::or)
+            .unwrap_or_default();
+        // Same for each TCP option
+        let socket = [flags.socket, scope.socket, global.socket]
+            .into_iter()
+            .flatten()
+            .reduce(SocketOptions::or)
+            .unwrap_or_default();
+
         let default_preferred = russh::Preferred::default();
         let default_config = russh::client::Config::default();
 
         let kex = cascade!(kex => flags, scope, global;
             map = |v| v.into_iter().map(|n| n.0).collect();
-            default = default_preferred.kex;
         );
         let alg = cascade!(alg => flags, scope, global;
             map = |v| v.into_iter().map(|n| n.0).collect();
-            default = default_preferred.key;
         );
         let cipher = cascade!(cipher => flags, scope, global;
             map = |v| v.into_iter().map(|n| n.0).collect();
-            default = default_preferred.cipher;
         );
         let mac = cascade!(mac => flags, scope, global;
             map = |v| v.into_iter().map(|n| n.0).collect();
-            default = default_preferred.mac;
         );
+        let profile: Option<SecurityProfile> = cascade!(profile => flags, scope, global);
         let timeout = cascade!(timeout => flags, scope, global;
             map = Duration::from_secs;
         );
@@ -94,13 +203,37 @@ impl ConnectionData {
         let retries = cascade!(retries => flags, scope, global;
             default = default_config.keepalive_max;
         );
+        let buffer_size = cascade!(buffer_size => flags, scope, global;
+            default = DEFAULT_BUFFER_SIZE;
+        );
+        // Nothing could be read into an empty buffer
+        if buffer_size == 0 {
+            return Err(ConnectionError::ZeroBufferSize);
+        }
+        let idle_timeout = cascade!(idle_timeout => flags, scope, global;
+            map = |mins| Duration::from_secs(mins * 60);
+        );
+        let terminal_title = cascade!(terminal_title => flags, scope, global; default = true);
+        let term = cascade!(term => flags, scope, global);
+        let log_sessions = cascade!(log_sessions => flags, scope, global;
+            map = get_full_path;
+        );
+        let dns_cache_ttl = cascade!(dns_cache_ttl => flags, scope, global;
+            default = DNS_CACHE_TTL;
+        );
+        let login_script = cascade!(login_script => flags, scope, global;
+            default = Vec::new();
+        );
 
-        let preferred = russh::Preferred {
-            kex,
-            key: alg,
-            cipher,
-            mac,
-            ..default_preferred
+        let preferred = match profile {
+            Some(profile) => profile.constrain(kex, alg, cipher, mac)?,
+            None => russh::Preferred {
+                kex: kex.map_or(default_preferred.kex, Into::into),
+                key: alg.map_or(default_preferred.key, Into::into),
+                cipher: cipher.map_or(default_preferred.cipher, Into::into),
+                mac: mac.map_or(default_preferred.mac, Into::into),
+                ..default_preferred
+            },
         };
         let config = russh::client::Config {
             preferred,
@@ -112,13 +245,307 @@ impl ConnectionData {
 
         Ok(Self {
             address,
+            fallback: fallback.unwrap_or_default(),
             user,
             port,
             remote_cmd,
             private_key,
             openssh_cert,
             known_hosts,
+            extra_known_hosts,
+            host_key_check,
+            pinned_keys,
+            visual_host_key,
+            known_hosts_read_only,
+            password,
+            password_auth,
+            password_attempts,
+            preferred_auth,
+            sudo,
+            shared_sudo_password,
+            auth_log,
+            event_log,
+            hooks,
+            buffer_size,
+            idle_timeout,
+            terminal_title,
+            term,
+            log_sessions,
+            login_script,
+            socket,
+            dns_cache: false,
+            dns_cache_ttl,
+            replace_host_key: false,
             config,
         })
     }
+
+    /// `user@address:port` the connection is made to
+    pub fn target(&self) -> String {
+        format!("{}@{}:{}", self.user, self.address, self.port)
+    }
+
+    /// Host key verifier selected by `host_key_check`, takes the files and fingerprints
+    pub fn verifier(&mut self, prompts: Arc<dyn PromptProvider>) -> Box<dyn HostKeyVerifier> {
+        match self.host_key_check {
+            HostKeyCheck::KnownHosts => Box::new(KnownHostsVerifier {
+                known_hosts: mem::take(&mut self.known_hosts),
+                extra_known_hosts: mem::take(&mut self.extra_known_hosts),
+                prompts,
+                visual_host_key: self.visual_host_key,
+                read_only: self.known_hosts_read_only,
+                replace_changed: self.replace_host_key,
+            }),
+            HostKeyCheck::Pinned => Box::new(PinnedVerifier(mem::take(&mut self.pinned_keys))),
+            HostKeyCheck::AcceptAll => Box::new(AcceptAllVerifier),
+        }
+    }
+}
+
+/// Finds the first layer that has the field and describes its value.
+/// Syntax: field => (source, layer), ...
+macro_rules! trace {
+    ($field:ident => $(($src:expr, $layer:expr)),+ $(,)?) => {
+        None$(.or_else(|| $layer.$field.as_ref().map(|v| ($src, v.describe()))))+
+    };
+}
+
+impl ConnectionData {
+    /// Describes every resolved parameter along with the layer it comes from,
+    /// following the same precedence as [`ConnectionData::new`]
+    pub fn trace(
+        uri: &ServerUri,
+        flags: &Scope,
+        server: &Scope,
+        scope: &Scope,
+        global: &Scope,
+    ) -> Vec<TracedField> {
+        let mut fields = Vec::new();
+        let mut push = |name, traced: Option<(Source, String)>, default: Option<String>| {
+            if let Some((source, value)) = traced {
+                fields.push((name, value, source));
+            } else if let Some(value) = default {
+                fields.push((name, value, Source::BuiltIn));
+            }
+        };
+        let default_config = russh::client::Config::default();
+        // A profile replaces the built-in algorithm lists
+        let profile = [flags, server, scope, global]
+            .iter()
+            .find_map(|layer| layer.profile);
+        let default_preferred = match profile {
+            Some(profile) => profile
+                .constrain(None, None, None, None)
+                .unwrap_or_default(),
+            None => russh::Preferred::default(),
+        };
+
+        macro_rules! trace_layers {
+            ($field:ident $(, $uri:ident)?; default = $default:expr) => {
+                push(
+                    stringify!($field),
+                    trace!($field =>
+                        $((Source::Flag, $uri),)?
+                        (Source::Flag, flags),
+                        (Source::Server, server),
+                        (Source::Scope, scope),
+                        (Source::Default, global),
+                    ),
+                    $default,
+                )
+            };
+        }
+
+        // Settings grouped under one key are traced field by field, as they're merged
+        macro_rules! trace_nested {
+            ($group:ident . $field:ident; default = $default:expr) => {
+                push(
+                    concat!(stringify!($group), ".", stringify!($field)),
+                    [
+                        (Source::Flag, flags),
+                        (Source::Server, server),
+                        (Source::Scope, scope),
+                        (Source::Default, global),
+                    ]
+                    .into_iter()
+                    .find_map(|(source, layer)| {
+                        let value = layer.$group.as_ref()?.$field.as_ref()?;
+                        Some((source, value.describe()))
+                    }),
+                    $default,
+                )
+            };
+        }
+
+        let default_known_hosts = WORK_DIR.join(DEFAULT_KNOWN_HOSTS_FILE);
+
+        trace_layers!(user, uri; default = env::var("USER").ok());
+        trace_layers!(port, uri; default = Some(DEFAULT_SSH_PORT.describe()));
+        trace_layers!(known_hosts; default = Some(default_known_hosts.describe()));
+        trace_layers!(extra_known_hosts; default = Some(default_openssh_known_hosts().describe()));
+        trace_layers!(private_key; default = None);
+        trace_layers!(openssh_cert; default = None);
+        trace_layers!(host_key_check; default = Some(HostKeyCheck::default().describe()));
+        trace_layers!(pinned_keys; default = None);
+        trace_layers!(visual_host_key; default = Some(false.describe()));
+        trace_layers!(known_hosts_read_only; default = Some(false.describe()));
+        trace_layers!(password; default = None);
+        trace_layers!(password_auth; default = Some(true.describe()));
+        trace_layers!(password_attempts; default = Some(DEFAULT_PASSWORD_ATTEMPTS.describe()));
+        trace_layers!(preferred_auth; default = None);
+        trace_layers!(sudo; default = Some(false.describe()));
+        trace_layers!(shared_sudo_password; default = Some(false.describe()));
+        trace_layers!(auth_log; default = Some(false.describe()));
+        trace_layers!(event_log; default = None);
+        trace_layers!(kex; default = Some(describe_names(&default_preferred.kex)));
+        trace_layers!(alg; default = Some(describe_names(&default_preferred.key)));
+        trace_layers!(cipher; default = Some(describe_names(&default_preferred.cipher)));
+        trace_layers!(mac; default = Some(describe_names(&default_preferred.mac)));
+        trace_layers!(profile; default = None);
+        trace_layers!(timeout; default = None);
+        trace_layers!(interval; default = None);
+        trace_layers!(retries; default = Some(default_config.keepalive_max.describe()));
+        trace_layers!(idle_timeout; default = None);
+        trace_layers!(buffer_size; default = Some(DEFAULT_BUFFER_SIZE.describe()));
+        trace_layers!(terminal_title; default = Some(true.describe()));
+        trace_layers!(term; default = env::var("TERM").ok());
+        trace_layers!(log_sessions; default = None);
+        trace_layers!(login_script; default = None);
+        trace_layers!(dns_cache_ttl; default = Some(DNS_CACHE_TTL.describe()));
+        trace_nested!(hooks.pre_connect; default = None);
+        trace_nested!(hooks.post_disconnect; default = None);
+        trace_nested!(socket.nodelay; default = Some(true.describe()));
+        trace_nested!(socket.keepalive; default = Some(false.describe()));
+        trace_nested!(socket.send_buffer; default = None);
+        trace_nested!(socket.recv_buffer; default = None);
+
+        fields
+    }
+}
+
+/// The user's OpenSSH known_hosts, consulted read-only
+#[inline]
+fn default_openssh_known_hosts() -> PathBuf {
+    get_full_path(PathBuf::from(OPENSSH_KNOWN_HOSTS_FILE))
+}
+
+#[inline]
+fn describe_names<T: AsRef<str>>(names: &[T]) -> String {
+    names.iter().map(AsRef::as_ref).join(", ")
+}
+
+/// Human-readable representation of a configuration value
+trait Describe {
+    fn describe(&self) -> String;
+}
+
+macro_rules! describe_as_display {
+    ($($ty:ty),+ $(,)?) => {
+        $(impl Describe for $ty {
+            fn describe(&self) -> String {
+                self.to_string()
+            }
+        })+
+    };
+}
+
+describe_as_display!(
+    String,
+    bool,
+    u8,
+    u16,
+    u32,
+    u64,
+    usize,
+    SecretRef,
+    KexName,
+    AlgoName,
+    CipherName,
+    MacName,
+    HostKeyCheck,
+    AuthMethod,
+    EventLog,
+    SecurityProfile,
+);
+
+impl Describe for PathBuf {
+    fn describe(&self) -> String {
+        self.display().to_string()
+    }
+}
+
+impl<T: Describe> Describe for Vec<T> {
+    fn describe(&self) -> String {
+        self.iter().map(Describe::describe).join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_respects_precedence() {
+        let uri = ServerUri {
+            address: "host".into(),
+            user: None,
+            port: Some(2222),
+            options: Box::default(),
+            query: None,
+        };
+        let flags = Scope::default();
+        let server = Scope {
+            user: Some("server".into()),
+            ..Default::default()
+        };
+        let scope = Scope {
+            user: Some("scope".into()),
+            timeout: Some(5),
+            ..Default::default()
+        };
+        let global = Scope {
+            timeout: Some(10),
+            hooks: Some(Hooks {
+                pre_connect: Some("vpn up".into()),
+                post_disconnect: Some("vpn down".into()),
+            }),
+            socket: Some(SocketOptions {
+                keepalive: Some(true),
+                recv_buffer: Some(65536),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let server = Scope {
+            hooks: Some(Hooks {
+                post_disconnect: Some("notify".into()),
+                ..Default::default()
+            }),
+            socket: Some(SocketOptions {
+                keepalive: Some(false),
+                ..Default::default()
+            }),
+            ..server
+        };
+        let fields = ConnectionData::trace(&uri, &flags, &server, &scope, &global);
+        let find = |name| {
+            fields
+                .iter()
+                .find(|(n, _, _)| *n == name)
+                .map(|(_, v, s)| (v.as_str(), *s))
+        };
+        assert_eq!(find("user"), Some(("server", Source::Server)));
+        assert_eq!(find("port"), Some(("2222", Source::Flag)));
+        assert_eq!(find("timeout"), Some(("5", Source::Scope)));
+        assert_eq!(find("retries").map(|(_, s)| s), Some(Source::BuiltIn));
+        assert_eq!(find("private_key"), None);
+        assert_eq!(find("hooks.pre_connect"), Some(("vpn up", Source::Default)));
+        assert_eq!(
+            find("hooks.post_disconnect"),
+            Some(("notify", Source::Server))
+        );
+        assert_eq!(find("socket.keepalive"), Some(("false", Source::Server)));
+        assert_eq!(find("socket.recv_buffer"), Some(("65536", Source::Default)));
+        assert_eq!(find("socket.nodelay"), Some(("true", Source::BuiltIn)));
+    }
 }
@@ -0,0 +1,577 @@
+use anyhow::Result;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use crate::cli::parser::ServerUri;
+use crate::client::connect::Session;
+use crate::client::hooks::Visit;
+use crate::client::prompt::PromptProvider;
+use crate::error::CliError;
+use crate::storage::provider::WORK_DIR;
+
+/// Directory in the work dir with the control sockets and logs of running forwards
+const FORWARDS_DIRNAME: &str = "forwards";
+const DEFAULT_BIND: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+/// How long a detached forward may take to connect before it's given up on
+const DETACH_TIMEOUT: Duration = Duration::from_secs(30);
+const DETACH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the session is checked, and how long the server may take to answer
+const HEALTH_INTERVAL: Duration = Duration::from_secs(15);
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delays between attempts to re-establish a dropped forward, doubled after each failure
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// A local port forwarded to a host as seen from the server (`[BIND:]PORT:HOST:HOSTPORT`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForwardSpec {
+    /// Local address to listen on (loopback by default)
+    pub bind: IpAddr,
+    pub port: u16,
+    pub host: String,
+    pub host_port: u16,
+}
+
+/// What a running forward reports about itself
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ForwardStatus {
+    pub name: String,
+    /// Server the connections go through
+    pub server: String,
+    pub local: SocketAddr,
+    /// `host:port` as seen from the server
+    pub remote: String,
+    pub pid: u32,
+    /// Unix time the forward was started
+    pub since: u64,
+    pub state: ForwardState,
+    /// How many times the forward was re-established
+    pub restarts: u32,
+    /// Why the forward went down last
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardState {
+    Up,
+    /// The session dropped and is being re-established
+    Reconnecting,
+}
+
+impl ForwardSpec {
+    pub fn local(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    /// Forwards are named after the server and the local port (`db-5432`)
+    pub fn name(&self, server: &ServerUri) -> String {
+        format!("{}-{}", server.address.replace(['/', ':'], "_"), self.port)
+    }
+}
+
+/// Socket the forward is controlled through
+pub fn socket_path(name: &str) -> PathBuf {
+    WORK_DIR
+        .join(FORWARDS_DIRNAME)
+        .join(format!("{}.sock", name))
+}
+
+/// Where a detached forward writes its messages
+pub fn log_path(name: &str) -> PathBuf {
+    WORK_DIR
+        .join(FORWARDS_DIRNAME)
+        .join(format!("{}.log", name))
+}
+
+/// What a forward goes through, a session with its hooks in practice
+pub trait Tunnel {
+    fn forward_connection(&self, stream: TcpStream, peer: SocketAddr, host: &str, port: u16);
+    fn ping(&self, timeout: Duration) -> impl Future<Output = Result<()>>;
+    /// Whatever fails while closing is only reported
+    fn close(self) -> impl Future<Output = ()>;
+}
+
+/// Session of a forward with the hooks run around it
+pub struct Link {
+    pub session: Session,
+    pub visit: Visit,
+}
+
+impl Tunnel for Link {
+    fn forward_connection(&self, stream: TcpStream, peer: SocketAddr, host: &str, port: u16) {
+        self.session.forward_connection(stream, peer, host, port)
+    }
+
+    fn ping(&self, timeout: Duration) -> impl Future<Output = Result<()>> {
+        self.session.ping(timeout)
+    }
+
+    async fn close(self) {
+        // The session may be gone already
+        let closed = self.session.close().await;
+        if let Err(e) = self.visit.end(&closed, None) {
+            warn!("{}", e);
+        }
+    }
+}
+
+/// Forwards until stopped through the control socket. The tunnel is checked
+/// periodically, when it drops it's re-established with `connect` (with backoff),
+/// while the local port stays bound.
+pub async fn serve<T, F, Fut>(
+    tunnel: T,
+    connect: F,
+    spec: &ForwardSpec,
+    status: ForwardStatus,
+) -> Result<()>
+where
+    T: Tunnel,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let path = socket_path(&status.name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Left behind by a forward that was killed
+    _ = fs::remove_file(&path);
+    let control_listener = UnixListener::bind(&path)?;
+    let listener = TcpListener::bind(spec.local()).await?;
+    let status = Mutex::new(status);
+
+    let stop = control(control_listener, &status);
+    let result = keep_forwarding(tunnel, connect, spec, listener, stop, &status).await;
+    _ = fs::remove_file(&path);
+
+    result
+}
+
+/// Returns once `stop` completes (with its result) or the listener fails,
+/// the tunnel in use is closed either way
+async fn keep_forwarding<T, F, Fut>(
+    mut tunnel: T,
+    connect: F,
+    spec: &ForwardSpec,
+    listener: TcpListener,
+    stop: impl Future<Output = Result<()>>,
+    status: &Mutex<ForwardStatus>,
+) -> Result<()>
+where
+    T: Tunnel,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    tokio::pin!(stop);
+    let name = status.lock().expect("not poisoned").name.clone();
+    loop {
+        let failure = tokio::select! {
+            failed = accept(&tunnel, spec, &listener) => failed,
+            failed = monitor(&tunnel) => failed,
+            stopped = &mut stop => {
+                tunnel.close().await;
+                return stopped;
+            }
+        };
+        tunnel.close().await;
+        if let Some(io) = failure.downcast_ref::<io::Error>() {
+            // The listener itself is broken, nothing to re-establish
+            return Err(anyhow::anyhow!(
+                "Listening on {} failed: {}",
+                spec.local(),
+                io
+            ));
+        }
+        warn!("Forward '{}' is down: {}", name, failure);
+        {
+            let mut status = status.lock().expect("not poisoned");
+            status.state = ForwardState::Reconnecting;
+            status.last_error = Some(failure.to_string());
+        }
+
+        let mut delay = BACKOFF_MIN;
+        tunnel = loop {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                stopped = &mut stop => return stopped,
+            }
+            match connect().await {
+                Ok(tunnel) => break tunnel,
+                Err(e) => {
+                    delay = (delay * 2).min(BACKOFF_MAX);
+                    warn!(
+                        "Failed to re-establish forward '{}': {} (next attempt in {}s)",
+                        name,
+                        e,
+                        delay.as_secs()
+                    );
+                }
+            }
+        };
+        let mut status = status.lock().expect("not poisoned");
+        status.state = ForwardState::Up;
+        status.restarts += 1;
+        info!(
+            "Forward '{}' is up again (restart {})",
+            name, status.restarts
+        );
+    }
+}
+
+/// Hands accepted connections to the tunnel, returns why accepting failed
+async fn accept(tunnel: &impl Tunnel, spec: &ForwardSpec, listener: &TcpListener) -> anyhow::Error {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tunnel.forward_connection(stream, peer, &spec.host, spec.host_port)
+            }
+            Err(e) => return e.into(),
+        }
+    }
+}
+
+/// Pings the server periodically, returns once it stops answering
+async fn monitor(tunnel: &impl Tunnel) -> anyhow::Error {
+    loop {
+        tokio::time::sleep(HEALTH_INTERVAL).await;
+        if let Err(e) = tunnel.ping(HEALTH_TIMEOUT).await {
+            return e;
+        }
+        debug!("Session is alive");
+    }
+}
+
+/// Answers requests of `forward ls` and `forward stop`, returns once asked to stop
+async fn control(listener: UnixListener, status: &Mutex<ForwardStatus>) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (reader, mut writer) = stream.into_split();
+        let mut request = String::new();
+        BufReader::new(reader).read_line(&mut request).await?;
+
+        match request.trim() {
+            "status" => {
+                let reply = serde_json::to_string(&*status.lock().expect("not poisoned"))?;
+                writer.write_all(format!("{}\n", reply).as_bytes()).await?;
+            }
+            "stop" => {
+                info!(
+                    "Forward '{}' asked to stop",
+                    status.lock().expect("not poisoned").name
+                );
+                writer.write_all(b"ok\n").await?;
+                return Ok(());
+            }
+            other => warn!("Unknown forward request '{}'", other),
+        }
+    }
+}
+
+/// Sends the request to the forward and returns the reply,
+/// `None` if it isn't running (its stale socket is removed)
+pub async fn request(name: &str, request: &str) -> Result<Option<String>> {
+    let path = socket_path(name);
+    let stream = match UnixStream::connect(&path).await {
+        Ok(stream) => stream,
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            debug!("Removing stale socket of forward '{}'", name);
+            _ = fs::remove_file(&path);
+            return Ok(None);
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let (reader, mut writer) = stream.into_split();
+    writer
+        .write_all(format!("{}\n", request).as_bytes())
+        .await?;
+    let mut reply = String::new();
+    BufReader::new(reader).read_line(&mut reply).await?;
+
+    Ok(Some(reply.trim_end().to_string()))
+}
+
+/// Statuses of the running forwards, by name
+pub async fn list() -> Result<Vec<ForwardStatus>> {
+    let Ok(entries) = fs::read_dir(WORK_DIR.join(FORWARDS_DIRNAME)) else {
+        return Ok(Vec::new());
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.strip_suffix(".sock").map(str::to_string)
+        })
+        .collect();
+    names.sort_unstable();
+
+    let mut running = Vec::with_capacity(names.len());
+    for name in names {
+        if let Some(reply) = request(&name, "status").await? {
+            running.push(serde_json::from_str(&reply)?);
+        }
+    }
+
+    Ok(running)
+}
+
+/// Starts this program again as a background process serving the forward,
+/// and waits until it's connected. It's in its own process group,
+/// so it isn't hung up on when the terminal closes.
+pub async fn spawn_detached(
+    server: &ServerUri,
+    spec: &ForwardSpec,
+    name: &str,
+    scope: &str,
+) -> Result<()> {
+    let log = log_path(name);
+    if let Some(parent) = log.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        // Verbose, so that the log shows when the forward goes down and comes back
+        .args(["-v", "forward", "start", "--daemon"])
+        .arg(format!("--in-scope={}", scope))
+        .arg(server.to_string())
+        .arg(spec.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(File::create(&log)?);
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut child = command.spawn()?;
+    info!("Started background process {} for '{}'", child.id(), name);
+
+    let started = std::time::Instant::now();
+    while started.elapsed() < DETACH_TIMEOUT {
+        if child.try_wait()?.is_some() {
+            return Err(
+                CliError::ForwardFailed(name.into(), log.display().to_string().into()).into(),
+            );
+        }
+        if request(name, "status").await?.is_some() {
+            return Ok(());
+        }
+        tokio::time::sleep(DETACH_POLL_INTERVAL).await;
+    }
+    _ = child.kill();
+
+    Err(CliError::ForwardFailed(name.into(), log.display().to_string().into()).into())
+}
+
+/// Nobody can answer questions in the background, so authentication has to work
+/// without them (agent, unencrypted key, password from the keyring, known server)
+pub struct DetachedPrompts;
+
+impl PromptProvider for DetachedPrompts {
+    fn text(&self, prompt: &str) -> io::Result<String> {
+        Err(io::Error::other(format!(
+            "can't ask '{}' in the background",
+            prompt.trim()
+        )))
+    }
+
+    fn secret(&self, prompt: &str) -> io::Result<secrecy::SecretString> {
+        Err(io::Error::other(format!(
+            "can't ask '{}' in the background",
+            prompt.trim()
+        )))
+    }
+
+    fn message(&self, text: &str) {
+        info!("{}", text);
+    }
+}
+
+impl FromStr for ForwardSpec {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || CliError::InvalidForward(s.into());
+        let mut parts = s.rsplitn(4, ':');
+        let host_port = parts.next().and_then(|p| p.parse().ok());
+        let host = parts.next().filter(|h| !h.is_empty());
+        let port = parts.next().and_then(|p| p.parse().ok());
+        let bind = match parts.next() {
+            None => Some(DEFAULT_BIND),
+            Some(bind) => bind
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .ok(),
+        };
+
+        match (bind, port, host, host_port) {
+            (Some(bind), Some(port), Some(host), Some(host_port)) => Ok(Self {
+                bind,
+                port,
+                host: host.to_string(),
+                host_port,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Display for ForwardState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Up => "up",
+            Self::Reconnecting => "reconnecting",
+        })
+    }
+}
+
+impl Display for ForwardSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.bind {
+            IpAddr::V6(bind) => write!(f, "[{}]", bind)?,
+            IpAddr::V4(bind) => write!(f, "{}", bind)?,
+        }
+        write!(f, ":{}:{}:{}", self.port, self.host, self.host_port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    #[test]
+    fn parse_spec() {
+        let spec: ForwardSpec = "5432:db.internal:5432".parse().unwrap();
+        assert_eq!(spec.local(), "127.0.0.1:5432".parse().unwrap());
+        assert_eq!(spec.host, "db.internal");
+        assert_eq!(spec.to_string(), "127.0.0.1:5432:db.internal:5432");
+
+        let spec: ForwardSpec = "[::1]:8080:localhost:80".parse().unwrap();
+        assert_eq!(spec.local(), "[::1]:8080".parse().unwrap());
+        assert_eq!(spec.to_string().parse::<ForwardSpec>().unwrap(), spec);
+
+        assert!("8080:localhost".parse::<ForwardSpec>().is_err());
+        assert!("0.0.0.0:x:localhost:80".parse::<ForwardSpec>().is_err());
+    }
+
+    /// Answers pings until it's taken down, records when it's closed
+    struct FakeTunnel {
+        id: u32,
+        healthy: bool,
+        closed: Rc<RefCell<Vec<u32>>>,
+    }
+
+    impl Tunnel for FakeTunnel {
+        fn forward_connection(&self, _: TcpStream, _: SocketAddr, _: &str, _: u16) {}
+
+        fn ping(&self, _: Duration) -> impl Future<Output = Result<()>> {
+            let healthy = self.healthy;
+            async move {
+                match healthy {
+                    true => Ok(()),
+                    false => Err(anyhow::anyhow!("no reply")),
+                }
+            }
+        }
+
+        async fn close(self) {
+            self.closed.borrow_mut().push(self.id);
+        }
+    }
+
+    /// Runs the loop on a paused clock until `stop_after`, the first tunnel
+    /// is down and the next ones come up from the given attempt on
+    fn run_forward(up_from_attempt: u32, stop_after: Duration) -> (Vec<u32>, u32, ForwardStatus) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        let closed = Rc::new(RefCell::new(Vec::new()));
+        let attempts = Cell::new(0);
+        let status = Mutex::new(ForwardStatus {
+            name: "db-5432".into(),
+            server: "db".into(),
+            local: "127.0.0.1:0".parse().unwrap(),
+            remote: "localhost:5432".into(),
+            pid: 0,
+            since: 0,
+            state: ForwardState::Up,
+            restarts: 0,
+            last_error: None,
+        });
+        let spec: ForwardSpec = "0:localhost:5432".parse().unwrap();
+
+        let result = rt.block_on(async {
+            let listener = TcpListener::bind(spec.local()).await.unwrap();
+            let first = FakeTunnel {
+                id: 0,
+                healthy: false,
+                closed: Rc::clone(&closed),
+            };
+            let connect = || {
+                attempts.set(attempts.get() + 1);
+                let attempt = attempts.get();
+                let closed = Rc::clone(&closed);
+                async move {
+                    if attempt < up_from_attempt {
+                        return Err(anyhow::anyhow!("unreachable"));
+                    }
+                    Ok(FakeTunnel {
+                        id: attempt,
+                        healthy: true,
+                        closed,
+                    })
+                }
+            };
+            let stop = async {
+                tokio::time::sleep(stop_after).await;
+                Ok(())
+            };
+            keep_forwarding(first, connect, &spec, listener, stop, &status).await
+        });
+        assert!(result.is_ok());
+
+        let closed = closed.borrow().clone();
+        (closed, attempts.get(), status.into_inner().unwrap())
+    }
+
+    #[test]
+    fn restart_with_backoff() {
+        let started = Instant::now();
+        // Down at the first check (15s), attempts after 1s, 2s and 4s more
+        let (closed, attempts, status) = run_forward(3, Duration::from_secs(60));
+
+        assert_eq!(attempts, 3);
+        // The dropped tunnel and the one in use when stopped
+        assert_eq!(closed, [0, 3]);
+        assert_eq!(status.state, ForwardState::Up);
+        assert_eq!(status.restarts, 1);
+        assert_eq!(status.last_error.as_deref(), Some("no reply"));
+        // The clock was paused, nothing actually waited
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn stop_while_reconnecting() {
+        let (closed, attempts, status) = run_forward(u32::MAX, Duration::from_secs(29));
+
+        // 16s, 18s and 22s, the next one would be at 30s
+        assert_eq!(attempts, 3);
+        assert_eq!(closed, [0]);
+        assert_eq!(status.state, ForwardState::Reconnecting);
+        assert_eq!(status.restarts, 0);
+    }
+}
@@ -1,96 +1,68 @@
-use crate::error::FileError;
-use log::info;
-use russh::client::Handler;
-use russh::keys::{HashAlg, PublicKey, PublicKeyBase64};
-use std::io::Write;
-use std::net::IpAddr;
-use std::path::PathBuf;
-use tokio::fs;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use crate::client::verify::{HostInfo, HostKeyVerifier};
+use crate::storage::audit::weak_algorithm;
+use itertools::Itertools;
+use log::{debug, warn};
+use russh::client::{Handler, Session};
+use russh::keys::{HashAlg, PublicKey};
+use russh::{Names, cipher, mac};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+
+/// Ciphers that authenticate the data themselves, the negotiated MAC goes unused
+const AEAD_CIPHERS: [cipher::Name; 3] = [
+    cipher::CHACHA20_POLY1305,
+    cipher::AES_256_GCM,
+    cipher::AES_128_GCM,
+];
 
-#[derive(Debug)]
 pub struct ClientHandler {
-    server_ip: IpAddr,
-    known_hosts: PathBuf,
+    host: HostInfo,
+    verifier: Box<dyn HostKeyVerifier>,
+    /// Fingerprint of the key presented by the server, readable after the handshake
+    server_key: Arc<OnceLock<String>>,
+    /// What the first key exchange settled on, readable after the handshake
+    negotiated: Arc<OnceLock<Negotiated>>,
+    /// Legacy algorithms are reported once, not on every rekey
+    legacy_reported: bool,
 }
 
 impl ClientHandler {
-    pub fn new(server_ip: IpAddr, known_hosts: PathBuf) -> Self {
+    pub fn new(address: &str, socket: SocketAddr, verifier: Box<dyn HostKeyVerifier>) -> Self {
         Self {
-            server_ip,
-            known_hosts,
+            host: HostInfo {
+                address: address.to_string(),
+                ip: socket.ip(),
+                port: socket.port(),
+            },
+            verifier,
+            server_key: Arc::default(),
+            negotiated: Arc::default(),
+            legacy_reported: false,
         }
     }
 
-    async fn handle_unknown_host(&self, key: &PublicKey) -> anyhow::Result<bool> {
-        let fingerprint = key.fingerprint(HashAlg::default());
-
-        print!(
-            "*Alright, here is the door: {}*\n\
-            - Knock, knock!\n\
-            - \"Greetings! I am {} {}, and you?\"\n\
-            *Hmm, I don't recognize this one...*\n\n\
-            Trust and add to 'known_hosts'? (yes/no/[fingerprint]): ",
-            self.server_ip,
-            key.algorithm(),
-            fingerprint,
-        );
-        std::io::stdout().flush()?;
-
-        let mut input = String::new();
-        std::io::stdin()
-            .read_line(&mut input)
-            .map_err(FileError::Std)?;
-        let input = input.trim();
-
-        if input.eq_ignore_ascii_case("y")
-            || input.eq_ignore_ascii_case("yes")
-            || input.as_bytes() == fingerprint.as_bytes()
-        {
-            self.trust_host(key).await?;
-            return Ok(true);
-        }
-
-        Ok(false)
+    /// Shared with the handler, which is owned by the session once connected
+    pub fn server_key(&self) -> Arc<OnceLock<String>> {
+        Arc::clone(&self.server_key)
     }
 
-    async fn handle_key_changed(&self, key: &PublicKey) -> anyhow::Result<bool> {
-        eprintln!(
-            "*Ah, home sweet home: {}*\n\
-            - Knock, knock!\n\
-            - \"Greetings! I am {} {}, and you?\"\n\
-            *Wait a minute. You are not the guy who usually lives here.*\n\
-            *Did he move out? ...or are you trying to pretend to be him? (Man-in-the-Middle)*\n\
-            *I better get out of here fast!*\n\n\
-            We should probably forget our old key and remove it from 'known_hosts'.\n\
-            Or, if this is a trap... we should report this incident!",
-            self.server_ip,
-            key.algorithm(),
-            key.fingerprint(HashAlg::default())
-        );
-
-        Ok(false)
+    /// Shared the same way as [`ClientHandler::server_key`]
+    pub fn negotiated(&self) -> Arc<OnceLock<Negotiated>> {
+        Arc::clone(&self.negotiated)
     }
+}
 
-    async fn trust_host(&self, key: &PublicKey) -> anyhow::Result<()> {
-        let entry = format!(
-            "{} {} {}\n",
-            self.server_ip,
-            key.algorithm(),
-            key.public_key_base64()
-        );
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.known_hosts)
-            .await
-            .map_err(FileError::from)?;
-        file.write_all(entry.as_bytes())
-            .await
-            .map_err(FileError::from)?;
-
-        Ok(())
-    }
+/// Server software and the algorithms agreed on with it
+#[derive(Debug)]
+pub struct Negotiated {
+    /// Identification string of the server (`SSH-2.0-OpenSSH_9.6`)
+    pub server_version: String,
+    pub kex: String,
+    pub host_key: String,
+    pub cipher: String,
+    /// `None` for AEAD ciphers, which need no separate MAC
+    pub mac: Option<String>,
 }
 
 impl Handler for ClientHandler {
@@ -100,55 +72,89 @@ impl Handler for ClientHandler {
         &mut self,
         server_public_key: &PublicKey,
     ) -> anyhow::Result<bool, Self::Error> {
-        info!(
-            "Checking server public key in '{}'...",
-            self.known_hosts.display()
+        let fingerprint = server_public_key.fingerprint(HashAlg::default());
+        debug!(
+            "Server public key: {} {}",
+            server_public_key.algorithm().as_str(),
+            fingerprint
         );
+        _ = self.server_key.set(fingerprint.to_string());
 
-        if !self.known_hosts.exists() {
-            if let Some(parent) = self.known_hosts.parent() {
-                fs::create_dir_all(parent).await.map_err(FileError::from)?;
-            }
-            fs::write(&self.known_hosts, "")
-                .await
-                .map_err(FileError::from)?;
-        }
-        let (server_ip, key_alg, key_b64) = (
-            self.server_ip.to_string(),
-            server_public_key.algorithm(),
-            server_public_key.public_key_base64(),
-        );
-        let key_alg = key_alg.as_str();
+        self.verifier.verify(&self.host, server_public_key).await
+    }
 
-        let file = fs::File::open(&self.known_hosts)
-            .await
-            .map_err(FileError::from)?;
-        let reader = BufReader::new(file);
-        let mut lines = reader.lines();
+    async fn kex_done(
+        &mut self,
+        _shared_secret: Option<&[u8]>,
+        names: &Names,
+        session: &mut Session,
+    ) -> anyhow::Result<(), Self::Error> {
+        debug!(
+            "Server version: {}",
+            String::from_utf8_lossy(session.remote_sshid()).trim()
+        );
+        debug!(
+            "Negotiated kex {}, host key {}, cipher {}, MAC {} (client) / {} (server)",
+            names.kex.as_ref(),
+            names.key,
+            names.cipher.as_ref(),
+            names.client_mac.as_ref(),
+            names.server_mac.as_ref(),
+        );
+        _ = self.negotiated.set(Negotiated {
+            server_version: String::from_utf8_lossy(session.remote_sshid())
+                .trim()
+                .to_string(),
+            kex: names.kex.as_ref().to_string(),
+            host_key: names.key.to_string(),
+            cipher: names.cipher.as_ref().to_string(),
+            mac: (!AEAD_CIPHERS.contains(&names.cipher) && names.client_mac != mac::NONE)
+                .then(|| names.client_mac.as_ref().to_string()),
+        });
+        if !self.legacy_reported {
+            self.legacy_reported = true;
+            report_legacy(&self.host, names);
+        }
 
-        let mut key_changed = false;
-        while let Some(line) = lines.next_line().await? {
-            let line = line.trim_start();
-            if line.starts_with('#') || line.is_empty() {
-                continue;
-            }
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 3 && parts[0] == server_ip {
-                info!("Found existing host key for '{}'", server_ip);
+        Ok(())
+    }
+}
 
-                key_changed = true;
-                if parts[1] == key_alg && parts[2] == key_b64 {
-                    info!("Server public key matches known host entry");
-                    return Ok(true);
-                }
-                break;
-            }
+impl Display for Negotiated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "server {}, kex {}, host key {}, cipher {}",
+            self.server_version, self.kex, self.host_key, self.cipher
+        )?;
+        if let Some(mac) = &self.mac {
+            write!(f, ", MAC {}", mac)?;
         }
 
-        if key_changed {
-            self.handle_key_changed(server_public_key).await
-        } else {
-            self.handle_unknown_host(server_public_key).await
-        }
+        Ok(())
+    }
+}
+
+/// One line naming the negotiated algorithms that are considered weak
+fn report_legacy(host: &HostInfo, names: &Names) {
+    // AEAD ciphers negotiate no MAC
+    let macs = [&names.client_mac, &names.server_mac]
+        .into_iter()
+        .filter(|name| **name != mac::NONE);
+    let legacy = [
+        names.kex.as_ref(),
+        names.key.as_str(),
+        names.cipher.as_ref(),
+    ]
+    .into_iter()
+    .chain(macs.map(|name| name.as_ref()))
+    .filter(|name| weak_algorithm(name).is_some())
+    .unique()
+    .join(", ");
+    if !legacy.is_empty() {
+        warn!(
+            "Legacy algorithms negotiated with {}: {}",
+            host.address, legacy
+        );
     }
 }
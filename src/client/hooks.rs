@@ -0,0 +1,201 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_with_macros::skip_serializing_none;
+use std::fmt::Display;
+use std::io;
+use std::mem;
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::client::data::ConnectionData;
+use crate::error::CliError;
+use crate::storage::context::{Context, HistoryEntry};
+use crate::storage::provider::StorageProvider;
+
+/// Local commands run around a connection (by `sh -c`, their output goes to stderr).
+/// They get the target in `SHH_SERVER`, `SHH_ADDRESS`, `SHH_USER` and `SHH_PORT`.
+#[skip_serializing_none]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Hooks {
+    /// Runs before connecting, the connection is cancelled if it fails
+    pub pre_connect: Option<String>,
+    /// Runs after the session ends (also gets `SHH_EXIT_STATUS` and `SHH_DURATION`)
+    pub post_disconnect: Option<String>,
+}
+
+/// Variables describing the target for the hooks
+pub type HookEnv = Vec<(&'static str, String)>;
+
+/// A connection with the hooks run around it, recorded in the history once over
+pub struct Visit {
+    host: String,
+    address: String,
+    user: String,
+    hooks: Hooks,
+    env: HookEnv,
+    started: SystemTime,
+}
+
+impl Visit {
+    /// Takes the hooks out of the data and runs `pre_connect`,
+    /// the connection is cancelled if it fails
+    pub fn begin(host: String, data: &mut ConnectionData) -> Result<Self, CliError> {
+        let env: HookEnv = vec![
+            ("SHH_SERVER", host.clone()),
+            ("SHH_ADDRESS", data.address.clone()),
+            ("SHH_USER", data.user.clone()),
+            ("SHH_PORT", data.port.to_string()),
+        ];
+        let hooks = mem::take(&mut data.hooks);
+        hooks.pre_connect(&env)?;
+
+        Ok(Self {
+            host,
+            address: data.address.clone(),
+            user: data.user.clone(),
+            hooks,
+            env,
+            started: SystemTime::now(),
+        })
+    }
+
+    /// Runs `post_disconnect` whatever the outcome, a connection that
+    /// was made is recorded with the exit status of the remote side
+    pub fn end<T>(
+        mut self,
+        outcome: &anyhow::Result<T>,
+        exit_status: Option<u32>,
+    ) -> anyhow::Result<()> {
+        let duration = self.started.elapsed().unwrap_or_default().as_secs();
+        self.env.push((
+            "SHH_EXIT_STATUS",
+            exit_status.map(|s| s.to_string()).unwrap_or_default(),
+        ));
+        self.env.push(("SHH_DURATION", duration.to_string()));
+        self.hooks.post_disconnect(&self.env);
+        if outcome.is_err() {
+            return Ok(());
+        }
+
+        // Reloaded to not overwrite changes made during the session
+        Context::load_from_file()?
+            .record(HistoryEntry {
+                host: self.host,
+                address: self.address,
+                user: self.user,
+                timestamp: self.started.duration_since(UNIX_EPOCH)?.as_secs(),
+                duration,
+                exit_status,
+            })
+            .save_to_file()?;
+
+        Ok(())
+    }
+}
+
+impl Hooks {
+    pub fn pre_connect(&self, env: &HookEnv) -> Result<(), CliError> {
+        match &self.pre_connect {
+            Some(cmd) => run("pre_connect", cmd, env),
+            None => Ok(()),
+        }
+    }
+
+    /// Fills the hooks not set here from the other ones
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            pre_connect: self.pre_connect.or(other.pre_connect),
+            post_disconnect: self.post_disconnect.or(other.post_disconnect),
+        }
+    }
+
+    /// Failures are only reported, the session is over anyway
+    pub fn post_disconnect(&self, env: &HookEnv) {
+        if let Some(cmd) = &self.post_disconnect
+            && let Err(e) = run("post_disconnect", cmd, env)
+        {
+            warn!("{}", e);
+        }
+    }
+}
+
+fn run(name: &'static str, cmd: &str, env: &HookEnv) -> Result<(), CliError> {
+    info!("Running {} hook '{}'...", name, cmd);
+
+    // stdout is kept for the output of the remote side
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdout(Stdio::from(io::stderr()))
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .status()
+        .map_err(|e| CliError::HookFailed(name, e.to_string().into()))?;
+    if !status.success() {
+        return Err(CliError::HookFailed(name, status.to_string().into()));
+    }
+
+    Ok(())
+}
+
+impl Display for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            pre_connect,
+            post_disconnect,
+        } = self;
+        let mut hooks = Vec::new();
+        if let Some(cmd) = pre_connect {
+            hooks.push(format!("pre_connect `{}`", cmd));
+        }
+        if let Some(cmd) = post_disconnect {
+            hooks.push(format!("post_disconnect `{}`", cmd));
+        }
+
+        write!(f, "{}", hooks.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pre_connect_sees_target() {
+        let env: HookEnv = vec![("SHH_USER", "alice".into()), ("SHH_PORT", "22".into())];
+        let hooks = |cmd: &str| Hooks {
+            pre_connect: Some(cmd.into()),
+            post_disconnect: None,
+        };
+
+        assert!(
+            hooks(r#"test "$SHH_USER@$SHH_PORT" = alice@22"#)
+                .pre_connect(&env)
+                .is_ok()
+        );
+        assert!(matches!(
+            hooks("exit 3").pre_connect(&env),
+            Err(CliError::HookFailed("pre_connect", _))
+        ));
+        assert!(Hooks::default().pre_connect(&env).is_ok());
+    }
+
+    #[test]
+    fn merge_hook_by_hook() {
+        let server = Hooks {
+            post_disconnect: Some("notify".into()),
+            ..Default::default()
+        };
+        let scope = Hooks {
+            pre_connect: Some("vpn up".into()),
+            post_disconnect: Some("vpn down".into()),
+        };
+
+        assert_eq!(
+            server.or(scope),
+            Hooks {
+                pre_connect: Some("vpn up".into()),
+                post_disconnect: Some("notify".into()),
+            }
+        );
+    }
+}
@@ -0,0 +1,510 @@
+use data_encoding::BASE64;
+use hmac::{Hmac, Mac};
+use russh::keys::{PublicKey, PublicKeyBase64};
+use sha1::Sha1;
+use std::fmt::Display;
+use std::io::{ErrorKind, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use crate::client::data::DEFAULT_KNOWN_HOSTS_FILE;
+use crate::error::FileError;
+use crate::storage::provider::{WORK_DIR, write_atomically};
+
+const DEFAULT_SSH_PORT: u16 = 22;
+const HASHED_PREFIX: &str = "|1|";
+/// Comment of the keys trusted by shh, followed by the time (`added:2024-05-01T12:00:00Z`)
+pub const ADDED_PREFIX: &str = "added:";
+
+/// Special kind of a known_hosts line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Marker {
+    CertAuthority,
+    Revoked,
+}
+
+/// A single line of an OpenSSH-compatible known_hosts file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// Line number (starting from 1)
+    pub line: usize,
+    pub marker: Option<Marker>,
+    /// Comma-separated host patterns, possibly hashed
+    pub hosts: String,
+    pub algorithm: String,
+    /// Base64 of the public key
+    pub key: String,
+    /// Rest of the line
+    pub comment: Option<String>,
+}
+
+/// Result of looking up a server key in known_hosts files
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// The key is recorded for the host
+    Trusted,
+    /// The key is explicitly revoked
+    Revoked { path: PathBuf, line: usize },
+    /// Another key of the same algorithm is recorded for the host
+    Changed { path: PathBuf, entry: Entry },
+    /// The host is not recorded at all
+    Unknown,
+}
+
+/// Parses every meaningful line, skipping comments and malformed entries
+pub fn parse(content: &str) -> Vec<Entry> {
+    content
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let mut parts = line.split_whitespace().peekable();
+            let marker = match *parts.peek()? {
+                "@cert-authority" => Some(Marker::CertAuthority),
+                "@revoked" => Some(Marker::Revoked),
+                m if m.starts_with('@') => return None,
+                _ => None,
+            };
+            if marker.is_some() {
+                parts.next();
+            }
+
+            Some(Entry {
+                line: i + 1,
+                marker,
+                hosts: parts.next()?.into(),
+                algorithm: parts.next()?.into(),
+                key: parts.next()?.into(),
+                comment: Some(parts.collect::<Vec<_>>().join(" ")).filter(|c| !c.is_empty()),
+            })
+        })
+        .collect()
+}
+
+/// Names the host may be recorded under: `host` for the default port, `[host]:port` otherwise.
+/// Older versions recorded the plain IP regardless of the port in their own file,
+/// so in that (`legacy`) file a plain IP also names servers on other ports.
+pub fn host_candidates(address: &str, ip: IpAddr, port: u16, legacy: bool) -> Vec<String> {
+    let ip = ip.to_string();
+    let mut candidates = vec![host_name(address, port), host_name(&ip, port)];
+    if legacy && port != DEFAULT_SSH_PORT {
+        candidates.push(ip);
+    }
+    candidates.dedup();
+
+    candidates
+}
+
+/// How the host is recorded: `host` for the default port, `[host]:port` otherwise
+pub fn host_name(host: &str, port: u16) -> String {
+    if port == DEFAULT_SSH_PORT {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+/// Whether it's the file of the work directory, which older versions wrote to
+pub fn is_legacy_file(path: &Path) -> bool {
+    path == WORK_DIR.join(DEFAULT_KNOWN_HOSTS_FILE)
+}
+
+impl Entry {
+    /// Checks whether any of the candidate names matches the host patterns.
+    /// A matching negated pattern (`!host`) rejects the whole entry.
+    pub fn matches(&self, candidates: &[String]) -> bool {
+        let mut matched = false;
+        for pattern in self.hosts.split(',') {
+            if let Some(hashed) = pattern.strip_prefix(HASHED_PREFIX) {
+                matched |= candidates.iter().any(|c| matches_hashed(hashed, c));
+            } else if let Some(negated) = pattern.strip_prefix('!') {
+                if candidates.iter().any(|c| wildcard_match(negated, c)) {
+                    return false;
+                }
+            } else {
+                matched |= candidates.iter().any(|c| wildcard_match(pattern, c));
+            }
+        }
+
+        matched
+    }
+
+    /// Whether the host is listed by name (or hashed), wildcard patterns don't count
+    pub fn names(&self, candidates: &[String]) -> bool {
+        self.hosts
+            .split(',')
+            .any(|pattern| match pattern.strip_prefix(HASHED_PREFIX) {
+                Some(hashed) => candidates.iter().any(|c| matches_hashed(hashed, c)),
+                None => candidates.iter().any(|c| pattern.eq_ignore_ascii_case(c)),
+            })
+    }
+
+    /// When the key was trusted, if it was recorded by shh
+    pub fn added(&self) -> Option<&str> {
+        self.comment.as_deref()?.strip_prefix(ADDED_PREFIX)
+    }
+
+    pub fn is_key(&self, key: &PublicKey) -> bool {
+        self.algorithm == key.algorithm().as_str() && self.key == key.public_key_base64()
+    }
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.marker {
+            Some(Marker::CertAuthority) => f.write_str("@cert-authority ")?,
+            Some(Marker::Revoked) => f.write_str("@revoked ")?,
+            None => {}
+        }
+        write!(f, "{} {} {}", self.hosts, self.algorithm, self.key)?;
+        if let Some(comment) = &self.comment {
+            write!(f, " {}", comment)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Looks the key of the host up in each file in order; missing files are skipped
+pub async fn check(
+    files: &[PathBuf],
+    address: &str,
+    ip: IpAddr,
+    port: u16,
+    key: &PublicKey,
+) -> Verdict {
+    let mut changed = None;
+    for path in files {
+        let Ok(entries) = read_file(path).await else {
+            continue;
+        };
+        let candidates = host_candidates(address, ip, port, is_legacy_file(path));
+        for entry in entries {
+            if !entry.matches(&candidates) {
+                continue;
+            }
+            match entry.marker {
+                Some(Marker::Revoked) if entry.is_key(key) => {
+                    return Verdict::Revoked {
+                        path: path.clone(),
+                        line: entry.line,
+                    };
+                }
+                Some(_) => {}
+                None if entry.is_key(key) => return Verdict::Trusted,
+                None if entry.algorithm == key.algorithm().as_str() && changed.is_none() => {
+                    changed = Some(Verdict::Changed {
+                        path: path.clone(),
+                        entry,
+                    });
+                }
+                None => {}
+            }
+        }
+    }
+
+    changed.unwrap_or(Verdict::Unknown)
+}
+
+/// Adds a `@cert-authority` line trusting host certificates signed by the key,
+/// unless the same one is already there. Returns whether the file was changed.
+pub fn add_cert_authority(path: &Path, hosts: &str, key: &PublicKey) -> Result<bool, FileError> {
+    let content = read_existing(path)?;
+    let installed = parse(&content)
+        .iter()
+        .any(|e| e.marker == Some(Marker::CertAuthority) && e.hosts == hosts && e.is_key(key));
+    if installed {
+        return Ok(false);
+    }
+    let line = format!(
+        "@cert-authority {} {} {} {}",
+        hosts,
+        key.algorithm(),
+        key.public_key_base64(),
+        key.comment()
+    );
+    append_lines(path, &content, &[line.trim_end().to_string()])?;
+
+    Ok(true)
+}
+
+/// Appends the entries the file doesn't have yet (same marker, hosts and key).
+/// Hashed hosts are copied as they are, they are matched all the same.
+/// Returns how many entries were added.
+pub fn import(path: &Path, entries: &[Entry]) -> Result<usize, FileError> {
+    let content = read_existing(path)?;
+    let existing = parse(&content);
+    let mut added: Vec<&Entry> = Vec::new();
+    for entry in entries {
+        let same = |other: &&Entry| {
+            other.marker == entry.marker
+                && other.hosts == entry.hosts
+                && other.algorithm == entry.algorithm
+                && other.key == entry.key
+        };
+        if !existing.iter().any(|other| same(&other)) && !added.iter().any(same) {
+            added.push(entry);
+        }
+    }
+    if !added.is_empty() {
+        let lines: Vec<String> = added.iter().map(ToString::to_string).collect();
+        append_lines(path, &content, &lines)?;
+    }
+
+    Ok(added.len())
+}
+
+/// Removes the keys recorded for the host, like `ssh-keygen -R`. Lines with markers
+/// (`@cert-authority`, `@revoked`) are kept. The file is replaced in one step,
+/// so it's never left half-written. Returns how many entries were removed.
+pub fn remove(path: &Path, host: &str, port: u16) -> Result<usize, FileError> {
+    let content = read_existing(path)?;
+    let candidates = [host_name(host, port)];
+    let removed: Vec<usize> = parse(&content)
+        .into_iter()
+        .filter(|entry| entry.marker.is_none() && entry.names(&candidates))
+        .map(|entry| entry.line)
+        .collect();
+    if !removed.is_empty() {
+        remove_lines(path, &content, &removed)?;
+    }
+
+    Ok(removed.len())
+}
+
+/// Removes the entry on the given line (e.g. a stale key being replaced)
+pub fn remove_entry(path: &Path, line: usize) -> Result<(), FileError> {
+    let content = read_existing(path)?;
+    remove_lines(path, &content, &[line])
+}
+
+/// Replaces the file atomically with the content without the given lines (numbered from 1)
+fn remove_lines(path: &Path, content: &str, removed: &[usize]) -> Result<(), FileError> {
+    let mut kept = String::with_capacity(content.len());
+    for (i, line) in content.lines().enumerate() {
+        if !removed.contains(&(i + 1)) {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+
+    write_atomically(path, kept.as_bytes())
+}
+
+/// Content of the file, empty if it doesn't exist yet
+fn read_existing(path: &Path) -> Result<String, FileError> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Appends the lines to the file with the given content, creating it if needed
+fn append_lines(path: &Path, content: &str, lines: &[String]) -> Result<(), FileError> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    if !content.is_empty() && !content.ends_with('\n') {
+        writeln!(file)?;
+    }
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+
+    Ok(())
+}
+
+pub async fn read_file(path: &Path) -> Result<Vec<Entry>, FileError> {
+    Ok(parse(&fs::read_to_string(path).await?))
+}
+
+fn matches_hashed(hashed: &str, host: &str) -> bool {
+    let Some((salt, hash)) = hashed.split_once('|') else {
+        return false;
+    };
+    let (Ok(salt), Ok(hash)) = (
+        BASE64.decode(salt.as_bytes()),
+        BASE64.decode(hash.as_bytes()),
+    ) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha1>::new_from_slice(&salt).expect("HMAC accepts any key length");
+    mac.update(host.as_bytes());
+
+    mac.verify_slice(&hash).is_ok()
+}
+
+/// Matches OpenSSH-style wildcards: `*` (any sequence) and `?` (any character)
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c.eq_ignore_ascii_case(&text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    p = bp + 1;
+                    t = bt + 1;
+                    backtrack = Some((bp, bt + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+
+    /// Hashes the host name the way OpenSSH does with `HashKnownHosts yes`
+    fn hash_host(host: &str, salt: &[u8]) -> String {
+        let mut mac = Hmac::<Sha1>::new_from_slice(salt).expect("HMAC accepts any key length");
+        mac.update(host.as_bytes());
+
+        format!(
+            "{}{}|{}",
+            HASHED_PREFIX,
+            BASE64.encode(salt),
+            BASE64.encode(&mac.finalize().into_bytes())
+        )
+    }
+
+    #[test]
+    fn parse_lines() {
+        let content = format!(
+            "# comment\n\n\
+            host1,10.0.0.1 ssh-ed25519 {KEY} added:2024-05-01T12:00:00Z\n\
+            @cert-authority *.example.com ssh-ed25519 {KEY} ca\n\
+            @unknown host ssh-ed25519 {KEY}\n\
+            broken-line\n"
+        );
+        let entries = parse(&content);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].line, 3);
+        assert_eq!(entries[0].hosts, "host1,10.0.0.1");
+        assert_eq!(entries[0].added(), Some("2024-05-01T12:00:00Z"));
+        assert_eq!(entries[1].comment.as_deref(), Some("ca"));
+        assert_eq!(entries[1].added(), None);
+        assert_eq!(entries[1].marker, Some(Marker::CertAuthority));
+    }
+
+    #[test]
+    fn match_patterns() {
+        let entry = |hosts: &str| Entry {
+            line: 1,
+            marker: None,
+            hosts: hosts.into(),
+            algorithm: "ssh-ed25519".into(),
+            key: KEY.into(),
+            comment: None,
+        };
+        let candidates = vec!["web-01.example.com".to_string()];
+        assert!(entry("web-01.example.com").matches(&candidates));
+        assert!(entry("other,*.example.com").matches(&candidates));
+        assert!(entry("web-0?.example.com").matches(&candidates));
+        assert!(!entry("*.example.com,!web-01.example.com").matches(&candidates));
+        assert!(!entry("web-02.example.com").matches(&candidates));
+
+        let hashed = hash_host("web-01.example.com", b"0123456789abcdefghij");
+        assert!(entry(&hashed).matches(&candidates));
+        assert!(!entry(&hashed).matches(&["web-02.example.com".to_string()]));
+    }
+
+    #[test]
+    fn cert_authority_added_once() {
+        let key = PublicKey::from_openssh(&format!("ssh-ed25519 {KEY} ca")).expect("Valid key");
+        let path = std::env::temp_dir().join(format!("shh-ca-{}", std::process::id()));
+        assert!(add_cert_authority(&path, "*.example.com", &key).unwrap());
+        assert!(!add_cert_authority(&path, "*.example.com", &key).unwrap());
+
+        let entries = parse(&std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].marker, Some(Marker::CertAuthority));
+        assert_eq!(entries[0].hosts, "*.example.com");
+    }
+
+    #[test]
+    fn import_missing_entries() {
+        let hashed = hash_host("web-01.example.com", b"0123456789abcdefghij");
+        let source = parse(&format!(
+            "{hashed} ssh-ed25519 {KEY}\n\
+            @revoked old.example.com ssh-ed25519 {KEY}\n\
+            {hashed} ssh-ed25519 {KEY}\n"
+        ));
+        let path = std::env::temp_dir().join(format!("shh-import-{}", std::process::id()));
+        std::fs::write(&path, format!("@revoked old.example.com ssh-ed25519 {KEY}")).unwrap();
+        assert_eq!(import(&path, &source).unwrap(), 1);
+        assert_eq!(import(&path, &source).unwrap(), 0);
+
+        let entries = parse(&std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].hosts, hashed);
+        assert!(entries[1].matches(&["web-01.example.com".to_string()]));
+    }
+
+    #[test]
+    fn remove_host_entries() {
+        let hashed = hash_host("web-01.example.com", b"0123456789abcdefghij");
+        let content = format!(
+            "# servers\n\
+            web-01.example.com,10.0.0.1 ssh-ed25519 {KEY}\n\
+            {hashed} ssh-rsa {KEY}\n\
+            *.example.com ssh-ed25519 {KEY}\n\
+            [web-01.example.com]:2222 ssh-ed25519 {KEY}\n\
+            @revoked web-01.example.com ssh-ed25519 {KEY}\n"
+        );
+        let path = std::env::temp_dir().join(format!("shh-remove-{}", std::process::id()));
+        std::fs::write(&path, &content).unwrap();
+        assert_eq!(remove(&path, "web-01.example.com", 22).unwrap(), 2);
+        assert_eq!(remove(&path, "web-01.example.com", 22).unwrap(), 0);
+
+        let rest = std::fs::read_to_string(&path).unwrap();
+        assert!(rest.starts_with("# servers\n*.example.com"));
+        assert_eq!(remove(&path, "web-01.example.com", 2222).unwrap(), 1);
+        let entries = parse(&std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].marker, Some(Marker::Revoked));
+    }
+
+    #[test]
+    fn candidates_with_port() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(host_candidates("host", ip, 22, true), ["host", "10.0.0.1"]);
+        assert_eq!(
+            host_candidates("host", ip, 2222, true),
+            ["[host]:2222", "[10.0.0.1]:2222", "10.0.0.1"]
+        );
+        // A plain IP elsewhere is a server on the default port
+        assert_eq!(
+            host_candidates("host", ip, 2222, false),
+            ["[host]:2222", "[10.0.0.1]:2222"]
+        );
+    }
+}
@@ -0,0 +1,304 @@
+use log::debug;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, timeout_at};
+
+/// Multicast group and port of mDNS (RFC 6762)
+const MDNS_GROUP: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+/// DNS-SD service type of SSH servers (RFC 6763)
+const SSH_SERVICE: &str = "_ssh._tcp.local";
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// Top bit of the question class asks for a unicast answer (RFC 6762, section 5.4)
+const UNICAST_RESPONSE: u16 = 0x8000;
+/// Top bit of the header flags marks a response
+const FLAG_RESPONSE: u16 = 0x8000;
+const HEADER_LEN: usize = 12;
+/// Compression pointers are limited, so that loops in malformed packets end
+const MAX_POINTERS: usize = 16;
+
+/// SSH server announced on the local network
+#[derive(Clone, Debug, PartialEq)]
+pub struct Announced {
+    /// Name of the service instance (`raspberrypi`)
+    pub instance: String,
+    /// Host the service runs on (`raspberrypi.local`)
+    pub host: String,
+    pub port: u16,
+    pub addresses: Vec<IpAddr>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Record {
+    Ptr {
+        name: String,
+        target: String,
+    },
+    Srv {
+        name: String,
+        target: String,
+        port: u16,
+    },
+    Address {
+        name: String,
+        ip: IpAddr,
+    },
+}
+
+impl Announced {
+    /// Host name without the `.local` domain, usable as a server name
+    pub fn short_name(&self) -> &str {
+        self.host.strip_suffix(".local").unwrap_or(&self.host)
+    }
+}
+
+/// Asks the local network for SSH services and collects the answers until the limit.
+/// The query is sent from an ephemeral port, so responders answer it directly
+/// (no need to join the multicast group).
+pub async fn browse(limit: Duration) -> io::Result<Vec<Announced>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket
+        .send_to(&query(SSH_SERVICE, TYPE_PTR), MDNS_GROUP)
+        .await?;
+    debug!("Browsing for '{}'...", SSH_SERVICE);
+
+    let deadline = Instant::now() + limit;
+    let mut records = Vec::new();
+    let mut buffer = [0; 9000];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (len, from) = received?;
+        match parse(&buffer[..len]) {
+            Some(found) => records.extend(found),
+            None => debug!("Ignoring malformed answer from {}", from),
+        }
+    }
+
+    Ok(collect(records))
+}
+
+/// Joins the records of each announced instance, sorted by host
+fn collect(records: Vec<Record>) -> Vec<Announced> {
+    let mut announced: Vec<Announced> = Vec::new();
+    for record in &records {
+        let Record::Ptr { name, target } = record else {
+            continue;
+        };
+        let Some(instance) = target.strip_suffix(&format!(".{}", SSH_SERVICE)) else {
+            continue;
+        };
+        if !name.eq_ignore_ascii_case(SSH_SERVICE)
+            || announced.iter().any(|found| found.instance == instance)
+        {
+            continue;
+        }
+        let Some((host, port)) = records.iter().find_map(|record| match record {
+            Record::Srv {
+                name,
+                target: host,
+                port,
+            } if name == target => Some((host, *port)),
+            _ => None,
+        }) else {
+            debug!("No host announced for '{}'", instance);
+            continue;
+        };
+        let mut addresses: Vec<IpAddr> = records
+            .iter()
+            .filter_map(|record| match record {
+                Record::Address { name, ip } if name.eq_ignore_ascii_case(host) => Some(*ip),
+                _ => None,
+            })
+            .collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+
+        announced.push(Announced {
+            instance: instance.to_string(),
+            host: host.to_ascii_lowercase(),
+            port,
+            addresses,
+        });
+    }
+    announced.sort_unstable_by(|a, b| a.host.cmp(&b.host));
+
+    announced
+}
+
+fn query(name: &str, record_type: u16) -> Vec<u8> {
+    // ID 0, no flags, a single question
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in name.split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&record_type.to_be_bytes());
+    packet.extend_from_slice(&(CLASS_IN | UNICAST_RESPONSE).to_be_bytes());
+
+    packet
+}
+
+/// Records of all sections of a response, other record types are skipped.
+/// `None` if it isn't a well-formed response.
+fn parse(packet: &[u8]) -> Option<Vec<Record>> {
+    let u16_at = |pos: usize| {
+        Some(u16::from_be_bytes([
+            *packet.get(pos)?,
+            *packet.get(pos + 1)?,
+        ]))
+    };
+    if u16_at(2)? & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    let questions = u16_at(4)?;
+    let answers = [u16_at(6)?, u16_at(8)?, u16_at(10)?]
+        .into_iter()
+        .map(usize::from)
+        .sum();
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        // Name, type and class
+        pos = read_name(packet, pos)?.1 + 4;
+    }
+    let mut records = Vec::with_capacity(answers);
+    for _ in 0..answers {
+        let (name, next) = read_name(packet, pos)?;
+        let record_type = u16_at(next)?;
+        // Class and TTL don't matter here
+        let len = usize::from(u16_at(next + 8)?);
+        let data = next + 10;
+        let end = data + len;
+        if end > packet.len() {
+            return None;
+        }
+        match record_type {
+            TYPE_PTR => records.push(Record::Ptr {
+                name,
+                target: read_name(packet, data)?.0,
+            }),
+            TYPE_SRV => records.push(Record::Srv {
+                name,
+                // Priority and weight come first
+                port: u16_at(data + 4)?,
+                target: read_name(packet, data + 6)?.0,
+            }),
+            TYPE_A if len == 4 => {
+                let octets: [u8; 4] = packet[data..end].try_into().ok()?;
+                records.push(Record::Address {
+                    name,
+                    ip: Ipv4Addr::from(octets).into(),
+                });
+            }
+            TYPE_AAAA if len == 16 => {
+                let octets: [u8; 16] = packet[data..end].try_into().ok()?;
+                records.push(Record::Address {
+                    name,
+                    ip: Ipv6Addr::from(octets).into(),
+                });
+            }
+            _ => {}
+        }
+        pos = end;
+    }
+
+    Some(records)
+}
+
+/// Reads a possibly compressed name, returns it with the position after it
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    for _ in 0..MAX_POINTERS {
+        loop {
+            let len = usize::from(*packet.get(pos)?);
+            match len {
+                0 => {
+                    return Some((labels.join("."), end.unwrap_or(pos + 1)));
+                }
+                // Pointer to the rest of the name elsewhere in the packet
+                len if len & 0xC0 == 0xC0 => {
+                    let offset = (len & 0x3F) << 8 | usize::from(*packet.get(pos + 1)?);
+                    end.get_or_insert(pos + 2);
+                    pos = offset;
+                    break;
+                }
+                len => {
+                    let label = packet.get(pos + 1..pos + 1 + len)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + len;
+                }
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_announcement() {
+        let name = |name: &str| {
+            let mut bytes: Vec<u8> = name
+                .split('.')
+                .flat_map(|label| [&[label.len() as u8], label.as_bytes()].concat())
+                .collect();
+            bytes.push(0);
+            bytes
+        };
+        let record = |name: Vec<u8>, record_type: u16, data: Vec<u8>| {
+            [
+                name,
+                record_type.to_be_bytes().to_vec(),
+                vec![0, 1, 0, 0, 0, 120],
+                (data.len() as u16).to_be_bytes().to_vec(),
+                data,
+            ]
+            .concat()
+        };
+        // Answer, then the SRV and A records in the additional section
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 2];
+        packet.extend(record(
+            name(SSH_SERVICE),
+            TYPE_PTR,
+            name("pi._ssh._tcp.local"),
+        ));
+        // The SRV name points to the PTR target (right after the PTR header)
+        let target = (HEADER_LEN + name(SSH_SERVICE).len() + 10) as u8;
+        let srv = [vec![0, 0, 0, 0, 0x08, 0xAE], name("Pi.local")].concat();
+        packet.extend(record(vec![0xC0, target], TYPE_SRV, srv));
+        packet.extend(record(name("pi.local"), TYPE_A, vec![192, 168, 1, 5]));
+
+        let records = parse(&packet).unwrap();
+        assert_eq!(
+            records[1],
+            Record::Srv {
+                name: "pi._ssh._tcp.local".into(),
+                target: "Pi.local".into(),
+                port: 2222
+            }
+        );
+        let announced = collect(records);
+        assert_eq!(
+            announced,
+            [Announced {
+                instance: "pi".into(),
+                host: "pi.local".into(),
+                port: 2222,
+                addresses: vec!["192.168.1.5".parse().unwrap()],
+            }]
+        );
+        assert_eq!(announced[0].short_name(), "pi");
+
+        assert!(parse(&query(SSH_SERVICE, TYPE_PTR)).is_none());
+        assert!(parse(&packet[..40]).is_none());
+    }
+}
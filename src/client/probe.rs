@@ -0,0 +1,134 @@
+use log::debug;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::error::ProbeError;
+
+/// Sent in return for the server banner, so the server logs a clean disconnect
+const CLIENT_ID: &str = concat!("SSH-2.0-shh_", env!("CARGO_PKG_VERSION"), "\r\n");
+/// Servers may send other lines before the banner (RFC 4253, section 4.2)
+const MAX_BANNER_LINES: usize = 20;
+
+/// Reachability of a single server
+#[derive(Debug)]
+pub struct Probe {
+    pub scope: Option<String>,
+    pub name: String,
+    pub address: String,
+    pub port: u16,
+    pub result: Result<Reachable, ProbeError>,
+}
+
+#[derive(Debug)]
+pub struct Reachable {
+    /// Time the TCP connection took to establish
+    pub latency: Duration,
+    /// Identification string of the server, if requested
+    pub banner: Option<String>,
+}
+
+/// Connects to the server without authenticating, optionally reading its SSH banner.
+/// The limit applies to both steps separately.
+pub async fn probe(
+    address: &str,
+    port: u16,
+    banner: bool,
+    limit: Duration,
+) -> Result<Reachable, ProbeError> {
+    debug!("Probing {}:{}...", address, port);
+
+    let started = Instant::now();
+    let stream = timeout(limit, TcpStream::connect((address, port)))
+        .await
+        .map_err(|_| ProbeError::Timeout)??;
+    let latency = started.elapsed();
+    debug!("Connected to {}:{} in {:?}", address, port, latency);
+
+    let banner = if banner {
+        Some(
+            timeout(limit, read_banner(stream))
+                .await
+                .map_err(|_| ProbeError::Timeout)??,
+        )
+    } else {
+        None
+    };
+
+    Ok(Reachable { latency, banner })
+}
+
+/// Runs at most `limit` of the futures at once, each as a separate task.
+/// Results are collected as they arrive, but keep the order of the futures.
+pub async fn run_limited<F, T>(futures: impl IntoIterator<Item = F>, limit: usize) -> Vec<T>
+where
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut tasks = JoinSet::new();
+    let mut results = Vec::new();
+    for (i, future) in futures.into_iter().enumerate() {
+        if tasks.len() >= limit.max(1)
+            && let Some(done) = tasks.join_next().await
+        {
+            results.push(done.expect("Task must not panic"));
+        }
+        tasks.spawn(async move { (i, future.await) });
+    }
+    while let Some(done) = tasks.join_next().await {
+        results.push(done.expect("Task must not panic"));
+    }
+    results.sort_unstable_by_key(|(i, _)| *i);
+
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+async fn read_banner(mut stream: TcpStream) -> Result<String, ProbeError> {
+    let mut reader = BufReader::new(&mut stream);
+    let mut line = String::new();
+    for _ in 0..MAX_BANNER_LINES {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            break;
+        }
+        if line.starts_with("SSH-") {
+            let banner = line.trim_end().to_string();
+            stream.write_all(CLIENT_ID.as_bytes()).await?;
+            return Ok(banner);
+        }
+    }
+
+    Err(ProbeError::NoBanner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn limited_keeps_order() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let futures = (0..8u64).map(|i| {
+            let (running, peak) = (Arc::clone(&running), Arc::clone(&peak));
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                // Later ones finish first
+                tokio::time::sleep(Duration::from_millis(40 - 5 * i)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                i
+            }
+        });
+
+        let results = Runtime::new().unwrap().block_on(run_limited(futures, 3));
+        assert_eq!(results, (0..8).collect::<Vec<_>>());
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,207 @@
+use clap::ValueEnum;
+use russh::keys::{Algorithm, EcdsaCurve, HashAlg};
+use russh::{Preferred, cipher, kex, mac};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt::Display;
+
+use crate::error::ConnectionError;
+
+/// Named sets of algorithms offered during negotiation, selectable per scope.
+/// Configured lists are narrowed down to the profile, missing ones are taken from it.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SecurityProfile {
+    /// Current algorithms only, compatible with OpenSSH 7.4+
+    Modern,
+    /// Post-quantum/curve25519 key exchange and AEAD ciphers only
+    Strict,
+    /// FIPS 140 approved algorithms (NIST curves, AES, SHA-2)
+    Fips,
+}
+
+/// Algorithms allowed by a profile, in the order of preference
+pub struct Algorithms {
+    pub kex: &'static [kex::Name],
+    pub key: &'static [Algorithm],
+    pub cipher: &'static [cipher::Name],
+    pub mac: &'static [mac::Name],
+}
+
+const ED25519: Algorithm = Algorithm::Ed25519;
+const ECDSA_P256: Algorithm = Algorithm::Ecdsa {
+    curve: EcdsaCurve::NistP256,
+};
+const ECDSA_P384: Algorithm = Algorithm::Ecdsa {
+    curve: EcdsaCurve::NistP384,
+};
+const ECDSA_P521: Algorithm = Algorithm::Ecdsa {
+    curve: EcdsaCurve::NistP521,
+};
+const RSA_SHA512: Algorithm = Algorithm::Rsa {
+    hash: Some(HashAlg::Sha512),
+};
+const RSA_SHA256: Algorithm = Algorithm::Rsa {
+    hash: Some(HashAlg::Sha256),
+};
+
+const MODERN: Algorithms = Algorithms {
+    kex: &[
+        kex::MLKEM768X25519_SHA256,
+        kex::CURVE25519,
+        kex::CURVE25519_PRE_RFC_8731,
+        kex::DH_GEX_SHA256,
+        kex::DH_G18_SHA512,
+        kex::DH_G16_SHA512,
+        kex::EXTENSION_SUPPORT_AS_CLIENT,
+        kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+    ],
+    key: &[
+        ED25519, ECDSA_P256, ECDSA_P384, ECDSA_P521, RSA_SHA512, RSA_SHA256,
+    ],
+    cipher: &[
+        cipher::CHACHA20_POLY1305,
+        cipher::AES_256_GCM,
+        cipher::AES_128_GCM,
+        cipher::AES_256_CTR,
+        cipher::AES_192_CTR,
+        cipher::AES_128_CTR,
+    ],
+    mac: &[
+        mac::HMAC_SHA512_ETM,
+        mac::HMAC_SHA256_ETM,
+        mac::HMAC_SHA512,
+        mac::HMAC_SHA256,
+    ],
+};
+
+const STRICT: Algorithms = Algorithms {
+    kex: &[
+        kex::MLKEM768X25519_SHA256,
+        kex::CURVE25519,
+        kex::CURVE25519_PRE_RFC_8731,
+        kex::EXTENSION_SUPPORT_AS_CLIENT,
+        kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+    ],
+    key: &[ED25519, RSA_SHA512],
+    cipher: &[
+        cipher::CHACHA20_POLY1305,
+        cipher::AES_256_GCM,
+        cipher::AES_128_GCM,
+    ],
+    // AEAD ciphers don't use them, but one has to be offered
+    mac: &[mac::HMAC_SHA512_ETM, mac::HMAC_SHA256_ETM],
+};
+
+const FIPS: Algorithms = Algorithms {
+    kex: &[
+        kex::ECDH_SHA2_NISTP256,
+        kex::ECDH_SHA2_NISTP384,
+        kex::ECDH_SHA2_NISTP521,
+        kex::DH_GEX_SHA256,
+        kex::DH_G16_SHA512,
+        kex::DH_G14_SHA256,
+        kex::EXTENSION_SUPPORT_AS_CLIENT,
+        kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+    ],
+    key: &[ECDSA_P256, ECDSA_P384, ECDSA_P521, RSA_SHA512, RSA_SHA256],
+    cipher: &[
+        cipher::AES_256_GCM,
+        cipher::AES_128_GCM,
+        cipher::AES_256_CTR,
+        cipher::AES_192_CTR,
+        cipher::AES_128_CTR,
+    ],
+    mac: &[
+        mac::HMAC_SHA512_ETM,
+        mac::HMAC_SHA256_ETM,
+        mac::HMAC_SHA512,
+        mac::HMAC_SHA256,
+    ],
+};
+
+impl SecurityProfile {
+    pub fn algorithms(self) -> &'static Algorithms {
+        match self {
+            Self::Modern => &MODERN,
+            Self::Strict => &STRICT,
+            Self::Fips => &FIPS,
+        }
+    }
+
+    /// Keeps the configured algorithms the profile allows (in the configured order)
+    pub fn constrain(
+        self,
+        kex: Option<Vec<kex::Name>>,
+        key: Option<Vec<Algorithm>>,
+        cipher: Option<Vec<cipher::Name>>,
+        mac: Option<Vec<mac::Name>>,
+    ) -> Result<Preferred, ConnectionError> {
+        let allowed = self.algorithms();
+
+        Ok(Preferred {
+            kex: self.narrow("kex", kex, allowed.kex)?,
+            key: self.narrow("alg", key, allowed.key)?,
+            cipher: self.narrow("cipher", cipher, allowed.cipher)?,
+            mac: self.narrow("mac", mac, allowed.mac)?,
+            ..Preferred::default()
+        })
+    }
+
+    fn narrow<T: Clone + PartialEq>(
+        self,
+        field: &'static str,
+        configured: Option<Vec<T>>,
+        allowed: &'static [T],
+    ) -> Result<Cow<'static, [T]>, ConnectionError> {
+        let Some(configured) = configured else {
+            return Ok(Cow::Borrowed(allowed));
+        };
+        let kept: Vec<T> = configured
+            .into_iter()
+            .filter(|name| allowed.contains(name))
+            .collect();
+        if kept.is_empty() {
+            return Err(ConnectionError::ProfileExcludes(field, self));
+        }
+
+        Ok(Cow::Owned(kept))
+    }
+}
+
+impl Display for SecurityProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Modern => "modern",
+            Self::Strict => "strict",
+            Self::Fips => "fips",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constrain_to_profile() {
+        let preferred = SecurityProfile::Strict
+            .constrain(
+                None,
+                None,
+                Some(vec![cipher::AES_256_CTR, cipher::AES_256_GCM]),
+                None,
+            )
+            .unwrap();
+        assert_eq!(preferred.cipher.as_ref(), [cipher::AES_256_GCM]);
+        assert_eq!(preferred.kex.as_ref(), STRICT.kex);
+
+        assert!(matches!(
+            SecurityProfile::Fips.constrain(None, None, None, Some(vec![mac::HMAC_SHA1])),
+            Err(ConnectionError::ProfileExcludes(
+                "mac",
+                SecurityProfile::Fips
+            ))
+        ));
+    }
+}
@@ -0,0 +1,63 @@
+use secrecy::SecretString;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+/// Held while a question is on the terminal
+static TERMINAL: Mutex<()> = Mutex::new(());
+
+/// Source of answers to the questions asked while connecting
+/// (passwords, passphrases, whether to trust a server)
+pub trait PromptProvider: Send + Sync {
+    /// Asks for a visible answer
+    fn text(&self, prompt: &str) -> io::Result<String>;
+    /// Asks for an answer that must not be echoed
+    fn secret(&self, prompt: &str) -> io::Result<SecretString>;
+    /// Shows a message that needs no answer (e.g. instructions from the server)
+    fn message(&self, text: &str);
+}
+
+/// Asks on the terminal the program runs in. Questions go to stderr,
+/// so that they stay out of output that is piped or redirected.
+pub struct TerminalPrompts;
+
+impl PromptProvider for TerminalPrompts {
+    fn text(&self, prompt: &str) -> io::Result<String> {
+        eprint!("{}", prompt);
+        io::stderr().flush()?;
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+
+        Ok(input.trim().to_string())
+    }
+
+    fn secret(&self, prompt: &str) -> io::Result<SecretString> {
+        eprint!("{}", prompt);
+        io::stderr().flush()?;
+
+        rpassword::read_password().map(SecretString::from)
+    }
+
+    fn message(&self, text: &str) {
+        eprintln!("{}", text);
+    }
+}
+
+/// Asks on the terminal one question at a time, for connections made concurrently
+pub struct SerialPrompts;
+
+impl PromptProvider for SerialPrompts {
+    fn text(&self, prompt: &str) -> io::Result<String> {
+        let _terminal = TERMINAL.lock().unwrap_or_else(|e| e.into_inner());
+        TerminalPrompts.text(prompt)
+    }
+
+    fn secret(&self, prompt: &str) -> io::Result<SecretString> {
+        let _terminal = TERMINAL.lock().unwrap_or_else(|e| e.into_inner());
+        TerminalPrompts.secret(prompt)
+    }
+
+    fn message(&self, text: &str) {
+        let _terminal = TERMINAL.lock().unwrap_or_else(|e| e.into_inner());
+        TerminalPrompts.message(text)
+    }
+}
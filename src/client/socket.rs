@@ -0,0 +1,142 @@
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_with_macros::skip_serializing_none;
+use std::fmt::Display;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpSocket, TcpStream};
+
+/// TCP settings of the connection to the server
+#[skip_serializing_none]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SocketOptions {
+    /// Send keystrokes right away instead of waiting to fill a packet
+    /// (disables Nagle's algorithm, on by default)
+    pub nodelay: Option<bool>,
+    /// Let the system probe idle connections (off by default)
+    pub keepalive: Option<bool>,
+    /// Size of the send buffer of the socket (bytes, system default if not set)
+    pub send_buffer: Option<u32>,
+    /// Size of the receive buffer of the socket (bytes, system default if not set)
+    pub recv_buffer: Option<u32>,
+}
+
+impl SocketOptions {
+    /// Fills the options not set here from the other ones
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            nodelay: self.nodelay.or(other.nodelay),
+            keepalive: self.keepalive.or(other.keepalive),
+            send_buffer: self.send_buffer.or(other.send_buffer),
+            recv_buffer: self.recv_buffer.or(other.recv_buffer),
+        }
+    }
+
+    /// Opens a TCP connection with the options applied.
+    /// Options the system refuses are only reported.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(keepalive) = self.keepalive {
+            socket
+                .set_keepalive(keepalive)
+                .unwrap_or_else(|e| warn!("Failed to set TCP keepalive: {}", e));
+        }
+        if let Some(size) = self.send_buffer {
+            socket
+                .set_send_buffer_size(size)
+                .unwrap_or_else(|e| warn!("Failed to set the send buffer size: {}", e));
+        }
+        if let Some(size) = self.recv_buffer {
+            socket
+                .set_recv_buffer_size(size)
+                .unwrap_or_else(|e| warn!("Failed to set the receive buffer size: {}", e));
+        }
+
+        let stream = socket.connect(addr).await?;
+        let nodelay = self.nodelay.unwrap_or(true);
+        stream
+            .set_nodelay(nodelay)
+            .unwrap_or_else(|e| warn!("Failed to set TCP_NODELAY: {}", e));
+        debug!(
+            "TCP connection to {} established (nodelay: {})",
+            addr, nodelay
+        );
+
+        Ok(stream)
+    }
+}
+
+impl Display for SocketOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            nodelay,
+            keepalive,
+            send_buffer,
+            recv_buffer,
+        } = self;
+        let mut options = Vec::new();
+        if let Some(nodelay) = nodelay {
+            options.push(format!("nodelay {}", nodelay));
+        }
+        if let Some(keepalive) = keepalive {
+            options.push(format!("keepalive {}", keepalive));
+        }
+        if let Some(size) = send_buffer {
+            options.push(format!("send_buffer {}", size));
+        }
+        if let Some(size) = recv_buffer {
+            options.push(format!("recv_buffer {}", size));
+        }
+
+        write!(f, "{}", options.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn connect_with_options() {
+        let options = SocketOptions {
+            keepalive: Some(true),
+            recv_buffer: Some(64 * 1024),
+            ..Default::default()
+        };
+        let rt = Runtime::new().unwrap();
+        let stream = rt.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            options.connect(listener.local_addr().unwrap()).await
+        });
+
+        assert!(stream.expect("Connected").nodelay().unwrap());
+        assert_eq!(options.to_string(), "keepalive true, recv_buffer 65536");
+    }
+
+    #[test]
+    fn merge_option_by_option() {
+        let server = SocketOptions {
+            keepalive: Some(true),
+            ..Default::default()
+        };
+        let scope = SocketOptions {
+            nodelay: Some(false),
+            keepalive: Some(false),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            server.or(scope),
+            SocketOptions {
+                nodelay: Some(false),
+                keepalive: Some(true),
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::storage::event_log::utc_timestamp;
+
+/// Sessions started within the same second get numbered files, up to this many
+const SAME_SECOND_FILES: usize = 100;
+
+/// Record of what an interactive session showed on the terminal. Only the output
+/// is written: typed input appears through the remote echo, so answers to
+/// password prompts (which aren't echoed) stay out of it.
+/// Nothing is buffered, so the file is complete even if the program is killed.
+pub struct Transcript {
+    file: File,
+    path: PathBuf,
+}
+
+impl Transcript {
+    /// Creates `<timestamp>_<target>.log` in the directory (readable only by
+    /// the user, as is the directory when it's created here) and writes the header.
+    /// A directory that already exists keeps its permissions.
+    pub async fn create(dir: &Path, target: &str) -> io::Result<Self> {
+        if !fs::try_exists(dir).await? {
+            fs::create_dir_all(dir).await?;
+            #[cfg(unix)]
+            fs::set_permissions(dir, std::os::unix::fs::PermissionsExt::from_mode(0o700)).await?;
+        }
+
+        let started = now();
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut attempt = 1;
+        let (mut file, path) = loop {
+            let path = dir.join(file_name(started, target, attempt));
+            match options.open(&path).await {
+                Ok(file) => break (file, path),
+                Err(e)
+                    if e.kind() == io::ErrorKind::AlreadyExists && attempt < SAME_SECOND_FILES =>
+                {
+                    attempt += 1
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        let header = format!(
+            "Session with {} started {}\n",
+            target,
+            utc_timestamp(started)
+        );
+        file.write_all(header.as_bytes()).await?;
+        file.flush().await?;
+
+        Ok(Self { file, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data).await?;
+        self.file.flush().await
+    }
+
+    /// Writes the footer
+    pub async fn finish(mut self, exit_status: Option<u32>) -> io::Result<()> {
+        let status = exit_status.map_or("unknown".into(), |status| status.to_string());
+        let footer = format!(
+            "\nSession ended {} (exit status {})\n",
+            utc_timestamp(now()),
+            status
+        );
+        self.file.write_all(footer.as_bytes()).await?;
+        self.file.flush().await
+    }
+}
+
+/// Sortable by time, without characters that are awkward in file names.
+/// The ones after the first in the same second are numbered (`_2`, `_3`...).
+fn file_name(timestamp: u64, target: &str, attempt: usize) -> String {
+    let target: String = target
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' => '_',
+            c => c,
+        })
+        .collect();
+
+    let number = if attempt > 1 {
+        format!("_{}", attempt)
+    } else {
+        String::new()
+    };
+
+    format!(
+        "{}_{}{}.log",
+        utc_timestamp(timestamp).replace(':', ""),
+        target,
+        number
+    )
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn transcript_file() {
+        assert_eq!(
+            file_name(0, "alice@fe80::1", 1),
+            "1970-01-01T000000Z_alice@fe80__1.log"
+        );
+        assert_eq!(
+            file_name(0, "alice@db", 2),
+            "1970-01-01T000000Z_alice@db_2.log"
+        );
+
+        let dir = std::env::temp_dir().join(format!("shh-transcripts-{}", std::process::id()));
+        let rt = Runtime::new().unwrap();
+        let (path, other) = rt.block_on(async {
+            let mut transcript = Transcript::create(&dir, "alice@db").await.unwrap();
+            // Same second (most likely), gets its own file
+            let other = Transcript::create(&dir, "alice@db").await.unwrap();
+            transcript.write(b"$ uptime\r\n").await.unwrap();
+            let path = transcript.path().to_path_buf();
+            transcript.finish(Some(0)).await.unwrap();
+            (path, other.path().to_path_buf())
+        });
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_ne!(path, other);
+        assert!(content.starts_with("Session with alice@db started "));
+        assert!(content.contains("$ uptime\r\n"));
+        assert!(content.ends_with("(exit status 0)\n"));
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use log::warn;
+
+/// What a file is compared by: it's transferred when either differs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileStat {
+    pub size: u64,
+    /// Modification time, whole seconds since the epoch
+    pub mtime: u64,
+}
+
+/// Regular files of a directory by their paths relative to it (`/`-separated)
+pub type FileList = BTreeMap<String, FileStat>;
+
+/// Prints the regular files under the remote directory as `size\tmtime\tpath`,
+/// each ended by NUL (find of GNU/busybox). A missing directory has no files.
+pub const LIST_FILES: &str = "find . -type f -printf '%s\\t%T@\\t%P\\0'";
+
+/// Parses the output of [`LIST_FILES`], malformed entries are skipped
+pub fn parse_listing(output: &[u8]) -> FileList {
+    String::from_utf8_lossy(output)
+        .split('\0')
+        .filter_map(|entry| {
+            let mut fields = entry.splitn(3, '\t');
+            let size = fields.next()?.parse().ok()?;
+            // Fractional seconds
+            let mtime = fields.next()?.split('.').next()?.parse().ok()?;
+            let path = fields.next().filter(|path| !path.is_empty())?;
+            Some((path.to_string(), FileStat { size, mtime }))
+        })
+        .collect()
+}
+
+/// Regular files under the local directory, symbolic links aren't followed.
+/// A missing directory has no files.
+pub fn local_files(dir: &Path) -> io::Result<FileList> {
+    let mut files = FileList::new();
+    if !dir.exists() {
+        return Ok(files);
+    }
+    let mut pending = vec![(dir.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                warn!("Skipping '{}': not a UTF-8 name", entry.path().display());
+                continue;
+            };
+            let path = format!("{}{}", prefix, name);
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                pending.push((entry.path(), format!("{}/", path)));
+            } else if metadata.is_file() {
+                let mtime = metadata
+                    .modified()?
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                files.insert(
+                    path,
+                    FileStat {
+                        size: metadata.len(),
+                        mtime,
+                    },
+                );
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Files of the source that the destination lacks or has in another size or version
+pub fn changed_files(source: &FileList, destination: &FileList) -> Vec<String> {
+    source
+        .iter()
+        .filter(|(path, stat)| destination.get(*path) != Some(stat))
+        .map(|(path, _)| path.clone())
+        .collect()
+}
+
+/// Names for `tar --null -T -`
+pub fn tar_file_list(files: &[String]) -> Vec<u8> {
+    files
+        .iter()
+        .flat_map(|path| path.bytes().chain([0]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_listings() {
+        let remote = parse_listing(
+            b"12\t1700000000.5\tapp/main.js\0\
+            3\t1700000000.0\tREADME\0\
+            bad entry\0",
+        );
+        assert_eq!(
+            remote.get("app/main.js"),
+            Some(&FileStat {
+                size: 12,
+                mtime: 1700000000
+            })
+        );
+        assert_eq!(remote.len(), 2);
+
+        let mut local = remote.clone();
+        local.insert(
+            "README".into(),
+            FileStat {
+                size: 3,
+                mtime: 1700000100,
+            },
+        );
+        local.insert(
+            "app/new.js".into(),
+            FileStat {
+                size: 1,
+                mtime: 1700000000,
+            },
+        );
+        assert_eq!(changed_files(&local, &remote), ["README", "app/new.js"]);
+        // Files only at the destination are left alone
+        assert_eq!(changed_files(&remote, &local), ["README"]);
+        assert_eq!(tar_file_list(&["a".into(), "b/c".into()]), b"a\0b/c\0");
+    }
+}
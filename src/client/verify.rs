@@ -0,0 +1,490 @@
+use clap::ValueEnum;
+use log::{info, warn};
+use russh::keys::{Algorithm, HashAlg, PublicKey, PublicKeyBase64};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::future::Future;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::client::known_hosts::{self, Entry, Verdict};
+use crate::client::prompt::PromptProvider;
+use crate::error::FileError;
+use crate::storage::event_log::utc_timestamp;
+
+/// Boxed, so that verifiers can be chosen at runtime
+pub type VerifyFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<bool>> + Send + 'a>>;
+
+/// Decides whether the key presented by a server is to be trusted
+pub trait HostKeyVerifier: Send + Sync {
+    fn verify<'a>(&'a self, host: &'a HostInfo, key: &'a PublicKey) -> VerifyFuture<'a>;
+}
+
+/// The server whose key is being verified
+#[derive(Clone, Debug)]
+pub struct HostInfo {
+    /// Address as configured (a hostname or an IP)
+    pub address: String,
+    pub ip: IpAddr,
+    pub port: u16,
+}
+
+/// How server keys are verified, selectable in the config
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyCheck {
+    /// Look up known_hosts files, ask before trusting a new server
+    #[default]
+    KnownHosts,
+    /// Accept only the fingerprints listed in `pinned_keys`
+    Pinned,
+    /// Accept any key (insecure, for throwaway machines)
+    AcceptAll,
+}
+
+/// Trust on first use backed by OpenSSH-compatible known_hosts files
+pub struct KnownHostsVerifier {
+    /// Writable known_hosts file, new keys are added here
+    pub known_hosts: PathBuf,
+    /// Read-only known_hosts files (e.g. OpenSSH ones)
+    pub extra_known_hosts: Vec<PathBuf>,
+    /// Asks whether to trust a new server
+    pub prompts: Arc<dyn PromptProvider>,
+    /// Show the randomart of the key next to its fingerprint
+    pub visual_host_key: bool,
+    /// Don't write to the known_hosts file (e.g. it's managed centrally),
+    /// a new server is trusted for this connection only
+    pub read_only: bool,
+    /// Replace the recorded key of a server whose key changed without asking
+    pub replace_changed: bool,
+}
+
+/// Accepts only keys with the given fingerprints (`SHA256:...`)
+pub struct PinnedVerifier(pub Vec<String>);
+
+/// Accepts every key without looking
+pub struct AcceptAllVerifier;
+
+/// Leaves the decision to a function, e.g. a dialog of a GUI
+pub struct CallbackVerifier<F>(pub F);
+
+impl HostKeyVerifier for KnownHostsVerifier {
+    fn verify<'a>(&'a self, host: &'a HostInfo, key: &'a PublicKey) -> VerifyFuture<'a> {
+        Box::pin(self.check(host, key))
+    }
+}
+
+impl HostKeyVerifier for PinnedVerifier {
+    fn verify<'a>(&'a self, host: &'a HostInfo, key: &'a PublicKey) -> VerifyFuture<'a> {
+        let fingerprint = key.fingerprint(HashAlg::default()).to_string();
+        let pinned = self.0.contains(&fingerprint);
+        if pinned {
+            info!("Server public key matches a pinned fingerprint");
+        } else {
+            eprintln!(
+                "Server public key {} of {} is not among the pinned ones",
+                fingerprint, host.ip
+            );
+        }
+
+        Box::pin(async move { Ok(pinned) })
+    }
+}
+
+impl HostKeyVerifier for AcceptAllVerifier {
+    fn verify<'a>(&'a self, host: &'a HostInfo, _key: &'a PublicKey) -> VerifyFuture<'a> {
+        warn!("Accepting the key of {} without verification", host.ip);
+
+        Box::pin(async { Ok(true) })
+    }
+}
+
+impl<F> HostKeyVerifier for CallbackVerifier<F>
+where
+    F: Fn(&HostInfo, &PublicKey) -> bool + Send + Sync,
+{
+    fn verify<'a>(&'a self, host: &'a HostInfo, key: &'a PublicKey) -> VerifyFuture<'a> {
+        let trusted = (self.0)(host, key);
+
+        Box::pin(async move { Ok(trusted) })
+    }
+}
+
+impl KnownHostsVerifier {
+    async fn check(&self, host: &HostInfo, key: &PublicKey) -> anyhow::Result<bool> {
+        info!(
+            "Checking server public key in '{}'...",
+            self.known_hosts.display()
+        );
+
+        if !self.read_only && !self.known_hosts.exists() {
+            if let Some(parent) = self.known_hosts.parent() {
+                fs::create_dir_all(parent).await.map_err(FileError::from)?;
+            }
+            fs::write(&self.known_hosts, "")
+                .await
+                .map_err(FileError::from)?;
+        }
+        let files: Vec<PathBuf> = std::iter::once(self.known_hosts.clone())
+            .chain(self.extra_known_hosts.iter().cloned())
+            .collect();
+        match known_hosts::check(&files, &host.address, host.ip, host.port, key).await {
+            Verdict::Trusted => {
+                info!("Server public key matches known host entry");
+                Ok(true)
+            }
+            Verdict::Revoked { path, line } => {
+                eprintln!(
+                    "Server public key is revoked in '{}' (line {})",
+                    path.display(),
+                    line
+                );
+                Ok(false)
+            }
+            Verdict::Changed { path, entry } => {
+                info!(
+                    "Found existing host key in '{}' (line {})",
+                    path.display(),
+                    entry.line
+                );
+                self.handle_key_changed(host, key, &path, &entry).await
+            }
+            Verdict::Unknown => self.handle_unknown_host(host, key).await,
+        }
+    }
+
+    async fn handle_unknown_host(&self, host: &HostInfo, key: &PublicKey) -> anyhow::Result<bool> {
+        let fingerprint = key.fingerprint(HashAlg::default());
+
+        let question = format!(
+            "*Alright, here is the door: {}*\n\
+            - Knock, knock!\n\
+            - \"Greetings! I am {} {}, and you?\"\n{}\
+            *Hmm, I don't recognize this one...*\n\n\
+            {} (yes/no/[fingerprint]): ",
+            host.ip,
+            key.algorithm(),
+            fingerprint,
+            self.randomart(key),
+            if self.read_only {
+                "Trust for this connection only ('known_hosts' is read-only)?"
+            } else {
+                "Trust and add to 'known_hosts'?"
+            },
+        );
+        let input = self.prompts.text(&question).map_err(FileError::Std)?;
+        let input = input.trim();
+
+        if input.eq_ignore_ascii_case("y")
+            || input.eq_ignore_ascii_case("yes")
+            || input.as_bytes() == fingerprint.to_string().as_bytes()
+        {
+            if self.read_only {
+                info!(
+                    "Not adding the key to read-only '{}'",
+                    self.known_hosts.display()
+                );
+            } else {
+                self.trust_host(host, key).await?;
+            }
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// The stale entry can be replaced with the new key (asked unless `replace_changed`),
+    /// it's only removed from the writable file, other ones are shadowed by the new entry
+    async fn handle_key_changed(
+        &self,
+        host: &HostInfo,
+        key: &PublicKey,
+        path: &Path,
+        entry: &Entry,
+    ) -> anyhow::Result<bool> {
+        eprintln!(
+            "*Ah, home sweet home: {}*\n\
+            - Knock, knock!\n\
+            - \"Greetings! I am {} {}, and you?\"\n\
+            *Wait a minute. You are not the guy who usually lives here.*\n\
+            *Did he move out? ...or are you trying to pretend to be him? (Man-in-the-Middle)*\n\
+            *I better get out of here fast!*\n\n\
+            We should probably forget our old key and remove it from 'known_hosts'.\n\
+            Or, if this is a trap... we should report this incident!\n",
+            host.ip,
+            key.algorithm(),
+            key.fingerprint(HashAlg::default()),
+        );
+        let recorded = PublicKey::from_openssh(&format!("{} {}", entry.algorithm, entry.key)).ok();
+        let fingerprint = |key: &PublicKey| key.fingerprint(HashAlg::default()).to_string();
+        let recorded_fingerprint = recorded
+            .as_ref()
+            .map_or("(unreadable key)".into(), fingerprint);
+        let (algorithm, presented_fingerprint) = (key.algorithm(), fingerprint(key));
+        let location = format!("{}, line {}", path.display(), entry.line);
+        let presented_by = format!("{}:{}", host.ip, host.port);
+        let mut rows = vec![
+            ["", "Recorded", "Presented"],
+            ["Type", &entry.algorithm, algorithm.as_str()],
+            ["Fingerprint", &recorded_fingerprint, &presented_fingerprint],
+            ["From", &location, &presented_by],
+            ["Added", entry.added().unwrap_or("(unknown)"), "now"],
+        ];
+        // Pictures are easier to tell apart than fingerprints
+        let pictures = recorded
+            .as_ref()
+            .map(|recorded| (self.randomart(recorded), self.randomart(key)));
+        if let Some((old, new)) = &pictures {
+            rows.extend(
+                old.lines()
+                    .zip(new.lines())
+                    .map(|(old, new)| ["", old, new]),
+            );
+        }
+        eprint!("{}", side_by_side(&rows));
+        if self.read_only {
+            return Ok(false);
+        }
+
+        let replace = self.replace_changed || {
+            let input = self
+                .prompts
+                .text("Replace the recorded key with the presented one? (yes/no): ")
+                .map_err(FileError::Std)?;
+            let input = input.trim();
+            input.eq_ignore_ascii_case("y") || input.eq_ignore_ascii_case("yes")
+        };
+        if !replace {
+            return Ok(false);
+        }
+        if path == self.known_hosts {
+            known_hosts::remove_entry(path, entry.line)?;
+        }
+        self.trust_host(host, key).await?;
+        warn!(
+            "Replaced the key of {} in '{}'",
+            host.ip,
+            self.known_hosts.display()
+        );
+
+        Ok(true)
+    }
+
+    /// Picture of the fingerprint (as OpenSSH's VisualHostKey), if enabled
+    fn randomart(&self, key: &PublicKey) -> String {
+        if !self.visual_host_key {
+            return String::new();
+        }
+        let header = match key.algorithm() {
+            Algorithm::Ed25519 => "[ED25519 256]".into(),
+            Algorithm::Ecdsa { curve } => {
+                format!("[ECDSA {}]", curve.as_str().trim_start_matches("nistp"))
+            }
+            Algorithm::Rsa { .. } => "[RSA]".into(),
+            other => format!("[{}]", other.as_str().to_uppercase()),
+        };
+
+        format!(
+            "{}\n",
+            key.fingerprint(HashAlg::default()).to_randomart(&header)
+        )
+    }
+
+    async fn trust_host(&self, host: &HostInfo, key: &PublicKey) -> anyhow::Result<()> {
+        let added = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        // Other ports only match in brackets, see `known_hosts::host_candidates`
+        let entry = format!(
+            "{} {} {} {}{}\n",
+            known_hosts::host_name(&host.ip.to_string(), host.port),
+            key.algorithm(),
+            key.public_key_base64(),
+            known_hosts::ADDED_PREFIX,
+            utc_timestamp(added)
+        );
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.known_hosts)
+            .await
+            .map_err(FileError::from)?;
+        file.write_all(entry.as_bytes())
+            .await
+            .map_err(FileError::from)?;
+        // Writes of tokio files complete in the background otherwise
+        file.flush().await.map_err(FileError::from)?;
+
+        Ok(())
+    }
+}
+
+/// Rows of a label and two values, with the columns aligned
+fn side_by_side(rows: &[[&str; 3]]) -> String {
+    let width = |i: usize| {
+        rows.iter()
+            .map(|row| row[i].chars().count())
+            .max()
+            .unwrap_or_default()
+    };
+    let (labels, values) = (width(0), width(1));
+
+    rows.iter()
+        .map(|[label, old, new]| format!("{label:labels$}  {old:values$}  {new}\n"))
+        .collect()
+}
+
+impl Display for HostKeyCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::KnownHosts => "known-hosts",
+            Self::Pinned => "pinned",
+            Self::AcceptAll => "accept-all",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+
+    #[test]
+    fn pinned_and_callback() {
+        let key = PublicKey::from_openssh(&format!("ssh-ed25519 {KEY}")).expect("Valid key");
+        let host = HostInfo {
+            address: "db".into(),
+            ip: "10.0.0.1".parse().unwrap(),
+            port: 22,
+        };
+        let fingerprint = key.fingerprint(HashAlg::default()).to_string();
+        let rt = Runtime::new().unwrap();
+        let verify =
+            |verifier: &dyn HostKeyVerifier| rt.block_on(verifier.verify(&host, &key)).unwrap();
+
+        assert!(verify(&PinnedVerifier(vec![fingerprint])));
+        assert!(!verify(&PinnedVerifier(vec!["SHA256:other".into()])));
+        assert!(verify(&CallbackVerifier(
+            |host: &HostInfo, _: &PublicKey| host.port == 22
+        )));
+        assert!(verify(&AcceptAllVerifier));
+    }
+
+    struct Answer(&'static str);
+
+    impl PromptProvider for Answer {
+        fn text(&self, _prompt: &str) -> std::io::Result<String> {
+            Ok(self.0.into())
+        }
+        fn secret(&self, _prompt: &str) -> std::io::Result<secrecy::SecretString> {
+            Ok(self.0.into())
+        }
+        fn message(&self, _text: &str) {}
+    }
+
+    #[test]
+    fn trust_on_first_use() {
+        let key = PublicKey::from_openssh(&format!("ssh-ed25519 {KEY}")).expect("Valid key");
+        let host = HostInfo {
+            address: "10.0.0.1".into(),
+            ip: "10.0.0.1".parse().unwrap(),
+            port: 22,
+        };
+        let known_hosts = std::env::temp_dir().join(format!("shh-tofu-{}", std::process::id()));
+        let verifier = |answer| KnownHostsVerifier {
+            known_hosts: known_hosts.clone(),
+            extra_known_hosts: Vec::new(),
+            prompts: Arc::new(Answer(answer)),
+            visual_host_key: true,
+            read_only: false,
+            replace_changed: false,
+        };
+        let rt = Runtime::new().unwrap();
+
+        assert!(
+            verifier("no")
+                .randomart(&key)
+                .starts_with("+--[ED25519 256]--+")
+        );
+        assert!(!rt.block_on(verifier("no").verify(&host, &key)).unwrap());
+        assert!(rt.block_on(verifier("yes").verify(&host, &key)).unwrap());
+        // Known from now on, no question is asked
+        assert!(rt.block_on(verifier("no").verify(&host, &key)).unwrap());
+        std::fs::remove_file(&known_hosts).unwrap();
+
+        // Trusted once, but neither the file nor the key is written
+        let read_only = |answer| KnownHostsVerifier {
+            read_only: true,
+            ..verifier(answer)
+        };
+        assert!(rt.block_on(read_only("yes").verify(&host, &key)).unwrap());
+        assert!(!known_hosts.exists());
+        assert!(!rt.block_on(read_only("no").verify(&host, &key)).unwrap());
+
+        // A changed key is kept out unless replaced
+        let old = "AAAAC3NzaC1lZDI1NTE5AAAAIBLmHz0N0BfnRyGz5ucDsy4HCyJBDTn5j3cDdFFd4IFe";
+        std::fs::write(&known_hosts, format!("10.0.0.1 ssh-ed25519 {old}\n")).unwrap();
+        assert!(!rt.block_on(verifier("no").verify(&host, &key)).unwrap());
+        assert!(rt.block_on(verifier("yes").verify(&host, &key)).unwrap());
+        let entries = known_hosts::parse(&std::fs::read_to_string(&known_hosts).unwrap());
+        std::fs::remove_file(&known_hosts).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, KEY);
+        assert!(entries[0].added().is_some());
+    }
+
+    #[test]
+    fn trust_on_other_port() {
+        let key = PublicKey::from_openssh(&format!("ssh-ed25519 {KEY}")).expect("Valid key");
+        let host = HostInfo {
+            address: "db".into(),
+            ip: "10.0.0.1".parse().unwrap(),
+            port: 2222,
+        };
+        // Not the file of the work dir, so a plain IP wouldn't name the host
+        let known_hosts =
+            std::env::temp_dir().join(format!("shh-tofu-port-{}", std::process::id()));
+        let verifier = |answer| KnownHostsVerifier {
+            known_hosts: known_hosts.clone(),
+            extra_known_hosts: Vec::new(),
+            prompts: Arc::new(Answer(answer)),
+            visual_host_key: false,
+            read_only: false,
+            replace_changed: false,
+        };
+        let rt = Runtime::new().unwrap();
+
+        assert!(rt.block_on(verifier("yes").verify(&host, &key)).unwrap());
+        assert!(rt.block_on(verifier("no").verify(&host, &key)).unwrap());
+        let content = std::fs::read_to_string(&known_hosts).unwrap();
+        std::fs::remove_file(&known_hosts).unwrap();
+        let entries = known_hosts::parse(&content);
+        assert_eq!(entries.len(), 1);
+        assert!(content.starts_with("[10.0.0.1]:2222 ssh-ed25519 "));
+        // The default port is still unknown
+        let default_port = HostInfo { port: 22, ..host };
+        assert!(!entries[0].matches(&known_hosts::host_candidates(
+            &default_port.address,
+            default_port.ip,
+            default_port.port,
+            false
+        )));
+    }
+
+    #[test]
+    fn aligned_columns() {
+        let table = side_by_side(&[["", "Recorded", "Presented"], ["Added", "(unknown)", "now"]]);
+        assert_eq!(
+            table,
+            "       Recorded   Presented\nAdded  (unknown)  now\n"
+        );
+    }
+}
@@ -1,11 +1,16 @@
+use serde::Serialize;
 use thiserror::Error;
 
+use crate::client::profile::SecurityProfile;
+
 #[derive(Error, Debug)]
 pub enum CliError {
     #[error("Host must be specified")]
     HostMissing,
     #[error("User must be specified when '@' is present")]
     UserMissing,
+    #[error("Invalid connection option in the URI: {0}")]
+    InvalidQuery(Box<str>),
     #[error("Port must be a number specified after ':'")]
     PortMissing,
     #[error("Invalid IPv6: {0}")]
@@ -14,10 +19,60 @@ pub enum CliError {
     ServerNotFound(Box<str>),
     #[error("Scope '{0}' not found")]
     ScopeNotFound(Box<str>),
+    #[error("Context '{0}' not found")]
+    ContextNotFound(Box<str>),
+    #[error("Context '{0}' is in use, switch to another one first")]
+    ContextInUse(Box<str>),
     #[error("Server '{0}' already exists")]
     ServerExists(Box<str>),
     #[error("Scope '{0}' already exists")]
     ScopeExists(Box<str>),
+    #[error("Invalid value '{1}' for '{0}'")]
+    InvalidValue(&'static str, Box<str>),
+    #[error("Command alias '{0}' has unbalanced quotes (hint: check the config file)")]
+    InvalidAlias(Box<str>),
+    #[error("A command is required when servers are read from stdin")]
+    CommandRequired,
+    #[error("Confirmation is needed, but stdin is not a terminal (hint: pass {0})")]
+    ConfirmationRequired(&'static str),
+    #[error("Failed on {0} of {1} servers")]
+    BatchFailed(usize, usize),
+    #[error("The {0} hook failed ({1})")]
+    HookFailed(&'static str, Box<str>),
+    #[error("Invalid subnet '{0}' (expected CIDR, e.g. 10.0.0.0/8)")]
+    InvalidSubnet(Box<str>),
+    #[error("Audit failed with {0} error(s)")]
+    AuditFailed(usize),
+    #[error("Config test failed with {0} error(s)")]
+    ConfigTestFailed(usize),
+    #[error("Check failed in {0} step(s)")]
+    CheckFailed(usize),
+    #[error("Invalid public key file '{0}': {1}")]
+    InvalidPublicKey(Box<str>, Box<str>),
+    #[error("Invalid forward '{0}' (expected [BIND:]PORT:HOST:HOSTPORT)")]
+    InvalidForward(Box<str>),
+    #[error("Forward '{0}' is already running")]
+    ForwardRunning(Box<str>),
+    #[error("Forward '{0}' is not running")]
+    ForwardNotRunning(Box<str>),
+    #[error("Forward '{0}' failed to start (see '{1}')")]
+    ForwardFailed(Box<str>, Box<str>),
+    #[error("Failed to read script '{0}': {1}")]
+    ScriptUnreadable(Box<str>, std::io::Error),
+    #[error("Script exited with status {0}")]
+    ScriptFailed(u32),
+    #[error("Invalid remote path '{0}' (expected SERVER:PATH)")]
+    InvalidRemotePath(Box<str>),
+    #[error("Failed to copy: {0}")]
+    CopyFailed(Box<str>),
+    #[error("'{0}' is not a directory")]
+    NotADirectory(Box<str>),
+    #[error("Invalid CSV row {0}: {1}")]
+    InvalidCsvRow(usize, Box<str>),
+    #[error("The address column must be mapped (e.g. --map address=2)")]
+    AddressNotMapped,
+    #[error("Variable '{0}' is not defined (hint: add it to vars in the config file)")]
+    UndefinedVariable(Box<str>),
 }
 
 #[derive(Error, Debug)]
@@ -30,6 +85,30 @@ pub enum FileError {
     Json(#[from] serde_json::Error),
 }
 
+#[derive(Error, Debug)]
+pub enum SecretError {
+    #[error("Invalid secret reference '{0}' (hint: use keyring:NAME)")]
+    InvalidRef(Box<str>),
+    #[error("OS keyring is not available (hint: install 'secret-tool' or use macOS 'security')")]
+    KeyringUnavailable,
+    #[error("Keyring problem: {0}")]
+    Keyring(String),
+}
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error("Git is not available (hint: install git)")]
+    GitUnavailable,
+    #[error("Git problem: {0}")]
+    Git(String),
+    #[error(
+        "Remote changes conflict with local ones: {0} (hint: resolve them in the work directory)"
+    )]
+    Conflict(String),
+    #[error("'{0}' holds a private key, it's not synced (hint: remove its line from .gitignore)")]
+    PrivateKey(String),
+}
+
 #[derive(Error, Debug)]
 pub enum ConnectionError {
     #[error("No user is specified for the server (hint: check the config file)")]
@@ -38,6 +117,22 @@ pub enum ConnectionError {
     Regex(#[from] regex_lite::Error),
     #[error("DNS resolution error: {0}")]
     Dns(#[from] std::io::Error),
+    #[error(
+        "None of the configured {0} algorithms is allowed by the '{1}' profile (hint: check the config file)"
+    )]
+    ProfileExcludes(&'static str, SecurityProfile),
+    #[error("The buffer size must be greater than 0 (hint: check the config file)")]
+    ZeroBufferSize,
+}
+
+#[derive(Error, Debug)]
+pub enum ProbeError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("timed out")]
+    Timeout,
+    #[error("no SSH banner (not an SSH server?)")]
+    NoBanner,
 }
 
 #[derive(Error, Debug)]
@@ -60,6 +155,149 @@ pub enum SessionError {
     AuthUnavailable,
     #[error("Authentication failed, available methods: {0}")]
     AuthFailed(String),
+    #[error("Key authentication failed and password authentication is off, available methods: {0}")]
+    PasswordAuthDisabled(String),
     #[error("Failed to adjust terminal: {0}")]
     Terminal(#[source] russh::Error),
+    #[error("Session was closed")]
+    Closed,
+    #[error("Server didn't answer within {0}s")]
+    NoReply(u64),
+    #[error("Command exited with status {0}")]
+    CommandFailed(u32),
+}
+
+/// Kind of failure, reported to the calling process as the exit code
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Failure {
+    /// Anything not covered by the other kinds
+    General = 1,
+    /// Invalid arguments (the same code clap uses)
+    Usage = 2,
+    /// Unreadable or invalid config, context or secrets
+    Config = 3,
+    /// Unknown server or scope, or an address that doesn't resolve
+    Resolution = 4,
+    /// Server is unreachable or the SSH session broke
+    Connection = 5,
+    /// Server rejected all authentication attempts
+    Auth = 6,
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::General => "general",
+            Self::Usage => "usage",
+            Self::Config => "config",
+            Self::Resolution => "resolution",
+            Self::Connection => "connection",
+            Self::Auth => "auth",
+        })
+    }
+}
+
+impl Failure {
+    /// Kind of the outermost known error in the chain
+    pub fn of(err: &anyhow::Error) -> Self {
+        err.chain()
+            .find_map(Self::classify)
+            .unwrap_or(Self::General)
+    }
+
+    fn classify(err: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        if let Some(err) = err.downcast_ref::<CliError>() {
+            return Some(match err {
+                CliError::HostMissing
+                | CliError::UserMissing
+                | CliError::InvalidQuery(_)
+                | CliError::InvalidRemotePath(_)
+                | CliError::PortMissing
+                | CliError::InvalidIPv6(_)
+                | CliError::InvalidValue(..)
+                | CliError::InvalidForward(_)
+                | CliError::AddressNotMapped
+                | CliError::CommandRequired
+                | CliError::ConfirmationRequired(_) => Self::Usage,
+                CliError::ServerNotFound(_)
+                | CliError::ScopeNotFound(_)
+                | CliError::ContextNotFound(_) => Self::Resolution,
+                CliError::InvalidAlias(_)
+                | CliError::InvalidSubnet(_)
+                | CliError::InvalidCsvRow(..)
+                | CliError::UndefinedVariable(_)
+                | CliError::AuditFailed(_)
+                | CliError::ConfigTestFailed(_)
+                | CliError::InvalidPublicKey(..) => Self::Config,
+                CliError::ServerExists(_)
+                | CliError::ScopeExists(_)
+                | CliError::BatchFailed(..)
+                | CliError::HookFailed(..)
+                | CliError::ContextInUse(_)
+                | CliError::ForwardRunning(_)
+                | CliError::ForwardNotRunning(_)
+                | CliError::ScriptUnreadable(..)
+                | CliError::ScriptFailed(_)
+                | CliError::CheckFailed(_)
+                | CliError::CopyFailed(_)
+                | CliError::NotADirectory(_) => Self::General,
+                CliError::ForwardFailed(..) => Self::Connection,
+            });
+        }
+        if let Some(err) = err.downcast_ref::<ConnectionError>() {
+            return Some(match err {
+                ConnectionError::UserRequired
+                | ConnectionError::Regex(_)
+                | ConnectionError::ProfileExcludes(..)
+                | ConnectionError::ZeroBufferSize => Self::Config,
+                ConnectionError::Dns(_) => Self::Resolution,
+            });
+        }
+        if let Some(err) = err.downcast_ref::<SessionError>() {
+            return Some(match err {
+                SessionError::Connect(_)
+                | SessionError::Terminal(_)
+                | SessionError::Closed
+                | SessionError::NoReply(_) => Self::Connection,
+                SessionError::CommandFailed(_) => Self::General,
+                _ => Self::Auth,
+            });
+        }
+        if err.is::<FileError>() || err.is::<SecretError>() {
+            return Some(Self::Config);
+        }
+        if err.is::<ProbeError>() {
+            return Some(Self::Connection);
+        }
+
+        None
+    }
+}
+
+impl From<Failure> for std::process::ExitCode {
+    fn from(failure: Failure) -> Self {
+        Self::from(failure as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_of_error_chain() {
+        let not_found = anyhow::Error::from(CliError::ServerNotFound("db".into()));
+        assert_eq!(Failure::of(&not_found), Failure::Resolution);
+
+        let auth = anyhow::Error::from(SessionError::AuthFailed("password".into()));
+        assert_eq!(Failure::of(&auth), Failure::Auth);
+        // The outermost known error wins
+        let refused = SessionError::Connect(FileError::Std(std::io::Error::other("")).into());
+        assert_eq!(Failure::of(&refused.into()), Failure::Connection);
+
+        let context = anyhow::Error::from(FileError::Std(std::io::Error::other(""))).context("x");
+        assert_eq!(Failure::of(&context), Failure::Config);
+        assert_eq!(Failure::of(&anyhow::anyhow!("unknown")), Failure::General);
+    }
 }
@@ -1,82 +1,454 @@
+/// Informational message for humans, suppressed by `--quiet`
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !$crate::cli::control::is_quiet() {
+            println!($($arg)*);
+        }
+    };
+}
+
 pub(crate) mod cli {
+    pub mod complete;
     pub mod control;
     pub mod output;
+    pub mod pager;
     pub mod parser;
+    pub mod picker;
+    pub mod shell_init;
+    pub mod tui;
 }
 pub(crate) mod storage {
+    pub mod audit;
     pub mod config;
     pub mod context;
+    pub mod csv;
+    pub mod event_log;
+    pub mod import;
+    pub mod network;
     pub mod provider;
+    pub mod secret;
+    pub mod sync;
+    pub mod theme;
 }
 pub(crate) mod client {
     pub mod connect;
     pub mod data;
+    pub mod forward;
     pub mod handler;
+    pub mod hooks;
+    pub mod known_hosts;
+    pub mod mdns;
+    pub mod preflight;
+    pub mod probe;
+    pub mod profile;
+    pub mod prompt;
+    pub mod socket;
+    pub mod transcript;
+    pub mod transfer;
+    pub mod verify;
 }
 pub(crate) mod error;
 
+use crate::cli::complete::{CompletionKind, dynamic_script};
 pub use crate::cli::control::start_cli;
-use crate::cli::output::TestOutput;
-use crate::cli::parser::{Cli, CliSubcommand, ServerUri};
-use crate::client::connect::initiate_connection;
-use crate::client::data::ConnectionData;
-use crate::error::CliError;
-use crate::storage::config::{Config, Scope, Server, ServerEntry};
-use crate::storage::context::Context;
-use crate::storage::provider::{CONFIG_PATH, StorageProvider, ensure_work_dir};
+use crate::cli::control::{replace_host_key, use_dns_cache};
+use crate::cli::output::{
+    AuditOutput, CheckOutput, CompletionOutput, ContextsOutput, CsvOutput, CurrentOutput,
+    DiscoverOutput, FindOutput, ForwardsOutput, HistoryOutput, ImportOutput, JsonOutput,
+    JsonServer, LastSeen, LongOutput, PingOutput, PruneOutput, ResolveOutput, RmOutput, SetPreview,
+    TestOutput, TreeOutput, UtcTime, WarmOutput, WatchOutput,
+};
+use crate::cli::parser::{
+    Cli, CliSubcommand, ContextAction, ExportFormat, ForwardAction, HostsAction, ImportSource,
+    ListFormat, OutputFormat, PromptStyle, RemotePath, SecretAction, ServerUri, SyncAction,
+    read_servers,
+};
+use crate::cli::picker::pick;
+use crate::cli::shell_init::{self, DEFAULT_TEMPLATE, PromptShell};
+use crate::cli::tui;
+pub use crate::client::connect::Session;
+use crate::client::connect::{initiate_connection, remote_path_arg};
+pub use crate::client::data::ConnectionData;
+use crate::client::data::{DEFAULT_KNOWN_HOSTS_FILE, DEFAULT_SSH_PORT};
+use crate::client::forward::{
+    self, DetachedPrompts, ForwardSpec, ForwardState, ForwardStatus, Link,
+};
+use crate::client::hooks::Visit;
+use crate::client::known_hosts::{self, add_cert_authority};
+use crate::client::mdns::{self, Announced};
+use crate::client::preflight::{self, Step, StepStatus};
+use crate::client::probe::{Probe, probe, run_limited};
+pub use crate::client::prompt::{PromptProvider, SerialPrompts, TerminalPrompts};
+use crate::client::transfer;
+pub use crate::client::verify::{
+    AcceptAllVerifier, CallbackVerifier, HostInfo, HostKeyCheck, HostKeyVerifier,
+    KnownHostsVerifier, PinnedVerifier,
+};
+use crate::error::{CliError, ConnectionError, FileError, SessionError};
+use crate::storage::audit::audit;
+use crate::storage::config::{
+    Config, Finding, PruneCriteria, Scope, Server, ServerEntry, ServerFilter, Severity, is_pattern,
+};
+use crate::storage::context::{
+    Context, SCOPE_ENV, SCOPE_FILENAME, active_scope, env_scope, find_scope_file, scope_with_origin,
+};
+use crate::storage::import::{parse_csv, parse_hosts};
+use crate::storage::provider::{
+    CACHE_PATH, CONFIG_PATH, StorageProvider, WORK_DIR, cli_path, ensure_work_dir, get_full_path,
+};
+use crate::storage::secret;
+use crate::storage::sync::sync_work_dir;
 use clap::CommandFactory;
 use clap_complete::generate;
-use log::info;
-use regex_lite::Regex;
+use crossterm::cursor::MoveTo;
+use crossterm::execute;
+use crossterm::terminal::{Clear, ClearType};
+use log::{debug, info, trace, warn};
+use russh::keys::{HashAlg, load_public_key};
+use secrecy::SecretString;
+use std::cmp::Reverse;
 use std::fmt::Display;
-use std::path::Path;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::{env, mem};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, fs, mem};
 use tokio::runtime::Runtime;
 
+/// Days without connections after which prune flags a server by default
+const DEFAULT_PRUNE_DAYS: u64 = 90;
+/// Completion gives up on servers that take longer to list a directory
+const REMOTE_COMPLETION_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub(crate) fn handle_server_connection(
-    mut server_uri: ServerUri,
+    server_uri: ServerUri,
     remote_cmd: Option<String>,
     conn_flags: Scope,
 ) -> anyhow::Result<()> {
+    if server_uri.is_stdin() {
+        return run_on_servers(remote_cmd, conn_flags);
+    }
     info!("Searching for server configuration...");
 
     let mut config = Config::load_from_file()?;
-    let current_scope = Context::load_from_file()?.into_scope();
+    config.expand_vars()?;
+    let current_scope = active_scope(&config)?;
+    connect_in_scope(
+        server_uri,
+        remote_cmd,
+        conn_flags,
+        &mut config,
+        current_scope,
+    )
+}
+
+/// Runs the command on every server listed in stdin, one after another.
+/// A failure is reported without stopping the rest.
+fn run_on_servers(remote_cmd: Option<String>, conn_flags: Scope) -> anyhow::Result<()> {
+    // Stdin is taken by the list, so an interactive session can't work
+    let remote_cmd = remote_cmd.ok_or(CliError::CommandRequired)?;
+    let servers = read_servers(std::io::stdin().lock())?;
+    let mut config = Config::load_from_file()?;
+    config.expand_vars()?;
+    let current_scope = active_scope(&config)?;
+    info!("Running '{}' on {} server(s)", remote_cmd, servers.len());
+
+    let (total, mut failed) = (servers.len(), 0);
+    for server_uri in servers {
+        let host = server_uri.address.clone();
+        status!("==> {} <==", host);
+        if let Err(err) = connect_in_scope(
+            server_uri,
+            Some(remote_cmd.clone()),
+            conn_flags.clone(),
+            &mut config,
+            current_scope.clone(),
+        ) {
+            eprintln!("{}: {}", host, err);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(CliError::BatchFailed(failed, total).into());
+    }
+
+    Ok(())
+}
+
+/// Connects to the server as if `current_scope` was the active scope,
+/// the variables of the config have to be expanded
+fn connect_in_scope(
+    server_uri: ServerUri,
+    remote_cmd: Option<String>,
+    conn_flags: Scope,
+    config: &mut Config,
+    current_scope: String,
+) -> anyhow::Result<()> {
+    let host = server_uri.address.clone();
+    let mut data = connection_data_with(server_uri, remote_cmd, conn_flags, config, current_scope)?;
+    trace!("{:#?}", data);
+    let visit = Visit::begin(host, &mut data)?;
+
+    let rt = Runtime::new()?;
+    let result = rt.block_on(initiate_connection(data));
+    let exit_status = result.as_ref().ok().copied().flatten();
+    visit.end(&result, exit_status)?;
+    result?;
+
+    Ok(())
+}
 
-    let server =
-        resolve_server(&server_uri.address, &mut config, current_scope)?.unwrap_or_else(|| {
+/// Resolves the server through the config into everything needed to connect
+fn connection_data(
+    server_uri: ServerUri,
+    remote_cmd: Option<String>,
+    conn_flags: Scope,
+    mut config: Config,
+    current_scope: String,
+) -> anyhow::Result<ConnectionData> {
+    config.expand_vars()?;
+    connection_data_with(
+        server_uri,
+        remote_cmd,
+        conn_flags,
+        &mut config,
+        current_scope,
+    )
+}
+
+/// Same as [`connection_data`] for a config with expanded variables,
+/// which can be reused for other servers (e.g. of a batch)
+fn connection_data_with(
+    mut server_uri: ServerUri,
+    remote_cmd: Option<String>,
+    conn_flags: Scope,
+    config: &mut Config,
+    current_scope: String,
+) -> anyhow::Result<ConnectionData> {
+    debug!("Active scope: '{}'", current_scope);
+    let conn_flags = mem::take(&mut *server_uri.options).merged_with(conn_flags);
+
+    let server = match resolve_server(&server_uri.address, config, current_scope)? {
+        Some((mut server, scope)) => {
+            if let Some(scope) = scope {
+                server.apply_scope(scope);
+            }
+            server
+        }
+        None => {
             info!("No matching server configuration found");
             Server::from_uri_address(&mut server_uri)
-        });
+        }
+    };
 
-    let data = ConnectionData::new(
+    let mut data = ConnectionData::new(
         server_uri,
         remote_cmd,
         conn_flags,
         server,
-        config.default.unwrap_or_default(),
+        config.default.clone().unwrap_or_default(),
     )?;
-    let rt = Runtime::new()?;
-    rt.block_on(initiate_connection(data))?;
+    debug!(
+        "Resolved connection: {}@{}:{} (known_hosts '{}', private key {:?})",
+        data.user,
+        data.address,
+        data.port,
+        data.known_hosts.display(),
+        data.private_key,
+    );
+    data.dns_cache = use_dns_cache();
+    data.replace_host_key = replace_host_key();
 
-    Ok(())
+    Ok(data)
+}
+
+/// Connects to a server chosen in the interactive picker,
+/// the most frequently and recently used servers are offered first
+pub(crate) fn pick_server(conn_flags: Scope) -> anyhow::Result<()> {
+    let config = Config::load_from_file()?;
+    let mut names = config.server_names(&active_scope(&config)?);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let frecency = Context::load_from_file()?.frecency(now);
+    names.sort_by_cached_key(|name| Reverse(frecency.score([name])));
+
+    match pick(&names)? {
+        Some(name) => handle_server_connection(ServerUri::from_str(&name)?, None, conn_flags),
+        None => Ok(()),
+    }
+}
+
+/// Opens the browser and connects to the server chosen there
+fn browse_servers() -> anyhow::Result<()> {
+    let mut config = Config::load_from_file()?;
+    let current_scope = active_scope(&config)?;
+    let Some((scope, name)) = tui::run(&mut config, &current_scope)? else {
+        return Ok(());
+    };
+    // Global servers are looked up outside of any scope
+    let scope = scope.unwrap_or_default();
+    config.expand_vars()?;
+
+    connect_in_scope(
+        ServerUri::from_str(&name)?,
+        None,
+        Scope::default(),
+        &mut config,
+        scope,
+    )
+}
+
+pub(crate) fn resolve_connection(
+    mut server_uri: ServerUri,
+    conn_flags: Scope,
+) -> anyhow::Result<ResolveOutput> {
+    let mut config = Config::load_from_file()?;
+    config.expand_vars()?;
+    let current_scope = active_scope(&config)?;
+    let host = server_uri.address.clone();
+    let conn_flags = mem::take(&mut *server_uri.options).merged_with(conn_flags);
+
+    let (server, scope, scope_name) =
+        match resolve_server(&server_uri.address, &mut config, current_scope.clone())? {
+            Some((server, Some(scope))) => (server, scope, Some(current_scope)),
+            Some((server, None)) => (server, Scope::default(), None),
+            None => (
+                Server::from_uri_address(&mut server_uri),
+                Scope::default(),
+                None,
+            ),
+        };
+    let fields = ConnectionData::trace(
+        &server_uri,
+        &conn_flags,
+        &server.scope,
+        &scope,
+        &config.default.unwrap_or_default(),
+    );
+
+    Ok(ResolveOutput {
+        host,
+        address: server.address,
+        scope: scope_name,
+        fields,
+    })
 }
 
 pub(crate) fn execute_subcommand(cmd: CliSubcommand) -> anyhow::Result<Option<Box<dyn Display>>> {
     match cmd {
-        CliSubcommand::Ls { all, scopes } => Config::load_from_file()?
-            .list(Context::load_from_file()?.into_scope(), all, scopes)
-            .map(|o| Some(Box::new(o) as Box<dyn Display>)),
-        CliSubcommand::Use { scope } => {
-            if !Config::load_from_file()?.check_scope(&scope) {
+        CliSubcommand::Ls {
+            all,
+            scopes,
+            tag,
+            frecent,
+            user,
+            port,
+            key,
+            long,
+            last,
+            format,
+        } => {
+            let context = Context::load_from_file()?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let frecency = frecent.then(|| context.frecency(now));
+            let last_seen = last.then(|| LastSeen {
+                times: context.last_seen(),
+                now,
+            });
+
+            let mut config = Config::load_from_file()?;
+            config.expand_vars()?;
+            let current_scope = active_scope(&config)?;
+            let output = config.list(
+                current_scope,
+                all,
+                scopes,
+                &ServerFilter {
+                    tags: tag,
+                    user,
+                    port,
+                    private_key: key,
+                },
+                frecency.as_ref(),
+            )?;
+            let output: Box<dyn Display> = match format {
+                ListFormat::Tree if long || last => {
+                    Box::new(LongOutput(config.effective_servers(output), last_seen))
+                }
+                ListFormat::Tree => Box::new(output),
+                ListFormat::Json => Box::new(JsonOutput::from(output)),
+                ListFormat::Table | ListFormat::Wide => {
+                    Box::new(config.table(output, format == ListFormat::Wide, last_seen.as_ref()))
+                }
+            };
+
+            Ok(Some(output))
+        }
+        CliSubcommand::Show {
+            name,
+            scope,
+            format,
+        } => {
+            let mut config = Config::load_from_file()?;
+            let current_scope = active_scope(&config)?;
+            config
+                .show(name, scope, &current_scope)
+                .map(|o| Some(formatted(o, format)))
+        }
+        CliSubcommand::Tree => Ok(Some(Box::new(TreeOutput(Config::load_from_file()?)))),
+        CliSubcommand::Find { query } => {
+            let found = Config::load_from_file()?.find(&query);
+            if found.is_empty() {
+                return Err(CliError::ServerNotFound(query.into()).into());
+            }
+
+            Ok(Some(Box::new(FindOutput(found))))
+        }
+        CliSubcommand::Resolve {
+            server,
+            conn_flags,
+            format,
+        } => resolve_connection(server, conn_flags).map(|o| Some(formatted(o, format))),
+        CliSubcommand::Use { scope, ttl, auto } => {
+            let Some(scope) = scope.filter(|_| !auto) else {
+                return Context::load_from_file()?
+                    .change_scope(None)
+                    .save_to_file()
+                    .map(|_| None);
+            };
+            let config = Config::load_from_file()?;
+            if !config.check_scope(&scope) {
                 return Err(CliError::ScopeNotFound(scope.into()).into());
             }
+            if env_scope().is_some() {
+                warn!("{} overrides the scope in this shell", SCOPE_ENV);
+            } else if find_scope_file(&env::current_dir()?)?.is_some() {
+                warn!(
+                    "A {} file overrides the scope in this directory",
+                    SCOPE_FILENAME
+                );
+            }
+            let expires = match ttl {
+                Some(ttl) => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+                    let expires = (now + ttl).as_secs();
+                    status!(
+                        "Scope '{}' is active until {} (UTC)",
+                        scope,
+                        UtcTime(expires)
+                    );
+                    Some(expires)
+                }
+                None => None,
+            };
             Context::load_from_file()?
                 .change_scope(Some(scope))
+                .expire_scope_at(expires)
                 .save_to_file()
                 .map(|_| None)
         }
+        CliSubcommand::Current => show_current().map(|o| Some(Box::new(o) as _)),
         CliSubcommand::AddServer {
             name,
             server,
@@ -85,14 +457,90 @@ pub(crate) fn execute_subcommand(cmd: CliSubcommand) -> anyhow::Result<Option<Bo
             .add_server(name, server, global)?
             .save_to_file()
             .map(|_| None),
-        CliSubcommand::AddScope { name, scope } => Config::load_from_file()?
-            .add_scope(name, scope)?
+        CliSubcommand::AddScope {
+            name,
+            description,
+            scope,
+        } => Config::load_from_file()?
+            .add_scope(
+                name,
+                Scope {
+                    description,
+                    ..scope
+                },
+            )?
             .save_to_file()
             .map(|_| None),
-        CliSubcommand::Rm { server, scope } => Config::load_from_file()?
-            .remove(server, scope)?
+        CliSubcommand::Rm {
+            servers,
+            scope,
+            tag,
+            force,
+            dry_run,
+        } => remove_entries(servers, scope, tag, force, dry_run),
+        CliSubcommand::Set {
+            scope_name: Some(name),
+            servers: true,
+            dry_run,
+            yes,
+            scope,
+            ..
+        } => set_scope_servers(name, scope, dry_run, yes),
+        CliSubcommand::Set {
+            scope_name: Some(name),
+            description,
+            scope,
+            ..
+        } => Config::load_from_file()?
+            .set_scope(
+                name,
+                Scope {
+                    description,
+                    ..scope
+                },
+            )?
             .save_to_file()
             .map(|_| None),
+        CliSubcommand::Set {
+            name,
+            address,
+            fallback,
+            tags,
+            aliases,
+            notes,
+            scope,
+            ..
+        } => {
+            let config = Config::load_from_file()?;
+            let current_scope = active_scope(&config)?;
+            config
+                .set_server(
+                    name.expect("Server name is required"),
+                    address,
+                    fallback,
+                    tags,
+                    aliases,
+                    notes,
+                    scope,
+                    &current_scope,
+                )?
+                .save_to_file()
+                .map(|_| None)
+        }
+        CliSubcommand::Clone {
+            name,
+            new_name,
+            address,
+            scope,
+        } => {
+            let config = Config::load_from_file()?;
+            let current_scope = active_scope(&config)?;
+            config
+                .clone_server(name, new_name, address, scope, &current_scope)?
+                .save_to_file()
+                .map(|_| None)
+        }
+        CliSubcommand::Mv { old, new, scope } => rename_entry(old, new, scope).map(|_| None),
         CliSubcommand::Default { scope } => Config::load_from_file()?
             .set_default(scope)?
             .save_to_file()
@@ -100,13 +548,747 @@ pub(crate) fn execute_subcommand(cmd: CliSubcommand) -> anyhow::Result<Option<Bo
         CliSubcommand::Generate { shell } => {
             let mut cmd = Cli::command();
             let cmd_name = cmd.get_name().to_string();
-            generate(shell, &mut cmd, cmd_name, &mut std::io::stdout());
+            generate(shell, &mut cmd, &cmd_name, &mut std::io::stdout());
+            if let Some(script) = dynamic_script(shell, &cmd_name) {
+                print!("{}", script);
+            }
 
             Ok(None)
         }
+        CliSubcommand::Prompt { style, shell } => {
+            prompt_segment(style, shell).map(|o| Some(Box::new(o) as _))
+        }
+        CliSubcommand::ShellInit { shell, style } => {
+            let mut args = String::new();
+            if style.format != DEFAULT_TEMPLATE {
+                args += &format!(" --format {}", shlex::try_quote(&style.format)?);
+            }
+            if let Some(color) = style.color {
+                args += &format!(" --color {}", shlex::try_quote(&color.name)?);
+            }
+
+            Ok(Some(Box::new(shell_init::init_script(
+                shell,
+                Cli::command().get_name(),
+                &args,
+            ))))
+        }
+        CliSubcommand::Complete { kind, word } => {
+            let config = Config::load_from_file()?;
+            let values = match kind {
+                CompletionKind::Servers => config.server_names(&active_scope(&config)?),
+                CompletionKind::Scopes => config.scopes.keys().cloned().collect(),
+                CompletionKind::RemotePaths => complete_remote_path(&word, config)?,
+            };
+
+            Ok(Some(Box::new(CompletionOutput(values))))
+        }
+        CliSubcommand::Secret { action } => manage_secret(action).map(|_| None),
+        CliSubcommand::Sync {
+            action: Some(action),
+            ..
+        } => sync_directory(action).map(|_| None),
+        CliSubcommand::Sync {
+            action: None,
+            remote,
+        } => sync_work_dir(remote).map(|_| None),
+        CliSubcommand::Prune {
+            days,
+            never_used,
+            dns,
+            keys,
+            dry_run,
+            yes,
+        } => {
+            let criteria = if days.is_none() && !dns && !keys {
+                PruneCriteria {
+                    unused_days: Some(DEFAULT_PRUNE_DAYS),
+                    never_used,
+                    dns: true,
+                    keys: true,
+                }
+            } else {
+                PruneCriteria {
+                    unused_days: days,
+                    never_used,
+                    dns,
+                    keys,
+                }
+            };
+            prune_servers(criteria, dry_run, yes)
+        }
+        CliSubcommand::Import { source } => import_servers(source),
+        CliSubcommand::Export { format, scope } => export_servers(format, scope).map(Some),
+        CliSubcommand::Discover {
+            scope,
+            timeout,
+            ip,
+            dry_run,
+            yes,
+        } => discover_servers(scope, timeout, ip, dry_run, yes),
+        CliSubcommand::Ping {
+            servers,
+            scope,
+            all,
+            ssh,
+            timeout,
+            jobs,
+        } => ping_servers(servers, scope, all, ssh, timeout, jobs).map(|o| Some(Box::new(o) as _)),
+        CliSubcommand::Watch {
+            servers,
+            scope,
+            all,
+            ssh,
+            timeout,
+            jobs,
+            interval,
+        } => watch_servers(servers, scope, all, ssh, timeout, jobs, interval).map(|_| None),
+        CliSubcommand::Warm {
+            servers,
+            scope,
+            all,
+            jobs,
+        } => warm_servers(servers, scope, all, jobs).map(|o| Some(Box::new(o) as _)),
+        CliSubcommand::Forward { action } => manage_forward(action),
+        CliSubcommand::Run {
+            server,
+            script,
+            args,
+        } => run_script(server, script, args).map(|_| None),
+        CliSubcommand::Cp {
+            source,
+            destination,
+            recursive,
+            exclude,
+        } => copy_between(source, destination, recursive.then_some(exclude)).map(|_| None),
+        CliSubcommand::Hosts { action } => manage_hosts(action).map(|_| None),
+        CliSubcommand::TrustCa {
+            key,
+            domain,
+            known_hosts,
+        } => trust_ca(key, domain, known_hosts).map(|_| None),
+        CliSubcommand::Pick => pick_server(Scope::default()).map(|_| None),
+        CliSubcommand::Tui => browse_servers().map(|_| None),
+        CliSubcommand::History { server, limit } => {
+            let history = Context::load_from_file()?
+                .history()
+                .iter()
+                .rev()
+                .filter(|entry| server.as_ref().is_none_or(|s| *s == entry.host))
+                .take(limit)
+                .cloned()
+                .collect();
+
+            Ok(Some(Box::new(HistoryOutput(history))))
+        }
+        CliSubcommand::Context { action } => manage_contexts(action),
         CliSubcommand::Edit => edit_config_file().map(|_| None),
-        CliSubcommand::Test => run_config_test().map(|_| None),
+        CliSubcommand::Check { server, format } => check_server(server, format).map(|_| None),
+        CliSubcommand::Audit { format } => audit_config(format).map(|_| None),
+        CliSubcommand::Test { format } => test_config(format).map(|_| None),
+    }
+}
+
+/// Boxes the output as is or as its serializable counterpart
+#[inline]
+fn formatted<T>(output: T, format: OutputFormat) -> Box<dyn Display>
+where
+    T: Display + 'static,
+    JsonOutput: From<T>,
+{
+    match format {
+        OutputFormat::Tree => Box::new(output),
+        OutputFormat::Json => Box::new(JsonOutput::from(output)),
+    }
+}
+
+#[inline]
+fn manage_secret(action: SecretAction) -> anyhow::Result<()> {
+    match action {
+        SecretAction::Set { name } => {
+            print!("Secret for '{}': ", name);
+            std::io::stdout().flush()?;
+            let secret = SecretString::from(rpassword::read_password()?);
+            secret::store(&name, &secret)
+        }
+        SecretAction::Rm { name } => secret::remove(&name),
+    }
+}
+
+/// Removes servers (by name or tags) or a scope,
+/// asks before removing a scope along with its servers
+fn remove_entries(
+    servers: Vec<String>,
+    scope: Option<String>,
+    tags: Vec<String>,
+    force: bool,
+    dry_run: bool,
+) -> anyhow::Result<Option<Box<dyn Display>>> {
+    let config = Config::load_from_file()?;
+    let current_scope = active_scope(&config)?;
+    let filter = ServerFilter {
+        tags,
+        ..Default::default()
+    };
+    let removed = RmOutput {
+        servers: match &scope {
+            Some(name) => config.scope_servers(name),
+            None if filter.is_empty() => servers.clone(),
+            None => config.matching_servers(&filter, &current_scope),
+        },
+        scope: scope.clone(),
+    };
+    // Removed in memory first, so that missing names are reported even in the dry-run mode
+    let config = if filter.is_empty() {
+        config.remove(&servers, scope.clone(), &current_scope)?
+    } else {
+        config.remove_tagged(&filter, &current_scope)?
+    };
+    if dry_run {
+        return Ok(Some(Box::new(removed)));
+    }
+    if scope.is_some() && !removed.servers.is_empty() && !force {
+        let question = format!(
+            "{}Remove the scope along with {} server(s)? (yes/no): ",
+            removed,
+            removed.servers.len()
+        );
+        if !matches!(ask(&question, "--force")?.as_str(), "y" | "yes") {
+            status!("Nothing removed");
+            return Ok(None);
+        }
+    }
+    config.save_to_file()?;
+
+    if let Some(scope) = scope {
+        let context = Context::load_from_file()?;
+        if *context.scope() == scope {
+            context.change_scope(None).save_to_file()?;
+        }
+    }
+
+    Ok(None)
+}
+
+/// Renames a server or a scope. The config is saved first, so that the context
+/// never points to a scope the config doesn't have under that name.
+fn rename_entry(old: String, new: String, scope: bool) -> anyhow::Result<()> {
+    let config = Config::load_from_file()?;
+    let current_scope = active_scope(&config)?;
+    config
+        .rename(old.clone(), new.clone(), scope, &current_scope)?
+        .save_to_file()?;
+    if scope {
+        let context = Context::load_from_file()?;
+        if *context.scope() == old {
+            context.change_scope(Some(new)).save_to_file()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Lowercased answer to the question asked on the terminal. Without a terminal
+/// nobody can answer, so the flag that skips the question is suggested instead.
+fn ask(question: &str, flag: &'static str) -> anyhow::Result<String> {
+    if !std::io::stdin().is_terminal() {
+        return Err(CliError::ConfirmationRequired(flag).into());
+    }
+
+    Ok(TerminalPrompts.text(question)?.to_ascii_lowercase())
+}
+
+/// Applies the settings to all servers of the scope in one go, after showing
+/// what changes and asking for confirmation
+fn set_scope_servers(
+    name: String,
+    scope: Scope,
+    dry_run: bool,
+    yes: bool,
+) -> anyhow::Result<Option<Box<dyn Display>>> {
+    let mut config = Config::load_from_file()?;
+    let changes = config.set_scope_servers(&name, &scope)?;
+    if changes.is_empty() {
+        status!("No servers of scope '{}' to change", name);
+        return Ok(None);
+    }
+    let count = changes.len();
+    let preview = SetPreview(changes);
+    if dry_run {
+        return Ok(Some(Box::new(preview)));
+    }
+
+    if !yes {
+        let question = format!("{}Change {} server(s)? (yes/no): ", preview, count);
+        if !matches!(ask(&question, "--yes")?.as_str(), "y" | "yes") {
+            status!("Nothing changed");
+            return Ok(None);
+        }
+    }
+    config.save_to_file()?;
+    status!("Changed {} server(s) of scope '{}'", count, name);
+
+    Ok(None)
+}
+
+/// Flags stale servers and removes those confirmed one by one
+fn prune_servers(
+    criteria: PruneCriteria,
+    dry_run: bool,
+    yes: bool,
+) -> anyhow::Result<Option<Box<dyn Display>>> {
+    let config = Config::load_from_file()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let stale = config.stale_servers(&criteria, &Context::load_from_file()?.last_seen(), now);
+    if stale.is_empty() {
+        status!("No stale servers found");
+        return Ok(None);
+    }
+    if dry_run {
+        return Ok(Some(Box::new(PruneOutput(stale))));
+    }
+
+    let mut confirmed = Vec::with_capacity(stale.len());
+    let mut remove_all = yes;
+    for server in stale {
+        if remove_all {
+            println!("{}", server);
+        } else {
+            let question = format!("{}\nRemove? (yes/no/all/quit): ", server);
+            match ask(&question, "--yes")?.as_str() {
+                "y" | "yes" => {}
+                "a" | "all" => remove_all = true,
+                "q" | "quit" => break,
+                _ => continue,
+            }
+        }
+        confirmed.push(server);
+    }
+    if !confirmed.is_empty() {
+        config.remove_stale(&confirmed).save_to_file()?;
+        status!("Removed {} server(s)", confirmed.len());
+    }
+
+    Ok(None)
+}
+
+/// Servers whose name is taken in the scope or whose address is already
+/// in the config are skipped
+fn import_servers(source: ImportSource) -> anyhow::Result<Option<Box<dyn Display>>> {
+    let (servers, scope, dry_run) = match source {
+        ImportSource::Hosts {
+            path,
+            domain,
+            scope,
+            dry_run,
+        } => {
+            let path = cli_path(path).map_err(FileError::from)?;
+            let content = fs::read_to_string(&path).map_err(FileError::from)?;
+            (parse_hosts(&content, domain.as_deref()), scope, dry_run)
+        }
+        ImportSource::Csv {
+            path,
+            map,
+            header,
+            scope,
+            dry_run,
+        } => {
+            let path = cli_path(path).map_err(FileError::from)?;
+            let content = fs::read_to_string(&path).map_err(FileError::from)?;
+            (parse_csv(&content, &map, header)?, scope, dry_run)
+        }
+    };
+    let mut config = Config::load_from_file()?;
+    let scope = match scope {
+        Some(scope) if !config.check_scope(&scope) => {
+            return Err(CliError::ScopeNotFound(scope.into()).into());
+        }
+        Some(scope) => scope,
+        None => active_scope(&config)?,
+    };
+
+    // Global servers share the names with server groups of scopes
+    let taken = if scope.is_empty() {
+        config.servers.keys().cloned().collect()
+    } else {
+        config.scope_servers(&scope)
+    };
+    let mut imported = Vec::with_capacity(servers.len());
+    for (name, server) in servers {
+        if taken.contains(&name) || config.has_address(&server.address) {
+            info!(
+                "Skipping '{}' ({}), it's already there",
+                name, server.address
+            );
+            continue;
+        }
+        if !dry_run {
+            config.add_server_to(&scope, name.clone(), server.clone())?;
+        }
+        imported.push((name, server));
+    }
+    if imported.is_empty() {
+        status!("No new servers to import");
+        return Ok(None);
+    }
+    if !dry_run {
+        config.save_to_file()?;
+        status!("Imported {} server(s)", imported.len());
+        return Ok(None);
+    }
+
+    Ok(Some(Box::new(ImportOutput(imported))))
+}
+
+/// All servers, or those of the scope, with the settings they inherit merged in
+fn export_servers(format: ExportFormat, scope: Option<String>) -> anyhow::Result<Box<dyn Display>> {
+    let mut config = Config::load_from_file()?;
+    config.expand_vars()?;
+    let output = match scope {
+        Some(scope) if !config.check_scope(&scope) => {
+            return Err(CliError::ScopeNotFound(scope.into()).into());
+        }
+        Some(scope) => config.list(scope, false, false, &ServerFilter::default(), None)?,
+        None => config.list(String::new(), true, false, &ServerFilter::default(), None)?,
+    };
+    let servers = config.effective_servers(output);
+    info!("Exporting {} server(s)", servers.len());
+
+    Ok(match format {
+        ExportFormat::Json => Box::new(JsonOutput::Servers(
+            servers
+                .into_iter()
+                .map(|(scope, name, server)| JsonServer {
+                    scope,
+                    name,
+                    server,
+                })
+                .collect(),
+        )),
+        ExportFormat::Csv => Box::new(CsvOutput(servers)),
+    })
+}
+
+/// Servers already in the config (by the name or any of the addresses) are left out
+fn discover_servers(
+    scope: Option<String>,
+    timeout: u64,
+    ip: bool,
+    dry_run: bool,
+    yes: bool,
+) -> anyhow::Result<Option<Box<dyn Display>>> {
+    let mut config = Config::load_from_file()?;
+    let scope = match scope {
+        Some(scope) if !config.check_scope(&scope) => {
+            return Err(CliError::ScopeNotFound(scope.into()).into());
+        }
+        Some(scope) => scope,
+        None => active_scope(&config)?,
+    };
+    let rt = Runtime::new()?;
+    let announced: Vec<Announced> = rt
+        .block_on(mdns::browse(Duration::from_secs(timeout)))?
+        .into_iter()
+        .filter(|found| {
+            !config.has_address(&found.host)
+                && !found
+                    .addresses
+                    .iter()
+                    .any(|ip| config.has_address(&ip.to_string()))
+        })
+        .collect();
+    if announced.is_empty() {
+        status!("No new SSH servers found");
+        return Ok(None);
+    }
+    if dry_run {
+        return Ok(Some(Box::new(DiscoverOutput(announced))));
+    }
+
+    let mut added = 0;
+    let mut add_all = yes;
+    for found in announced {
+        if add_all {
+            println!("{}", found);
+        } else {
+            let question = format!("{}\nAdd? (yes/no/all/quit): ", found);
+            match ask(&question, "--yes")?.as_str() {
+                "y" | "yes" => {}
+                "a" | "all" => add_all = true,
+                "q" | "quit" => break,
+                _ => continue,
+            }
+        }
+        let address = match found.addresses.first() {
+            Some(address) if ip => address.to_string(),
+            _ => found.host.clone(),
+        };
+        let server = Server {
+            address,
+            scope: Scope {
+                port: (found.port != DEFAULT_SSH_PORT).then_some(found.port),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        match config.add_server_to(&scope, found.short_name().to_string(), server) {
+            Ok(()) => added += 1,
+            Err(e) => warn!("{}", e),
+        }
+    }
+    if added > 0 {
+        config.save_to_file()?;
+        status!("Added {} server(s)", added);
+    }
+
+    Ok(None)
+}
+
+/// Checks up to `jobs` servers at once, an unreachable one doesn't stop the rest
+fn ping_servers(
+    servers: Vec<ServerUri>,
+    scope: Option<String>,
+    all: bool,
+    ssh: bool,
+    timeout: u64,
+    jobs: usize,
+) -> anyhow::Result<PingOutput> {
+    let targets = probe_targets(servers, scope, all)?;
+    let rt = Runtime::new()?;
+
+    Ok(PingOutput(rt.block_on(probe_all(
+        targets,
+        ssh,
+        Duration::from_secs(timeout),
+        jobs,
+    ))))
+}
+
+/// Checks the servers in rounds until interrupted, redrawing the table after each
+/// (on a terminal, otherwise the tables are written one after another)
+fn watch_servers(
+    servers: Vec<ServerUri>,
+    scope: Option<String>,
+    all: bool,
+    ssh: bool,
+    timeout: u64,
+    jobs: usize,
+    interval: u64,
+) -> anyhow::Result<()> {
+    let targets = probe_targets(servers, scope, all)?;
+    let (limit, interval) = (Duration::from_secs(timeout), interval.max(1));
+    let rt = Runtime::new()?;
+    let mut stdout = std::io::stdout();
+    let redraw = stdout.is_terminal();
+    let mut last: Vec<(bool, u64)> = Vec::new();
+    loop {
+        let started = Instant::now();
+        let probes = rt.block_on(probe_all(targets.clone(), ssh, limit, jobs));
+        let checked = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        last = probes
+            .iter()
+            .enumerate()
+            .map(|(i, probe)| match last.get(i) {
+                Some(&(up, since)) if up == probe.result.is_ok() => (up, since),
+                _ => (probe.result.is_ok(), checked),
+            })
+            .collect();
+        let output = WatchOutput {
+            probes,
+            since: last.iter().map(|&(_, since)| since).collect(),
+            checked,
+            interval,
+        };
+        if redraw {
+            execute!(stdout, MoveTo(0, 0), Clear(ClearType::All))?;
+        }
+        write!(stdout, "{}", output)?;
+        if !redraw {
+            writeln!(stdout)?;
+        }
+        stdout.flush()?;
+
+        std::thread::sleep(Duration::from_secs(interval).saturating_sub(started.elapsed()));
+    }
+}
+
+/// Scope, name, address and port of a server to probe
+type ProbeTarget = (Option<String>, String, String, u16);
+
+/// Servers to probe with the address and port they are reached at
+fn probe_targets(
+    servers: Vec<ServerUri>,
+    scope: Option<String>,
+    all: bool,
+) -> anyhow::Result<Vec<ProbeTarget>> {
+    let mut config = Config::load_from_file()?;
+    config.expand_vars()?;
+    let targets = select_targets(&config, servers, scope, all)?;
+
+    let default_port = config.default.as_ref().and_then(|default| default.port);
+    let mut resolved = Vec::with_capacity(targets.len());
+    for (scope, mut server_uri) in targets {
+        let name = server_uri.address.clone();
+        let (server, scope) = match resolve_server(&name, &mut config, scope.clone())? {
+            Some((mut server, Some(found))) => {
+                server.apply_scope(found);
+                (server, Some(scope))
+            }
+            Some((server, None)) => (server, None),
+            None => (Server::from_uri_address(&mut server_uri), None),
+        };
+        let port = server_uri
+            .port
+            .or(server.scope.port)
+            .or(default_port)
+            .unwrap_or(DEFAULT_SSH_PORT);
+        resolved.push((scope, name, server.address, port));
+    }
+
+    Ok(resolved)
+}
+
+/// Probes up to `jobs` servers at once
+async fn probe_all(
+    targets: Vec<ProbeTarget>,
+    ssh: bool,
+    limit: Duration,
+    jobs: usize,
+) -> Vec<Probe> {
+    let checks = targets.iter().map(|(_, _, address, port)| {
+        let (address, port) = (address.clone(), *port);
+        async move { probe(&address, port, ssh, limit).await }
+    });
+    let results = run_limited(checks, jobs).await;
+
+    targets
+        .into_iter()
+        .zip(results)
+        .map(|((scope, name, address, port), result)| Probe {
+            scope,
+            name,
+            address,
+            port,
+            result,
+        })
+        .collect()
+}
+
+/// Connects to the servers (several at once, questions are asked one at a time)
+/// and disconnects right away, so that addresses get cached, new host keys
+/// trusted and credentials checked up front. The sessions aren't kept for reuse.
+fn warm_servers(
+    servers: Vec<ServerUri>,
+    scope: Option<String>,
+    all: bool,
+    jobs: usize,
+) -> anyhow::Result<WarmOutput> {
+    let mut config = Config::load_from_file()?;
+    config.expand_vars()?;
+    let targets = select_targets(&config, servers, scope, all)?;
+
+    let mut warmed = Vec::with_capacity(targets.len());
+    let mut visits = Vec::new();
+    let mut connections = Vec::new();
+    for (scope, server_uri) in targets {
+        let name = server_uri.address.clone();
+        let scope_name = (!scope.is_empty()).then(|| scope.clone());
+        // Hooks run one by one, before any connection is made
+        let prepared = connection_data_with(server_uri, None, Scope::default(), &mut config, scope)
+            .and_then(|mut data| Ok((Visit::begin(name.clone(), &mut data)?, data)));
+        match prepared {
+            Ok((visit, data)) => {
+                visits.push((warmed.len(), visit));
+                connections.push(warm(data));
+                warmed.push((scope_name, name, Ok(Duration::ZERO)));
+            }
+            Err(e) => warmed.push((scope_name, name, Err(e))),
+        }
+    }
+
+    let results = Runtime::new()?.block_on(run_limited(connections, jobs));
+    // A failed hook or history update is reported along with the server,
+    // the others still get theirs
+    for ((i, visit), result) in visits.into_iter().zip(results) {
+        warmed[i].2 = match (visit.end(&result, None), result) {
+            (Err(e), Ok(_)) => Err(e),
+            (Err(e), Err(connecting)) => {
+                warn!("{}: {}", warmed[i].1, e);
+                Err(connecting)
+            }
+            (Ok(()), result) => result,
+        };
     }
+
+    Ok(WarmOutput(warmed))
+}
+
+/// How long the connection took to set up
+async fn warm(mut data: ConnectionData) -> anyhow::Result<Duration> {
+    let prompts: Arc<dyn PromptProvider> = Arc::new(SerialPrompts);
+    let verifier = data.verifier(Arc::clone(&prompts));
+    let started = Instant::now();
+    let session = Session::connect_with(data, verifier, prompts).await?;
+    let elapsed = started.elapsed();
+    session.close().await?;
+
+    Ok(elapsed)
+}
+
+/// Servers given on the command line (or stdin), of a scope or all of them,
+/// each with the scope it's looked up in ("" for global ones). Patterns are skipped.
+fn select_targets(
+    config: &Config,
+    servers: Vec<ServerUri>,
+    scope: Option<String>,
+    all: bool,
+) -> anyhow::Result<Vec<(String, ServerUri)>> {
+    let current_scope = active_scope(config)?;
+    let uri = |address: &String| ServerUri {
+        address: address.clone(),
+        user: None,
+        port: None,
+        options: Box::default(),
+        query: None,
+    };
+    // Each server is looked up as if its scope was active ("" for global ones)
+    let targets = if all {
+        config
+            .servers
+            .iter()
+            .flat_map(|(name, entry)| match entry {
+                ServerEntry::Global(_) => vec![(String::new(), uri(name))],
+                ServerEntry::Scope(scope_servers) => scope_servers
+                    .keys()
+                    .map(|server| (name.clone(), uri(server)))
+                    .collect(),
+            })
+            .filter(|(_, server)| !is_pattern(&server.address))
+            .collect()
+    } else if let Some(scope) = scope {
+        if !config.check_scope(&scope) {
+            return Err(CliError::ScopeNotFound(scope.into()).into());
+        }
+        config
+            .scope_servers(&scope)
+            .iter()
+            .filter(|name| !is_pattern(name))
+            .map(|name| (scope.clone(), uri(name)))
+            .collect()
+    } else {
+        let mut targets = Vec::with_capacity(servers.len());
+        for server in servers {
+            if server.is_stdin() {
+                let listed = read_servers(std::io::stdin().lock())?;
+                targets.extend(listed.into_iter().map(|s| (current_scope.clone(), s)));
+            } else {
+                targets.push((current_scope.clone(), server));
+            }
+        }
+        targets
+    };
+
+    Ok(targets)
 }
 
 #[inline]
@@ -121,63 +1303,695 @@ fn edit_config_file() -> anyhow::Result<()> {
     Ok(())
 }
 
-#[inline]
-fn run_config_test() -> anyhow::Result<()> {
-    let test = TestOutput(
-        Config::load_from_file()
-            .map(|_| (*CONFIG_PATH).clone())
-            .map_err(|e| e.to_string()),
+fn manage_forward(action: ForwardAction) -> anyhow::Result<Option<Box<dyn Display>>> {
+    let rt = Runtime::new()?;
+    match action {
+        ForwardAction::Start {
+            server,
+            spec,
+            detach,
+            daemon,
+            in_scope,
+        } => rt.block_on(start_forward(server, spec, detach, daemon, in_scope))?,
+        ForwardAction::Ls => {
+            return Ok(Some(Box::new(ForwardsOutput(
+                rt.block_on(forward::list())?,
+            ))));
+        }
+        ForwardAction::Stop { name } => {
+            rt.block_on(forward::request(&name, "stop"))?
+                .ok_or(CliError::ForwardNotRunning(name.as_str().into()))?;
+            status!("Forward '{}' stopped", name);
+        }
+    }
+
+    Ok(None)
+}
+
+/// Runs the forward in this process, or hands it to a background one with `detach`.
+/// The scope is resolved once, so that a restarted forward reaches the same server
+/// whatever scope is active by then.
+async fn start_forward(
+    server: ServerUri,
+    spec: ForwardSpec,
+    detach: bool,
+    daemon: bool,
+    in_scope: Option<String>,
+) -> anyhow::Result<()> {
+    let name = spec.name(&server);
+    if !daemon && forward::request(&name, "status").await?.is_some() {
+        return Err(CliError::ForwardRunning(name.into()).into());
+    }
+    let scope = match in_scope {
+        Some(scope) => scope,
+        None => active_scope(&Config::load_from_file()?)?,
+    };
+    if detach && !daemon {
+        forward::spawn_detached(&server, &spec, &name, &scope).await?;
+        status!(
+            "Forwarding {} to {}:{} in the background (stop with `forward stop {}`)",
+            spec.local(),
+            spec.host,
+            spec.host_port,
+            name
+        );
+        return Ok(());
+    }
+
+    let session = connect_forward(server.clone(), &scope, daemon).await?;
+    status!(
+        "Forwarding {} to {}:{} as '{}'",
+        spec.local(),
+        spec.host,
+        spec.host_port,
+        name
+    );
+    let status = ForwardStatus {
+        name,
+        server: server.to_string(),
+        local: spec.local(),
+        remote: format!("{}:{}", spec.host, spec.host_port),
+        pid: std::process::id(),
+        since: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        state: ForwardState::Up,
+        restarts: 0,
+        last_error: None,
+    };
+
+    forward::serve(
+        session,
+        || connect_forward(server.clone(), &scope, daemon),
+        &spec,
+        status,
+    )
+    .await
+}
+
+/// The config is read on every connection, so a restarted forward picks up changes
+async fn connect_forward(server: ServerUri, scope: &str, daemon: bool) -> anyhow::Result<Link> {
+    let config = Config::load_from_file()?;
+    let host = server.address.clone();
+    let mut data = connection_data(server, None, Scope::default(), config, scope.into())?;
+    let visit = Visit::begin(host, &mut data)?;
+    let connected = if daemon {
+        let prompts: Arc<dyn PromptProvider> = Arc::new(DetachedPrompts);
+        let verifier = data.verifier(Arc::clone(&prompts));
+        Session::connect_with(data, verifier, prompts).await
+    } else {
+        Session::connect(data).await
+    };
+    match connected {
+        Ok(session) => Ok(Link { session, visit }),
+        Err(e) => {
+            let failed = Err(e);
+            visit.end(&failed, None)?;
+            failed
+        }
+    }
+}
+
+/// Streams the output of the script, a non-zero exit status is returned as an error
+fn run_script(server: ServerUri, script: PathBuf, args: Vec<String>) -> anyhow::Result<()> {
+    let script_path = cli_path(script).map_err(FileError::from)?;
+    let script = fs::read(&script_path)
+        .map_err(|e| CliError::ScriptUnreadable(script_path.display().to_string().into(), e))?;
+    let config = Config::load_from_file()?;
+    let current_scope = active_scope(&config)?;
+    let host = server.address.clone();
+    let mut data = connection_data(server, None, Scope::default(), config, current_scope)?;
+    let visit = Visit::begin(host, &mut data)?;
+
+    let rt = Runtime::new()?;
+    let result = rt.block_on(async {
+        let session = Session::connect(data).await?;
+        let exit_status = session.run_script(&script, &args).await;
+        // The session may be gone already
+        _ = session.close().await;
+        exit_status
+    });
+    visit.end(&result, result.as_ref().ok().copied().flatten())?;
+    let exit_status = result?;
+
+    match exit_status {
+        Some(0) => Ok(()),
+        // Without a status the script was killed by a signal, reported like ssh does
+        status => Err(CliError::ScriptFailed(status.unwrap_or(255)).into()),
+    }
+}
+
+/// Transfers the files that differ (by size or modification time) between the
+/// local directory and the one on the server, in the direction of the action.
+/// Files only present at the destination are kept.
+fn sync_directory(action: SyncAction) -> anyhow::Result<()> {
+    let (server, local, remote, dry_run, push) = match action {
+        SyncAction::Push {
+            server,
+            local,
+            remote,
+            dry_run,
+        } => (server, local, remote, dry_run, true),
+        SyncAction::Pull {
+            server,
+            local,
+            remote,
+            dry_run,
+        } => (server, local, remote, dry_run, false),
+    };
+    let local = cli_path(local).map_err(FileError::from)?;
+    if push && !local.is_dir() {
+        return Err(CliError::NotADirectory(local.display().to_string().into()).into());
+    }
+    let config = Config::load_from_file()?;
+    let current_scope = active_scope(&config)?;
+    let host = server.address.clone();
+    let mut data = connection_data(server, None, Scope::default(), config, current_scope)?;
+    let visit = Visit::begin(host, &mut data)?;
+
+    let rt = Runtime::new()?;
+    let result = rt.block_on(async {
+        let session = Session::connect(data).await?;
+        let synced = async {
+            let local_files = transfer::local_files(&local).map_err(FileError::from)?;
+            let remote_files = session.list_files(&remote).await?;
+            let files = if push {
+                transfer::changed_files(&local_files, &remote_files)
+            } else {
+                transfer::changed_files(&remote_files, &local_files)
+            };
+            if dry_run || files.is_empty() {
+                return anyhow::Ok((files, None));
+            }
+            let size = if push {
+                session.push_files(&local, &remote, &files).await?
+            } else {
+                session.pull_files(&remote, &local, &files).await?
+            };
+            Ok((files, Some(size)))
+        }
+        .await;
+        _ = session.close().await;
+        synced
+    });
+    visit.end(&result, None)?;
+    let (files, size) = result?;
+
+    match size {
+        _ if files.is_empty() => status!("Nothing to transfer, the files are the same"),
+        None => files.iter().for_each(|file| println!("{}", file)),
+        Some(size) => status!(
+            "{} {} files ({} bytes)",
+            if push { "Sent" } else { "Fetched" },
+            files.len(),
+            size
+        ),
+    }
+
+    Ok(())
+}
+
+fn show_current() -> anyhow::Result<CurrentOutput> {
+    let mut config = Config::load_from_file()?;
+    let (scope, origin) = scope_with_origin(&config)?;
+    let servers = match config.servers.get(&scope) {
+        Some(ServerEntry::Scope(servers)) if !scope.is_empty() => servers.len(),
+        _ => 0,
+    };
+    let global_servers = config
+        .servers
+        .values()
+        .filter(|entry| matches!(entry, ServerEntry::Global(_)))
+        .count();
+
+    Ok(CurrentOutput {
+        settings: config.scopes.swap_remove(&scope),
+        scope,
+        origin,
+        servers,
+        global_servers,
+        context: Context::load_from_file()?.context().into(),
+        context_path: &CACHE_PATH,
+    })
+}
+
+/// Runs for every prompt, so the config isn't parsed when the scope comes
+/// from the environment or a scope file
+fn prompt_segment(style: PromptStyle, shell: Option<PromptShell>) -> anyhow::Result<String> {
+    let scope = match env_scope() {
+        Some(scope) => scope,
+        None => match find_scope_file(&env::current_dir()?)? {
+            Some(scope) => scope,
+            None => active_scope(&Config::load_from_file()?)?,
+        },
+    };
+    let context = Context::load_from_file()?;
+
+    Ok(shell_init::render(
+        &style.format,
+        &scope,
+        context.context(),
+        style.color.map(|color| color.color),
+        shell,
+    ))
+}
+
+/// Server names while there is no path yet, then the entries of the remote
+/// directory starting with the typed name (directories end with `/`).
+/// Nobody can answer prompts during completion, so the server has to be
+/// reachable without them.
+fn complete_remote_path(word: &str, config: Config) -> anyhow::Result<Vec<String>> {
+    let current_scope = active_scope(&config)?;
+    let Some((server, path)) = RemotePath::split(word) else {
+        return Ok(config
+            .server_names(&current_scope)
+            .into_iter()
+            .filter(|name| name.starts_with(word))
+            .map(|name| format!("{}:", name))
+            .collect());
+    };
+    let mut data = connection_data(
+        server.parse()?,
+        None,
+        Scope::default(),
+        config,
+        current_scope,
+    )?;
+    let visit = Visit::begin(server.into(), &mut data)?;
+    let (dir, name) = path.split_at(path.rfind('/').map_or(0, |i| i + 1));
+    let dir = if dir.is_empty() { "." } else { dir };
+    // Hidden entries are only listed once their name is started
+    let command = format!(
+        "cd {} 2>/dev/null || exit 1; for f in {}*; do \
+        if [ -d \"$f\" ]; then printf '%s/\\n' \"$f\"; \
+        elif [ -e \"$f\" ]; then printf '%s\\n' \"$f\"; fi; done",
+        remote_path_arg(dir)?,
+        shlex::try_quote(name)?
+    );
+    let prefix = &word[..word.len() - name.len()];
+
+    let rt = Runtime::new()?;
+    let output = rt.block_on(async {
+        let listing = async {
+            let prompts: Arc<dyn PromptProvider> = Arc::new(DetachedPrompts);
+            let verifier = data.verifier(Arc::clone(&prompts));
+            let session = Session::connect_with(data, verifier, prompts).await?;
+            let output = session
+                .output(&format!("sh -c {}", shlex::try_quote(&command)?))
+                .await;
+            _ = session.close().await;
+            output
+        };
+        tokio::time::timeout(REMOTE_COMPLETION_TIMEOUT, listing)
+            .await
+            .map_err(|_| SessionError::NoReply(REMOTE_COMPLETION_TIMEOUT.as_secs()))?
+    });
+    visit.end(&output, None)?;
+    let output = output?;
+
+    Ok(String::from_utf8_lossy(&output)
+        .lines()
+        .filter(|entry| !matches!(*entry, "./" | "../"))
+        .map(|entry| format!("{}{}", prefix, entry))
+        .collect())
+}
+
+/// Copies the file, or the directory with `tree` set to the patterns to exclude
+/// (maybe none). Both servers are connected to in turn (so that prompts don't mix),
+/// then the data is relayed from one to the other.
+fn copy_between(
+    source: RemotePath,
+    destination: RemotePath,
+    tree: Option<Vec<String>>,
+) -> anyhow::Result<()> {
+    let mut config = Config::load_from_file()?;
+    config.expand_vars()?;
+    let current_scope = active_scope(&config)?;
+    let (from_name, to_name) = (
+        source.server.address.clone(),
+        destination.server.address.clone(),
+    );
+    let mut from = connection_data_with(
+        source.server,
+        None,
+        Scope::default(),
+        &mut config,
+        current_scope.clone(),
+    )?;
+    let mut to = connection_data_with(
+        destination.server,
+        None,
+        Scope::default(),
+        &mut config,
+        current_scope,
+    )?;
+    let (from_host, to_host) = (from.address.clone(), to.address.clone());
+    let reading = Visit::begin(from_name, &mut from)?;
+    let writing = match Visit::begin(to_name, &mut to) {
+        Ok(visit) => visit,
+        Err(e) => {
+            let failed = Err(e.into());
+            reading.end(&failed, None)?;
+            return failed;
+        }
+    };
+
+    let rt = Runtime::new()?;
+    let copied = rt.block_on(async {
+        let reader = Session::connect(from).await?;
+        let writer = Session::connect(to).await?;
+        let copied = match &tree {
+            Some(exclude) => {
+                reader
+                    .copy_tree_to(&source.path, &writer, &destination.path, exclude)
+                    .await
+            }
+            None => {
+                reader
+                    .copy_to(&source.path, &writer, &destination.path)
+                    .await
+            }
+        };
+        _ = reader.close().await;
+        _ = writer.close().await;
+        copied
+    });
+    reading.end(&copied, None)?;
+    writing.end(&copied, None)?;
+    let copied = copied?;
+    status!(
+        "Copied {} bytes from {}:{} to {}:{}",
+        copied,
+        from_host,
+        source.path,
+        to_host,
+        destination.path
     );
-    println!("{}", test);
 
     Ok(())
 }
 
+fn manage_contexts(action: ContextAction) -> anyhow::Result<Option<Box<dyn Display>>> {
+    let context = Context::load_from_file()?;
+    match action {
+        ContextAction::Use { name } => {
+            if !context.has_context(&name) {
+                status!("Created context '{}'", name);
+            }
+            let context = context.switch_context(name);
+            context.save_to_file()?;
+            if !context.scope().is_empty() {
+                status!("Active scope: '{}'", context.scope());
+            }
+        }
+        ContextAction::Ls => {
+            let contexts = context
+                .contexts()
+                .into_iter()
+                .map(|(name, scope)| (name.to_string(), scope.to_string()))
+                .collect();
+            let current = context.context().to_string();
+            return Ok(Some(Box::new(ContextsOutput(contexts, current))));
+        }
+        ContextAction::Rm { name } => context.remove_context(&name)?.save_to_file()?,
+    }
+
+    Ok(None)
+}
+
+fn manage_hosts(action: HostsAction) -> anyhow::Result<()> {
+    match action {
+        HostsAction::Import { path, known_hosts } => {
+            let path = cli_path(path).map_err(FileError::from)?;
+            let entries = known_hosts::parse(&fs::read_to_string(&path).map_err(FileError::from)?);
+            let known_hosts = known_hosts_file(known_hosts)?;
+            let added = known_hosts::import(&known_hosts, &entries)?;
+            status!(
+                "Imported {} of {} entries into '{}'",
+                added,
+                entries.len(),
+                known_hosts.display()
+            );
+        }
+        HostsAction::Rm {
+            host,
+            port,
+            known_hosts,
+        } => {
+            let known_hosts = known_hosts_file(known_hosts)?;
+            let removed = known_hosts::remove(&known_hosts, &host, port)?;
+            if removed > 0 {
+                status!(
+                    "Removed {} entries of '{}' from '{}'",
+                    removed,
+                    host,
+                    known_hosts.display()
+                );
+            } else {
+                status!("No entries of '{}' in '{}'", host, known_hosts.display());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The given file (relative to the current directory), or the known_hosts of the default settings
+fn known_hosts_file(path: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    Ok(match path {
+        Some(path) => cli_path(path).map_err(FileError::from)?,
+        None => Config::load_from_file()?
+            .default
+            .and_then(|default| default.known_hosts)
+            .map(get_full_path)
+            .unwrap_or_else(|| WORK_DIR.join(DEFAULT_KNOWN_HOSTS_FILE)),
+    })
+}
+
+/// The entry goes to the known_hosts of the default settings unless a file is given
+fn trust_ca(key: PathBuf, domain: String, known_hosts: Option<PathBuf>) -> anyhow::Result<()> {
+    let key_path = cli_path(key).map_err(FileError::from)?;
+    let key = load_public_key(&key_path).map_err(|e| {
+        CliError::InvalidPublicKey(key_path.display().to_string().into(), e.to_string().into())
+    })?;
+    let known_hosts = known_hosts_file(known_hosts)?;
+
+    if add_cert_authority(&known_hosts, &domain, &key)? {
+        status!(
+            "Trusting certificates of '{}' signed by {} ({})",
+            domain,
+            key.fingerprint(HashAlg::default()),
+            known_hosts.display()
+        );
+    } else {
+        status!("The CA is already trusted for '{}'", domain);
+    }
+
+    Ok(())
+}
+
+/// Prints the findings, the output comes before the failure for CI logs
+fn audit_config(format: OutputFormat) -> anyhow::Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut config = Config::load_from_file()?;
+    config.expand_vars()?;
+    let output = AuditOutput::new(audit(&config, now));
+    let errors = output
+        .findings
+        .iter()
+        .filter(|finding| finding.severity == Severity::Error)
+        .count();
+    print!("{}", formatted(output, format));
+    if errors > 0 {
+        return Err(CliError::AuditFailed(errors).into());
+    }
+
+    Ok(())
+}
+
+/// Reports each step, fails if any of them did
+fn check_server(server_uri: ServerUri, format: OutputFormat) -> anyhow::Result<()> {
+    let mut config = Config::load_from_file()?;
+    config.expand_vars()?;
+    let current_scope = active_scope(&config)?;
+    let name = server_uri.to_string();
+    let entry = match resolve_server(&server_uri.address, &mut config, current_scope.clone())? {
+        Some((_, Some(_))) => Step::new(
+            "entry",
+            StepStatus::Ok,
+            format!(
+                "'{}' found in scope '{}'",
+                server_uri.address, current_scope
+            ),
+        ),
+        Some((server, None)) => Step::new(
+            "entry",
+            StepStatus::Ok,
+            format!(
+                "'{}' found (address {})",
+                server_uri.address, server.address
+            ),
+        ),
+        None => Step::new(
+            "entry",
+            StepStatus::Warning,
+            format!(
+                "'{}' isn't in the config, it's connected to as an address",
+                server_uri.address
+            ),
+        ),
+    };
+    let mut steps = vec![entry];
+    match connection_data_with(
+        server_uri,
+        None,
+        Scope::default(),
+        &mut config,
+        current_scope,
+    ) {
+        Ok(data) => {
+            steps.push(Step::new(
+                "settings",
+                StepStatus::Ok,
+                format!("{}@{}:{}", data.user, data.address, data.port),
+            ));
+            steps.extend(Runtime::new()?.block_on(preflight::check(&data)));
+        }
+        Err(e) => steps.push(Step::new("settings", StepStatus::Failed, e.to_string())),
+    }
+
+    let failed = steps
+        .iter()
+        .filter(|step| step.status == StepStatus::Failed)
+        .count();
+    print!(
+        "{}",
+        formatted(
+            CheckOutput {
+                server: name,
+                steps
+            },
+            format
+        )
+    );
+    if failed > 0 {
+        return Err(CliError::CheckFailed(failed).into());
+    }
+
+    Ok(())
+}
+
+/// Prints the findings, fails if any of them is an error
+fn test_config(format: OutputFormat) -> anyhow::Result<()> {
+    let output = run_config_test();
+    let errors = output
+        .findings
+        .iter()
+        .filter(|finding| finding.severity == Severity::Error)
+        .count();
+    print!("{}", formatted(output, format));
+    if errors > 0 {
+        return Err(CliError::ConfigTestFailed(errors).into());
+    }
+
+    Ok(())
+}
+
+/// Syntax errors are reported as a finding at their location in the file
+fn run_config_test() -> TestOutput {
+    let path = (*CONFIG_PATH).clone();
+    let findings = match Config::load_from_file() {
+        // Paths are checked with the variables expanded
+        Ok(mut config) => match config.expand_vars() {
+            Ok(()) => config.lint(),
+            Err(e) => vec![Finding {
+                severity: Severity::Error,
+                path: "vars".into(),
+                message: e.to_string(),
+            }],
+        },
+        Err(e) => {
+            let location = e
+                .downcast_ref::<FileError>()
+                .and_then(|e| match e {
+                    FileError::Yaml(e) => e.location(),
+                    _ => None,
+                })
+                .map(|l| format!("{}:{}:{}", path, l.line(), l.column()));
+            vec![Finding {
+                severity: Severity::Error,
+                path: location.unwrap_or_else(|| path.to_string()),
+                message: e.to_string(),
+            }]
+        }
+    };
+
+    TestOutput::new(path, findings)
+}
+
+/// Finds the server entry for the host along with the scope it was found in.
+/// The scope is returned separately so that callers can trace each layer.
+/// Entries are copied, so the config (and its compiled patterns) serves the next host too.
 fn resolve_server(
     host: &str,
     config: &mut Config,
     current_scope: String,
-) -> anyhow::Result<Option<Server>> {
+) -> anyhow::Result<Option<(Server, Option<Scope>)>> {
     // Search for the server in the current scope
-    if let Some(ServerEntry::Scope(scoped_servers)) = config.servers.get_mut(&current_scope) {
-        let server = if let Some(scoped_server) = scoped_servers.get_mut(host) {
-            Some(mem::take(scoped_server).into())
-        } else {
-            let mut server = None;
-            for (pattern, scoped_server) in scoped_servers.iter_mut() {
-                if Regex::new(pattern)?.is_match(host) {
-                    let mut matched_server: Server = mem::take(scoped_server).into();
-                    matched_server.apply_host_placeholder(host);
-                    server = Some(matched_server);
-                    break;
-                }
+    let found = match config.servers.get(&current_scope) {
+        Some(ServerEntry::Scope(scoped_servers)) => {
+            if let Some(scoped_server) = scoped_servers.get(host) {
+                debug!("Found server '{}' in scope '{}'", host, current_scope);
+                Some(scoped_server.clone().into())
+            } else if let Some(scoped_server) = scoped_servers.values().find(|s| s.has_alias(host))
+            {
+                debug!("Found alias '{}' in scope '{}'", host, current_scope);
+                Some(scoped_server.clone().into())
+            } else {
+                None
             }
-
-            server
-        };
-        if let Some(mut server) = server {
-            let scope = config
-                .scopes
-                .get_mut(&current_scope)
-                .ok_or_else(|| CliError::ScopeNotFound(current_scope.into()))?;
-
-            server.apply_scope(mem::take(scope));
-            return Ok(Some(server));
         }
+        _ => None,
+    };
+    let server = match found {
+        Some(server) => Some(server),
+        None => config
+            .find_matching(Some(&current_scope), host)
+            .map_err(ConnectionError::Regex)?
+            .map(|(pattern, server)| {
+                debug!(
+                    "Host matches pattern '{}' in scope '{}'",
+                    pattern, current_scope
+                );
+                server
+            }),
+    };
+    if let Some(server) = server {
+        let scope = config
+            .scopes
+            .get(&current_scope)
+            .ok_or_else(|| CliError::ScopeNotFound(current_scope.into()))?;
+
+        return Ok(Some((server, Some(scope.clone()))));
     }
     // Search for the server in the global scope
-    if let Some(ServerEntry::Global(server)) = config.servers.get_mut(host) {
-        return Ok(Some(mem::take(server).into()));
+    if let Some(ServerEntry::Global(server)) = config.servers.get(host) {
+        debug!("Found global server '{}'", host);
+        return Ok(Some((server.clone().into(), None)));
     }
-    for (pattern, entry) in config.servers.iter_mut() {
+    for entry in config.servers.values() {
         if let ServerEntry::Global(server) = entry
-            && Regex::new(pattern)?.is_match(host)
+            && server.has_alias(host)
         {
-            let mut server: Server = mem::take(server).into();
-            server.apply_host_placeholder(host);
-            return Ok(Some(server));
+            debug!("Found global alias '{}'", host);
+            return Ok(Some((server.clone().into(), None)));
         }
     }
+    if let Some((pattern, server)) = config
+        .find_matching(None, host)
+        .map_err(ConnectionError::Regex)?
+    {
+        debug!("Host matches global pattern '{}'", pattern);
+        return Ok(Some((server, None)));
+    }
 
     Ok(None)
 }
@@ -1,7 +1,7 @@
-use anyhow::Result;
+use std::process::ExitCode;
 
 use shh::start_cli;
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     start_cli()
 }
@@ -0,0 +1,218 @@
+use russh::keys::load_openssh_certificate;
+use std::path::Path;
+
+use crate::client::verify::HostKeyCheck;
+use crate::storage::config::{Config, Finding, ScopedServer, ServerEntry, Severity};
+use crate::storage::config::{Scope, inherited_scope};
+use crate::storage::provider::get_full_path;
+
+/// Scope names and tags marking servers whose host keys should be pinned
+const PRODUCTION_MARKERS: [&str; 2] = ["prod", "production"];
+
+/// Checks the config for weak security settings. Every layer (defaults, scopes and
+/// servers) is checked for what it sets, servers also for their effective settings.
+pub fn audit(config: &Config, now: u64) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    if let Some(default) = &config.default {
+        audit_layer(&mut findings, "", default, now);
+    }
+    for (name, scope) in &config.scopes {
+        audit_layer(&mut findings, &format!("scopes.{}.", name), scope, now);
+    }
+    for (entry_name, entry) in &config.servers {
+        let servers: Vec<(Option<&str>, &String, &ScopedServer)> = match entry {
+            ServerEntry::Global(server) => vec![(None, entry_name, server)],
+            ServerEntry::Scope(servers) => servers
+                .iter()
+                .map(|(name, server)| (Some(entry_name.as_str()), name, server))
+                .collect(),
+        };
+        for (scope, name, server) in servers {
+            let path = match scope {
+                Some(scope) => format!("servers.{}.{}", scope, name),
+                None => format!("servers.{}", name),
+            };
+            let own = match server {
+                ScopedServer::Override(server) => server.scope.clone(),
+                ScopedServer::Address(_) => Scope::default(),
+            };
+            audit_layer(&mut findings, &format!("{}.", path), &own, now);
+
+            let effective =
+                own.merged_with(inherited_scope(&config.scopes, &config.default, scope));
+            audit_server(&mut findings, path, scope, server.tags(), &effective);
+        }
+    }
+
+    findings
+}
+
+/// Settings made in a single layer, so that each one is reported once
+fn audit_layer(findings: &mut Vec<Finding>, prefix: &str, scope: &Scope, now: u64) {
+    let mut push = |field: &str, severity, message: String| {
+        findings.push(Finding {
+            severity,
+            path: format!("{}{}", prefix, field),
+            message,
+        })
+    };
+
+    let names = |names: Option<Vec<String>>| names.unwrap_or_default();
+    let algorithms = [
+        ("kex", names(scope.kex.as_deref().map(to_strings))),
+        ("alg", names(scope.alg.as_deref().map(to_strings))),
+        ("cipher", names(scope.cipher.as_deref().map(to_strings))),
+        ("mac", names(scope.mac.as_deref().map(to_strings))),
+    ];
+    for (field, names) in algorithms {
+        for name in names {
+            if let Some((severity, reason)) = weak_algorithm(&name) {
+                push(field, severity, format!("'{}' {}", name, reason));
+            }
+        }
+    }
+
+    if scope.host_key_check == Some(HostKeyCheck::AcceptAll) {
+        push(
+            "host_key_check",
+            Severity::Error,
+            "Server keys are accepted without verification".into(),
+        );
+    }
+    if let Some(key) = &scope.private_key
+        && let Some(mode) = permissions(key)
+        && mode & 0o077 != 0
+    {
+        push(
+            "private_key",
+            Severity::Error,
+            format!(
+                "Key '{}' is accessible by others (mode {:o}, use chmod 600)",
+                key.display(),
+                mode & 0o777
+            ),
+        );
+    }
+    if let Some(cert) = &scope.openssh_cert
+        && (cert.is_absolute() || cert.starts_with("~/"))
+        && let Ok(certificate) = load_openssh_certificate(get_full_path(cert.clone()))
+        && certificate.valid_before() <= now
+    {
+        push(
+            "openssh_cert",
+            Severity::Error,
+            format!("Certificate '{}' has expired", cert.display()),
+        );
+    }
+}
+
+/// Effective settings of a server (merged with its scope and defaults)
+fn audit_server(
+    findings: &mut Vec<Finding>,
+    path: String,
+    scope: Option<&str>,
+    tags: &[String],
+    effective: &Scope,
+) {
+    if effective.password.is_some()
+        && effective.password_auth != Some(false)
+        && effective.private_key.is_none()
+        && effective.openssh_cert.is_none()
+    {
+        findings.push(Finding {
+            severity: Severity::Warning,
+            path: path.clone(),
+            message: "Only password authentication is configured".into(),
+        });
+    }
+
+    let is_production = scope
+        .into_iter()
+        .chain(tags.iter().map(String::as_str))
+        .any(|name| PRODUCTION_MARKERS.contains(&name.to_lowercase().as_str()));
+    if is_production && effective.host_key_check != Some(HostKeyCheck::Pinned) {
+        findings.push(Finding {
+            severity: Severity::Warning,
+            path,
+            message: "Production server without pinned host keys".into(),
+        });
+    }
+}
+
+/// Algorithms considered weak, with the reason
+pub fn weak_algorithm(name: &str) -> Option<(Severity, &'static str)> {
+    match name {
+        "none" => Some((Severity::Error, "disables encryption")),
+        "ssh-dss" => Some((Severity::Error, "is DSA, which is limited to 1024 bits")),
+        "diffie-hellman-group1-sha1" => Some((Severity::Error, "uses a 1024-bit group")),
+        "ssh-rsa" => Some((Severity::Warning, "signs with SHA-1")),
+        name if name.ends_with("-cbc") => Some((Severity::Error, "uses CBC mode")),
+        name if name.contains("sha1") => Some((Severity::Warning, "uses SHA-1")),
+        _ => None,
+    }
+}
+
+#[inline]
+fn to_strings<T: ToString>(names: &[T]) -> Vec<String> {
+    names.iter().map(ToString::to_string).collect()
+}
+
+/// Mode bits of the file, if it can be read (only on Unix)
+fn permissions(path: &Path) -> Option<u32> {
+    if !path.is_absolute() && !path.starts_with("~/") {
+        return None;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(get_full_path(path.to_path_buf()))
+            .ok()
+            .map(|metadata| metadata.permissions().mode())
+    }
+    #[cfg(not(unix))]
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::parser::{CipherName, MacName};
+    use indexmap::IndexMap;
+    use std::str::FromStr;
+
+    #[test]
+    fn audit_findings() {
+        let mut config = Config::default();
+        config.scopes.insert(
+            "prod".into(),
+            Scope {
+                cipher: Some(vec![CipherName::from_str("aes256-cbc").unwrap()]),
+                mac: Some(vec![MacName::from_str("hmac-sha1").unwrap()]),
+                password: Some("keyring:db".parse().unwrap()),
+                ..Default::default()
+            },
+        );
+        let mut servers = IndexMap::new();
+        servers.insert("db".to_string(), ScopedServer::Address("10.0.0.1".into()));
+        config
+            .servers
+            .insert("prod".into(), ServerEntry::Scope(servers));
+
+        let findings = audit(&config, 0);
+        let found: Vec<_> = findings
+            .iter()
+            .map(|f| (f.path.as_str(), f.severity))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("scopes.prod.cipher", Severity::Error),
+                ("scopes.prod.mac", Severity::Warning),
+                ("servers.prod.db", Severity::Warning),
+                ("servers.prod.db", Severity::Warning),
+            ]
+        );
+        assert!(audit(&Config::default(), 0).is_empty());
+        assert_eq!(weak_algorithm("chacha20-poly1305@openssh.com"), None);
+    }
+}
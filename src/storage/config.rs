@@ -1,42 +1,170 @@
 use crate::cli::parser::ServerUri;
 use crate::{
     cli::{
-        output::LsOutput,
+        output::{LastSeen, LsOutput, ShowOutput, TableOutput},
         parser::{AlgoName, CipherName, KexName, MacName, empty_scope_is_none},
     },
+    client::{
+        connect::AuthMethod, data::DEFAULT_SSH_PORT, hooks::Hooks, profile::SecurityProfile,
+        socket::SocketOptions, verify::HostKeyCheck,
+    },
     error::{CliError, FileError},
     storage::{
-        context::Context,
-        provider::{CONFIG_PATH, StorageProvider},
+        context::{Frecency, SECS_PER_DAY, active_scope, last_connected},
+        event_log::EventLog,
+        network::{NetworkRules, NetworkState},
+        provider::{CONFIG_PATH, StorageProvider, get_full_path},
+        secret::SecretRef,
+        theme::Theme,
     },
 };
 use anyhow::Result;
 use clap::{Args, Parser};
 use indexmap::IndexMap;
+use regex_lite::Regex;
 use serde::{Deserialize, Serialize};
 use serde_with_macros::skip_serializing_none;
 use smart_default::SmartDefault;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
 use std::ops::AddAssign;
-use std::{clone::Clone, mem, path::PathBuf, sync::LazyLock};
+use std::{clone::Clone, env, iter, mem, path::PathBuf, sync::LazyLock};
+
+/// Number of previous config versions kept in the backup directory, unless set
+const CONFIG_BACKUPS: usize = 10;
 
 /// The configuration is hierarchical: default settings can be overridden by
 /// scopes, which can be overridden by individual server entries.
-#[derive(Default, Deserialize, Serialize)]
+#[derive(Clone, Default, Deserialize, Serialize)]
 pub struct Config {
     /// Default settings applied to all connections unless overridden
     #[serde(flatten)]
     #[serde(deserialize_with = "empty_scope_is_none")]
     pub default: Option<Scope>,
+    /// Values referenced as `{{name}}` in addresses and paths
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub vars: IndexMap<String, String>,
     /// Named scopes with specific connection settings
     pub scopes: IndexMap<String, Scope>,
     /// Server entries, either global or scoped
     pub servers: IndexMap<String, ServerEntry>,
+    /// How server names are matched against the host
+    #[serde(default, skip_serializing_if = "PatternSyntax::is_auto")]
+    pub pattern_syntax: PatternSyntax,
+    /// Shortcuts for whole invocations (`prod-db: "db1 --user admin"`)
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub command_aliases: IndexMap<String, String>,
+    /// Colors of the output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub theme: Option<Theme>,
+    /// Trees drawn without box-drawing characters
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ascii: bool,
+    /// Number of previous versions kept in the backup directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backups: Option<usize>,
+    /// Server names compiled as patterns, built on the first lookup and dropped by
+    /// the methods that add, remove, rename or reorder servers. It isn't persisted
+    /// in the cache: compiled regexes can't be stored, and checking stored results
+    /// against the config would cost about as much as compiling the names.
+    #[serde(skip)]
+    patterns: Option<PatternIndex>,
+}
+
+/// Server names compiled once per run, in the order they are tried
+#[derive(Clone, Debug, Default)]
+struct PatternIndex {
+    /// Patterns of the servers of each scope
+    scoped: HashMap<String, Vec<CompiledPattern>>,
+    /// Patterns of the global servers
+    global: Vec<CompiledPattern>,
+}
+
+/// An invalid pattern is only reported when a lookup reaches it
+type CompiledPattern = (String, Result<Regex, regex_lite::Error>);
+
+/// Syntax of server names used as host patterns
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PatternSyntax {
+    /// Glob if the name has only wildcards (`web-*`), regex otherwise
+    #[default]
+    Auto,
+    /// Regular expression (`vm-[0-9]+`)
+    Regex,
+    /// OpenSSH-style wildcards: `*` and `?` (`10.0.?.1`)
+    Glob,
+}
+
+/// Criteria for selecting servers in listings and batch operations
+#[derive(Debug, Default)]
+pub struct ServerFilter {
+    /// Server must have all of these tags
+    pub tags: Vec<String>,
+    /// Effective user (taking the scope and defaults into account)
+    pub user: Option<String>,
+    /// Effective port
+    pub port: Option<u16>,
+    /// Effective private key
+    pub private_key: Option<PathBuf>,
+}
+
+/// A server found by a query along with the scope it lives in
+#[derive(Debug)]
+pub struct FoundServer {
+    pub scope: Option<String>,
+    pub name: String,
+    pub address: String,
+    /// Fields (and their values) matching the query
+    pub matched: Vec<(&'static str, String)>,
+}
+
+/// Checks to run when looking for stale servers
+#[derive(Debug, Default)]
+pub struct PruneCriteria {
+    /// Not connected to for this many days
+    pub unused_days: Option<u64>,
+    /// Also flag servers missing from the history, which may just predate it
+    pub never_used: bool,
+    /// Address no longer resolves
+    pub dns: bool,
+    /// Referenced key files are missing
+    pub keys: bool,
+}
+
+/// Why a server is considered stale
+#[derive(Debug, PartialEq)]
+pub enum StaleReason {
+    /// Unix time of the last connection, if any
+    Unused(Option<u64>),
+    Unresolved,
+    MissingKey(PathBuf),
+}
+
+/// A server flagged by prune along with the scope it lives in
+#[derive(Debug)]
+pub struct StaleServer {
+    pub scope: Option<String>,
+    pub name: String,
+    pub reasons: Vec<StaleReason>,
+}
+
+/// Settings of a server before and after a bulk update
+#[derive(Debug)]
+pub struct ServerChange {
+    pub name: String,
+    pub before: Scope,
+    pub after: Scope,
 }
 
 /// A scope defines a set of SSH connection parameters.
 #[skip_serializing_none]
 #[derive(Args, Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct Scope {
+    /// What the scope is for, shown when listing scopes
+    #[arg(skip)]
+    pub description: Option<String>,
     /// User to connect as
     #[arg(short, long)]
     #[arg(value_name = "STRING")]
@@ -49,10 +177,66 @@ pub struct Scope {
     #[arg(short = 'h', long)]
     #[arg(value_name = "FILE_PATH")]
     pub known_hosts: Option<PathBuf>,
+    /// Additional read-only known_hosts files (default: ~/.ssh/known_hosts)
+    #[arg(long)]
+    #[arg(value_name = "CSV")]
+    #[arg(value_delimiter = ',')]
+    pub extra_known_hosts: Option<Vec<PathBuf>>,
+    /// How to verify server keys
+    #[arg(long)]
+    #[arg(value_enum)]
+    pub host_key_check: Option<HostKeyCheck>,
+    /// Fingerprints of the accepted server keys (for pinned host_key_check)
+    #[arg(long)]
+    #[arg(value_name = "CSV")]
+    #[arg(value_delimiter = ',')]
+    pub pinned_keys: Option<Vec<String>>,
+    /// Show the randomart of unknown or changed server keys
+    #[arg(long)]
+    #[arg(num_args = 0, default_missing_value = "true")]
+    pub visual_host_key: Option<bool>,
+    /// Never write to known_hosts, new servers are trusted for the connection only
+    #[arg(long)]
+    #[arg(num_args = 0, default_missing_value = "true")]
+    pub known_hosts_read_only: Option<bool>,
     /// Path to the private key
     #[arg(short = 'k', long)]
     #[arg(value_name = "FILE_PATH")]
     pub private_key: Option<PathBuf>,
+    /// Password stored in the OS keyring
+    #[arg(long)]
+    #[arg(value_name = "keyring:NAME")]
+    pub password: Option<SecretRef>,
+    /// Fail instead of asking for a password when keys are not accepted
+    #[arg(long = "no-password")]
+    #[arg(num_args = 0, default_missing_value = "false")]
+    pub password_auth: Option<bool>,
+    /// Number of times to ask for the password before giving up
+    #[arg(long)]
+    #[arg(value_name = "NUM")]
+    pub password_attempts: Option<u8>,
+    /// Authentication methods to try, in this order (default: the order of the server)
+    #[arg(long)]
+    #[arg(value_name = "CSV", value_enum)]
+    #[arg(value_delimiter = ',')]
+    pub preferred_auth: Option<Vec<AuthMethod>>,
+    /// Run remote commands with sudo, answering its password prompt
+    /// with the password or by asking once
+    #[arg(long)]
+    #[arg(num_args = 0, default_missing_value = "true")]
+    pub sudo: Option<bool>,
+    /// Reuse the sudo password asked for on one server for the others of a batch
+    #[arg(long)]
+    #[arg(num_args = 0, default_missing_value = "true")]
+    pub shared_sudo_password: Option<bool>,
+    /// Record connection attempts in the auth log of the work dir
+    #[arg(long)]
+    #[arg(num_args = 0, default_missing_value = "true")]
+    pub auth_log: Option<bool>,
+    /// Record connects, authentications and disconnects in a file of the work dir or syslog
+    #[arg(long)]
+    #[arg(value_enum)]
+    pub event_log: Option<EventLog>,
     /// Path to the OpenSSH certificate
     #[arg(short = 'c', long)]
     #[arg(value_name = "FILE_PATH", requires = "private_key")]
@@ -77,6 +261,10 @@ pub struct Scope {
     #[arg(value_name = "CSV")]
     #[arg(value_delimiter = ',')]
     pub mac: Option<Vec<MacName>>,
+    /// Restrict the algorithms above to a named set
+    #[arg(long)]
+    #[arg(value_enum)]
+    pub profile: Option<SecurityProfile>,
     /// Set the time to wait for a connection
     #[arg(short = 't', long)]
     #[arg(value_name = "SECS")]
@@ -89,10 +277,47 @@ pub struct Scope {
     #[arg(short = 'r', long)]
     #[arg(value_name = "NUM")]
     pub retries: Option<usize>,
+    /// Close interactive sessions after this long without input (warns a minute before)
+    #[arg(long)]
+    #[arg(value_name = "MINS")]
+    pub idle_timeout: Option<u64>,
+    /// Size of the buffer for data passed through the session
+    #[arg(long)]
+    #[arg(value_name = "BYTES")]
+    pub buffer_size: Option<usize>,
+    /// Don't show `user@address` in the terminal title during interactive sessions
+    #[arg(long = "no-title")]
+    #[arg(num_args = 0, default_missing_value = "false")]
+    pub terminal_title: Option<bool>,
+    /// Terminal type announced to the server (default: the local $TERM)
+    #[arg(long)]
+    #[arg(value_name = "TERM")]
+    pub term: Option<String>,
+    /// Write a transcript of each interactive session to a file in this directory
+    #[arg(long)]
+    #[arg(value_name = "DIR_PATH")]
+    pub log_sessions: Option<PathBuf>,
+    /// Lines to type into the interactive shell once its prompt appears (repeatable)
+    #[arg(long = "send")]
+    #[arg(value_name = "LINE")]
+    pub login_script: Option<Vec<String>>,
+    /// How long addresses stay in the DNS cache
+    #[arg(long)]
+    #[arg(value_name = "SECS")]
+    pub dns_cache_ttl: Option<u64>,
+    /// Network conditions to activate the scope automatically
+    #[arg(skip)]
+    pub network: Option<NetworkRules>,
+    /// Local commands to run before connecting and after disconnecting
+    #[arg(skip)]
+    pub hooks: Option<Hooks>,
+    /// TCP settings of the connection
+    #[arg(skip)]
+    pub socket: Option<SocketOptions>,
 }
 
 /// Represents a server entry, either global or scoped.
-#[derive(Debug, Deserialize, Serialize, SmartDefault)]
+#[derive(Clone, Debug, Deserialize, Serialize, SmartDefault)]
 #[serde(untagged)]
 pub enum ServerEntry {
     /// A server available globally
@@ -103,7 +328,7 @@ pub enum ServerEntry {
 }
 
 /// A scoped server can either be a simple address or an overridden server.
-#[derive(Debug, Deserialize, Serialize, SmartDefault)]
+#[derive(Clone, Debug, Deserialize, Serialize, SmartDefault)]
 #[serde(untagged)]
 pub enum ScopedServer {
     /// Just the address of the server
@@ -119,20 +344,233 @@ pub enum ScopedServer {
 pub struct Server {
     /// Address of the server
     pub address: String,
+    /// Addresses tried in order when the address can't be reached (e.g. VPN, public DNS)
+    #[arg(short = 'F', long = "fallback")]
+    #[arg(value_name = "CSV")]
+    #[arg(value_delimiter = ',')]
+    pub fallback: Option<Vec<String>>,
+    /// Free-form tags for grouping servers across scopes
+    #[arg(short = 'T', long = "tag")]
+    #[arg(value_name = "CSV")]
+    #[arg(value_delimiter = ',')]
+    pub tags: Option<Vec<String>>,
+    /// Alternate names the server can be connected by
+    #[arg(short = 'A', long = "alias")]
+    #[arg(value_name = "CSV")]
+    #[arg(value_delimiter = ',')]
+    pub aliases: Option<Vec<String>>,
+    /// Free-form notes (e.g. how to reach the console)
+    #[arg(short = 'N', long)]
+    #[arg(value_name = "TEXT")]
+    pub notes: Option<String>,
     /// Scope-specific connection parameters
     #[command(flatten)]
     #[serde(flatten)]
     pub scope: Scope,
 }
 
+/// Problem found while checking the config
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Where the problem is, as dotted keys (or the file location for syntax errors)
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The config can't be used as intended
+    Error,
+    /// Likely a mistake, but the config still works
+    Warning,
+}
+
 impl Config {
     pub fn check_scope(&self, scope: &str) -> bool {
         self.scopes.contains_key(scope)
     }
 
-    pub fn list(&mut self, current_scope: String, all: bool, scopes: bool) -> Result<LsOutput> {
+    /// Checks what the YAML syntax can't: scopes of server groups,
+    /// server name patterns, paths of private keys and buffer sizes
+    pub fn lint(&self) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        if let Some(default) = &self.default {
+            findings.extend(lint_scope("", default));
+        }
+        for (name, scope) in &self.scopes {
+            findings.extend(lint_scope(&format!("scopes.{}.", name), scope));
+        }
+        for (name, entry) in &self.servers {
+            if matches!(entry, ServerEntry::Scope(_)) && !self.check_scope(name) {
+                findings.push(Finding {
+                    severity: Severity::Error,
+                    path: format!("servers.{}", name),
+                    message: format!("Scope '{}' is not defined in scopes", name),
+                });
+            }
+        }
+        let servers = self.servers.iter().flat_map(|(name, entry)| match entry {
+            ServerEntry::Global(server) => vec![(None, name, server)],
+            ServerEntry::Scope(servers) => servers
+                .iter()
+                .map(|(server_name, server)| (Some(name), server_name, server))
+                .collect(),
+        });
+        for (scope, name, server) in servers {
+            let path = match scope {
+                Some(scope) => format!("servers.{}.{}", scope, name),
+                None => format!("servers.{}", name),
+            };
+            if let ScopedServer::Override(server) = server {
+                findings.extend(lint_scope(&format!("{}.", path), &server.scope));
+            }
+            if is_pattern(name)
+                && let Err(e) = self.pattern_syntax.compile(name)
+            {
+                findings.push(Finding {
+                    severity: Severity::Error,
+                    path,
+                    message: format!("Invalid pattern: {}", e),
+                });
+            }
+        }
+
+        findings
+    }
+
+    /// Replaces the references to `vars` in addresses and paths with their values.
+    /// The config isn't saved afterwards, so the file keeps the references.
+    pub fn expand_vars(&mut self) -> Result<(), CliError> {
+        let Self {
+            default,
+            vars,
+            scopes,
+            servers,
+            ..
+        } = self;
+        for scope in default.iter_mut().chain(scopes.values_mut()) {
+            scope.expand_vars(vars)?;
+        }
+        for entry in servers.values_mut() {
+            let servers = match entry {
+                ServerEntry::Global(server) => vec![server],
+                ServerEntry::Scope(servers) => servers.values_mut().collect(),
+            };
+            for server in servers {
+                match server {
+                    ScopedServer::Address(address) => *address = expand(address, vars)?,
+                    ScopedServer::Override(server) => {
+                        for address in server.addresses_mut() {
+                            *address = expand(address, vars)?;
+                        }
+                        server.scope.expand_vars(vars)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The first server of the scope (or the first global one if `None`) whose name
+    /// as a pattern matches the host, with the host and capture groups applied.
+    /// Patterns are compiled on the first call, later ones reuse them.
+    pub fn find_matching(
+        &mut self,
+        scope: Option<&str>,
+        host: &str,
+    ) -> Result<Option<(String, Server)>, regex_lite::Error> {
+        let patterns = self
+            .patterns
+            .get_or_insert_with(|| PatternIndex::new(&self.servers, self.pattern_syntax));
+        let Some((pattern, groups)) = patterns.find(scope, host)? else {
+            return Ok(None);
+        };
+        let entry = match scope {
+            Some(scope) => match self.servers.get(scope) {
+                Some(ServerEntry::Scope(servers)) => servers.get(pattern),
+                _ => None,
+            },
+            None => match self.servers.get(pattern) {
+                Some(ServerEntry::Global(server)) => Some(server),
+                _ => None,
+            },
+        };
+
+        Ok(entry.map(|entry| {
+            let mut server: Server = entry.clone().into();
+            server.apply_host_placeholder(host);
+            server.apply_captures(&groups);
+            (pattern.to_string(), server)
+        }))
+    }
+
+    /// The server names changed, patterns are compiled again on the next lookup
+    #[inline]
+    fn forget_patterns(&mut self) {
+        self.patterns = None;
+    }
+
+    /// Whether any scope can be activated by the network
+    pub fn has_network_rules(&self) -> bool {
+        self.scopes.values().any(|scope| {
+            scope
+                .network
+                .as_ref()
+                .is_some_and(|rules| !rules.is_empty())
+        })
+    }
+
+    /// The first scope whose network rules match the network state
+    pub fn auto_scope(&self, state: &NetworkState) -> Option<&str> {
+        self.scopes
+            .iter()
+            .find(|(_, scope)| {
+                scope
+                    .network
+                    .as_ref()
+                    .is_some_and(|rules| !rules.is_empty() && rules.matches(state))
+            })
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Literal server names and aliases reachable from the current scope, patterns are skipped
+    pub fn server_names(&self, current_scope: &str) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut push = |name: &String, server: &ScopedServer| {
+            names.extend(
+                iter::once(name)
+                    .chain(server.aliases())
+                    .filter(|name| !is_pattern(name))
+                    .cloned(),
+            );
+        };
+        if let Some(ServerEntry::Scope(scope_servers)) = self.servers.get(current_scope) {
+            scope_servers.iter().for_each(|(n, s)| push(n, s));
+        }
+        for (name, entry) in &self.servers {
+            if let ServerEntry::Global(server) = entry {
+                push(name, server);
+            }
+        }
+
+        names
+    }
+
+    pub fn list(
+        &mut self,
+        current_scope: String,
+        all: bool,
+        scopes: bool,
+        filter: &ServerFilter,
+        frecency: Option<&Frecency>,
+    ) -> Result<LsOutput> {
+        self.forget_patterns();
+        self.retain_servers(filter);
         if all {
-            self.sort_servers();
+            self.sort_servers(frecency);
             return Ok(LsOutput::All(mem::take(&mut self.servers)));
         }
         if scopes {
@@ -145,7 +583,7 @@ impl Config {
         if self.scopes.contains_key(&current_scope) {
             let result = match self.servers.get_mut(&current_scope) {
                 Some(ServerEntry::Scope(servers)) => {
-                    servers.sort_unstable_keys();
+                    sort_scoped_servers(servers, frecency);
                     mem::take(servers)
                 }
                 _ => IndexMap::new(),
@@ -153,41 +591,111 @@ impl Config {
             return Ok(LsOutput::Scope(current_scope, result));
         }
 
-        self.sort_servers();
+        self.sort_servers(frecency);
         Ok(LsOutput::All(mem::take(&mut self.servers)))
     }
 
-    pub fn add_server(mut self, name: String, server: Server, global: bool) -> Result<Self> {
-        if global {
-            self.add_global_server(name, server)?;
-            return Ok(self);
+    /// Lays the listing out as a table, servers are shown with their effective settings
+    pub fn table(&self, output: LsOutput, wide: bool, last_seen: Option<&LastSeen>) -> TableOutput {
+        if let LsOutput::AllScopes(default, scopes) = output {
+            return TableOutput::scopes(default, scopes, wide);
         }
-        let current_scope = Context::load_from_file()?.into_scope();
-        if current_scope.is_empty() {
-            self.add_global_server(name, server)?;
-            return Ok(self);
+
+        TableOutput::servers(self.effective_servers(output), wide, last_seen)
+    }
+
+    /// Listed servers with the settings of their scope and the defaults merged in
+    pub fn effective_servers(&self, output: LsOutput) -> Vec<(Option<String>, String, Server)> {
+        output
+            .into_servers()
+            .into_iter()
+            .map(|(scope, name, server)| {
+                let mut server: Server = server.into();
+                let inherited = inherited_scope(&self.scopes, &self.default, scope.as_deref());
+                server.scope = mem::take(&mut server.scope).merged_with(inherited);
+                server.scope.user = server.scope.user.or_else(|| env::var("USER").ok());
+                server.scope.port = server.scope.port.or(Some(DEFAULT_SSH_PORT));
+                (scope, name, server)
+            })
+            .collect()
+    }
+
+    /// Finds a server in every scope (the current one first) or a single scope by name
+    pub fn show(&mut self, name: String, scope: bool, current_scope: &str) -> Result<ShowOutput> {
+        self.forget_patterns();
+        if scope {
+            return match self.scopes.swap_remove(&name) {
+                Some(found) => Ok(ShowOutput::Scope(name, Box::new(found))),
+                None => Err(CliError::ScopeNotFound(name.into()).into()),
+            };
+        }
+        let mut found = Vec::new();
+        if let Some(ServerEntry::Scope(scope_servers)) = self.servers.get_mut(current_scope)
+            && let Some(server) = scope_servers.swap_remove(&name)
+        {
+            found.push((Some(current_scope.to_string()), name.clone(), server));
+        }
+        if let Some(ServerEntry::Global(server)) = self.servers.get_mut(&name) {
+            found.push((None, name.clone(), mem::take(server)));
+        }
+        for (scope_name, entry) in self.servers.iter_mut() {
+            if let ServerEntry::Scope(scope_servers) = entry
+                && let Some(server) = scope_servers.swap_remove(&name)
+            {
+                found.push((Some(scope_name.clone()), name.clone(), server));
+            }
+        }
+        if found.is_empty() {
+            return Err(CliError::ServerNotFound(name.into()).into());
+        }
+
+        Ok(ShowOutput::Servers(found))
+    }
+
+    pub fn add_server(mut self, name: String, server: Server, global: bool) -> Result<Self> {
+        let current_scope = if global {
+            String::new()
+        } else {
+            active_scope(&self)?
+        };
+        self.add_server_to(&current_scope, name, server)?;
+
+        Ok(self)
+    }
+
+    /// Adds the server to the scope (or as a global one if the scope is empty)
+    pub fn add_server_to(&mut self, scope: &str, name: String, server: Server) -> Result<()> {
+        self.forget_patterns();
+        if scope.is_empty() {
+            return self.add_global_server(name, server);
         }
         let mut scope_servers;
-        if let Some(ServerEntry::Scope(servers)) = self.servers.get_mut(&current_scope) {
+        if let Some(ServerEntry::Scope(servers)) = self.servers.get_mut(scope) {
             if servers.contains_key(&name) {
                 return Err(CliError::ServerExists(name.into()).into());
             }
             scope_servers = mem::take(servers);
         } else {
-            if !self.scopes.contains_key(&current_scope) {
-                return Err(CliError::ScopeNotFound(current_scope.into()).into());
+            if !self.scopes.contains_key(scope) {
+                return Err(CliError::ScopeNotFound(scope.into()).into());
             }
             scope_servers = IndexMap::new();
         }
-        if server.is_only_address() {
-            scope_servers.insert(name, ScopedServer::Address(server.address));
-        } else {
-            scope_servers.insert(name, ScopedServer::Override(Box::new(server)));
-        }
+        scope_servers.insert(name, server.into());
         self.servers
-            .insert(current_scope, ServerEntry::Scope(scope_servers));
+            .insert(scope.to_string(), ServerEntry::Scope(scope_servers));
 
-        Ok(self)
+        Ok(())
+    }
+
+    /// Whether any server (in any scope) has the address
+    pub fn has_address(&self, address: &str) -> bool {
+        self.servers.values().any(|entry| match entry {
+            ServerEntry::Global(server) => server.address().eq_ignore_ascii_case(address),
+            ServerEntry::Scope(servers) => servers
+                .values()
+                .any(|server| server.address().eq_ignore_ascii_case(address)),
+        })
     }
 
     pub fn add_scope(mut self, name: String, scope: Scope) -> Result<Self> {
@@ -199,37 +707,247 @@ impl Config {
         Ok(self)
     }
 
-    pub fn remove(mut self, server: Option<String>, scope: Option<String>) -> Result<Self> {
+    /// Removes the servers from the current scope (or global ones), or a scope with all of its servers
+    pub fn remove(
+        mut self,
+        servers: &[String],
+        scope: Option<String>,
+        current_scope: &str,
+    ) -> Result<Self> {
+        self.forget_patterns();
         if let Some(scope_name) = scope {
             if self.scopes.swap_remove(&scope_name).is_none() {
                 return Err(CliError::ScopeNotFound(scope_name.into()).into());
             }
             self.servers.swap_remove(&scope_name);
-            let context = Context::load_from_file()?;
-            if *context.scope() == scope_name {
-                context.change_scope(None).save_to_file()?;
-            }
             return Ok(self);
         }
-        let name = server.expect("Server name is required");
-        let current_scope = &Context::load_from_file()?.into_scope();
+        for name in servers {
+            self.remove_server(name, current_scope)?;
+        }
+
+        Ok(self)
+    }
+
+    pub fn remove_tagged(self, filter: &ServerFilter, current_scope: &str) -> Result<Self> {
+        let names = self.matching_servers(filter, current_scope);
+        if names.is_empty() {
+            return Err(CliError::ServerNotFound(filter.tags.join(", ").into()).into());
+        }
+
+        self.remove(&names, None, current_scope)
+    }
+
+    /// Names of the servers in the current scope (or global ones) matching the filter
+    pub fn matching_servers(&self, filter: &ServerFilter, current_scope: &str) -> Vec<String> {
+        let (scopes, default) = (&self.scopes, &self.default);
+        if current_scope.is_empty() {
+            return self
+                .servers
+                .iter()
+                .filter(|(_, entry)| match entry {
+                    ServerEntry::Global(server) => {
+                        filter.matches(server, || inherited_scope(scopes, default, None))
+                    }
+                    ServerEntry::Scope(_) => false,
+                })
+                .map(|(name, _)| name.clone())
+                .collect();
+        }
+        match self.servers.get(current_scope) {
+            Some(ServerEntry::Scope(scope_servers)) => scope_servers
+                .iter()
+                .filter(|(_, server)| {
+                    filter.matches(server, || {
+                        inherited_scope(scopes, default, Some(current_scope))
+                    })
+                })
+                .map(|(name, _)| name.clone())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Names of the servers filed under the scope
+    pub fn scope_servers(&self, scope: &str) -> Vec<String> {
+        match self.servers.get(scope) {
+            Some(ServerEntry::Scope(scope_servers)) => scope_servers.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    #[inline]
+    fn remove_server(&mut self, name: &str, current_scope: &str) -> Result<()> {
         if current_scope.is_empty() {
-            if self.servers.swap_remove(&name).is_some() {
-                return Ok(self);
+            if let Some(ServerEntry::Global(_)) = self.servers.get(name) {
+                self.servers.swap_remove(name);
+                return Ok(());
+            }
+        } else if let Some(ServerEntry::Scope(scope_servers)) = self.servers.get_mut(current_scope)
+            && scope_servers.swap_remove(name).is_some()
+        {
+            if scope_servers.is_empty() {
+                self.servers.swap_remove(current_scope);
             }
+            return Ok(());
+        }
+
+        Err(CliError::ServerNotFound(name.into()).into())
+    }
+
+    /// Copies a server within its scope (or among global servers), applying the changes on top
+    pub fn clone_server(
+        mut self,
+        name: String,
+        new_name: String,
+        address: Option<String>,
+        scope: Scope,
+        current_scope: &str,
+    ) -> Result<Self> {
+        self.forget_patterns();
+        let Some(entry) = self.server_mut(&name, current_scope) else {
             return Err(CliError::ServerNotFound(name.into()).into());
+        };
+        let mut server: Server = entry.clone().into();
+        if let Some(address) = address {
+            server.address = address;
+        }
+        server.scope = scope.merged_with(server.scope);
+
+        if current_scope.is_empty() {
+            self.add_global_server(new_name, server)?;
+            return Ok(self);
         }
         if let Some(ServerEntry::Scope(scope_servers)) = self.servers.get_mut(current_scope) {
-            if scope_servers.swap_remove(&name).is_none() {
-                return Err(CliError::ServerNotFound(name.into()).into());
+            if scope_servers.contains_key(&new_name) {
+                return Err(CliError::ServerExists(new_name.into()).into());
             }
-            if scope_servers.is_empty() {
-                self.servers.swap_remove(current_scope);
+            scope_servers.insert(new_name, server.into());
+        }
+
+        Ok(self)
+    }
+
+    /// Renames the server of the current scope, or the scope itself. The context
+    /// keeps the old name of an active scope, it's up to the caller to update it
+    /// once the config is saved.
+    pub fn rename(
+        mut self,
+        old: String,
+        new: String,
+        scope: bool,
+        current_scope: &str,
+    ) -> Result<Self> {
+        self.forget_patterns();
+        if scope {
+            self.rename_scope(old, new)?;
+            return Ok(self);
+        }
+        if current_scope.is_empty() {
+            match self.servers.get(&new) {
+                Some(ServerEntry::Global(_)) => {
+                    return Err(CliError::ServerExists(new.into()).into());
+                }
+                Some(ServerEntry::Scope(_)) => return Err(CliError::ScopeExists(new.into()).into()),
+                None => {}
+            }
+            if !matches!(self.servers.get(&old), Some(ServerEntry::Global(_))) {
+                return Err(CliError::ServerNotFound(old.into()).into());
             }
+            rename_key(&mut self.servers, &old, new);
             return Ok(self);
         }
+        let Some(ServerEntry::Scope(scope_servers)) = self.servers.get_mut(current_scope) else {
+            return Err(CliError::ServerNotFound(old.into()).into());
+        };
+        if scope_servers.contains_key(&new) {
+            return Err(CliError::ServerExists(new.into()).into());
+        }
+        if !rename_key(scope_servers, &old, new) {
+            return Err(CliError::ServerNotFound(old.into()).into());
+        }
 
-        Err(CliError::ServerNotFound(name.into()).into())
+        Ok(self)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_server(
+        mut self,
+        name: String,
+        address: Option<String>,
+        fallback: Option<Vec<String>>,
+        tags: Option<Vec<String>>,
+        aliases: Option<Vec<String>>,
+        notes: Option<String>,
+        scope: Scope,
+        current_scope: &str,
+    ) -> Result<Self> {
+        let Some(entry) = self.server_mut(&name, current_scope) else {
+            return Err(CliError::ServerNotFound(name.into()).into());
+        };
+        let mut server: Server = mem::take(entry).into();
+        if let Some(address) = address {
+            server.address = address;
+        }
+        // An empty list removes the fallback addresses
+        if let Some(fallback) = fallback {
+            let fallback: Vec<_> = fallback
+                .into_iter()
+                .filter(|address| !address.is_empty())
+                .collect();
+            server.fallback = (!fallback.is_empty()).then_some(fallback);
+        }
+        if tags.is_some() {
+            server.tags = tags;
+        }
+        if aliases.is_some() {
+            server.aliases = aliases;
+        }
+        // Empty notes remove them
+        if let Some(notes) = notes {
+            server.notes = (!notes.is_empty()).then_some(notes);
+        }
+        server.scope = scope.merged_with(server.scope);
+        *entry = server.into();
+
+        Ok(self)
+    }
+
+    pub fn set_scope(mut self, name: String, scope: Scope) -> Result<Self> {
+        let Some(existing) = self.scopes.get_mut(&name) else {
+            return Err(CliError::ScopeNotFound(name.into()).into());
+        };
+        *existing = scope.merged_with(mem::take(existing));
+
+        Ok(self)
+    }
+
+    /// Applies the settings to every server of the scope at once, returning
+    /// the servers that change (nothing is written until the config is saved)
+    pub fn set_scope_servers(&mut self, name: &str, scope: &Scope) -> Result<Vec<ServerChange>> {
+        if !self.scopes.contains_key(name) {
+            return Err(CliError::ScopeNotFound(name.into()).into());
+        }
+        let Some(ServerEntry::Scope(servers)) = self.servers.get_mut(name) else {
+            return Ok(Vec::new());
+        };
+
+        let mut changes = Vec::new();
+        for (server_name, entry) in servers.iter_mut() {
+            let mut server: Server = mem::take(entry).into();
+            let before = server.scope.clone();
+            server.scope = scope.clone().merged_with(mem::take(&mut server.scope));
+            if server.scope != before {
+                changes.push(ServerChange {
+                    name: server_name.clone(),
+                    before,
+                    after: server.scope.clone(),
+                });
+            }
+            *entry = server.into();
+        }
+
+        Ok(changes)
     }
 
     pub fn set_default(mut self, scope: Scope) -> Result<Self> {
@@ -240,6 +958,7 @@ impl Config {
 
     #[inline]
     fn add_global_server(&mut self, name: String, server: Server) -> Result<()> {
+        self.forget_patterns();
         if let Some(entry) = self.servers.get(&name) {
             let err = match entry {
                 ServerEntry::Global(_) => CliError::ServerExists(name.into()),
@@ -247,19 +966,205 @@ impl Config {
             };
             return Err(err.into());
         }
-        let server = if server.is_only_address() {
-            ScopedServer::Address(server.address)
-        } else {
-            ScopedServer::Override(Box::new(server))
-        };
-        self.servers.insert(name, ServerEntry::Global(server));
+        self.servers
+            .insert(name, ServerEntry::Global(server.into()));
+
+        Ok(())
+    }
+
+    /// Finds a server in the given scope (or among global servers if the scope is empty)
+    fn server_mut(&mut self, name: &str, current_scope: &str) -> Option<&mut ScopedServer> {
+        if current_scope.is_empty() {
+            return match self.servers.get_mut(name) {
+                Some(ServerEntry::Global(server)) => Some(server),
+                _ => None,
+            };
+        }
+        match self.servers.get_mut(current_scope) {
+            Some(ServerEntry::Scope(scope_servers)) => scope_servers.get_mut(name),
+            _ => None,
+        }
+    }
+
+    fn rename_scope(&mut self, old: String, new: String) -> Result<()> {
+        // Global servers share the names with server groups of scopes
+        if let Some(ServerEntry::Global(_)) = self.servers.get(&new) {
+            return Err(CliError::ServerExists(new.into()).into());
+        }
+        if self.scopes.contains_key(&new) || self.servers.contains_key(&new) {
+            return Err(CliError::ScopeExists(new.into()).into());
+        }
+        if !rename_key(&mut self.scopes, &old, new.clone()) {
+            return Err(CliError::ScopeNotFound(old.into()).into());
+        }
+        if matches!(self.servers.get(&old), Some(ServerEntry::Scope(_))) {
+            rename_key(&mut self.servers, &old, new);
+        }
 
         Ok(())
     }
 
+    /// Searches names, aliases, addresses and tags in every scope.
+    /// Substring matches go first, then fuzzy ones (characters in order).
+    pub fn find(&self, query: &str) -> Vec<FoundServer> {
+        let query = query.to_lowercase();
+        let mut exact = Vec::new();
+        let mut fuzzy = Vec::new();
+        let mut check = |scope: Option<&String>, name: &String, server: &ScopedServer| {
+            let fields = iter::once(("name", name.as_str()))
+                .chain(server.aliases().iter().map(|a| ("alias", a.as_str())))
+                .chain(iter::once(("address", server.address())))
+                .chain(server.tags().iter().map(|t| ("tag", t.as_str())));
+            let (mut substring, mut subsequence) = (Vec::new(), Vec::new());
+            for (field, value) in fields {
+                let value_lower = value.to_lowercase();
+                if value_lower.contains(&query) {
+                    substring.push((field, value.to_string()));
+                } else if is_subsequence(&query, &value_lower) {
+                    subsequence.push((field, value.to_string()));
+                }
+            }
+            let (target, matched) = if !substring.is_empty() {
+                (&mut exact, substring)
+            } else if !subsequence.is_empty() {
+                (&mut fuzzy, subsequence)
+            } else {
+                return;
+            };
+            target.push(FoundServer {
+                scope: scope.cloned(),
+                name: name.clone(),
+                address: server.address().to_string(),
+                matched,
+            });
+        };
+        for (key, entry) in &self.servers {
+            match entry {
+                ServerEntry::Global(server) => check(None, key, server),
+                ServerEntry::Scope(scope_servers) => scope_servers
+                    .iter()
+                    .for_each(|(name, server)| check(Some(key), name, server)),
+            }
+        }
+        exact.extend(fuzzy);
+
+        exact
+    }
+
+    /// Flags servers in every scope, patterns are only checked for missing keys
+    pub fn stale_servers(
+        &self,
+        criteria: &PruneCriteria,
+        last_seen: &HashMap<String, u64>,
+        now: u64,
+    ) -> Vec<StaleServer> {
+        let check = |scope: Option<&String>, name: &String, server: &ScopedServer| {
+            let mut reasons = Vec::new();
+            let literal = !is_pattern(name);
+            if let Some(days) = criteria.unused_days
+                && literal
+            {
+                let last = last_connected(last_seen, iter::once(name).chain(server.aliases()));
+                let stale = match last {
+                    Some(last) => now.saturating_sub(last) > days * SECS_PER_DAY,
+                    None => criteria.never_used,
+                };
+                if stale {
+                    reasons.push(StaleReason::Unused(last));
+                }
+            }
+            if criteria.dns && literal && !server.resolves() {
+                reasons.push(StaleReason::Unresolved);
+            }
+            if criteria.keys
+                && let ScopedServer::Override(server) = server
+            {
+                let Scope {
+                    private_key,
+                    openssh_cert,
+                    ..
+                } = &server.scope;
+                reasons.extend(
+                    [private_key, openssh_cert]
+                        .into_iter()
+                        .flatten()
+                        .filter(|path| !get_full_path(path.to_path_buf()).exists())
+                        .map(|path| StaleReason::MissingKey(path.clone())),
+                );
+            }
+
+            (!reasons.is_empty()).then(|| StaleServer {
+                scope: scope.cloned(),
+                name: name.clone(),
+                reasons,
+            })
+        };
+
+        self.servers
+            .iter()
+            .flat_map(|(key, entry)| match entry {
+                ServerEntry::Global(server) => check(None, key, server).into_iter().collect(),
+                ServerEntry::Scope(scope_servers) => scope_servers
+                    .iter()
+                    .filter_map(|(name, server)| check(Some(key), name, server))
+                    .collect::<Vec<_>>(),
+            })
+            .collect()
+    }
+
+    /// Removes the given servers, dropping scoped blocks left empty
+    pub fn remove_stale(mut self, stale: &[StaleServer]) -> Self {
+        self.forget_patterns();
+        for server in stale {
+            match &server.scope {
+                None => {
+                    self.servers.shift_remove(&server.name);
+                }
+                Some(scope) => {
+                    if let Some(ServerEntry::Scope(scope_servers)) = self.servers.get_mut(scope) {
+                        scope_servers.shift_remove(&server.name);
+                        if scope_servers.is_empty() {
+                            self.servers.shift_remove(scope);
+                        }
+                    }
+                }
+            }
+        }
+
+        self
+    }
+
+    /// Drops the servers (and then empty scoped blocks) not matching the filter
+    fn retain_servers(&mut self, filter: &ServerFilter) {
+        if filter.is_empty() {
+            return;
+        }
+        let (scopes, default) = (&self.scopes, &self.default);
+        self.servers.retain(|key, entry| match entry {
+            ServerEntry::Global(server) => {
+                filter.matches(server, || inherited_scope(scopes, default, None))
+            }
+            ServerEntry::Scope(scope_servers) => {
+                scope_servers.retain(|_, server| {
+                    filter.matches(server, || inherited_scope(scopes, default, Some(key)))
+                });
+                !scope_servers.is_empty()
+            }
+        });
+    }
+
     #[inline]
-    fn sort_servers(&mut self) {
+    /// Sorts alphabetically, the most used servers go first if frecency is given
+    fn sort_servers(&mut self, frecency: Option<&Frecency>) {
         self.servers.sort_unstable_keys();
+        if let Some(frecency) = frecency {
+            self.servers.sort_by_cached_key(|name, entry| match entry {
+                ServerEntry::Global(server) => {
+                    Reverse(frecency.score(iter::once(name).chain(server.aliases())))
+                }
+                ServerEntry::Scope(_) => Reverse(0),
+            });
+        }
         self.servers
             .values_mut()
             .filter_map(|entry| {
@@ -269,16 +1174,49 @@ impl Config {
                     None
                 }
             })
-            .for_each(|s| s.sort_unstable_keys());
+            .for_each(|s| sort_scoped_servers(s, frecency));
+    }
+}
+
+#[inline]
+fn sort_scoped_servers(servers: &mut IndexMap<String, ScopedServer>, frecency: Option<&Frecency>) {
+    servers.sort_unstable_keys();
+    if let Some(frecency) = frecency {
+        servers.sort_by_cached_key(|name, server| {
+            Reverse(frecency.score(iter::once(name).chain(server.aliases())))
+        });
     }
 }
 
+/// Whether all characters of the needle appear in the haystack in order
+#[inline]
+pub(crate) fn is_subsequence(needle: &str, haystack: &str) -> bool {
+    let mut haystack = haystack.chars();
+    needle.chars().all(|c| haystack.any(|h| h == c))
+}
+
+/// Renames a key in place, keeping the entry at the same position
+fn rename_key<V>(map: &mut IndexMap<String, V>, old: &str, new: String) -> bool {
+    let Some((index, _, value)) = map.swap_remove_full(old) else {
+        return false;
+    };
+    let (new_index, _) = map.insert_full(new, value);
+    map.swap_indices(index, new_index);
+
+    true
+}
+
 impl StorageProvider for Config {
     #[inline]
     fn work_file() -> &'static LazyLock<Box<str>> {
         &CONFIG_PATH
     }
 
+    #[inline]
+    fn backup_limit(&self) -> usize {
+        self.backups.unwrap_or(CONFIG_BACKUPS)
+    }
+
     fn serialize(&self) -> Result<String> {
         Ok(serde_yml::to_string(&self).map_err(FileError::Yaml)?)
     }
@@ -288,10 +1226,262 @@ impl StorageProvider for Config {
     }
 }
 
+impl PatternIndex {
+    fn new(servers: &IndexMap<String, ServerEntry>, syntax: PatternSyntax) -> Self {
+        let compiled = |name: &String| (name.clone(), syntax.compile(name));
+        let mut index = Self::default();
+        for (name, entry) in servers {
+            match entry {
+                ServerEntry::Global(_) => index.global.push(compiled(name)),
+                ServerEntry::Scope(servers) => {
+                    index
+                        .scoped
+                        .insert(name.clone(), servers.keys().map(compiled).collect());
+                }
+            }
+        }
+
+        index
+    }
+
+    /// The first server name of the scope (or among the global servers if `None`)
+    /// matching the host, along with its capture groups (`$0` is the whole match)
+    fn find<'h>(
+        &self,
+        scope: Option<&str>,
+        host: &'h str,
+    ) -> Result<Option<(&str, Vec<&'h str>)>, regex_lite::Error> {
+        let patterns = match scope {
+            Some(scope) => self.scoped.get(scope).map_or(&[][..], Vec::as_slice),
+            None => &self.global,
+        };
+        for (name, regex) in patterns {
+            if let Some(caps) = regex.as_ref().map_err(Clone::clone)?.captures(host) {
+                let groups = caps
+                    .iter()
+                    .map(|group| group.map_or("", |m| m.as_str()))
+                    .collect();
+                return Ok(Some((name, groups)));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Checks the settings of a layer, `prefix` is its path with a trailing dot (if any)
+fn lint_scope(prefix: &str, scope: &Scope) -> impl Iterator<Item = Finding> {
+    let buffer_size = (scope.buffer_size == Some(0)).then(|| Finding {
+        severity: Severity::Error,
+        path: format!("{}buffer_size", prefix),
+        message: "Buffer size must be greater than 0".into(),
+    });
+
+    [
+        lint_key(format!("{}private_key", prefix), scope),
+        buffer_size,
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// Keys are looked up relative to the home directory only
+fn lint_key(path: String, scope: &Scope) -> Option<Finding> {
+    let key = scope.private_key.as_ref()?;
+    if !key.is_absolute() && !key.starts_with("~/") {
+        return Some(Finding {
+            severity: Severity::Error,
+            path,
+            message: format!("Key '{}' must be absolute or begin with ~/", key.display()),
+        });
+    }
+    if !get_full_path(key.clone()).exists() {
+        return Some(Finding {
+            severity: Severity::Warning,
+            path,
+            message: format!("Key '{}' does not exist", key.display()),
+        });
+    }
+
+    None
+}
+
+/// Characters that only make sense in a regular expression
+const REGEX_ONLY_CHARS: &[char] = &['^', '$', '(', ')', '[', ']', '{', '}', '+', '|', '\\'];
+
+impl PatternSyntax {
+    pub fn is_auto(&self) -> bool {
+        *self == Self::Auto
+    }
+
+    /// Compiles the server name into a matcher according to the syntax
+    pub fn compile(self, pattern: &str) -> Result<Regex, regex_lite::Error> {
+        match self {
+            Self::Regex => Regex::new(pattern),
+            Self::Glob => Regex::new(&glob_to_regex(pattern)),
+            Self::Auto if is_glob(pattern) => Regex::new(&glob_to_regex(pattern)),
+            Self::Auto => Regex::new(pattern),
+        }
+    }
+}
+
+#[inline]
+pub(crate) fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '?']) || name.contains(REGEX_ONLY_CHARS)
+}
+
+#[inline]
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?']) && !pattern.contains(REGEX_ONLY_CHARS) && !pattern.contains(".*")
+}
+
+/// Translates wildcards into an anchored regular expression,
+/// each wildcard becomes a capture group
+fn glob_to_regex(pattern: &str) -> String {
+    let mut regex = String::with_capacity(pattern.len() + 2);
+    regex.push('^');
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str("(.*)"),
+            '?' => regex.push_str("(.)"),
+            c => regex.push_str(&regex_lite::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+
+    regex
+}
+
+impl ServerFilter {
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && !self.has_attributes()
+    }
+
+    /// Whether the filter needs the effective settings of servers
+    #[inline]
+    fn has_attributes(&self) -> bool {
+        self.user.is_some() || self.port.is_some() || self.private_key.is_some()
+    }
+
+    /// The inherited scope (of the server's scope and defaults) is built only if needed
+    pub fn matches(&self, server: &ScopedServer, inherited: impl FnOnce() -> Scope) -> bool {
+        let tags = server.tags();
+        if !self.tags.iter().all(|tag| tags.contains(tag)) {
+            return false;
+        }
+        if !self.has_attributes() {
+            return true;
+        }
+        let effective = match server {
+            ScopedServer::Address(_) => inherited(),
+            ScopedServer::Override(server) => server.scope.clone().merged_with(inherited()),
+        };
+        let user = effective.user.or_else(|| env::var("USER").ok());
+        let port = effective.port.unwrap_or(DEFAULT_SSH_PORT);
+
+        self.user.as_ref().is_none_or(|u| user.as_ref() == Some(u))
+            && self.port.is_none_or(|p| p == port)
+            && self.private_key.as_ref().is_none_or(|key| {
+                effective.private_key.map(get_full_path) == Some(get_full_path(key.clone()))
+            })
+    }
+}
+
+/// Settings a server inherits from its scope (if any) and the defaults
+pub(crate) fn inherited_scope(
+    scopes: &IndexMap<String, Scope>,
+    default: &Option<Scope>,
+    scope: Option<&str>,
+) -> Scope {
+    let default = default.clone().unwrap_or_default();
+    match scope.and_then(|name| scopes.get(name)) {
+        Some(scope) => scope.clone().merged_with(default),
+        None => default,
+    }
+}
+
+impl ScopedServer {
+    pub fn tags(&self) -> &[String] {
+        match self {
+            Self::Address(_) => &[],
+            Self::Override(server) => server.tags.as_deref().unwrap_or_default(),
+        }
+    }
+
+    pub fn aliases(&self) -> &[String] {
+        match self {
+            Self::Address(_) => &[],
+            Self::Override(server) => server.aliases.as_deref().unwrap_or_default(),
+        }
+    }
+
+    pub fn has_alias(&self, name: &str) -> bool {
+        self.aliases().iter().any(|alias| alias == name)
+    }
+
+    pub fn address(&self) -> &str {
+        match self {
+            Self::Address(address) => address,
+            Self::Override(server) => &server.address,
+        }
+    }
+
+    /// Whether the address resolves, those with placeholders are assumed to
+    pub fn resolves(&self) -> bool {
+        let address = self.address();
+        // The port does not matter for the lookup
+        address.contains('$') || (address, 0).to_socket_addrs().is_ok()
+    }
+}
+
 impl Scope {
     pub fn is_empty(&self) -> bool {
         *self == Scope::default()
     }
+
+    fn expand_vars(&mut self, vars: &IndexMap<String, String>) -> Result<(), CliError> {
+        let paths = [
+            &mut self.known_hosts,
+            &mut self.private_key,
+            &mut self.openssh_cert,
+            &mut self.log_sessions,
+        ];
+        let extra = self.extra_known_hosts.iter_mut().flatten();
+        for path in paths.into_iter().flatten().chain(extra) {
+            if let Some(text) = path.to_str() {
+                *path = expand(text, vars)?.into();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fields of `self` take precedence, the rest are taken from `other`
+    pub fn merged_with(mut self, other: Scope) -> Self {
+        self += other;
+        self
+    }
+}
+
+/// Substitutes `{{name}}` (spaces inside the braces are allowed) with the value of the variable
+fn expand(text: &str, vars: &IndexMap<String, String>) -> Result<String, CliError> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let name = rest[start + 2..start + 2 + len].trim();
+        let value = vars
+            .get(name)
+            .ok_or_else(|| CliError::UndefinedVariable(name.into()))?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(value);
+        rest = &rest[start + len + 4..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
 }
 
 impl Server {
@@ -308,10 +1498,30 @@ impl Server {
 
     pub fn is_only_address(&self) -> bool {
         self.scope.is_empty()
+            && self.fallback.is_none()
+            && self.tags.is_none()
+            && self.aliases.is_none()
+            && self.notes.is_none()
+    }
+
+    /// The address followed by the fallback ones
+    fn addresses_mut(&mut self) -> impl Iterator<Item = &mut String> {
+        iter::once(&mut self.address).chain(self.fallback.iter_mut().flatten())
     }
 
     pub fn apply_host_placeholder(&mut self, host: &str) {
-        self.address = self.address.replace("$h", host);
+        for address in self.addresses_mut() {
+            *address = address.replace("$h", host);
+        }
+    }
+
+    /// Substitutes `$N` or `${N}` with the N-th capture group of the matched pattern
+    pub fn apply_captures(&mut self, groups: &[&str]) {
+        for address in self.addresses_mut() {
+            if address.contains('$') {
+                *address = substitute_captures(address, groups);
+            }
+        }
     }
 
     pub fn apply_scope(&mut self, scope: Scope) {
@@ -319,21 +1529,72 @@ impl Server {
     }
 }
 
+fn substitute_captures(template: &str, groups: &[&str]) -> String {
+    let mut address = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.find('$') {
+        address.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        let braced = rest.starts_with('{');
+        let digits = if braced { &rest[1..] } else { rest };
+        let len = digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(digits.len());
+        // Braced form requires the closing brace right after the digits
+        let closed = !braced || digits[len..].starts_with('}');
+        match digits[..len].parse::<usize>() {
+            Ok(index) if closed => {
+                address.push_str(groups.get(index).copied().unwrap_or_default());
+                rest = &digits[len + usize::from(braced)..];
+            }
+            _ => address.push('$'),
+        }
+    }
+    address.push_str(rest);
+
+    address
+}
+
 impl AddAssign for Scope {
     fn add_assign(&mut self, other: Self) {
         let Self {
+            description,
             user,
             port,
             known_hosts,
+            extra_known_hosts,
+            host_key_check,
+            pinned_keys,
+            visual_host_key,
+            known_hosts_read_only,
             private_key,
+            password,
+            password_auth,
+            password_attempts,
+            preferred_auth,
+            sudo,
+            shared_sudo_password,
+            auth_log,
+            event_log,
             openssh_cert,
             kex,
             alg,
             cipher,
             mac,
+            profile,
             timeout,
             interval,
             retries,
+            idle_timeout,
+            buffer_size,
+            terminal_title,
+            term,
+            log_sessions,
+            login_script,
+            dns_cache_ttl,
+            network,
+            hooks,
+            socket,
         } = self;
 
         macro_rules! merge_fields {
@@ -345,19 +1606,63 @@ impl AddAssign for Scope {
         }
 
         merge_fields!(
+            description,
             user,
             port,
             known_hosts,
+            extra_known_hosts,
+            host_key_check,
+            pinned_keys,
+            visual_host_key,
+            known_hosts_read_only,
             private_key,
+            password,
+            password_auth,
+            password_attempts,
+            preferred_auth,
+            sudo,
+            shared_sudo_password,
+            auth_log,
+            event_log,
             openssh_cert,
             kex,
             alg,
             cipher,
             mac,
+            profile,
             timeout,
             interval,
             retries,
+            idle_timeout,
+            buffer_size,
+            terminal_title,
+            term,
+            log_sessions,
+            login_script,
+            dns_cache_ttl,
+            network,
         );
+        *hooks = merge_nested(hooks.take(), other.hooks, Hooks::or);
+        *socket = merge_nested(socket.take(), other.socket, SocketOptions::or);
+    }
+}
+
+/// Settings grouped under one key are merged field by field,
+/// the fields set here win over the other ones
+fn merge_nested<T>(ours: Option<T>, theirs: Option<T>, merge: fn(T, T) -> T) -> Option<T> {
+    match (ours, theirs) {
+        (Some(ours), Some(theirs)) => Some(merge(ours, theirs)),
+        (ours, theirs) => ours.or(theirs),
+    }
+}
+
+impl From<Server> for ScopedServer {
+    fn from(server: Server) -> Self {
+        if server.is_only_address() {
+            ScopedServer::Address(server.address)
+        } else {
+            ScopedServer::Override(Box::new(server))
+        }
     }
 }
 
@@ -373,6 +1678,7 @@ impl From<ScopedServer> for Server {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::context::{Context, HistoryEntry};
     use crate::storage::provider::StorageProvider;
 
     // Config
@@ -387,13 +1693,13 @@ mod tests {
     #[test]
     fn list_flags() {
         let mut cfg = Config::default();
-        let res = cfg.list("".into(), true, false);
+        let res = cfg.list("".into(), true, false, &ServerFilter::default(), None);
         assert!(matches!(res, Ok(LsOutput::All(_))));
-        let res = cfg.list("".into(), false, true);
+        let res = cfg.list("".into(), false, true, &ServerFilter::default(), None);
         assert!(matches!(res, Ok(LsOutput::AllScopes(_, _))));
-        let res = cfg.list("".into(), true, true);
+        let res = cfg.list("".into(), true, true, &ServerFilter::default(), None);
         assert!(matches!(res, Ok(LsOutput::All(_))));
-        let res = cfg.list("".into(), false, false);
+        let res = cfg.list("".into(), false, false, &ServerFilter::default(), None);
         assert!(matches!(res, Ok(LsOutput::All(_))));
     }
 
@@ -406,7 +1712,7 @@ mod tests {
         cfg.servers.insert(host1.clone(), ServerEntry::default());
         cfg.servers.insert(host2.clone(), ServerEntry::default());
 
-        let res = cfg.list("".into(), true, false);
+        let res = cfg.list("".into(), true, false, &ServerFilter::default(), None);
         assert!(matches!(res, Ok(LsOutput::All(_))));
         if let Ok(LsOutput::All(servers)) = res {
             assert_eq!(servers.len(), 2);
@@ -417,7 +1723,7 @@ mod tests {
         cfg.scopes.insert(scope1.clone(), Scope::default());
         cfg.scopes.insert(scope2.clone(), Scope::default());
 
-        let res = cfg.list("".into(), false, true);
+        let res = cfg.list("".into(), false, true, &ServerFilter::default(), None);
         assert!(matches!(res, Ok(LsOutput::AllScopes(_, _))));
         if let Ok(LsOutput::AllScopes(_, scopes)) = res {
             assert_eq!(scopes.len(), 2);
@@ -431,7 +1737,7 @@ mod tests {
         cfg.servers
             .insert(scope1.clone(), ServerEntry::Scope(scoped_servers));
 
-        let res = cfg.list(scope1, false, false);
+        let res = cfg.list(scope1, false, false, &ServerFilter::default(), None);
         assert!(matches!(res, Ok(LsOutput::Scope(_, _))));
         if let Ok(LsOutput::Scope(_, servers)) = res {
             assert_eq!(servers.len(), 1);
@@ -439,6 +1745,680 @@ mod tests {
         }
     }
 
+    #[test]
+    fn list_tag_filter() {
+        let mut cfg = Config::default();
+        let tagged = |tags: &[&str]| {
+            ServerEntry::Global(ScopedServer::Override(Box::new(Server {
+                address: "host".into(),
+                tags: Some(tags.iter().map(|t| t.to_string()).collect()),
+                ..Default::default()
+            })))
+        };
+        cfg.servers.insert("db-eu".into(), tagged(&["db", "eu"]));
+        cfg.servers.insert("db-us".into(), tagged(&["db", "us"]));
+        cfg.servers.insert("plain".into(), ServerEntry::default());
+
+        let filter = ServerFilter {
+            tags: vec!["db".into(), "eu".into()],
+            ..Default::default()
+        };
+        let res = cfg.list("".into(), true, false, &filter, None);
+        if let Ok(LsOutput::All(servers)) = res {
+            assert_eq!(servers.len(), 1);
+            assert!(servers.contains_key("db-eu"));
+        } else {
+            panic!("Expected all servers listing");
+        }
+    }
+
+    #[test]
+    fn effective_servers_merge_scope() {
+        let mut cfg = Config {
+            default: Some(Scope {
+                user: Some("admin".into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        cfg.scopes.insert(
+            "prod".into(),
+            Scope {
+                port: Some(2222),
+                ..Default::default()
+            },
+        );
+        let mut scoped_servers = IndexMap::new();
+        scoped_servers.insert("db".into(), ScopedServer::default());
+        cfg.servers
+            .insert("prod".into(), ServerEntry::Scope(scoped_servers));
+
+        let res = cfg.list("prod".into(), false, false, &ServerFilter::default(), None);
+        let servers = cfg.effective_servers(res.unwrap());
+        assert_eq!(servers.len(), 1);
+        let (scope, name, server) = &servers[0];
+        assert_eq!((scope.as_deref(), name.as_str()), (Some("prod"), "db"));
+        assert_eq!(server.scope.user.as_deref(), Some("admin"));
+        assert_eq!(server.scope.port, Some(2222));
+    }
+
+    #[test]
+    fn lint_findings() {
+        let mut cfg = Config::default();
+        cfg.scopes.insert(
+            "prod".into(),
+            Scope {
+                private_key: Some("keys/id".into()),
+                buffer_size: Some(0),
+                ..Default::default()
+            },
+        );
+        let mut scoped_servers = IndexMap::new();
+        scoped_servers.insert("web-(".into(), ScopedServer::default());
+        cfg.servers
+            .insert("dev".into(), ServerEntry::Scope(scoped_servers));
+
+        let findings = cfg.lint();
+        let paths: Vec<_> = findings.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "scopes.prod.private_key",
+                "scopes.prod.buffer_size",
+                "servers.dev",
+                "servers.dev.web-("
+            ]
+        );
+        assert!(findings.iter().all(|f| f.severity == Severity::Error));
+        assert!(Config::default().lint().is_empty());
+    }
+
+    #[test]
+    fn server_names_with_aliases() {
+        let mut cfg = Config::default();
+        let aliased = ScopedServer::Override(Box::new(Server {
+            address: "10.0.0.1".into(),
+            aliases: Some(vec!["db1".into(), "primary-db".into()]),
+            ..Default::default()
+        }));
+        assert!(aliased.has_alias("db1"));
+        assert!(!aliased.has_alias("db2"));
+
+        let mut scoped_servers = IndexMap::new();
+        scoped_servers.insert("app".into(), ScopedServer::default());
+        cfg.servers
+            .insert("job".into(), ServerEntry::Scope(scoped_servers));
+        cfg.servers
+            .insert("database".into(), ServerEntry::Global(aliased));
+        cfg.servers
+            .insert("web-*".into(), ServerEntry::Global(ScopedServer::default()));
+
+        assert_eq!(
+            cfg.server_names("job"),
+            ["app", "database", "db1", "primary-db"]
+        );
+        assert_eq!(cfg.server_names(""), ["database", "db1", "primary-db"]);
+    }
+
+    #[test]
+    fn list_by_frecency() {
+        let mut cfg = Config::default();
+        for name in ["alpha", "beta", "gamma"] {
+            cfg.servers.insert(name.into(), ServerEntry::default());
+        }
+        let context = Context::default().record(HistoryEntry {
+            host: "gamma".into(),
+            address: "gamma".into(),
+            user: "root".into(),
+            timestamp: 0,
+            duration: 0,
+            exit_status: Some(0),
+        });
+
+        let frecency = context.frecency(0);
+        let res = cfg.list(
+            "".into(),
+            true,
+            false,
+            &ServerFilter::default(),
+            Some(&frecency),
+        );
+        if let Ok(LsOutput::All(servers)) = res {
+            assert!(servers.keys().eq(["gamma", "alpha", "beta"]));
+        } else {
+            panic!("Expected all servers listing");
+        }
+    }
+
+    #[test]
+    fn prune_stale_servers() {
+        let mut cfg = Config::default();
+        let mut scoped_servers = IndexMap::new();
+        scoped_servers.insert(
+            "legacy".into(),
+            ScopedServer::Override(Box::new(Server {
+                address: "legacy.local".into(),
+                scope: Scope {
+                    private_key: Some("/nonexistent/legacy_rsa".into()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })),
+        );
+        cfg.servers
+            .insert("job".into(), ServerEntry::Scope(scoped_servers));
+        cfg.servers.insert("fresh".into(), ServerEntry::default());
+        cfg.servers.insert("old".into(), ServerEntry::default());
+        cfg.servers.insert("web-*".into(), ServerEntry::default());
+
+        let now = 100 * SECS_PER_DAY;
+        let last_seen = HashMap::from([
+            ("fresh".to_string(), now - SECS_PER_DAY),
+            ("old".to_string(), now - 50 * SECS_PER_DAY),
+        ]);
+        let criteria = PruneCriteria {
+            unused_days: Some(30),
+            keys: true,
+            ..Default::default()
+        };
+        let stale = cfg.stale_servers(&criteria, &last_seen, now);
+        let names: Vec<_> = stale.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["legacy", "old"]);
+        assert_eq!(
+            stale[0].reasons,
+            [StaleReason::MissingKey("/nonexistent/legacy_rsa".into())]
+        );
+
+        // Servers missing from the history are only flagged when asked for
+        let unused = PruneCriteria {
+            unused_days: Some(30),
+            ..Default::default()
+        };
+        assert!(cfg.stale_servers(&unused, &HashMap::new(), now).is_empty());
+        let criteria = PruneCriteria {
+            never_used: true,
+            ..criteria
+        };
+        let stale = cfg.stale_servers(&criteria, &last_seen, now);
+        assert_eq!(
+            stale[0].reasons,
+            [
+                StaleReason::Unused(None),
+                StaleReason::MissingKey("/nonexistent/legacy_rsa".into())
+            ]
+        );
+
+        let cfg = cfg.remove_stale(&stale);
+        assert!(cfg.servers.keys().eq(["fresh", "web-*"]));
+    }
+
+    #[test]
+    fn find_servers() {
+        let mut cfg = Config::default();
+        let mut scoped_servers = IndexMap::new();
+        scoped_servers.insert(
+            "primary".into(),
+            ScopedServer::Override(Box::new(Server {
+                address: "10.0.0.5".into(),
+                tags: Some(vec!["Database".into()]),
+                ..Default::default()
+            })),
+        );
+        cfg.servers
+            .insert("job".into(), ServerEntry::Scope(scoped_servers));
+        cfg.servers.insert(
+            "web".into(),
+            ServerEntry::Global(ScopedServer::Address("db-proxy.example.com".into())),
+        );
+
+        let found = cfg.find("DB");
+        let names: Vec<_> = found.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["web", "primary"]);
+        assert_eq!(
+            found[0].matched,
+            [("address", "db-proxy.example.com".into())]
+        );
+        assert_eq!(found[1].scope.as_deref(), Some("job"));
+        assert_eq!(found[1].matched, [("tag", "Database".into())]);
+        assert!(cfg.find("zzz").is_empty());
+    }
+
+    #[test]
+    fn list_attribute_filter() {
+        let names = |filter: ServerFilter| {
+            let mut cfg = Config {
+                default: Some(Scope {
+                    user: Some("root".into()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            cfg.scopes.insert(
+                "job".into(),
+                Scope {
+                    user: Some("deploy".into()),
+                    port: Some(2222),
+                    ..Default::default()
+                },
+            );
+            let mut scoped_servers = IndexMap::new();
+            scoped_servers.insert("app".into(), ScopedServer::default());
+            scoped_servers.insert(
+                "legacy".into(),
+                ScopedServer::Override(Box::new(Server {
+                    address: "legacy".into(),
+                    scope: Scope {
+                        port: Some(22),
+                        private_key: Some("/keys/legacy_rsa".into()),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })),
+            );
+            cfg.servers
+                .insert("job".into(), ServerEntry::Scope(scoped_servers));
+            cfg.servers.insert("global".into(), ServerEntry::default());
+
+            match cfg.list("".into(), true, false, &filter, None) {
+                Ok(LsOutput::All(servers)) => servers
+                    .into_iter()
+                    .flat_map(|(name, entry)| match entry {
+                        ServerEntry::Global(_) => vec![name],
+                        ServerEntry::Scope(servers) => servers.into_keys().collect(),
+                    })
+                    .collect::<Vec<_>>(),
+                _ => panic!("Expected all servers listing"),
+            }
+        };
+
+        let by_user = names(ServerFilter {
+            user: Some("deploy".into()),
+            ..Default::default()
+        });
+        assert_eq!(by_user, ["app", "legacy"]);
+        let by_port = names(ServerFilter {
+            port: Some(22),
+            ..Default::default()
+        });
+        assert_eq!(by_port, ["global", "legacy"]);
+        let by_key = names(ServerFilter {
+            private_key: Some("/keys/legacy_rsa".into()),
+            ..Default::default()
+        });
+        assert_eq!(by_key, ["legacy"]);
+    }
+
+    #[test]
+    fn remove_tagged() {
+        let mut cfg = Config::default();
+        let mut scoped_servers = IndexMap::new();
+        scoped_servers.insert(
+            "db".into(),
+            ScopedServer::Override(Box::new(Server {
+                address: "db.local".into(),
+                tags: Some(vec!["db".into()]),
+                ..Default::default()
+            })),
+        );
+        scoped_servers.insert("web".into(), ScopedServer::default());
+        cfg.servers
+            .insert("scope".into(), ServerEntry::Scope(scoped_servers));
+
+        let filter = ServerFilter {
+            tags: vec!["db".into()],
+            ..Default::default()
+        };
+        let cfg = cfg
+            .remove_tagged(&filter, "scope")
+            .expect("Tagged server removed");
+        if let Some(ServerEntry::Scope(servers)) = cfg.servers.get("scope") {
+            assert!(!servers.contains_key("db") && servers.contains_key("web"));
+        } else {
+            panic!("Scope block should remain");
+        }
+        assert!(cfg.remove_tagged(&filter, "scope").is_err());
+    }
+
+    #[test]
+    fn remove_servers_and_scope() {
+        let mut cfg = Config::default();
+        let mut scoped_servers = IndexMap::new();
+        scoped_servers.insert("a".into(), ScopedServer::default());
+        scoped_servers.insert("b".into(), ScopedServer::default());
+        cfg.scopes.insert("job".into(), Scope::default());
+        cfg.servers
+            .insert("job".into(), ServerEntry::Scope(scoped_servers));
+        cfg.servers
+            .insert("web".into(), ServerEntry::Global(ScopedServer::default()));
+        assert_eq!(cfg.scope_servers("job"), ["a", "b"]);
+
+        let names = ["a".to_string(), "b".to_string()];
+        let cfg = cfg.remove(&names, None, "job").expect("Servers removed");
+        assert!(cfg.scope_servers("job").is_empty());
+        // A scope block is not a global server
+        let cfg = cfg
+            .remove(&["web".into()], None, "")
+            .expect("Global server removed");
+        assert!(cfg.remove(&["job".into()], None, "").is_err());
+
+        let mut cfg = Config::default();
+        cfg.scopes.insert("job".into(), Scope::default());
+        let cfg = cfg
+            .remove(&[], Some("job".into()), "")
+            .expect("Scope removed");
+        assert!(!cfg.check_scope("job"));
+    }
+
+    #[test]
+    fn rename_key_keeps_position() {
+        let mut map: IndexMap<String, u8> = IndexMap::new();
+        for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+            map.insert(key.into(), i as u8);
+        }
+        assert!(rename_key(&mut map, "a", "z".into()));
+        assert_eq!(map.keys().collect::<Vec<_>>(), ["z", "b", "c"]);
+        assert_eq!(map.get("z"), Some(&0));
+        assert!(!rename_key(&mut map, "a", "y".into()));
+    }
+
+    fn rename_config() -> Config {
+        let mut cfg = Config::default();
+        cfg.scopes.insert("job".into(), Scope::default());
+        cfg.scopes.insert("prod".into(), Scope::default());
+        let mut servers = IndexMap::new();
+        servers.insert("db".into(), ScopedServer::Address("10.0.0.1".into()));
+        servers.insert("web".into(), ScopedServer::Address("10.0.0.2".into()));
+        cfg.servers
+            .insert("job".into(), ServerEntry::Scope(servers));
+        cfg.servers.insert(
+            "proxy".into(),
+            ServerEntry::Global(ScopedServer::Address("10.0.0.3".into())),
+        );
+        cfg
+    }
+
+    fn cli_error(result: Result<Config>) -> CliError {
+        let Err(err) = result else {
+            panic!("Should fail");
+        };
+        err.downcast::<CliError>().expect("CLI error")
+    }
+
+    #[test]
+    fn rename_servers_and_scopes() {
+        let clash = rename_config().rename("job".into(), "prod".into(), true, "");
+        assert!(matches!(cli_error(clash), CliError::ScopeExists(_)));
+        let clash = rename_config().rename("job".into(), "proxy".into(), true, "");
+        assert!(matches!(cli_error(clash), CliError::ServerExists(_)));
+        let missing = rename_config().rename("dev".into(), "test".into(), true, "");
+        assert!(matches!(cli_error(missing), CliError::ScopeNotFound(_)));
+
+        let cfg = rename_config()
+            .rename("job".into(), "work".into(), true, "")
+            .expect("Scope renamed");
+        assert_eq!(cfg.scopes.keys().collect::<Vec<_>>(), ["work", "prod"]);
+        assert_eq!(cfg.scope_servers("work"), ["db", "web"]);
+        assert!(!cfg.servers.contains_key("job"));
+
+        let cfg = cfg
+            .rename("web".into(), "www".into(), false, "work")
+            .expect("Server renamed");
+        assert_eq!(cfg.scope_servers("work"), ["db", "www"]);
+        let clash = cfg.clone().rename("db".into(), "www".into(), false, "work");
+        assert!(matches!(cli_error(clash), CliError::ServerExists(_)));
+        let clash = cfg.clone().rename("proxy".into(), "work".into(), false, "");
+        assert!(matches!(cli_error(clash), CliError::ScopeExists(_)));
+        let cfg = cfg
+            .rename("proxy".into(), "gw".into(), false, "")
+            .expect("Global server renamed");
+        assert!(matches!(
+            cfg.servers.get("gw"),
+            Some(ServerEntry::Global(_))
+        ));
+    }
+
+    #[test]
+    fn set_server_updates_fields() {
+        let settings = Scope {
+            port: Some(2222),
+            ..Default::default()
+        };
+        let cfg = rename_config()
+            .set_server(
+                "db".into(),
+                Some("10.0.1.1".into()),
+                None,
+                Some(vec!["eu".into()]),
+                None,
+                Some("primary".into()),
+                settings,
+                "job",
+            )
+            .expect("Server updated");
+        let user = Scope {
+            user: Some("admin".into()),
+            ..Default::default()
+        };
+        // Empty notes remove them, the other settings stay
+        let cfg = cfg
+            .set_server(
+                "db".into(),
+                None,
+                None,
+                None,
+                None,
+                Some(String::new()),
+                user,
+                "job",
+            )
+            .expect("Server updated");
+        let Some(ServerEntry::Scope(servers)) = cfg.servers.get("job") else {
+            panic!("Expected the servers of the scope");
+        };
+        let db: Server = servers["db"].clone().into();
+        assert_eq!(db.address, "10.0.1.1");
+        assert_eq!(db.tags.as_deref(), Some(&["eu".to_string()][..]));
+        assert_eq!(db.notes, None);
+        assert_eq!(
+            (db.scope.port, db.scope.user.as_deref()),
+            (Some(2222), Some("admin"))
+        );
+
+        // Only global servers without a scope
+        let missing = cfg.set_server(
+            "db".into(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            Scope::default(),
+            "",
+        );
+        assert!(matches!(cli_error(missing), CliError::ServerNotFound(_)));
+    }
+
+    #[test]
+    fn set_scope_merges_fields() {
+        let mut cfg = Config::default();
+        let scope = Scope {
+            description: Some("EU cluster".into()),
+            user: Some("old".into()),
+            port: Some(22),
+            ..Default::default()
+        };
+        cfg.scopes.insert("scope".into(), scope);
+        let changes = Scope {
+            user: Some("new".into()),
+            ..Default::default()
+        };
+        let cfg = cfg
+            .set_scope("scope".into(), changes)
+            .expect("Scope updated");
+        let scope = &cfg.scopes["scope"];
+        assert_eq!(scope.user.as_deref(), Some("new"));
+        assert_eq!(scope.port, Some(22));
+        assert_eq!(scope.description.as_deref(), Some("EU cluster"));
+        assert!(cfg.set_scope("other".into(), Scope::default()).is_err());
+    }
+
+    #[test]
+    fn set_scope_servers_at_once() {
+        let mut cfg = Config::default();
+        cfg.scopes.insert("staging".into(), Scope::default());
+        let mut servers = IndexMap::new();
+        servers.insert("web".into(), ScopedServer::Address("10.0.0.1".into()));
+        let mut deployed = Server::new("10.0.0.2".into());
+        deployed.scope.user = Some("deploy".into());
+        servers.insert("db".into(), deployed.into());
+        cfg.servers
+            .insert("staging".into(), ServerEntry::Scope(servers));
+
+        let changes = Scope {
+            user: Some("deploy".into()),
+            ..Default::default()
+        };
+        let changed = cfg
+            .set_scope_servers("staging", &changes)
+            .expect("Servers updated");
+        let names: Vec<_> = changed.iter().map(|change| change.name.as_str()).collect();
+        assert_eq!(names, ["web"]);
+        assert_eq!(changed[0].before, Scope::default());
+        let Some(ServerEntry::Scope(servers)) = cfg.servers.get("staging") else {
+            panic!("Expected the servers of the scope");
+        };
+        let web: Server = servers["web"].clone().into();
+        assert_eq!(web.scope.user.as_deref(), Some("deploy"));
+        assert!(cfg.set_scope_servers("missing", &changes).is_err());
+    }
+
+    #[test]
+    fn show_current_scope_first() {
+        let mut cfg = Config::default();
+        for scope in ["other", "current"] {
+            let mut scoped_servers = IndexMap::new();
+            scoped_servers.insert("host".into(), ScopedServer::Address(scope.into()));
+            cfg.servers
+                .insert(scope.into(), ServerEntry::Scope(scoped_servers));
+        }
+        let res = cfg.show("host".into(), false, "current");
+        if let Ok(ShowOutput::Servers(found)) = res {
+            let scopes: Vec<_> = found.iter().map(|(s, _, _)| s.as_deref()).collect();
+            assert_eq!(scopes, [Some("current"), Some("other")]);
+        } else {
+            panic!("Expected servers to be found");
+        }
+        assert!(cfg.show("missing".into(), false, "current").is_err());
+        assert!(cfg.show("missing".into(), true, "current").is_err());
+    }
+
+    #[test]
+    fn clone_scoped_server() {
+        let mut cfg = Config::default();
+        let mut scoped_servers = IndexMap::new();
+        scoped_servers.insert(
+            "web1".into(),
+            ScopedServer::Override(Box::new(Server {
+                address: "10.0.0.1".into(),
+                scope: Scope {
+                    port: Some(2222),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })),
+        );
+        cfg.servers
+            .insert("scope".into(), ServerEntry::Scope(scoped_servers));
+
+        let cfg = cfg
+            .clone_server(
+                "web1".into(),
+                "web2".into(),
+                Some("10.0.0.2".into()),
+                Scope::default(),
+                "scope",
+            )
+            .expect("Server cloned");
+        let Some(ServerEntry::Scope(servers)) = cfg.servers.get("scope") else {
+            panic!("Scope block should remain");
+        };
+        let Some(ScopedServer::Override(clone)) = servers.get("web2") else {
+            panic!("Clone should keep overrides");
+        };
+        assert_eq!(clone.address, "10.0.0.2");
+        assert_eq!(clone.scope.port, Some(2222));
+
+        let res = cfg.clone_server(
+            "web1".into(),
+            "web2".into(),
+            None,
+            Scope::default(),
+            "scope",
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn pattern_syntax() {
+        let matches = |syntax: PatternSyntax, pattern, host| {
+            syntax.compile(pattern).is_ok_and(|r| r.is_match(host))
+        };
+        assert!(matches(PatternSyntax::Auto, "web-*", "web-01"));
+        assert!(!matches(PatternSyntax::Auto, "web-*", "my-web-01"));
+        assert!(matches(PatternSyntax::Auto, "10.0.?.1", "10.0.5.1"));
+        assert!(!matches(PatternSyntax::Auto, "10.0.?.1", "10.0.51"));
+        assert!(matches(PatternSyntax::Auto, "vm-[0-9]*", "vm-101"));
+        assert!(matches(PatternSyntax::Auto, "db.*", "dbx"));
+        assert!(!matches(PatternSyntax::Glob, "db.*", "dbx"));
+        assert!(matches(PatternSyntax::Regex, "web-*", "web"));
+        assert!(PatternSyntax::Regex.compile("vm-[").is_err());
+    }
+
+    #[test]
+    fn find_matching_pattern() {
+        let mut cfg = Config::default();
+        let mut scoped_servers = IndexMap::new();
+        scoped_servers.insert("vm-*".into(), ScopedServer::Address("10.0.0.$1".into()));
+        cfg.servers
+            .insert("lab".into(), ServerEntry::Scope(scoped_servers));
+        cfg.servers.insert(
+            "web-*".into(),
+            ServerEntry::Global(ScopedServer::Address("$h.example.com".into())),
+        );
+        cfg.servers.insert(
+            "db[".into(),
+            ServerEntry::Global(ScopedServer::Address("db".into())),
+        );
+
+        let (pattern, server) = cfg.find_matching(Some("lab"), "vm-7").unwrap().unwrap();
+        assert_eq!(
+            (pattern.as_str(), server.address.as_str()),
+            ("vm-*", "10.0.0.7")
+        );
+        assert!(cfg.find_matching(Some("lab"), "web-1").unwrap().is_none());
+        let (_, server) = cfg.find_matching(None, "web-1").unwrap().unwrap();
+        assert_eq!(server.address, "web-1.example.com");
+        // Invalid patterns fail only the lookups reaching them
+        assert!(cfg.find_matching(None, "other").is_err());
+
+        // Entries stay in place for the next lookups
+        let (_, server) = cfg.find_matching(None, "web-2").unwrap().unwrap();
+        assert_eq!(server.address, "web-2.example.com");
+
+        // Changed names are picked up by the next lookup
+        cfg = cfg.remove(&["db[".into()], None, "").unwrap();
+        cfg.add_server_to("", "db-*".into(), Server::new("$h.internal".into()))
+            .unwrap();
+        assert!(cfg.find_matching(None, "other").unwrap().is_none());
+        let (_, server) = cfg.find_matching(None, "db-1").unwrap().unwrap();
+        assert_eq!(server.address, "db-1.internal");
+        cfg = cfg
+            .rename("vm-*".into(), "box-*".into(), false, "lab")
+            .unwrap();
+        assert!(cfg.find_matching(Some("lab"), "vm-8").unwrap().is_none());
+        assert!(cfg.find_matching(Some("lab"), "box-8").unwrap().is_some());
+    }
+
     #[test]
     fn add_scope() {
         let cfg = Config::default();
@@ -472,6 +2452,59 @@ mod tests {
         }
     }
 
+    #[test]
+    fn expand_config_vars() {
+        let data = "private_key: ~/.ssh/{{ env }}.key
+vars:
+  domain: corp.example.com
+  env: prod
+scopes: {}
+servers:
+  db: db.{{domain}}
+  web:
+    address: web.{{domain}}
+    known_hosts: ~/{{env}}/known_hosts
+";
+        let mut cfg: Config = StorageProvider::deserialize(data).expect("Valid config");
+        cfg.expand_vars().expect("Defined variables");
+        let default = cfg.default.as_ref().expect("Default settings");
+        assert_eq!(
+            default.private_key.as_deref(),
+            Some(std::path::Path::new("~/.ssh/prod.key"))
+        );
+        let server = |name: &str| -> Server {
+            match &cfg.servers[name] {
+                ServerEntry::Global(server) => server.clone().into(),
+                ServerEntry::Scope(_) => panic!("Expected a global server"),
+            }
+        };
+        assert_eq!(server("db").address, "db.corp.example.com");
+        assert_eq!(
+            server("web").scope.known_hosts.as_deref(),
+            Some(std::path::Path::new("~/prod/known_hosts"))
+        );
+
+        assert_eq!(
+            expand("{{missing}}.{{", &IndexMap::new())
+                .unwrap_err()
+                .to_string(),
+            CliError::UndefinedVariable("missing".into()).to_string()
+        );
+        assert_eq!(expand("a.{{b", &IndexMap::new()).unwrap(), "a.{{b");
+    }
+
+    #[test]
+    fn deserialize_command_aliases() {
+        let data = "user: admin\ncommand_aliases:\n  prod-db: db1 --user 'db admin'\n\
+            scopes: {}\nservers: {}\n";
+        let cfg: Config = StorageProvider::deserialize(data).expect("Valid config");
+        assert_eq!(cfg.default.and_then(|s| s.user).as_deref(), Some("admin"));
+        assert_eq!(
+            cfg.command_aliases.get("prod-db").map(String::as_str),
+            Some("db1 --user 'db admin'")
+        );
+    }
+
     #[test]
     fn deserialize_invalid_yaml() {
         let data = "invalid :: yaml";
@@ -486,6 +2519,8 @@ mod tests {
             address: "host".into(),
             user: Some(String::default()),
             port: Some(22),
+            options: Box::default(),
+            query: None,
         };
         let srv = Server::from_uri_address(&mut uri);
         assert!(uri.address.is_empty() && !srv.address.is_empty());
@@ -499,10 +2534,38 @@ mod tests {
         assert!(srv.is_only_address());
     }
 
+    #[test]
+    fn server_with_tags_is_override() {
+        let srv = Server {
+            tags: Some(vec!["db".into()]),
+            ..Server::new("host".into())
+        };
+        assert!(!srv.is_only_address());
+        assert!(matches!(ScopedServer::from(srv), ScopedServer::Override(_)));
+    }
+
+    #[test]
+    fn server_apply_captures() {
+        let mut srv = Server::new("$2.$1.internal.example.com".into());
+        srv.apply_captures(&["db-01", "db", "01"]);
+        assert_eq!(srv.address, "01.db.internal.example.com");
+
+        let mut srv = Server::new("${1}0.$h.$9$".into());
+        srv.apply_captures(&["vm1", "vm"]);
+        assert_eq!(srv.address, "vm0.$h.$");
+
+        let glob = PatternSyntax::Glob.compile("web-*").expect("Valid glob");
+        let caps = glob.captures("web-eu").expect("Glob matches");
+        assert_eq!(caps.get(1).map(|m| m.as_str()), Some("eu"));
+    }
+
     #[test]
     fn server_apply_host_placeholder() {
         let mut srv = Server::new("$h.local".into());
+        srv.fallback = Some(vec!["$h.vpn.example.com".into()]);
         srv.apply_host_placeholder("vm-01");
         assert_eq!(srv.address, "vm-01.local");
+        assert_eq!(srv.fallback, Some(vec!["vm-01.vpn.example.com".into()]));
+        assert!(!srv.is_only_address());
     }
 }
@@ -1,16 +1,119 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fs, mem};
 
 use anyhow::Result;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    error::FileError,
+    error::{CliError, FileError},
+    storage::config::Config,
+    storage::network::NetworkState,
     storage::provider::{CACHE_PATH, StorageProvider},
 };
 
+/// Per-directory file naming the scope to use instead of the context one
+pub const SCOPE_FILENAME: &str = ".seashell";
+/// Environment variable pinning the scope for a shell, ahead of any other source
+pub const SCOPE_ENV: &str = "SEASHELL_SCOPE";
+/// How many connections the history keeps, the oldest ones are dropped first
+const HISTORY_LIMIT: usize = 1000;
+/// How long resolved addresses are reused (seconds), the system resolver doesn't tell the TTL
+pub const DNS_CACHE_TTL: u64 = 3600;
+/// How long the probed network state is reused (seconds), so that frequent
+/// commands (prompt, completion) don't run the network tools each time
+const NETWORK_CACHE_TTL: u64 = 30;
+/// Context in use until another one is chosen
+pub const DEFAULT_CONTEXT: &str = "default";
+
+/// Where the scope in effect comes from
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScopeOrigin {
+    /// `SEASHELL_SCOPE`
+    Env,
+    /// The nearest scope file
+    File(PathBuf),
+    /// The network matches the scope's conditions, none was chosen with `use`
+    Network,
+    /// Chosen with `use`, possibly until a time (Unix seconds)
+    Context(Option<u64>),
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Context {
+    /// Name of the context in use (`None` for the default one),
+    /// its scope and history are kept in the fields below
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    context: Option<String>,
     current_scope: String,
+    /// Unix time (seconds) after which the scope reverts to none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope_expires: Option<u64>,
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    history: VecDeque<HistoryEntry>,
+    /// Shared by all contexts
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    dns: HashMap<String, DnsEntry>,
+    /// Last probed network state, shared by all contexts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    network: Option<NetworkEntry>,
+    /// Contexts not in use by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    contexts: BTreeMap<String, Workspace>,
+}
+
+/// Active scope and history of a context while another one is in use
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Workspace {
+    current_scope: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope_expires: Option<u64>,
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    history: VecDeque<HistoryEntry>,
+}
+
+/// Addresses a hostname resolved to
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DnsEntry {
+    pub addresses: Vec<IpAddr>,
+    /// Unix time (seconds) after which the hostname is resolved again
+    pub expires: u64,
+}
+
+/// Network state as it was probed
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct NetworkEntry {
+    pub state: NetworkState,
+    /// Unix time (seconds) after which the network is probed again
+    pub expires: u64,
+}
+
+/// Weights of a single visit by its age (days), as in Firefox frecency
+const FRECENCY_BUCKETS: [(u64, u64); 4] = [(4, 100), (14, 70), (31, 50), (90, 30)];
+const FRECENCY_OLD_WEIGHT: u64 = 10;
+pub const SECS_PER_DAY: u64 = 86400;
+
+/// Scores of hosts combining how often and how recently they were connected to
+#[derive(Debug, Default)]
+pub struct Frecency(HashMap<String, u64>);
+
+/// A single successful connection
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    /// Server name as it was entered
+    pub host: String,
+    pub address: String,
+    pub user: String,
+    /// Unix time (seconds) of the connection start
+    pub timestamp: u64,
+    /// Duration of the connection (seconds)
+    pub duration: u64,
+    /// Exit status reported by the remote side
+    pub exit_status: Option<u32>,
 }
 
 impl Context {
@@ -22,15 +125,296 @@ impl Context {
         self.current_scope
     }
 
+    /// Connections from the oldest to the most recent
+    pub fn history(&self) -> &VecDeque<HistoryEntry> {
+        &self.history
+    }
+
+    pub fn frecency(&self, now: u64) -> Frecency {
+        let mut scores = HashMap::new();
+        for entry in &self.history {
+            let age = now.saturating_sub(entry.timestamp) / SECS_PER_DAY;
+            let weight = FRECENCY_BUCKETS
+                .iter()
+                .find(|(days, _)| age < *days)
+                .map_or(FRECENCY_OLD_WEIGHT, |(_, weight)| *weight);
+            *scores.entry(entry.host.clone()).or_default() += weight;
+        }
+
+        Frecency(scores)
+    }
+
+    /// Unix time of the last connection to each host
+    pub fn last_seen(&self) -> HashMap<String, u64> {
+        let mut last_seen = HashMap::new();
+        for entry in &self.history {
+            let last = last_seen.entry(entry.host.clone()).or_default();
+            *last = entry.timestamp.max(*last);
+        }
+
+        last_seen
+    }
+
+    pub fn record(mut self, entry: HistoryEntry) -> Self {
+        self.history.push_back(entry);
+        if self.history.len() > HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+
+        self
+    }
+
+    /// Addresses of the hostname, unless they have expired
+    pub fn cached_addresses(&self, hostname: &str, now: u64) -> Option<&[IpAddr]> {
+        self.dns
+            .get(hostname)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| entry.addresses.as_slice())
+    }
+
+    /// Remembers the addresses for `ttl` seconds, expired entries are dropped along the way
+    pub fn cache_addresses(
+        mut self,
+        hostname: String,
+        addresses: Vec<IpAddr>,
+        now: u64,
+        ttl: u64,
+    ) -> Self {
+        self.dns.retain(|_, entry| entry.expires > now);
+        self.dns.insert(
+            hostname,
+            DnsEntry {
+                addresses,
+                expires: now + ttl,
+            },
+        );
+
+        self
+    }
+
+    /// Drops the cached addresses of the hostname (e.g. the server has moved)
+    pub fn forget_addresses(mut self, hostname: &str) -> Self {
+        self.dns.remove(hostname);
+
+        self
+    }
+
+    /// The probed network state, unless it has expired
+    pub fn cached_network(&self, now: u64) -> Option<&NetworkState> {
+        self.network
+            .as_ref()
+            .filter(|entry| entry.expires > now)
+            .map(|entry| &entry.state)
+    }
+
+    pub fn cache_network(mut self, state: NetworkState, now: u64) -> Self {
+        self.network = Some(NetworkEntry {
+            state,
+            expires: now + NETWORK_CACHE_TTL,
+        });
+
+        self
+    }
+
+    /// Name of the context in use
+    pub fn context(&self) -> &str {
+        self.context.as_deref().unwrap_or(DEFAULT_CONTEXT)
+    }
+
+    /// Every context (sorted by name) with its active scope
+    pub fn contexts(&self) -> Vec<(&str, &str)> {
+        let mut contexts: Vec<_> = self
+            .contexts
+            .iter()
+            .map(|(name, workspace)| (name.as_str(), workspace.current_scope.as_str()))
+            .chain([(self.context(), self.scope())])
+            .collect();
+        contexts.sort_unstable();
+
+        contexts
+    }
+
+    pub fn has_context(&self, name: &str) -> bool {
+        self.context() == name || self.contexts.contains_key(name)
+    }
+
+    /// Puts the scope and history of the current context aside and takes those
+    /// of the given one (empty if it's new)
+    pub fn switch_context(mut self, name: String) -> Self {
+        if self.context() == name {
+            return self;
+        }
+        let current = Workspace {
+            current_scope: mem::take(&mut self.current_scope),
+            scope_expires: self.scope_expires.take(),
+            history: mem::take(&mut self.history),
+        };
+        self.contexts.insert(self.context().to_string(), current);
+
+        let Workspace {
+            current_scope,
+            scope_expires,
+            history,
+        } = self.contexts.remove(&name).unwrap_or_default();
+        self.current_scope = current_scope;
+        self.scope_expires = scope_expires;
+        self.history = history;
+        self.context = (name != DEFAULT_CONTEXT).then_some(name);
+
+        self
+    }
+
+    /// Drops a context along with its history, the one in use can't be removed
+    pub fn remove_context(mut self, name: &str) -> Result<Self, CliError> {
+        if self.context() == name {
+            return Err(CliError::ContextInUse(name.into()));
+        }
+        self.contexts
+            .remove(name)
+            .ok_or_else(|| CliError::ContextNotFound(name.into()))?;
+
+        Ok(self)
+    }
+
+    /// Keeps the expiry of the previous scope, unless there's none now
     pub fn change_scope(mut self, scope: Option<String>) -> Self {
         if let Some(scope) = scope {
             self.current_scope = scope;
         } else {
             self.current_scope.clear();
+            self.scope_expires = None;
         }
 
         self
     }
+
+    /// Sets when the scope reverts to none (`None` keeps it until changed)
+    pub fn expire_scope_at(mut self, expires: Option<u64>) -> Self {
+        self.scope_expires = expires;
+        self
+    }
+
+    /// Whether the scope was activated for a time which is over
+    pub fn scope_expired(&self, now: u64) -> bool {
+        self.scope_expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Scope in effect: the one set in `SEASHELL_SCOPE`, the one named in the nearest
+/// `.seashell` file (in the current directory or its parents), the one chosen
+/// with `use` or the one matching the network otherwise
+pub fn active_scope(config: &Config) -> Result<String> {
+    scope_with_origin(config).map(|(scope, _)| scope)
+}
+
+/// The scope in effect along with where it comes from (see [`active_scope`])
+pub fn scope_with_origin(config: &Config) -> Result<(String, ScopeOrigin)> {
+    if let Some(scope) = env_scope() {
+        info!("Using scope from {}", SCOPE_ENV);
+        return Ok((scope, ScopeOrigin::Env));
+    }
+    if let Some((path, scope)) = scope_file(&env::current_dir().map_err(FileError::Std)?)? {
+        return Ok((scope, ScopeOrigin::File(path)));
+    }
+
+    let mut context = Context::load_from_file()?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if context.scope_expired(now) {
+        warn!(
+            "Scope '{}' has expired, no scope is active",
+            context.scope()
+        );
+        context = context.change_scope(None);
+        context.save_to_file()?;
+    }
+    if !context.scope().is_empty() {
+        let expires = context.scope_expires;
+        return Ok((context.into_scope(), ScopeOrigin::Context(expires)));
+    }
+    if let Some(scope) = network_scope(config, context, now)? {
+        info!("Network matches scope '{}'", scope);
+        return Ok((scope, ScopeOrigin::Network));
+    }
+
+    Ok((String::new(), ScopeOrigin::Context(None)))
+}
+
+/// Scope whose network rules match, the network is only probed
+/// when there are rules and the cached state has expired
+fn network_scope(config: &Config, context: Context, now: u64) -> Result<Option<String>> {
+    if !config.has_network_rules() {
+        return Ok(None);
+    }
+    let state = match context.cached_network(now) {
+        Some(state) => state.clone(),
+        None => {
+            let state = NetworkState::probe();
+            context.cache_network(state.clone(), now).save_to_file()?;
+            state
+        }
+    };
+
+    Ok(config.auto_scope(&state).map(String::from))
+}
+
+/// Scope set in the environment, an empty value counts as unset
+pub fn env_scope() -> Option<String> {
+    env::var(SCOPE_ENV)
+        .ok()
+        .filter(|scope| !scope.trim().is_empty())
+}
+
+/// Reads the scope name from the nearest scope file up the directory tree
+pub fn find_scope_file(dir: &Path) -> Result<Option<String>> {
+    scope_file(dir).map(|found| found.map(|(_, scope)| scope))
+}
+
+/// The nearest scope file up the directory tree with the scope it names
+fn scope_file(dir: &Path) -> Result<Option<(PathBuf, String)>> {
+    for dir in dir.ancestors() {
+        let path = dir.join(SCOPE_FILENAME);
+        if path.is_file() {
+            info!("Using scope from '{}'", path.display());
+
+            let content = fs::read_to_string(&path).map_err(FileError::Std)?;
+            let scope = parse_scope_file(&content);
+            return Ok(Some((path, scope)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The first line that is neither empty nor a comment
+#[inline]
+fn parse_scope_file(content: &str) -> String {
+    content
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .unwrap_or_default()
+        .into()
+}
+
+/// Most recent connection by any of the names the server is known by
+pub fn last_connected<'a>(
+    last_seen: &HashMap<String, u64>,
+    names: impl IntoIterator<Item = &'a String>,
+) -> Option<u64> {
+    names
+        .into_iter()
+        .filter_map(|name| last_seen.get(name).copied())
+        .max()
+}
+
+impl Frecency {
+    /// Sum of the scores of every name the server is known by
+    pub fn score<'a>(&self, names: impl IntoIterator<Item = &'a String>) -> u64 {
+        names.into_iter().filter_map(|name| self.0.get(name)).sum()
+    }
 }
 
 impl StorageProvider for Context {
@@ -57,6 +441,7 @@ mod tests {
     fn scope_and_into_scope() {
         let ctx = Context {
             current_scope: "test".into(),
+            ..Default::default()
         };
         assert_eq!(ctx.scope(), "test");
         assert_eq!(ctx.into_scope(), "test");
@@ -70,10 +455,26 @@ mod tests {
         assert_eq!(ctx.scope(), "test");
     }
 
+    #[test]
+    fn scope_expiry() {
+        let ctx = Context::default()
+            .change_scope(Some("prod".into()))
+            .expire_scope_at(Some(100));
+        assert!(!ctx.scope_expired(99));
+        assert!(ctx.scope_expired(100));
+
+        // Renaming the scope keeps the expiry, clearing it drops the expiry
+        let ctx = ctx.change_scope(Some("production".into()));
+        assert_eq!(ctx.scope_expires, Some(100));
+        let ctx = ctx.change_scope(None);
+        assert!(!ctx.scope_expired(u64::MAX));
+    }
+
     #[test]
     fn serialize_deserialize() {
         let ctx = Context {
             current_scope: "test".into(),
+            ..Default::default()
         };
         if let Ok(serialized) = StorageProvider::serialize(&ctx) {
             assert_eq!(serialized, r#"{"current_scope":"test"}"#);
@@ -84,6 +485,134 @@ mod tests {
         }
     }
 
+    #[test]
+    fn record_history() {
+        let entry = |timestamp| HistoryEntry {
+            host: "db1".into(),
+            address: "10.0.0.1".into(),
+            user: "root".into(),
+            timestamp,
+            duration: 1,
+            exit_status: Some(0),
+        };
+        let mut ctx = Context::default();
+        for timestamp in 0..=HISTORY_LIMIT as u64 {
+            ctx = ctx.record(entry(timestamp));
+        }
+        assert_eq!(ctx.history().len(), HISTORY_LIMIT);
+        assert_eq!(ctx.history().front(), Some(&entry(1)));
+        assert_eq!(ctx.history().back(), Some(&entry(HISTORY_LIMIT as u64)));
+    }
+
+    #[test]
+    fn switch_contexts() {
+        let entry = HistoryEntry {
+            host: "nas".into(),
+            address: "192.168.1.2".into(),
+            user: "root".into(),
+            timestamp: 0,
+            duration: 0,
+            exit_status: None,
+        };
+        let ctx = Context::default()
+            .change_scope(Some("prod".into()))
+            .switch_context("homelab".into())
+            .change_scope(Some("lab".into()))
+            .record(entry);
+        assert_eq!((ctx.context(), ctx.scope()), ("homelab", "lab"));
+        assert_eq!(ctx.contexts(), [("default", "prod"), ("homelab", "lab")]);
+
+        let ctx = ctx.switch_context(DEFAULT_CONTEXT.into());
+        assert_eq!((ctx.context(), ctx.scope()), ("default", "prod"));
+        assert!(ctx.history().is_empty());
+        assert!(ctx.context.is_none());
+        assert!(matches!(
+            ctx.remove_context(DEFAULT_CONTEXT),
+            Err(CliError::ContextInUse(_))
+        ));
+
+        let ctx = Context::default().switch_context("homelab".into());
+        let ctx = ctx.switch_context("homelab".into());
+        assert_eq!(ctx.contexts().len(), 2);
+        assert!(ctx.remove_context("work").is_err());
+    }
+
+    #[test]
+    fn dns_cache_expires() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let ctx = Context::default()
+            .cache_addresses("old.local".into(), vec![ip], 0, DNS_CACHE_TTL)
+            .cache_addresses("db.local".into(), vec![ip], DNS_CACHE_TTL, DNS_CACHE_TTL)
+            .cache_addresses("short.local".into(), vec![ip], DNS_CACHE_TTL, 60);
+
+        assert_eq!(
+            ctx.cached_addresses("db.local", DNS_CACHE_TTL + 1),
+            Some(&[ip][..])
+        );
+        assert_eq!(ctx.cached_addresses("db.local", 2 * DNS_CACHE_TTL), None);
+        assert_eq!(
+            ctx.cached_addresses("short.local", DNS_CACHE_TTL + 60),
+            None
+        );
+        // Dropped while caching the second one
+        assert!(!ctx.dns.contains_key("old.local"));
+
+        let ctx = ctx.forget_addresses("db.local");
+        assert_eq!(ctx.cached_addresses("db.local", DNS_CACHE_TTL), None);
+    }
+
+    #[test]
+    fn network_cache_expires() {
+        let state = NetworkState {
+            ssid: Some("Office".into()),
+            ..Default::default()
+        };
+        let ctx = Context::default().cache_network(state.clone(), 100);
+
+        assert_eq!(ctx.cached_network(100), Some(&state));
+        assert_eq!(ctx.cached_network(100 + NETWORK_CACHE_TTL), None);
+    }
+
+    #[test]
+    fn frecency_prefers_recent() {
+        let now = 100 * SECS_PER_DAY;
+        let entry = |host: &str, days_ago: u64| HistoryEntry {
+            host: host.into(),
+            address: host.into(),
+            user: "root".into(),
+            timestamp: now - days_ago * SECS_PER_DAY,
+            duration: 0,
+            exit_status: None,
+        };
+        let mut ctx = Context::default();
+        for _ in 0..5 {
+            ctx = ctx.record(entry("old", 95));
+        }
+        ctx = ctx.record(entry("new", 1)).record(entry("alias", 20));
+        let frecency = ctx.frecency(now);
+
+        let (old, new) = ("old".to_string(), "new".to_string());
+        assert_eq!(frecency.score([&old]), 50);
+        assert_eq!(frecency.score([&new]), 100);
+        assert_eq!(frecency.score([&new, &"alias".to_string()]), 150);
+        assert_eq!(frecency.score([&"unknown".to_string()]), 0);
+    }
+
+    #[test]
+    fn scope_file_lookup() {
+        assert_eq!(parse_scope_file("# client project\n\n  acme \n"), "acme");
+        assert_eq!(parse_scope_file(""), "");
+
+        let root = env::temp_dir().join(format!("shh-scope-file-{}", std::process::id()));
+        let nested = root.join("src").join("bin");
+        fs::create_dir_all(&nested).expect("Failed to create directories");
+        fs::write(root.join(SCOPE_FILENAME), "acme\n").expect("Failed to write scope file");
+
+        let found = find_scope_file(&nested);
+        fs::remove_dir_all(&root).expect("Failed to clean up");
+        assert_eq!(found.ok().flatten().as_deref(), Some("acme"));
+    }
+
     #[test]
     fn deserialize_invalid_json() {
         let data = "{ invalid json }";
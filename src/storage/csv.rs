@@ -0,0 +1,79 @@
+/// Splits the content into rows of fields (RFC 4180: fields may be quoted
+/// with `"`, doubled inside, and then contain commas and line breaks).
+/// Blank lines are skipped.
+pub fn parse(content: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let (mut row, mut field) = (Vec::new(), String::new());
+    let mut quoted = false;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => row.push(std::mem::take(&mut field)),
+            '\r' if !quoted => {}
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field));
+                if row.iter().any(|field| !field.is_empty()) {
+                    rows.push(std::mem::take(&mut row));
+                } else {
+                    row.clear();
+                }
+            }
+            c => field.push(c),
+        }
+    }
+    row.push(field);
+    if row.iter().any(|field| !field.is_empty()) {
+        rows.push(row);
+    }
+
+    rows
+}
+
+/// Joins the fields into a line, quoting those that need it
+pub fn row<S: AsRef<str>>(fields: impl IntoIterator<Item = S>) -> String {
+    fields
+        .into_iter()
+        .map(|field| {
+            let field = field.as_ref();
+            if field.contains([',', '"', '\r', '\n']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rows() {
+        let rows = parse("name,address\r\n\"db, main\",10.0.0.1\n\n\"say \"\"hi\"\"\",\"a\nb\"");
+        assert_eq!(
+            rows,
+            [
+                vec!["name", "address"],
+                vec!["db, main", "10.0.0.1"],
+                vec!["say \"hi\"", "a\nb"],
+            ]
+        );
+    }
+
+    #[test]
+    fn write_row() {
+        let line = row(["db, main", "10.0.0.1", "say \"hi\"", ""]);
+        assert_eq!(line, "\"db, main\",10.0.0.1,\"say \"\"hi\"\"\",");
+        assert_eq!(
+            parse(&line),
+            [vec!["db, main", "10.0.0.1", "say \"hi\"", ""]]
+        );
+    }
+}
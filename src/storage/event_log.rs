@@ -0,0 +1,222 @@
+use clap::ValueEnum;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::storage::context::SECS_PER_DAY;
+use crate::storage::provider::WORK_DIR;
+
+/// File in the work dir the events are appended to
+pub const EVENT_LOG_FILE: &str = "events.log";
+/// File in the work dir the authentication attempts are appended to
+pub const AUTH_LOG_FILE: &str = "auth.log";
+/// Socket of the local syslog daemon (journald listens there as well)
+#[cfg(all(unix, not(target_os = "macos")))]
+const SYSLOG_SOCKET: &str = "/dev/log";
+#[cfg(target_os = "macos")]
+const SYSLOG_SOCKET: &str = "/var/run/syslog";
+/// Facility `user`, already shifted into the priority
+const SYSLOG_FACILITY: u8 = 1 << 3;
+const SEVERITY_NOTICE: u8 = 5;
+const SEVERITY_INFO: u8 = 6;
+
+/// Where connection events are recorded, selectable in the config
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventLog {
+    /// Lines appended to events.log in the work dir
+    File,
+    /// Messages sent to the local syslog daemon (or journald)
+    Syslog,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    /// The handshake with the server, including the host key check
+    Connect,
+    Auth,
+    /// The end of the session
+    Disconnect,
+}
+
+/// Something that happened to a connection, written as `key=value` fields
+pub struct Event<'a> {
+    pub kind: EventKind,
+    /// `user@address:port`
+    pub target: &'a str,
+    /// Details depending on the kind (e.g. the auth method)
+    pub fields: Vec<(&'static str, String)>,
+    /// Why it failed, if it did
+    pub error: Option<String>,
+}
+
+impl EventLog {
+    /// Problems with the log are only reported, they don't affect the connection
+    pub fn record(self, event: &Event) {
+        let result = match self {
+            Self::File => append(EVENT_LOG_FILE, event),
+            Self::Syslog => send_to_syslog(event),
+        };
+        if let Err(e) = result {
+            warn!("Failed to record the {} event: {}", event.kind, e);
+        }
+    }
+}
+
+/// Appends the attempt to the auth log, whichever sink the other events go to.
+/// Problems with the log are only reported.
+pub fn record_auth_attempt(event: &Event) {
+    if let Err(e) = append(AUTH_LOG_FILE, event) {
+        warn!("Failed to write the auth log: {}", e);
+    }
+}
+
+/// Appends the line to the log, which is created readable only by the user
+fn append(file_name: &str, event: &Event) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut file = options.open(WORK_DIR.join(file_name))?;
+    writeln!(file, "{} {}", utc_timestamp(timestamp), event)
+}
+
+/// Sends the event as a local syslog message (the daemon adds the time and host)
+#[cfg(unix)]
+fn send_to_syslog(event: &Event) -> io::Result<()> {
+    let severity = match event.error {
+        Some(_) => SEVERITY_NOTICE,
+        None => SEVERITY_INFO,
+    };
+    let message = format!(
+        "<{}>shh[{}]: {}",
+        SYSLOG_FACILITY | severity,
+        std::process::id(),
+        event
+    );
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    socket.send_to(message.as_bytes(), SYSLOG_SOCKET)?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_to_syslog(_: &Event) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "syslog is only available on Unix",
+    ))
+}
+
+/// Formats Unix time as ISO 8601 in UTC (`2024-05-01T12:00:00Z`)
+pub fn utc_timestamp(secs: u64) -> String {
+    let (days, time) = (secs / SECS_PER_DAY, secs % SECS_PER_DAY);
+    // Civil date from days since the epoch (proleptic Gregorian calendar)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+impl Display for Event<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "event={} target={} result={}",
+            self.kind,
+            self.target,
+            if self.error.is_none() {
+                "success"
+            } else {
+                "failure"
+            },
+        )?;
+        for (key, value) in &self.fields {
+            write!(f, " {}={}", key, value)?;
+        }
+        if let Some(error) = &self.error {
+            write!(f, " error={:?}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Connect => "connect",
+            Self::Auth => "auth",
+            Self::Disconnect => "disconnect",
+        })
+    }
+}
+
+impl Display for EventLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::File => "file",
+            Self::Syslog => "syslog",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_line() {
+        let event = Event {
+            kind: EventKind::Disconnect,
+            target: "alice@10.0.0.1:22",
+            fields: vec![("duration", "75".into()), ("exit_status", "0".into())],
+            error: None,
+        };
+        assert_eq!(
+            event.to_string(),
+            "event=disconnect target=alice@10.0.0.1:22 result=success duration=75 exit_status=0"
+        );
+
+        let event = Event {
+            kind: EventKind::Auth,
+            target: "alice@10.0.0.1:22",
+            fields: vec![("method", "password".into())],
+            error: Some("Authentication failed".into()),
+        };
+        assert!(
+            event
+                .to_string()
+                .ends_with("result=failure method=password error=\"Authentication failed\"")
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(utc_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(utc_timestamp(1_709_210_096), "2024-02-29T12:34:56Z");
+    }
+}
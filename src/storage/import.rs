@@ -0,0 +1,223 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::error::CliError;
+use crate::storage::config::Server;
+use crate::storage::csv;
+
+/// Names every system has for itself, not worth a server entry
+const LOCAL_NAMES: [&str; 2] = ["localhost", "ip6-"];
+/// Separates the values of list fields (tags, aliases) in a CSV cell
+pub const LIST_SEPARATOR: char = ';';
+
+/// Server setting filled from a CSV column
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CsvField {
+    Name,
+    Address,
+    User,
+    Port,
+    Key,
+    Tags,
+    Aliases,
+}
+
+/// Column a setting is taken from (`FIELD=COLUMN`, columns are numbered from 1)
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnMap {
+    pub field: CsvField,
+    pub column: usize,
+}
+
+/// Servers of a hosts file (`IP NAME [ALIASES...]` per line), named by their first name
+/// (without the domain suffix, if given) and with the other names as aliases.
+/// With a domain suffix, only names ending with it are taken.
+pub fn parse_hosts(content: &str, domain: Option<&str>) -> Vec<(String, Server)> {
+    let suffix = domain.map(|domain| format!(".{}", domain.trim_start_matches('.')));
+    let mut servers: Vec<(String, Server)> = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(Ok(ip)) = fields.next().map(str::parse::<IpAddr>) else {
+            continue;
+        };
+        if ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() {
+            continue;
+        }
+        let names: Vec<&str> = fields
+            .filter(|name| !LOCAL_NAMES.iter().any(|local| name.starts_with(local)))
+            .filter(|name| suffix.as_ref().is_none_or(|suffix| name.ends_with(suffix)))
+            .collect();
+        let Some((first, others)) = names.split_first() else {
+            continue;
+        };
+        let name = match &suffix {
+            Some(suffix) => first.strip_suffix(suffix.as_str()).unwrap_or(first),
+            None => first,
+        };
+        // The same host may be listed more than once
+        if servers.iter().any(|(existing, _)| existing == name) {
+            continue;
+        }
+        let aliases: Vec<String> = others
+            .iter()
+            .chain(first.ne(&name).then_some(first))
+            .map(|alias| alias.to_string())
+            .collect();
+
+        servers.push((
+            name.to_string(),
+            Server {
+                address: ip.to_string(),
+                aliases: (!aliases.is_empty()).then_some(aliases),
+                ..Default::default()
+            },
+        ));
+    }
+
+    servers
+}
+
+/// Servers of the CSV rows, the address has to be mapped and is the name
+/// unless that's mapped as well. Empty cells leave the setting unset.
+pub fn parse_csv(
+    content: &str,
+    map: &[ColumnMap],
+    header: bool,
+) -> Result<Vec<(String, Server)>, CliError> {
+    if !map.iter().any(|m| m.field == CsvField::Address) {
+        return Err(CliError::AddressNotMapped);
+    }
+    let rows = csv::parse(content);
+    let mut servers = Vec::with_capacity(rows.len());
+    for (i, row) in rows.iter().enumerate().skip(usize::from(header)) {
+        let invalid = |reason: String| CliError::InvalidCsvRow(i + 1, reason.into());
+        let mut server = Server::default();
+        let mut name = None;
+        for ColumnMap { field, column } in map {
+            let value = row
+                .get(column - 1)
+                .map(|value| value.trim())
+                .unwrap_or_default();
+            if value.is_empty() {
+                continue;
+            }
+            let list = || {
+                value
+                    .split(LIST_SEPARATOR)
+                    .map(|v| v.trim().to_string())
+                    .collect()
+            };
+            match field {
+                CsvField::Name => name = Some(value.to_string()),
+                CsvField::Address => server.address = value.to_string(),
+                CsvField::User => server.scope.user = Some(value.to_string()),
+                CsvField::Port => {
+                    let port = value
+                        .parse()
+                        .map_err(|_| invalid(format!("port '{}' is not a number", value)))?;
+                    server.scope.port = Some(port);
+                }
+                CsvField::Key => server.scope.private_key = Some(PathBuf::from(value)),
+                CsvField::Tags => server.tags = Some(list()),
+                CsvField::Aliases => server.aliases = Some(list()),
+            }
+        }
+        if server.address.is_empty() {
+            return Err(invalid("the address is empty".into()));
+        }
+        let name = name.unwrap_or_else(|| server.address.clone());
+        if servers.iter().any(|(existing, _)| *existing == name) {
+            return Err(invalid(format!("'{}' is listed more than once", name)));
+        }
+        servers.push((name, server));
+    }
+
+    Ok(servers)
+}
+
+impl FromStr for ColumnMap {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || CliError::InvalidValue("map", s.into());
+        let (field, column) = s.split_once('=').ok_or_else(invalid)?;
+        let field = match field.trim() {
+            "name" => CsvField::Name,
+            "address" => CsvField::Address,
+            "user" => CsvField::User,
+            "port" => CsvField::Port,
+            "key" => CsvField::Key,
+            "tags" => CsvField::Tags,
+            "aliases" => CsvField::Aliases,
+            _ => return Err(invalid()),
+        };
+        let column = column.trim().parse().ok().filter(|&c| c > 0);
+
+        Ok(Self {
+            field,
+            column: column.ok_or_else(invalid)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_file() {
+        let content = "\
+            127.0.0.1 localhost\n\
+            ::1 localhost ip6-localhost ip6-loopback\n\
+            # lab machines\n\
+            10.0.0.5 web1.lab.example.com web1 # frontend\n\
+            10.0.0.6\tdb1.lab.example.com\n\
+            192.168.1.2 printer.home\n\
+            10.0.0.7 web1.lab.example.com\n";
+
+        let servers = parse_hosts(content, None);
+        let names: Vec<_> = servers.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "web1.lab.example.com",
+                "db1.lab.example.com",
+                "printer.home"
+            ]
+        );
+        assert_eq!(servers[0].1.aliases, Some(vec!["web1".into()]));
+
+        let servers = parse_hosts(content, Some(".lab.example.com"));
+        let (name, server) = &servers[0];
+        assert_eq!(
+            (name.as_str(), server.address.as_str()),
+            ("web1", "10.0.0.5")
+        );
+        assert_eq!(server.aliases, Some(vec!["web1.lab.example.com".into()]));
+        assert_eq!(servers.len(), 2);
+    }
+
+    #[test]
+    fn csv_rows() {
+        let map: Vec<ColumnMap> = ["name=1", "address=2", "port=4", "tags=3"]
+            .iter()
+            .map(|m| m.parse().unwrap())
+            .collect();
+        let content = "host,ip,groups,port\ndb,10.0.0.1,\"prod; db\",2222\nweb,10.0.0.2,,\n";
+
+        let servers = parse_csv(content, &map, true).unwrap();
+        assert_eq!(servers.len(), 2);
+        let (name, db) = &servers[0];
+        assert_eq!((name.as_str(), db.scope.port), ("db", Some(2222)));
+        assert_eq!(db.tags, Some(vec!["prod".into(), "db".into()]));
+        assert_eq!(servers[1].1.tags, None);
+
+        assert!(matches!(
+            parse_csv(content, &map, false),
+            Err(CliError::InvalidCsvRow(1, _))
+        ));
+        assert!("port=0".parse::<ColumnMap>().is_err());
+    }
+}
@@ -0,0 +1,276 @@
+use std::fmt::Display;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::process::Command;
+use std::str::FromStr;
+
+use itertools::Itertools;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_with_macros::skip_serializing_none;
+
+use crate::error::CliError;
+
+/// Any routable address works, nothing is sent to it
+const PROBE_ADDRESS: (Ipv4Addr, u16) = (Ipv4Addr::new(192, 0, 2, 1), 9);
+const ROUTE_TABLE: &str = "/proc/net/route";
+const INTERFACES_DIR: &str = "/sys/class/net";
+
+/// Conditions on the current network for activating a scope automatically.
+/// Every specified condition must hold, any value of a list satisfies it.
+#[skip_serializing_none]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct NetworkRules {
+    /// Local address belongs to one of the subnets
+    pub subnet: Option<Vec<Subnet>>,
+    /// Default gateway is one of the addresses
+    pub gateway: Option<Vec<IpAddr>>,
+    /// Connected to one of the Wi-Fi networks
+    pub ssid: Option<Vec<String>>,
+    /// One of the interfaces is present (e.g. `tun0` or `wg0` of a VPN)
+    pub interface: Option<Vec<String>>,
+}
+
+/// Address block in CIDR notation (`10.0.0.0/8`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Subnet {
+    pub address: IpAddr,
+    pub prefix: u8,
+}
+
+/// What is known about the network the machine is connected to
+#[skip_serializing_none]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct NetworkState {
+    pub local_ip: Option<IpAddr>,
+    pub gateway: Option<IpAddr>,
+    pub ssid: Option<String>,
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+}
+
+impl NetworkRules {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn matches(&self, state: &NetworkState) -> bool {
+        fn check<T>(rule: &Option<Vec<T>>, f: impl Fn(&T) -> bool) -> bool {
+            rule.as_ref().is_none_or(|values| values.iter().any(f))
+        }
+
+        check(&self.subnet, |subnet| {
+            state.local_ip.is_some_and(|ip| subnet.contains(ip))
+        }) && check(&self.gateway, |gateway| state.gateway == Some(*gateway))
+            && check(&self.ssid, |ssid| state.ssid.as_ref() == Some(ssid))
+            && check(&self.interface, |name| state.interfaces.contains(name))
+    }
+}
+
+impl Display for NetworkRules {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            subnet,
+            gateway,
+            ssid,
+            interface,
+        } = self;
+        let mut rules = Vec::new();
+        if let Some(subnet) = subnet {
+            rules.push(format!("subnet {}", subnet.iter().join(" | ")));
+        }
+        if let Some(gateway) = gateway {
+            rules.push(format!("gateway {}", gateway.iter().join(" | ")));
+        }
+        if let Some(ssid) = ssid {
+            rules.push(format!("ssid {}", ssid.iter().join(" | ")));
+        }
+        if let Some(interface) = interface {
+            rules.push(format!("interface {}", interface.iter().join(" | ")));
+        }
+
+        write!(f, "{}", rules.join(", "))
+    }
+}
+
+impl Subnet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || CliError::InvalidSubnet(s.into());
+        let (address, prefix) = s.split_once('/').ok_or_else(invalid)?;
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+
+        Ok(Self { address, prefix })
+    }
+}
+
+impl Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl Serialize for Subnet {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Subnet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Self::from_str(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+impl NetworkState {
+    /// Collects the network details, each of them on a best-effort basis
+    pub fn probe() -> Self {
+        Self {
+            local_ip: local_ip(),
+            gateway: default_gateway(),
+            ssid: wifi_ssid(),
+            interfaces: interfaces(),
+        }
+    }
+}
+
+/// Source address the system would use for outgoing traffic
+fn local_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(PROBE_ADDRESS).ok()?;
+
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+fn default_gateway() -> Option<IpAddr> {
+    if cfg!(target_os = "macos") {
+        let output = run(Command::new("route").args(["-n", "get", "default"]))?;
+        return output
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("gateway:"))
+            .and_then(|gateway| gateway.trim().parse().ok());
+    }
+    // Columns: Iface Destination Gateway ..., addresses are little-endian hex
+    fs::read_to_string(ROUTE_TABLE)
+        .ok()?
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|columns| columns.get(1) == Some(&"00000000"))
+        .and_then(|columns| u32::from_str_radix(columns.get(2)?, 16).ok())
+        .map(|gateway| IpAddr::V4(Ipv4Addr::from(gateway.swap_bytes())))
+}
+
+fn wifi_ssid() -> Option<String> {
+    let ssid = if cfg!(target_os = "macos") {
+        run(Command::new("networksetup").args(["-getairportnetwork", "en0"]))?
+            .split_once(": ")?
+            .1
+            .to_string()
+    } else {
+        run(Command::new("iwgetid").arg("-r"))?
+    };
+
+    Some(ssid.trim().to_string()).filter(|ssid| !ssid.is_empty())
+}
+
+fn interfaces() -> Vec<String> {
+    if cfg!(target_os = "macos") {
+        return run(Command::new("ifconfig").arg("-l"))
+            .map(|list| list.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+    }
+
+    fs::read_dir(INTERFACES_DIR)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[inline]
+fn run(cmd: &mut Command) -> Option<String> {
+    let output = cmd.output().ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subnet_contains() {
+        let subnet: Subnet = "192.168.1.0/24".parse().expect("Valid subnet");
+        assert!(subnet.contains("192.168.1.42".parse().unwrap()));
+        assert!(!subnet.contains("192.168.2.1".parse().unwrap()));
+        assert!(!subnet.contains("::1".parse().unwrap()));
+
+        let any: Subnet = "0.0.0.0/0".parse().expect("Valid subnet");
+        assert!(any.contains("8.8.8.8".parse().unwrap()));
+        let v6: Subnet = "fd00::/8".parse().expect("Valid subnet");
+        assert!(v6.contains("fd12::1".parse().unwrap()));
+
+        assert!("10.0.0.0".parse::<Subnet>().is_err());
+        assert!("10.0.0.0/33".parse::<Subnet>().is_err());
+    }
+
+    #[test]
+    fn rules_match() {
+        let state = NetworkState {
+            local_ip: Some("10.1.2.3".parse().unwrap()),
+            gateway: Some("10.1.0.1".parse().unwrap()),
+            ssid: Some("Office".into()),
+            interfaces: vec!["lo".into(), "wlan0".into()],
+        };
+        let office = NetworkRules {
+            subnet: Some(vec!["10.0.0.0/8".parse().unwrap()]),
+            ssid: Some(vec!["Guest".into(), "Office".into()]),
+            ..Default::default()
+        };
+        assert!(office.matches(&state));
+
+        let vpn = NetworkRules {
+            interface: Some(vec!["tun0".into()]),
+            ..office
+        };
+        assert!(!vpn.matches(&state));
+        assert!(NetworkRules::default().matches(&state));
+    }
+}
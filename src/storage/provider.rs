@@ -1,7 +1,9 @@
 use std::{
     fs,
+    io::{self, ErrorKind, Write},
     path::{Path, PathBuf},
     sync::LazyLock,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Result;
@@ -10,6 +12,8 @@ use crate::error::FileError;
 
 const CONFIG_FILENAME: &str = "config.yml";
 const CACHE_FILENAME: &str = ".cache.json";
+const BACKUP_DIRNAME: &str = "backups";
+const TMP_SUFFIX: &str = ".tmp";
 
 pub static WORK_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
     directories::BaseDirs::new()
@@ -39,13 +43,21 @@ pub trait StorageProvider: Default {
     fn serialize(&self) -> Result<String>;
     fn deserialize(data: &str) -> Result<Self>;
 
+    /// How many previous versions of the file to keep (none by default)
+    fn backup_limit(&self) -> usize {
+        0
+    }
+
+    /// Replaces the file atomically (see [`write_atomically`]),
+    /// after backing up the previous version
     fn save_to_file(&self) -> Result<()> {
-        let file_path = &***Self::work_file();
-        if !Path::new(file_path).exists() {
+        let file_path = Path::new(&***Self::work_file());
+        if !file_path.exists() {
             ensure_work_dir()?;
+        } else if self.backup_limit() > 0 {
+            backup_file(file_path, self.backup_limit())?;
         }
-        let data = self.serialize()?;
-        fs::write(file_path, data).map_err(FileError::Std)?;
+        write_atomically(file_path, self.serialize()?.as_bytes())?;
 
         Ok(())
     }
@@ -64,6 +76,82 @@ pub trait StorageProvider: Default {
     }
 }
 
+/// Writes to a temporary file first and then renames it over the original, so a crash
+/// mid-write can never leave a truncated file. The temporary file is created with
+/// the permissions of the original (only the user's for a new one), and both it
+/// and the directory are synced, so the new content survives a power loss.
+pub fn write_atomically(path: &Path, data: &[u8]) -> Result<(), FileError> {
+    let tmp_path = PathBuf::from(format!("{}{}", path.display(), TMP_SUFFIX));
+    // Left over by a crash, maybe with other permissions
+    match fs::remove_file(&tmp_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        let mode = fs::metadata(path).map_or(0o600, |metadata| metadata.permissions().mode());
+        options.mode(mode & 0o7777);
+    }
+    let mut tmp_file = options.open(&tmp_path)?;
+    tmp_file.write_all(data)?;
+    tmp_file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Copies the file into the backup directory under a timestamped name
+/// and removes the oldest copies beyond the limit
+fn backup_file(file_path: &Path, limit: usize) -> Result<()> {
+    let backup_dir = WORK_DIR.join(BACKUP_DIRNAME);
+    fs::create_dir_all(&backup_dir).map_err(FileError::Std)?;
+
+    let file_name = file_path
+        .file_name()
+        .expect("Work file must have a name")
+        .to_string_lossy();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    fs::copy(
+        file_path,
+        backup_dir.join(format!("{}.{}", file_name, timestamp)),
+    )
+    .map_err(FileError::Std)?;
+
+    let prefix = format!("{}.", file_name);
+    let mut backups: Vec<(u128, PathBuf)> = fs::read_dir(&backup_dir)
+        .map_err(FileError::Std)?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let timestamp = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix(&prefix)?
+                .parse()
+                .ok()?;
+            Some((timestamp, entry.path()))
+        })
+        .collect();
+    if backups.len() > limit {
+        backups.sort_unstable_by_key(|(timestamp, _)| *timestamp);
+        for (_, path) in &backups[..backups.len() - limit] {
+            fs::remove_file(path).map_err(FileError::Std)?;
+        }
+    }
+
+    Ok(())
+}
+
 pub fn get_full_path(path: PathBuf) -> PathBuf {
     if path.is_absolute() {
         return path;
@@ -78,8 +166,61 @@ pub fn get_full_path(path: PathBuf) -> PathBuf {
         )
 }
 
+/// Paths given on the command line are relative to the current directory,
+/// unlike the ones of the config (`~/` is expanded for both)
+pub fn cli_path(path: PathBuf) -> io::Result<PathBuf> {
+    if path.starts_with("~") {
+        return Ok(get_full_path(path));
+    }
+
+    std::path::absolute(path)
+}
+
 pub fn ensure_work_dir() -> Result<()> {
     fs::create_dir_all(&*WORK_DIR)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_paths() {
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            cli_path("./fix-perms.sh".into()).unwrap(),
+            cwd.join("fix-perms.sh")
+        );
+        assert_eq!(cli_path(cwd.join("hosts")).unwrap(), cwd.join("hosts"));
+        assert_eq!(
+            cli_path("~/ca.pub".into()).unwrap(),
+            get_full_path("~/ca.pub".into())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn atomic_write_keeps_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("shh-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("known_hosts");
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        write_atomically(&path, b"first\n").unwrap();
+        assert_eq!(mode(&path), 0o600);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        // A stale temporary file doesn't get in the way
+        fs::write(dir.join("known_hosts.tmp"), "stale").unwrap();
+        write_atomically(&path, b"second\n").unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        let kept = mode(&path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(content, "second\n");
+        assert_eq!(kept, 0o640);
+    }
+}
@@ -0,0 +1,183 @@
+use std::fmt::Display;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use anyhow::Result;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::SecretError;
+
+const KEYRING_PREFIX: &str = "keyring:";
+const KEYRING_SERVICE: &str = env!("CARGO_PKG_NAME");
+
+/// Reference to a secret stored in the OS keyring (`keyring:NAME`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecretRef(pub String);
+
+impl SecretRef {
+    /// Looks up the secret, returns `None` if there is no such entry
+    pub fn resolve(&self) -> Result<Option<SecretString>> {
+        lookup(&self.0)
+    }
+}
+
+impl FromStr for SecretRef {
+    type Err = SecretError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(KEYRING_PREFIX) {
+            Some(name) if !name.is_empty() => Ok(Self(name.into())),
+            _ => Err(SecretError::InvalidRef(s.into())),
+        }
+    }
+}
+
+impl Serialize for SecretRef {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretRef {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Self::from_str(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Display for SecretRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", KEYRING_PREFIX, self.0)
+    }
+}
+
+/// Stores the secret under the given name, replacing the existing one.
+/// The secret is passed on stdin, never in the arguments visible to other users.
+pub fn store(name: &str, secret: &SecretString) -> Result<()> {
+    let (mut cmd, input) = if cfg!(target_os = "macos") {
+        // Interactive mode reads the command from stdin
+        let mut cmd = Command::new("security");
+        cmd.arg("-i");
+        (cmd, SecretString::from(security_command(name, secret)?))
+    } else {
+        let mut cmd = Command::new("secret-tool");
+        cmd.arg("store")
+            .arg(format!("--label={} {}", KEYRING_SERVICE, name))
+            .args(["service", KEYRING_SERVICE, "account", name]);
+        (cmd, secret.clone())
+    };
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|_| SecretError::KeyringUnavailable)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(input.expose_secret().as_bytes())
+            .map_err(|e| SecretError::Keyring(e.to_string()))?;
+    }
+    check_status(child.wait_with_output()?)?;
+
+    Ok(())
+}
+
+/// Line adding the password in the interactive mode of `security`,
+/// which splits it like a shell does with double quotes
+fn security_command(name: &str, secret: &SecretString) -> Result<String, SecretError> {
+    let quote = |value: &str| -> Result<String, SecretError> {
+        if value.contains(['\n', '\r']) {
+            return Err(SecretError::Keyring("line breaks can't be stored".into()));
+        }
+        Ok(format!(
+            "\"{}\"",
+            value.replace('\\', "\\\\").replace('"', "\\\"")
+        ))
+    };
+
+    Ok(format!(
+        "add-generic-password -U -s {} -a {} -w {}\n",
+        quote(KEYRING_SERVICE)?,
+        quote(name)?,
+        quote(secret.expose_secret())?
+    ))
+}
+
+/// Removes the secret with the given name from the keyring
+pub fn remove(name: &str) -> Result<()> {
+    let output = if cfg!(target_os = "macos") {
+        Command::new("security")
+            .args(["delete-generic-password", "-s", KEYRING_SERVICE, "-a", name])
+            .output()
+    } else {
+        Command::new("secret-tool")
+            .args(["clear", "service", KEYRING_SERVICE, "account", name])
+            .output()
+    }
+    .map_err(|_| SecretError::KeyringUnavailable)?;
+    check_status(output)?;
+
+    Ok(())
+}
+
+fn lookup(name: &str) -> Result<Option<SecretString>> {
+    let output = if cfg!(target_os = "macos") {
+        Command::new("security")
+            .args([
+                "find-generic-password",
+                "-s",
+                KEYRING_SERVICE,
+                "-a",
+                name,
+                "-w",
+            ])
+            .output()
+    } else {
+        Command::new("secret-tool")
+            .args(["lookup", "service", KEYRING_SERVICE, "account", name])
+            .output()
+    }
+    .map_err(|_| SecretError::KeyringUnavailable)?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Ok(None);
+    }
+    let secret =
+        String::from_utf8(output.stdout).map_err(|e| SecretError::Keyring(e.to_string()))?;
+
+    Ok(Some(SecretString::from(secret.trim_end_matches('\n'))))
+}
+
+#[inline]
+fn check_status(output: std::process::Output) -> Result<(), SecretError> {
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    Err(SecretError::Keyring(stderr.trim().into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_security_command() {
+        let secret = SecretString::from(r#"p"a\ss w"#);
+        assert_eq!(
+            security_command("db", &secret).unwrap(),
+            format!(
+                "add-generic-password -U -s \"{}\" -a \"db\" -w \"p\\\"a\\\\ss w\"\n",
+                KEYRING_SERVICE
+            )
+        );
+        assert!(security_command("db", &SecretString::from("a\nb")).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::process::Command;
+
+use anyhow::Result;
+use log::info;
+
+use crate::error::SyncError;
+use crate::storage::provider::{WORK_DIR, ensure_work_dir};
+
+const REMOTE_NAME: &str = "origin";
+/// Only the config and the known hosts are shared, everything else in the work dir
+/// (private keys, the cache, backups, logs, sockets of forwards) stays on the machine.
+/// Lines the user adds below these can share more files.
+const SHARED: [&str; 4] = ["/*", "!/.gitignore", "!/config.yml", "!/known_hosts"];
+/// End of the first line of PEM and OpenSSH private keys
+const PRIVATE_KEY_MARKER: &str = "PRIVATE KEY-----";
+
+/// Keeps the work directory in a git repository and exchanges it with the remote:
+/// commits local changes, rebases them onto the remote ones and pushes the result.
+pub fn sync_work_dir(remote: Option<String>) -> Result<()> {
+    ensure_work_dir()?;
+    if !WORK_DIR.join(".git").exists() {
+        info!("Initializing git repository in '{}'...", WORK_DIR.display());
+
+        git(&["init", "--quiet"])?;
+    }
+    update_gitignore()?;
+    if let Some(url) = remote {
+        if git(&["remote", "get-url", REMOTE_NAME]).is_ok() {
+            git(&["remote", "set-url", REMOTE_NAME, &url])?;
+        } else {
+            git(&["remote", "add", REMOTE_NAME, &url])?;
+        }
+    }
+
+    // Files committed before they were ignored
+    let tracked = git(&["ls-files", "--cached", "--ignored", "--exclude-standard"])?;
+    if !tracked.is_empty() {
+        let mut args = vec!["rm", "--cached", "--quiet", "--"];
+        args.extend(tracked.lines());
+        git(&args)?;
+    }
+    git(&["add", "--all"])?;
+    if let Some(key) = staged_private_key()? {
+        // Nothing is committed, the user decides what to do with the file
+        git(&["reset", "--quiet"])?;
+        return Err(SyncError::PrivateKey(key).into());
+    }
+    if !git(&["status", "--porcelain"])?.is_empty() {
+        let message = format!("Update from {}", hostname());
+        info!("Committing local changes...");
+
+        git(&["commit", "--quiet", "--message", &message])?;
+    }
+    if git(&["remote", "get-url", REMOTE_NAME]).is_err() {
+        info!("No remote configured, changes are kept locally");
+        return Ok(());
+    }
+
+    let branch = git(&["rev-parse", "--abbrev-ref", "HEAD"])?;
+    if !git(&["ls-remote", "--heads", REMOTE_NAME, &branch])?.is_empty() {
+        info!("Pulling remote changes...");
+
+        if let Err(e) = git(&["pull", "--rebase", "--quiet", REMOTE_NAME, &branch]) {
+            // Leave the local state untouched, the user resolves it manually
+            _ = git(&["rebase", "--abort"]);
+            return Err(SyncError::Conflict(e.to_string()).into());
+        }
+    }
+    info!("Pushing local changes...");
+    git(&["push", "--quiet", "--set-upstream", REMOTE_NAME, &branch])?;
+
+    Ok(())
+}
+
+/// Runs git inside the work directory and returns its trimmed stdout
+fn git(args: &[&str]) -> Result<String, SyncError> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(&*WORK_DIR)
+        .args(args)
+        .output()
+        .map_err(|_| SyncError::GitUnavailable)?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(SyncError::Git(stderr.trim().into()));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().into())
+}
+
+/// Puts the shared files first in `.gitignore`, keeping the user's lines after them
+fn update_gitignore() -> Result<()> {
+    let path = WORK_DIR.join(".gitignore");
+    let content = fs::read_to_string(&path).unwrap_or_default();
+    let mut updated: String = SHARED.iter().map(|line| format!("{}\n", line)).collect();
+    for line in content
+        .lines()
+        .filter(|line| !SHARED.contains(&line.trim()))
+    {
+        updated.push_str(line);
+        updated.push('\n');
+    }
+    if updated != content {
+        fs::write(path, updated)?;
+    }
+
+    Ok(())
+}
+
+/// The first file about to be committed that holds a private key
+fn staged_private_key() -> Result<Option<String>, SyncError> {
+    let staged = git(&["diff", "--cached", "--name-only", "-z"])?;
+
+    Ok(staged
+        .split('\0')
+        .filter(|name| !name.is_empty())
+        .find(|name| {
+            // Deleted files can't be read
+            fs::read_to_string(WORK_DIR.join(name))
+                .is_ok_and(|content| content.contains(PRIVATE_KEY_MARKER))
+        })
+        .map(str::to_string))
+}
+
+#[inline]
+fn hostname() -> String {
+    Command::new("hostname")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown host".into())
+}
@@ -0,0 +1,109 @@
+use owo_colors::DynColors;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_with_macros::skip_serializing_none;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::error::CliError;
+
+/// Colors of the output: a preset, of which single elements can be overridden
+#[skip_serializing_none]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Theme {
+    pub preset: Option<ThemePreset>,
+    /// Scope names
+    pub scope: Option<Color>,
+    /// Server names
+    pub server: Option<Color>,
+    /// Setting names, tree branches and annotations
+    pub attr: Option<Color>,
+    /// Setting values
+    pub value: Option<Color>,
+    /// Successful results
+    pub ok: Option<Color>,
+    /// Failed results and errors
+    pub failed: Option<Color>,
+    pub warning: Option<Color>,
+}
+
+/// Sets of colors to start from
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThemePreset {
+    /// Bright colors for dark terminal backgrounds
+    #[default]
+    Dark,
+    /// Darker colors for light terminal backgrounds
+    Light,
+    /// No colors at all
+    Plain,
+}
+
+/// Color as it's written: a name of the terminal palette (`red`, `bright cyan`)
+/// or an RGB hex code (`#ff8800`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Color {
+    pub name: String,
+    pub color: DynColors,
+}
+
+impl FromStr for Color {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let color = s
+            .parse()
+            .map_err(|_| CliError::InvalidValue("color", s.into()))?;
+
+        Ok(Self {
+            name: s.into(),
+            color,
+        })
+    }
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl Serialize for Color {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Self::from_str(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use owo_colors::AnsiColors;
+
+    #[test]
+    fn theme_colors() {
+        let theme: Theme =
+            serde_yml::from_str("preset: light\nscope: bright cyan\nvalue: '#336699'").unwrap();
+        assert_eq!(theme.preset, Some(ThemePreset::Light));
+        assert_eq!(
+            theme.scope.map(|color| color.color),
+            Some(DynColors::Ansi(AnsiColors::BrightCyan))
+        );
+        assert_eq!(
+            theme.value.map(|color| color.color),
+            Some(DynColors::Rgb(0x33, 0x66, 0x99))
+        );
+        assert!(serde_yml::from_str::<Theme>("ok: greenish").is_err());
+    }
+}